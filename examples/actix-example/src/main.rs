@@ -47,9 +47,9 @@ fn main() -> Result<(), Error> {
         .start(queue_handle.clone());
 
     // Queue our jobs
-    queue_handle.queue(MyJob::new(1, 2))?;
-    queue_handle.queue(MyJob::new(3, 4))?;
-    queue_handle.queue(MyJob::new(5, 6))?;
+    queue_handle.queue_fire_and_forget(MyJob::new(1, 2))?;
+    queue_handle.queue_fire_and_forget(MyJob::new(3, 4))?;
+    queue_handle.queue_fire_and_forget(MyJob::new(5, 6))?;
 
     // Block on Actix
     sys.run()?;
@@ -103,11 +103,13 @@ impl Processor for MyProcessor {
 
     // The number of times background-jobs should try to retry a job before giving up
     //
-    // Jobs can optionally override this value
-    const MAX_RETRIES: MaxRetries = MaxRetries::Count(1);
+    // Jobs can optionally override this value, and when it's None, the server's default is
+    // used
+    const MAX_RETRIES: Option<MaxRetries> = Some(MaxRetries::Count(1));
 
     // The logic to determine how often to retry this job if it fails
     //
-    // Jobs can optionally override this value
-    const BACKOFF_STRATEGY: Backoff = Backoff::Exponential(2);
+    // Jobs can optionally override this value, and when it's None, the server's default is
+    // used
+    const BACKOFF_STRATEGY: Option<Backoff> = Some(Backoff::Exponential(2));
 }
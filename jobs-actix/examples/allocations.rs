@@ -0,0 +1,98 @@
+//! Count the allocations made while jobs are queued, dispatched, run, and returned
+//!
+//! Every allocation in the process is counted, from the moment the jobs are queued until the
+//! last one has run, and the average per job is printed. The jobs do nothing, so the count is
+//! made up of the runtime's own work. Run it with a release build:
+//!
+//! ```text
+//! cargo run --release -p background-jobs-actix --example allocations
+//! ```
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System as Allocator},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use actix::System;
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::ok, Future};
+use serde_derive::{Deserialize, Serialize};
+
+const JOBS: usize = 1000;
+
+/// The system allocator, counting every allocation
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        Allocator.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Allocator.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Noop;
+
+#[derive(Clone)]
+struct NoopProcessor;
+
+impl Job for Noop {
+    type Processor = NoopProcessor;
+    type State = Arc<AtomicUsize>;
+
+    fn run(self, done: Arc<AtomicUsize>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        if done.fetch_add(1, Ordering::SeqCst) + 1 == JOBS {
+            System::current().stop();
+        }
+
+        Box::new(ok(()))
+    }
+}
+
+impl Processor for NoopProcessor {
+    type Job = Noop;
+
+    const NAME: &'static str = "NoopProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+fn main() -> Result<(), Error> {
+    let sys = System::new("allocations");
+    let done = Arc::new(AtomicUsize::new(0));
+    let state = done.clone();
+
+    let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+    WorkerConfig::new(move || state.clone())
+        .register(NoopProcessor)
+        .start(handle.clone());
+
+    let start = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0..JOBS {
+        handle.queue_fire_and_forget(Noop)?;
+    }
+    sys.run()?;
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - start;
+
+    println!(
+        "{} jobs made {} allocations, {} per job",
+        done.load(Ordering::SeqCst),
+        allocations,
+        allocations / JOBS
+    );
+
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Measure how long 10,000 jobs take to queue and run, queued one at a time or as a batch
+//!
+//! The jobs do nothing, so this measures the time spent storing, dispatching, and returning
+//! them. Run it with a release build:
+//!
+//! ```text
+//! cargo run --release -p background-jobs-actix --example throughput
+//! ```
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use actix::System;
+use background_jobs_actix::{QueueHandle, ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::ok, Future};
+use serde_derive::{Deserialize, Serialize};
+
+const JOBS: usize = 10_000;
+const WORKERS: u64 = 8;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Noop;
+
+#[derive(Clone)]
+struct NoopProcessor;
+
+impl Job for Noop {
+    type Processor = NoopProcessor;
+    type State = Arc<AtomicUsize>;
+
+    fn run(self, done: Arc<AtomicUsize>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        if done.fetch_add(1, Ordering::SeqCst) + 1 == JOBS {
+            System::current().stop();
+        }
+
+        Box::new(ok(()))
+    }
+}
+
+impl Processor for NoopProcessor {
+    type Job = Noop;
+
+    const NAME: &'static str = "NoopProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+fn main() -> Result<(), Error> {
+    measure("one at a time", |handle| {
+        for _ in 0..JOBS {
+            handle.queue_fire_and_forget(Noop)?;
+        }
+
+        Ok(())
+    })?;
+    measure("as a batch", |handle| handle.queue_batch(vec![Noop; JOBS]))?;
+
+    Ok(())
+}
+
+/// Queue the jobs with the given function, and print how long they took to run
+fn measure<F>(name: &str, queue: F) -> Result<(), Error>
+where
+    F: FnOnce(&QueueHandle) -> Result<(), Error>,
+{
+    let sys = System::new("throughput");
+    let done = Arc::new(AtomicUsize::new(0));
+    let state = done.clone();
+
+    let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+    WorkerConfig::new(move || state.clone())
+        .register(NoopProcessor)
+        .set_processor_count(NoopProcessor::QUEUE, WORKERS)
+        .start(handle.clone());
+
+    let start = Instant::now();
+    queue(&handle)?;
+    sys.run()?;
+
+    println!(
+        "{}: {} jobs in {:?}",
+        name,
+        done.load(Ordering::SeqCst),
+        start.elapsed()
+    );
+
+    Ok(())
+}
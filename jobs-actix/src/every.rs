@@ -1,14 +1,41 @@
-use std::time::Duration;
+use std::{
+    num::NonZeroU64,
+    time::{Duration, Instant},
+};
 
 use super::{Job, QueueHandle};
-use actix::{Actor, AsyncContext, Context};
-use log::error;
+use actix::{
+    fut::{self, wrap_future, ActorFuture},
+    Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message, Recipient,
+};
+use background_jobs_core::{Backoff, MaxRetries, NewJobInfo, Processor};
+use failure::Error;
+use futures::Future;
+use log::{debug, error};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 
 /// A type used to schedule recurring jobs.
 ///
+/// Each tick queues a copy of the job, but copies are coalesced while a previous copy is still
+/// pending. If workers fall behind, at most one copy of a recurring job waits in its queue at a
+/// time. Jobs that provide their own `unique_key` keep it, otherwise a key is derived from the
+/// processor name and the job's arguments.
+///
+/// When the process is paused for longer than the interval, for example by a sleeping laptop or
+/// a debugger, the `CatchUp` policy decides how many copies are queued for the missed ticks. By
+/// default, the missed ticks are coalesced into a single copy.
+///
+/// Copies that are already running don't coalesce new ones, so a job that runs for longer than
+/// its interval can have several copies running at once. With `skip_if_running`, there is at
+/// most one outstanding copy at a time instead: a tick is skipped while the copy queued by an
+/// earlier tick is pending, running, or waiting for a retry.
+///
+/// The recurring job stops once the actor receives the message sent by `EveryHandle::stop`.
+///
 /// ```rust,ignore
 /// let server = ServerConfig::new(storage).start();
-/// Every::new(server, Duration::from_secs(60 * 30), MyJob::new()).start();
+/// let every = EveryHandle::new(Every::new(server, Duration::from_secs(60 * 30), MyJob::new()).start());
 /// ```
 pub struct Every<J>
 where
@@ -17,6 +44,10 @@ where
     spawner: QueueHandle,
     duration: Duration,
     job: J,
+    catch_up: CatchUp,
+    skip_if_running: bool,
+    outstanding: bool,
+    next: Instant,
 }
 
 impl<J> Every<J>
@@ -28,10 +59,250 @@ where
             spawner,
             duration,
             job,
+            catch_up: CatchUp::default(),
+            skip_if_running: false,
+            outstanding: false,
+            next: Instant::now(),
+        }
+    }
+
+    /// Choose how many copies are queued for ticks missed while the process was paused
+    pub fn catch_up(mut self, catch_up: CatchUp) -> Self {
+        self.catch_up = catch_up;
+        self
+    }
+
+    /// Skip ticks until the last queued copy of the job has finished
+    ///
+    /// A copy has finished once it completes or fails for the last time. A tick that isn't
+    /// skipped queues a single copy, whatever the `CatchUp` policy. The copy is followed by its
+    /// ID, so this relies on the server's job watchers, which don't survive a restart of the
+    /// server. Defaults to `false`.
+    ///
+    /// ```rust
+    /// use std::{
+    ///     sync::{
+    ///         atomic::{AtomicUsize, Ordering},
+    ///         Arc,
+    ///     },
+    ///     thread,
+    ///     time::Duration,
+    /// };
+    ///
+    /// use actix::{Actor, System};
+    /// use background_jobs_actix::{Every, ServerConfig, WorkerConfig};
+    /// use background_jobs_core::{memory_storage, Job, Processor};
+    /// use failure::Error;
+    /// use futures::{sync::oneshot, Future};
+    /// use serde_derive::{Deserialize, Serialize};
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct Counts {
+    ///     started: Arc<AtomicUsize>,
+    ///     running: Arc<AtomicUsize>,
+    ///     most_running: Arc<AtomicUsize>,
+    /// }
+    ///
+    /// #[derive(Clone, Deserialize, Serialize)]
+    /// struct Report;
+    ///
+    /// #[derive(Clone)]
+    /// struct ReportProcessor;
+    ///
+    /// impl Job for Report {
+    ///     type Processor = ReportProcessor;
+    ///     type State = Counts;
+    ///
+    ///     // Each report takes longer than the interval between ticks
+    ///     fn run(self, counts: Counts) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         counts.started.fetch_add(1, Ordering::SeqCst);
+    ///         let running = counts.running.fetch_add(1, Ordering::SeqCst) + 1;
+    ///         counts.most_running.fetch_max(running, Ordering::SeqCst);
+    ///
+    ///         let (tx, rx) = oneshot::channel();
+    ///         thread::spawn(move || {
+    ///             thread::sleep(Duration::from_millis(1500));
+    ///             counts.running.fetch_sub(1, Ordering::SeqCst);
+    ///             let _ = tx.send(());
+    ///         });
+    ///
+    ///         Box::new(rx.from_err())
+    ///     }
+    /// }
+    ///
+    /// impl Processor for ReportProcessor {
+    ///     type Job = Report;
+    ///
+    ///     const NAME: &'static str = "ReportProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let sys = System::new("skip-if-running");
+    ///     let counts = Counts::default();
+    ///     let state = counts.clone();
+    ///
+    ///     let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+    ///     WorkerConfig::new(move || state.clone())
+    ///         .register(ReportProcessor)
+    ///         .start(handle.clone());
+    ///
+    ///     Every::new(handle, Duration::from_millis(500), Report)
+    ///         .skip_if_running(true)
+    ///         .start();
+    ///
+    ///     let system = System::current();
+    ///     thread::spawn(move || {
+    ///         thread::sleep(Duration::from_millis(2800));
+    ///         system.stop();
+    ///     });
+    ///
+    ///     sys.run()?;
+    ///
+    ///     // The second copy is only queued once the first one has finished
+    ///     assert_eq!(counts.most_running.load(Ordering::SeqCst), 1);
+    ///     assert_eq!(counts.started.load(Ordering::SeqCst), 2);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn skip_if_running(mut self, skip_if_running: bool) -> Self {
+        self.skip_if_running = skip_if_running;
+        self
+    }
+}
+
+/// How a recurring job makes up for ticks missed while the process was paused
+///
+/// ```rust
+/// use std::time::{Duration, Instant};
+///
+/// use background_jobs_actix::CatchUp;
+///
+/// let interval = Duration::from_secs(60);
+/// let due = Instant::now();
+///
+/// // A tick that fires on time queues a single copy
+/// assert_eq!(CatchUp::All.copies(due, due, interval), 1);
+///
+/// // The process was paused for five intervals past the tick
+/// let resumed = due + interval * 5 + Duration::from_secs(1);
+///
+/// assert_eq!(CatchUp::Coalesce.copies(due, resumed, interval), 1);
+/// assert_eq!(CatchUp::All.copies(due, resumed, interval), 6);
+/// assert_eq!(CatchUp::UpTo(3).copies(due, resumed, interval), 3);
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CatchUp {
+    /// Queue a single copy for any number of missed ticks
+    ///
+    /// Copies are also coalesced while a previous copy is still pending.
+    #[default]
+    Coalesce,
+
+    /// Queue one copy for every missed tick
+    ///
+    /// Copies aren't coalesced by a derived unique key, so every copy runs, even when workers
+    /// fall behind. A job's own `unique_key` still coalesces them.
+    All,
+
+    /// Queue one copy for every missed tick, up to the given number of copies
+    ///
+    /// Like `All`, copies aren't coalesced by a derived unique key.
+    UpTo(u64),
+}
+
+impl CatchUp {
+    /// The number of copies to queue for a tick that was due at `due` and fired at `now`
+    ///
+    /// Every full interval between the two is a missed tick.
+    pub fn copies(self, due: Instant, now: Instant, interval: Duration) -> u64 {
+        let ticks = ticks(due, now, interval).min(u64::MAX as u128) as u64;
+
+        match self {
+            CatchUp::Coalesce => 1,
+            CatchUp::All => ticks,
+            CatchUp::UpTo(max) => ticks.min(max.max(1)),
         }
     }
 }
 
+/// The number of ticks due between `due` and `now`, including the one at `due`
+fn ticks(due: Instant, now: Instant, interval: Duration) -> u128 {
+    let late = now.saturating_duration_since(due).as_nanos();
+
+    late / interval.as_nanos().max(1) + 1
+}
+
+impl<J> Every<J>
+where
+    J: Job + Clone + 'static,
+{
+    fn new_job(&self) -> Result<NewJobInfo, Error> {
+        let mut new_job = J::Processor::new_job(self.job.clone())?;
+
+        if new_job.unique_key().is_none() && self.catch_up == CatchUp::Coalesce {
+            let args = serde_json::to_string(&self.job)?;
+            new_job.set_unique_key(every_key(J::Processor::NAME, &args));
+        }
+
+        Ok(new_job)
+    }
+
+    /// Queue a copy of the job, and skip ticks until it has finished
+    fn queue_outstanding(&mut self, ctx: &mut Context<Self>, new_job: NewJobInfo) {
+        self.outstanding = true;
+
+        let spawner = self.spawner.clone();
+        let finished = self
+            .spawner
+            .enqueue_new_job(new_job)
+            .and_then(move |outcome| spawner.await_job(outcome.id()));
+
+        ctx.spawn(wrap_future::<_, Self>(finished).then(|res, actor, _| {
+            if let Err(e) = res {
+                error!("Failed to follow recurring job, {}", e);
+            }
+
+            actor.outstanding = false;
+            fut::ok(())
+        }));
+    }
+
+    /// Queue the copies of the job due for a tick that was due at `due` and fired at `now`
+    fn queue_copies(
+        &mut self,
+        ctx: &mut Context<Self>,
+        new_job: &NewJobInfo,
+        due: Instant,
+        now: Instant,
+    ) {
+        if !self.skip_if_running {
+            for _ in 0..self.catch_up.copies(due, now, self.duration) {
+                self.spawner.queue_new_job(new_job.clone());
+            }
+        } else if self.outstanding {
+            debug!(
+                "Skipping a tick of {}, the previous copy hasn't finished",
+                J::Processor::NAME
+            );
+        } else {
+            self.queue_outstanding(ctx, new_job.clone());
+        }
+    }
+}
+
+/// The unique key given to copies of a recurring job that don't provide their own
+///
+/// The arguments are hashed with 64-bit FNV-1a, which gives the same key in every build and on
+/// every machine, so copies queued by different versions of an application still coalesce.
+fn every_key(processor: &str, args: &str) -> String {
+    let hash = args.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+
+    format!("every-{}-{:x}", processor, hash)
+}
+
 impl<J> Actor for Every<J>
 where
     J: Job + Clone + 'static,
@@ -39,16 +310,264 @@ where
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        match self.spawner.queue(self.job.clone()) {
-            Ok(_) => (),
-            Err(_) => error!("Failed to queue job"),
+        let new_job = match self.new_job() {
+            Ok(new_job) => new_job,
+            Err(e) => {
+                error!("Failed to create recurring job, {}", e);
+                ctx.stop();
+                return;
+            }
         };
 
-        ctx.run_interval(self.duration.clone(), move |actor, _| {
-            match actor.spawner.queue(actor.job.clone()) {
-                Ok(_) => (),
-                Err(_) => error!("Failed to queue job"),
-            }
+        let now = Instant::now();
+        self.queue_copies(ctx, &new_job, now, now);
+
+        self.next = now + self.duration;
+        self.schedule(ctx, new_job);
+    }
+}
+
+impl<J> Every<J>
+where
+    J: Job + Clone + 'static,
+{
+    /// Queue copies of the job once the next tick is due
+    ///
+    /// Ticks are kept on their original schedule rather than counted from when the previous tick
+    /// fired, so a tick that fires late doesn't shift the following ones.
+    fn schedule(&mut self, ctx: &mut Context<Self>, new_job: NewJobInfo) {
+        let wait = self.next.saturating_duration_since(Instant::now());
+
+        ctx.run_later(wait, move |actor, ctx| {
+            let now = Instant::now();
+            let next = actor.next;
+            actor.queue_copies(ctx, &new_job, next, now);
+
+            // Skip past every tick that was due, whether or not it got a copy
+            let due = ticks(actor.next, now, actor.duration).min(u32::MAX as u128) as u32;
+            actor.next += actor.duration * due;
+            actor.schedule(ctx, new_job);
+        });
+    }
+}
+
+/// A handle to a recurring job, used to stop it
+///
+/// Dropping the handle leaves the recurring job running, so handles that are never used can be
+/// ignored. Copies that were already queued still run after the recurring job is stopped.
+///
+/// ```rust
+/// use std::{
+///     sync::{
+///         atomic::{AtomicUsize, Ordering},
+///         Arc,
+///     },
+///     thread,
+///     time::Duration,
+/// };
+///
+/// use actix::System;
+/// use background_jobs_actix::{ServerConfig, WorkerConfig};
+/// use background_jobs_core::{memory_storage, Job, Processor};
+/// use failure::Error;
+/// use futures::{future::ok, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct Tick;
+///
+/// #[derive(Clone)]
+/// struct TickProcessor;
+///
+/// impl Job for Tick {
+///     type Processor = TickProcessor;
+///     type State = Arc<AtomicUsize>;
+///
+///     fn run(self, ticks: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         ticks.fetch_add(1, Ordering::SeqCst);
+///         Box::new(ok(()))
+///     }
+/// }
+///
+/// impl Processor for TickProcessor {
+///     type Job = Tick;
+///
+///     const NAME: &'static str = "TickProcessor";
+///     const QUEUE: &'static str = "default";
+/// }
+///
+/// fn main() -> Result<(), Error> {
+///     let sys = System::new("every");
+///     let ticks = Arc::new(AtomicUsize::new(0));
+///     let state = ticks.clone();
+///
+///     let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+///     WorkerConfig::new(move || state.clone())
+///         .register(TickProcessor)
+///         .start(handle.clone());
+///
+///     // Copies are queued right away and after one second, then the job is stopped
+///     let every = handle.every(Duration::from_secs(1), Tick);
+///     let system = System::current();
+///
+///     thread::spawn(move || {
+///         thread::sleep(Duration::from_millis(1500));
+///         every.stop();
+///
+///         thread::sleep(Duration::from_secs(2));
+///         system.stop();
+///     });
+///
+///     sys.run()?;
+///     assert_eq!(ticks.load(Ordering::SeqCst), 2);
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct EveryHandle {
+    recipient: Recipient<StopEvery>,
+}
+
+impl EveryHandle {
+    /// Wrap the address of a started `Every`, or of another actor that stops on `StopEvery`
+    pub fn new<A>(addr: Addr<A>) -> Self
+    where
+        A: Actor<Context = Context<A>> + Handler<StopEvery>,
+    {
+        EveryHandle {
+            recipient: addr.recipient(),
+        }
+    }
+
+    /// Stop queueing copies of the job
+    ///
+    /// This does nothing if the recurring job has already stopped.
+    pub fn stop(self) {
+        let _ = self.recipient.do_send(StopEvery);
+    }
+}
+
+/// Stops a recurring job
+pub struct StopEvery;
+
+impl Message for StopEvery {
+    type Result = ();
+}
+
+impl<J> Handler<StopEvery> for Every<J>
+where
+    J: Job + Clone + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: StopEvery, ctx: &mut Self::Context) -> Self::Result {
+        ctx.stop();
+    }
+}
+
+/// A recurring job described by data instead of a `Job` type
+///
+/// This allows recurring jobs, like nightly maintenance, to be declared in a configuration file
+/// and registered at startup with `QueueHandle::register_from_descriptor`. The processor named
+/// here must be registered with the workers for the queue, and the arguments must deserialize
+/// into its job type, otherwise every copy of the job fails.
+///
+/// Like `Every`, copies are coalesced while a previous copy is still pending.
+///
+/// ```rust
+/// use background_jobs_actix::ScheduleDescriptor;
+///
+/// let descriptor: ScheduleDescriptor = serde_json::from_str(
+///     r#"{
+///         "processor": "CleanupProcessor",
+///         "queue": "maintenance",
+///         "args": { "older_than_days": 30 },
+///         "interval_secs": 86400
+///     }"#,
+/// )
+/// .unwrap();
+///
+/// assert_eq!(descriptor.interval_secs.get(), 86400);
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScheduleDescriptor {
+    /// The name of the processor that handles the job
+    pub processor: String,
+
+    /// The queue the job is added to
+    pub queue: String,
+
+    /// The arguments of the job, as they would be serialized by its `Job` type
+    #[serde(default)]
+    pub args: Value,
+
+    /// The number of seconds between copies of the job
+    pub interval_secs: NonZeroU64,
+
+    /// The maximum number of retries, or the server's default when missing
+    #[serde(default)]
+    pub max_retries: Option<MaxRetries>,
+
+    /// The backoff strategy, or the server's default when missing
+    #[serde(default)]
+    pub backoff: Option<Backoff>,
+}
+
+impl ScheduleDescriptor {
+    pub(crate) fn new_job(&self) -> Result<NewJobInfo, Error> {
+        let mut builder = NewJobInfo::builder(&self.processor, &self.queue).args(self.args.clone());
+
+        if let Some(ref max_retries) = self.max_retries {
+            builder = builder.max_retries(max_retries.clone());
+        }
+        if let Some(ref backoff) = self.backoff {
+            builder = builder.backoff(backoff.clone());
+        }
+
+        let args = serde_json::to_string(&self.args)?;
+        let builder = builder.unique_key(&every_key(&self.processor, &args));
+
+        Ok(builder.build()?)
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.get())
+    }
+}
+
+/// Queues an already created job on an interval
+pub(crate) struct Recurring {
+    spawner: QueueHandle,
+    duration: Duration,
+    new_job: NewJobInfo,
+}
+
+impl Recurring {
+    pub(crate) fn new(spawner: QueueHandle, duration: Duration, new_job: NewJobInfo) -> Self {
+        Recurring {
+            spawner,
+            duration,
+            new_job,
+        }
+    }
+}
+
+impl Actor for Recurring {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.spawner.queue_new_job(self.new_job.clone());
+
+        ctx.run_interval(self.duration, move |actor, _| {
+            actor.spawner.queue_new_job(actor.new_job.clone());
         });
     }
 }
+
+impl Handler<StopEvery> for Recurring {
+    type Result = ();
+
+    fn handle(&mut self, _: StopEvery, ctx: &mut Self::Context) -> Self::Result {
+        ctx.stop();
+    }
+}
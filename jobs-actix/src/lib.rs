@@ -1,28 +1,73 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use actix::{Actor, Addr, Arbiter, SyncArbiter};
-use background_jobs_core::{Job, Processor, ProcessorMap, Stats, Storage};
-use failure::Error;
-use futures::Future;
+use background_jobs_core::{
+    clock::{self, TimeDelta, Timestamp},
+    Backoff, EnqueueOutcome, Job, JobInfo, JobResult, MaxRetries, MissingProcessorPolicy,
+    NewJobInfo, Processor, ProcessorMap, Stats, Storage,
+};
+use failure::{Error, Fail};
+use futures::{
+    future::{join_all, Either},
+    sync::oneshot,
+    Future, Stream,
+};
+use log::{error, info};
+use tokio_timer::Interval;
 
 mod every;
 mod pinger;
 mod server;
+mod shutdown;
 mod storage;
 mod worker;
 
-pub use self::{every::Every, server::Server, worker::LocalWorker};
+pub use self::{
+    every::{CatchUp, Every, EveryHandle, ScheduleDescriptor, StopEvery},
+    server::{Server, WorkerCacheStats},
+    shutdown::ShutdownStrategy,
+    worker::{LocalWorker, Utilization},
+};
 
 use self::{
+    every::Recurring,
     pinger::Pinger,
-    server::{CheckDb, GetStats, NewJob, RequestJob, ReturningJob},
+    server::{
+        Backlog, CancelJob, CheckDb, FlushScheduled, GetStats, HealthCheck, ListDead, ListJobs,
+        ListScheduled, Maintain, NewJob, NewJobs, NextWakeup, Preemption, PurgeAll, QueueAndWatch,
+        QueueBounded, RequestJob, RequeueDead, RetryDefaults, RetryNow, RetryNowAll, ReturningJob,
+        SaveCheckpoint, SetPriority, TimedOut, WatchJob, WeightBudget, WorkerCache,
+    },
+    shutdown::ShutdownGuard,
     storage::{ActixStorage, StorageWrapper},
-    worker::Worker,
+    worker::{Gate, InFlight, RunningJobs, UtilizationMeter, Watchdog, Worker},
 };
 
+/// How often `QueueHandle::drain` checks whether the running jobs have finished
+const DRAIN_CHECK: Duration = Duration::from_millis(50);
+
 pub struct ServerConfig<S> {
     storage: S,
     threads: usize,
+    storage_retries: usize,
+    weight_budget: Option<usize>,
+    retry_defaults: RetryDefaults,
+    backlog: Option<usize>,
+    preemption: bool,
+    deterministic: bool,
+    missing_processor: MissingProcessorPolicy,
+    max_run_time: Option<Duration>,
+    checks_per_tick: Option<usize>,
+    shutdown: ShutdownStrategy,
+    max_cached_workers: Option<usize>,
+    requeue_stale: Option<Duration>,
 }
 
 impl<S> ServerConfig<S>
@@ -34,6 +79,18 @@ where
         ServerConfig {
             storage,
             threads: num_cpus::get(),
+            storage_retries: 3,
+            weight_budget: None,
+            retry_defaults: RetryDefaults::default(),
+            backlog: None,
+            preemption: false,
+            deterministic: false,
+            missing_processor: MissingProcessorPolicy::default(),
+            max_run_time: None,
+            checks_per_tick: None,
+            shutdown: ShutdownStrategy::default(),
+            max_cached_workers: None,
+            requeue_stale: None,
         }
     }
 
@@ -60,20 +117,388 @@ where
         self
     }
 
+    /// Set the number of times the server retries a failed storage operation
+    ///
+    /// When the job store returns an error while the server is handing a job to a worker, or
+    /// saving a running job's progress, the operation is retried with an increasing delay before
+    /// the error is reported. The delay starts at 20ms and doubles with each attempt, but never
+    /// goes over one second, so a large number of retries waits about a second between attempts.
+    /// Workers waiting for a job stay registered with the server either way.
+    ///
+    /// The server thread sleeps between attempts and handles no other messages meanwhile. With
+    /// the default of 3 retries, it waits at most 140ms in total, and each retry after the sixth
+    /// adds a full second.
+    ///
+    /// Storing and returning jobs are not retried, since each takes several writes, and repeating
+    /// them after a partial failure could store a job twice or count one attempt twice.
+    ///
+    /// By default, failed operations are retried 3 times.
+    pub fn storage_retries(mut self, retries: usize) -> Self {
+        self.storage_retries = retries;
+        self
+    }
+
+    /// Limit the combined weight of the jobs running at once
+    ///
+    /// Each job's weight comes from `Job::weight`, and is usually an estimate of the memory it
+    /// uses. While running jobs add up to this budget, no further weighted job is dispatched until
+    /// enough of them finish. A job heavier than the whole budget only runs while nothing else
+    /// with a weight is running.
+    ///
+    /// By default, there is no budget.
+    pub fn weight_budget(mut self, budget: usize) -> Self {
+        self.weight_budget = Some(budget);
+        self
+    }
+
+    /// Set the number of retries for jobs whose job and processor don't choose one
+    ///
+    /// This is the last fallback after `Job::max_retries` and `Processor::MAX_RETRIES`, so a
+    /// policy shared by most processors only has to be set here.
+    ///
+    /// By default, this is `MaxRetries::Count(5)`.
+    pub fn default_max_retries(mut self, max_retries: MaxRetries) -> Self {
+        self.retry_defaults.max_retries = max_retries;
+        self
+    }
+
+    /// Set the backoff strategy for jobs whose job and processor don't choose one
+    ///
+    /// This is the last fallback after `Job::backoff_strategy` and `Processor::BACKOFF_STRATEGY`.
+    ///
+    /// By default, this is `Backoff::Exponential(2)`.
+    pub fn default_backoff(mut self, backoff: Backoff) -> Self {
+        self.retry_defaults.backoff = backoff;
+        self
+    }
+
+    /// Quarantine jobs whose job and processor don't choose otherwise once they're out of
+    /// retries
+    ///
+    /// Quarantined jobs are replayed once more after the given delay, and only become dead if
+    /// that replay fails too. This is the fallback after `Processor::QUARANTINE`.
+    ///
+    /// By default, jobs aren't quarantined.
+    pub fn default_quarantine(mut self, delay: Duration) -> Self {
+        self.retry_defaults.quarantine = Some(delay);
+        self
+    }
+
+    /// Limit the number of jobs queued with `QueueHandle::try_queue` that the server hasn't
+    /// stored yet
+    ///
+    /// The server's mailbox is unbounded, so when jobs are queued faster than the job store can
+    /// take them, they pile up in memory. With a limit, `try_queue` fails with `ServerBusy`
+    /// instead of adding to the pile, so producers can slow down or shed load. Other ways of
+    /// queuing jobs are not limited, and don't count towards the limit.
+    ///
+    /// By default, there is no limit, and `try_queue` behaves like `queue_fire_and_forget`.
+    pub fn backlog_limit(mut self, limit: usize) -> Self {
+        self.backlog = Some(limit);
+        self
+    }
+
+    /// Ask low priority jobs to make room when a higher priority job can't find a free worker
+    ///
+    /// When a ready job is queued while every worker for its queue is busy, the running job with
+    /// the lowest priority below the new job's is cancelled through its `CancellationToken`.
+    /// This is cooperative: only jobs that override `Job::run_cancellable` and return an error
+    /// once cancelled yield their worker. A yielded job is put back in its queue without using
+    /// up a retry, and is counted in the `reclaimed` stat. A job that completes anyway is
+    /// recorded as complete. Only jobs run by workers started with the returned `QueueHandle`
+    /// can be preempted.
+    ///
+    /// By default, running jobs are never preempted.
+    pub fn preempt_lower_priority(mut self) -> Self {
+        self.preemption = true;
+        self
+    }
+
+    /// Choose what happens to jobs that no worker has a processor for
+    ///
+    /// When a worker takes a job whose processor isn't registered, for example because the
+    /// processor was removed while jobs for it were still stored, the job is handled according to
+    /// this policy. A job can only be recognized this way once a worker has taken it, so jobs in
+    /// queues no worker serves are left alone.
+    ///
+    /// By default, such jobs are requeued, so they keep cycling until a worker with their
+    /// processor takes them.
+    pub fn on_missing_processor(mut self, policy: MissingProcessorPolicy) -> Self {
+        self.missing_processor = policy;
+        self
+    }
+
+    /// Fail jobs that run for longer than the given duration
+    ///
+    /// Running jobs are checked about once per second. A job that has been running for longer
+    /// than its own `Job::timeout`, or this duration for jobs without one, is failed as if it had
+    /// returned an error, and is asked to stop through its `CancellationToken`. This works even
+    /// for jobs that block and can't be interrupted: their worker stays busy until the job
+    /// returns, but the job is no longer considered running, so it can be retried, and its late
+    /// result is discarded. Only jobs run by workers started with the returned `QueueHandle` are
+    /// checked. Workers for blocking jobs should be started in their own arbiter with
+    /// `WorkerConfig::start_in_arbiter`, since a job blocking the arbiter the server was started
+    /// in also holds up the server, and this check, until it returns.
+    ///
+    /// By default, only jobs with their own timeout are limited.
+    pub fn max_run_time(mut self, max_run_time: Duration) -> Self {
+        self.max_run_time = Some(max_run_time);
+        self
+    }
+
+    /// Set how many checks for ready jobs are made every second
+    ///
+    /// Each check asks one server thread to hand ready jobs to the workers waiting for them, so
+    /// more checks get waiting workers busy sooner, at the cost of more requests to the job store.
+    /// Storages with cheap lookups can take more checks than server threads, while storages with
+    /// expensive ones may fall behind if there are too many, and let checks pile up. Jobs are also
+    /// dispatched as they are queued and as workers finish, so this mostly matters for scheduled
+    /// jobs and retries. At least one check is made every second.
+    ///
+    /// By default, one check per server thread is made every second.
+    pub fn checks_per_tick(mut self, checks: usize) -> Self {
+        self.checks_per_tick = Some(checks);
+        self
+    }
+
+    /// Choose what happens to jobs that are still running when the actix `System` stops
+    ///
+    /// The strategy is applied when the arbiter the server was started from stops, which happens
+    /// when the `System` stops, using the job store directly. It only covers jobs run by workers
+    /// started with the returned `QueueHandle`, and a process that is killed without stopping its
+    /// `System` skips it.
+    ///
+    /// By default, running jobs are abandoned and stay marked as running in the job store.
+    pub fn on_shutdown(mut self, strategy: ShutdownStrategy) -> Self {
+        self.shutdown = strategy;
+        self
+    }
+
+    /// Requeue the jobs left running by a previous process when the server starts
+    ///
+    /// A process that dies while jobs are running, without getting to apply its
+    /// `ShutdownStrategy`, leaves them marked as running in the job store. With this set, every
+    /// running job that hasn't changed state for longer than the given duration is put back in its
+    /// queue by `Storage::requeue_stale_jobs` before the server starts, and counted in the
+    /// `reclaimed` stat. Jobs still running in another process sharing the job store are
+    /// requeued too once they are that old, so the duration should be longer than any job takes.
+    ///
+    /// By default, running jobs are left as they are.
+    pub fn requeue_stale_after(mut self, older_than: Duration) -> Self {
+        self.requeue_stale = Some(older_than);
+        self
+    }
+
+    /// Limit the number of workers each server thread keeps waiting for a job from a queue
+    ///
+    /// Workers that stopped are dropped from the cache as they're found, but workers that are
+    /// lost without stopping keep their entry. When a queue goes over this cap, a warning is
+    /// logged and the workers that have waited the longest are evicted, and counted in
+    /// `WorkerCacheStats::evicted`. Evicted workers don't get any more jobs, so the cap should be
+    /// above the number of workers started for the queue.
+    ///
+    /// By default, there is no cap.
+    pub fn max_cached_workers(mut self, max: usize) -> Self {
+        self.max_cached_workers = Some(max);
+        self
+    }
+
+    /// Run the server in a single thread, checking for ready jobs only once per second
+    ///
+    /// This is meant for tests that depend on the order jobs are dispatched in. With one server
+    /// thread, messages from the `QueueHandle` and the workers are handled one at a time, in the
+    /// order they arrive, so a single worker runs ready jobs strictly by priority and then by
+    /// creation order. This overrides `thread_count`. The server also stops scheduling the extra
+    /// checks it makes when a scheduled job or retry becomes ready, so those are dispatched by the
+    /// next once-per-second check instead of right away.
+    ///
+    /// By default, the server runs `thread_count` threads, and dispatches scheduled jobs as soon
+    /// as they become ready.
+    pub fn deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
+
     /// Spin up the server processes
+    ///
+    /// The server runs on its own `SyncArbiter`, with `thread_count` threads that block on the
+    /// job store. It can't run on threads managed by the application, since actix only runs
+    /// synchronous actors on threads it starts itself. To keep the thread count under control,
+    /// start a single server and clone the returned `QueueHandle` wherever jobs are queued or
+    /// workers are started, such as into each actix-web worker's state, rather than starting a
+    /// server per thread.
+    ///
+    /// Every server thread shares the same job store, so the store is accessed concurrently by
+    /// all of them. Its own locking decides how much of that work happens in parallel: the
+    /// memory storage serializes every operation behind one lock, and the sled storage locks
+    /// each queue while a job is requested from it. Threads beyond what the store can serve in
+    /// parallel only wait on those locks.
     pub fn start(self) -> QueueHandle {
-        let ServerConfig { storage, threads } = self;
+        let ServerConfig {
+            storage,
+            threads,
+            storage_retries,
+            weight_budget,
+            retry_defaults,
+            backlog,
+            preemption,
+            deterministic,
+            missing_processor,
+            max_run_time,
+            checks_per_tick,
+            shutdown,
+            max_cached_workers,
+            requeue_stale,
+        } = self;
+        let threads = if deterministic { 1 } else { threads };
+        let checks = checks_per_tick.unwrap_or(threads).max(1);
+        let maintenance = storage.maintenance_interval();
+        let paused = Arc::new(AtomicBool::new(false));
+        let draining = Arc::new(AtomicBool::new(false));
+        let budget = weight_budget.map(|max| Arc::new(Mutex::new(WeightBudget::new(max))));
+        let watchers = Arc::new(Mutex::new(HashMap::new()));
+        let affinity = Arc::new(Mutex::new(HashMap::new()));
+        let running: RunningJobs = Arc::new(Mutex::new(HashMap::new()));
+        let preemption = if preemption {
+            Some(Preemption::new(running.clone()))
+        } else {
+            None
+        };
+        let overdue = Arc::new(Mutex::new(HashSet::new()));
+        let worker_cache = WorkerCache::new(max_cached_workers);
+        let server_cache = worker_cache.clone();
+        let server_paused = paused.clone();
+        let server_draining = draining.clone();
+        let shutdown_storage = storage.clone();
+
+        if let Some(older_than) = requeue_stale {
+            requeue_stale_jobs(storage.clone(), older_than);
+        }
 
         let server = SyncArbiter::start(threads, move || {
-            Server::new(StorageWrapper(storage.clone()))
+            Server::new(
+                StorageWrapper(storage.clone()),
+                server_paused.clone(),
+                storage_retries,
+                budget.clone(),
+                watchers.clone(),
+                retry_defaults.clone(),
+                affinity.clone(),
+            )
+            .with_draining(server_draining.clone())
+            .with_preemption(preemption.clone())
+            .with_missing_processor(missing_processor)
+            .with_overdue(overdue.clone())
+            .with_worker_cache(server_cache.clone())
         });
 
-        Pinger::new(server.clone(), threads).start();
+        let mut pinger = Pinger::new(server.clone(), checks, maintenance)
+            .with_watchdog(Watchdog::new(running.clone(), max_run_time));
+        if deterministic {
+            pinger = pinger.without_wakeups();
+        }
+        pinger.start();
 
-        QueueHandle { inner: server }
+        if shutdown != ShutdownStrategy::Abandon {
+            ShutdownGuard::new(shutdown_storage, running.clone(), shutdown).start();
+        }
+
+        QueueHandle {
+            inner: server,
+            threads,
+            paused,
+            draining,
+            workers: Arc::new(Mutex::new(Workers::default())),
+            running,
+            returning: InFlight::default(),
+            handing_off: InFlight::default(),
+            utilization: UtilizationMeter::default(),
+            backlog: backlog.map(Backlog::new),
+            worker_cache,
+        }
     }
 }
 
+/// Put the jobs left running by a previous process back in their queue
+fn requeue_stale_jobs<S>(mut storage: S, older_than: Duration)
+where
+    S: Storage,
+{
+    match storage.requeue_stale_jobs(older_than) {
+        Ok(0) => (),
+        Ok(count) => info!("Requeued {} jobs left running by a previous process", count),
+        Err(e) => error!("Failed to requeue stale jobs, {}", e),
+    }
+}
+
+/// Register several processors with a `WorkerConfig` at once
+///
+/// `register_all!(config, A, B, C)` is the same as `config.register(A).register(B).register(C)`,
+/// so each processor's queue gets the same default worker count as with `register`.
+///
+/// ```rust
+/// use background_jobs_actix::{register_all, WorkerConfig};
+/// use background_jobs_core::{Job, Processor};
+/// use failure::Error;
+/// use futures::{future::ok, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct SendEmail;
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct ResizeImage;
+///
+/// #[derive(Clone)]
+/// struct EmailProcessor;
+///
+/// #[derive(Clone)]
+/// struct ImageProcessor;
+///
+/// impl Job for SendEmail {
+///     type Processor = EmailProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(ok(()))
+///     }
+/// }
+///
+/// impl Job for ResizeImage {
+///     type Processor = ImageProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(ok(()))
+///     }
+/// }
+///
+/// impl Processor for EmailProcessor {
+///     type Job = SendEmail;
+///
+///     const NAME: &'static str = "EmailProcessor";
+///     const QUEUE: &'static str = "email";
+/// }
+///
+/// impl Processor for ImageProcessor {
+///     type Job = ResizeImage;
+///
+///     const NAME: &'static str = "ImageProcessor";
+///     const QUEUE: &'static str = "images";
+/// }
+///
+/// let config = register_all!(WorkerConfig::new(|| ()), EmailProcessor, ImageProcessor);
+///
+/// // Every queue with workers has a processor, and every processor has workers
+/// assert!(config.validate().is_ok());
+/// ```
+#[macro_export]
+macro_rules! register_all {
+    ($config:expr, $($processor:expr),+ $(,)?) => {
+        $config$(.register($processor))+
+    };
+}
+
 /// Worker Configuration
 ///
 /// This type is used for configuring and creating workers to process jobs. Before starting the
@@ -85,7 +510,17 @@ where
     State: Clone + 'static,
 {
     processors: ProcessorMap<State>,
+    processor_queues: BTreeMap<String, String>,
     queues: BTreeMap<String, u64>,
+    pools: BTreeMap<String, Pool>,
+    gates: Vec<Gate>,
+}
+
+/// A named group of workers that share a set of queues
+#[derive(Clone)]
+struct Pool {
+    queues: Vec<Arc<str>>,
+    count: u64,
 }
 
 impl<State> WorkerConfig<State>
@@ -100,10 +535,40 @@ where
     pub fn new(state_fn: impl Fn() -> State + Send + Sync + 'static) -> Self {
         WorkerConfig {
             processors: ProcessorMap::new(Arc::new(state_fn)),
+            processor_queues: BTreeMap::new(),
             queues: BTreeMap::new(),
+            pools: BTreeMap::new(),
+            gates: Vec::new(),
         }
     }
 
+    /// Hold back the workers until the given future resolves
+    ///
+    /// Workers started from this configuration are created as usual, but don't request a job
+    /// until the future has resolved, so jobs can't run against state that is still being set
+    /// up, for example while migrations are running. To gate only some queues, configure those
+    /// queues in a separate `WorkerConfig`. When this is called several times, the workers wait
+    /// for every future. If a future fails, the error is logged and the workers never request a
+    /// job, and `start_checked` fails with it.
+    pub fn gate_on<F>(mut self, gate: F) -> Self
+    where
+        F: Future<Item = (), Error = Error> + Send + 'static,
+    {
+        let gate: Box<dyn Future<Item = (), Error = Error> + Send> = Box::new(gate);
+        self.gates.push(gate.shared());
+        self
+    }
+
+    /// Warn jobs that are close to running out of retries
+    ///
+    /// When a failed job is about to be retried, and this many retries or fewer will be left
+    /// after that retry, `Job::on_nearing_exhaustion` is called after `Job::on_retry`. This gives
+    /// operators some lead time before the job fails for the last time.
+    pub fn warn_when_retries_left(mut self, retries: u64) -> Self {
+        self.processors.warn_when_retries_left(retries);
+        self
+    }
+
     /// Register a `Processor` with the worker
     ///
     /// This enables the worker to handle jobs associated with this processor. If a processor is
@@ -115,6 +580,8 @@ where
         J: Job<State = State>,
     {
         self.queues.insert(P::QUEUE.to_owned(), 4);
+        self.processor_queues
+            .insert(P::NAME.to_owned(), P::QUEUE.to_owned());
         self.processors.register_processor(processor);
         self
     }
@@ -130,48 +597,293 @@ where
         self
     }
 
+    /// Add a named pool of workers that take jobs from several queues
+    ///
+    /// Each of the `count` workers in the pool takes jobs from any of the given queues, checking
+    /// them in order, and never from any other queue. Pools are independent of the workers set
+    /// up per queue and of each other, even when their queues overlap, so slow jobs can be kept
+    /// from tying up the workers meant for fast ones. Adding a pool with an existing name replaces
+    /// it.
+    pub fn add_pool(mut self, name: &str, queues: &[&str], count: u64) -> Self {
+        let queues = queues.iter().map(|queue| Arc::from(*queue)).collect();
+        self.pools.insert(name.to_owned(), Pool { queues, count });
+        self
+    }
+
+    /// Check the configuration for queues and processors that don't line up
+    ///
+    /// This reports every queue that has workers but no registered processor, as well as every
+    /// processor whose default queue has no workers. Only each processor's default `QUEUE` is
+    /// known here, so jobs that override their queue are not checked.
+    pub fn validate(&self) -> Result<(), Vec<ConfigIssue>> {
+        let mut issues = Vec::new();
+
+        let mut served = BTreeSet::new();
+        for (queue, count) in &self.queues {
+            if *count > 0 {
+                served.insert(queue.as_str());
+            }
+        }
+        for pool in self.pools.values() {
+            if pool.count > 0 {
+                served.extend(pool.queues.iter().map(|queue| &**queue));
+            }
+        }
+
+        for queue in &served {
+            if !self.processor_queues.values().any(|q| q == queue) {
+                issues.push(ConfigIssue::QueueWithoutProcessor((*queue).to_owned()));
+            }
+        }
+
+        for (processor, queue) in &self.processor_queues {
+            if !served.contains(queue.as_str()) {
+                issues.push(ConfigIssue::ProcessorWithoutWorkers {
+                    processor: processor.clone(),
+                    queue: queue.clone(),
+                });
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
     /// Start the workers in the current arbiter
     pub fn start(self, queue_handle: QueueHandle) {
-        let processors = self.processors.clone();
-
-        self.queues.into_iter().fold(0, |acc, (key, count)| {
-            (0..count).for_each(|i| {
-                LocalWorker::new(
-                    acc + i + 1000,
-                    key.clone(),
-                    processors.clone(),
-                    queue_handle.inner.clone(),
-                )
-                .start();
-            });
-
-            acc + count
-        });
+        for worker in self.workers(&queue_handle) {
+            worker.start();
+        }
+    }
+
+    /// Start the workers in the current arbiter, and check that they can reach the job store
+    ///
+    /// The returned future resolves once every worker has made its first job request, or fails
+    /// with the first storage error any of them ran into, after the server's storage retries. This
+    /// lets an application fail at startup instead of running with a broken job store. Workers
+    /// keep running either way. While dispatch is paused, requests don't reach the job store, so
+    /// they only show that the server can be reached.
+    pub fn start_checked(
+        self,
+        queue_handle: QueueHandle,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let readiness: Vec<_> = self
+            .workers(&queue_handle)
+            .into_iter()
+            .map(|worker| {
+                let (tx, rx) = oneshot::channel();
+                worker.with_ready(tx).start();
+                rx.then(coerce)
+            })
+            .collect();
+
+        Box::new(join_all(readiness).map(|_| ()))
     }
 
     /// Start the workers in the provided arbiter
     pub fn start_in_arbiter(self, arbiter: &Arbiter, queue_handle: QueueHandle) {
-        let processors = self.processors.clone();
-        self.queues.into_iter().fold(0, |acc, (key, count)| {
-            (0..count).for_each(|i| {
-                let processors = processors.clone();
-                let queue_handle = queue_handle.clone();
-                let key = key.clone();
-                LocalWorker::start_in_arbiter(arbiter, move |_| {
-                    LocalWorker::new(
-                        acc + i + 1000,
-                        key.clone(),
-                        processors.clone(),
-                        queue_handle.inner.clone(),
-                    )
-                });
-            });
+        for worker in self.workers(&queue_handle) {
+            LocalWorker::start_in_arbiter(arbiter, move |_| worker);
+        }
+    }
+
+    /// Bring the workers started through the given `QueueHandle` in line with this configuration
+    ///
+    /// For every queue and pool in this configuration, missing workers are started in the current
+    /// arbiter, and excess workers are told to stop. A worker that is told to stop finishes the
+    /// job it is currently processing before it stops. Queues and pools that are not part of this
+    /// configuration are left alone.
+    ///
+    /// Workers that are already running keep the processors they were started with, so processors
+    /// registered since then are only used by newly started workers.
+    pub fn apply(&self, queue_handle: QueueHandle) {
+        for (queue, count) in &self.queues {
+            let group = WorkerGroup::Queue(queue.clone());
+            let queues = vec![Arc::from(queue.as_str())];
+            self.reconcile(group, queues, *count, &queue_handle);
+        }
+
+        for (name, pool) in &self.pools {
+            let group = WorkerGroup::Pool(name.clone());
+            self.reconcile(group, pool.queues.clone(), pool.count, &queue_handle);
+        }
+    }
+
+    fn reconcile(
+        &self,
+        group: WorkerGroup,
+        queues: Vec<Arc<str>>,
+        count: u64,
+        queue_handle: &QueueHandle,
+    ) {
+        let running = queue_handle.workers.lock().unwrap().count(&group);
+
+        if running < count {
+            for _ in running..count {
+                self.worker(group.clone(), queues.clone(), queue_handle)
+                    .start();
+            }
+        } else {
+            queue_handle
+                .workers
+                .lock()
+                .unwrap()
+                .stop(&group, running - count);
+        }
+    }
+
+    /// Create every worker in this configuration
+    fn workers(&self, queue_handle: &QueueHandle) -> Vec<LocalWorker<Server, State>> {
+        let mut workers = Vec::new();
+
+        for (queue, count) in &self.queues {
+            for _ in 0..*count {
+                let group = WorkerGroup::Queue(queue.clone());
+                let queues = vec![Arc::from(queue.as_str())];
+                workers.push(self.worker(group, queues, queue_handle));
+            }
+        }
+
+        for (name, pool) in &self.pools {
+            for _ in 0..pool.count {
+                let group = WorkerGroup::Pool(name.clone());
+                workers.push(self.worker(group, pool.queues.clone(), queue_handle));
+            }
+        }
 
-            acc + count
+        workers
+    }
+
+    fn worker(
+        &self,
+        group: WorkerGroup,
+        queues: Vec<Arc<str>>,
+        queue_handle: &QueueHandle,
+    ) -> LocalWorker<Server, State> {
+        let (id, stop) = queue_handle.workers.lock().unwrap().add(group);
+
+        LocalWorker::for_queues(
+            id,
+            queues,
+            self.processors.clone(),
+            queue_handle.inner.clone(),
+        )
+        .with_stop(stop)
+        .with_running(queue_handle.running.clone())
+        .with_returning(queue_handle.returning.clone())
+        .with_handing_off(queue_handle.handing_off.clone())
+        .with_utilization(queue_handle.utilization.clone())
+        .with_gates(self.gates.clone())
+    }
+
+    /// Start the workers in the provided arbiter, pinning the arbiter's thread to a CPU core
+    ///
+    /// Keeping CPU-bound processors on a single core can improve cache locality, especially on
+    /// NUMA systems. Available cores can be listed with `core_affinity::get_core_ids`.
+    ///
+    /// Pinning is best-effort. Whether it is supported depends on the platform, and if the
+    /// thread cannot be pinned, the workers are started anyway. On Linux, where the thread's
+    /// affinity can be read back, a warning is logged when pinning fails.
+    #[cfg(feature = "core_affinity")]
+    pub fn start_in_arbiter_pinned(
+        self,
+        arbiter: &Arbiter,
+        core: core_affinity::CoreId,
+        queue_handle: QueueHandle,
+    ) {
+        arbiter.exec_fn(move || {
+            core_affinity::set_for_current(core);
+
+            if is_pinned_to(core) == Some(false) {
+                log::warn!("Could not pin the arbiter's thread to core {}", core.id);
+            }
         });
+
+        self.start_in_arbiter(arbiter, queue_handle);
+    }
+}
+
+/// Whether the current thread may only run on the given core, where the platform reports it
+#[cfg(feature = "core_affinity")]
+fn is_pinned_to(core: core_affinity::CoreId) -> Option<bool> {
+    // On Linux, the listed cores are the ones the current thread may run on
+    if cfg!(target_os = "linux") {
+        let cores = core_affinity::get_core_ids()?;
+        Some(cores.len() == 1 && cores[0].id == core.id)
+    } else {
+        None
+    }
+}
+
+/// The workers started through a `QueueHandle`, tracked so their counts can be reconciled
+#[derive(Default)]
+struct Workers {
+    next_id: u64,
+    groups: BTreeMap<WorkerGroup, Vec<Arc<AtomicBool>>>,
+}
+
+/// The part of a `WorkerConfig` a worker was started for
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum WorkerGroup {
+    /// A worker set up with `WorkerConfig::set_processor_count` or `register`
+    Queue(String),
+
+    /// A worker set up with `WorkerConfig::add_pool`
+    Pool(String),
+}
+
+impl Workers {
+    fn add(&mut self, group: WorkerGroup) -> (u64, Arc<AtomicBool>) {
+        let id = self.next_id + 1000;
+        self.next_id += 1;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        self.groups.entry(group).or_default().push(stop.clone());
+
+        (id, stop)
+    }
+
+    fn count(&self, group: &WorkerGroup) -> u64 {
+        self.groups.get(group).map(|w| w.len() as u64).unwrap_or(0)
+    }
+
+    fn stop(&mut self, group: &WorkerGroup, count: u64) {
+        if let Some(workers) = self.groups.get_mut(group) {
+            for _ in 0..count {
+                if let Some(stop) = workers.pop() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            }
+        }
     }
 }
 
+/// A problem found by `WorkerConfig::validate`
+#[derive(Clone, Debug, Eq, Fail, PartialEq)]
+pub enum ConfigIssue {
+    /// Workers are configured for a queue that no registered processor uses
+    #[fail(display = "Queue {} has workers, but no processor", _0)]
+    QueueWithoutProcessor(String),
+
+    /// A processor's queue has no workers, so its jobs will never run
+    #[fail(
+        display = "Processor {} uses queue {}, which has no workers",
+        processor, queue
+    )]
+    ProcessorWithoutWorkers { processor: String, queue: String },
+}
+
+/// The error returned by `QueueHandle::try_queue` when the server is too far behind
+#[derive(Clone, Debug, Fail)]
+#[fail(
+    display = "The job server already has {} jobs waiting to be stored",
+    _0
+)]
+pub struct ServerBusy(pub usize);
+
 /// A handle to the job server, used for queuing new jobs
 ///
 /// `QueueHandle` should be stored in your application's state in order to allow all parts of your
@@ -179,36 +891,624 @@ where
 #[derive(Clone)]
 pub struct QueueHandle {
     inner: Addr<Server>,
+    threads: usize,
+    paused: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+    workers: Arc<Mutex<Workers>>,
+    running: RunningJobs,
+    returning: InFlight,
+    handing_off: InFlight,
+    utilization: UtilizationMeter,
+    backlog: Option<Backlog>,
+    worker_cache: WorkerCache,
 }
 
 impl QueueHandle {
     /// Queues a job for execution
     ///
     /// This job will be sent to the server for storage, and will execute whenever a worker for the
-    /// job's queue is free to do so.
-    pub fn queue<J>(&self, job: J) -> Result<(), Error>
+    /// job's queue is free to do so. The returned future resolves to the job's ID once it is
+    /// stored, which can be used to look the job up later. When the job is coalesced with an
+    /// existing one, this is the ID of the existing job.
+    pub fn queue<J>(&self, job: J) -> Box<dyn Future<Item = u64, Error = Error> + Send>
+    where
+        J: Job,
+    {
+        Box::new(self.enqueue(job).map(|outcome| outcome.id()))
+    }
+
+    /// Queues a job for execution, without waiting for the server to store it
+    ///
+    /// This is `queue` for producers that don't need the job's ID. The job is sent to the server,
+    /// and only errors creating the job are returned, so a job the server then fails to store is
+    /// only logged.
+    pub fn queue_fire_and_forget<J>(&self, job: J) -> Result<(), Error>
+    where
+        J: Job,
+    {
+        self.queue_new_job(J::Processor::new_job(job)?);
+        Ok(())
+    }
+
+    /// Queues a job for execution in the given queue, instead of the one chosen by its processor
+    ///
+    /// This is useful when queues are named at runtime, like one queue per tenant. The queue
+    /// isn't checked: if no worker serves it with a processor for this job, the job stays pending
+    /// until one is started.
+    pub fn queue_on<J>(&self, queue: &str, job: J) -> Result<(), Error>
+    where
+        J: Job,
+    {
+        let mut new_job = J::Processor::new_job(job)?;
+        new_job.set_queue(queue.to_owned());
+
+        self.queue_new_job(new_job);
+        Ok(())
+    }
+
+    /// Queues a job for execution, tagged with the correlation ID of the request queuing it
+    ///
+    /// The ID is stored with the job, and included in the worker's log lines while the job is
+    /// processed, so they can be linked back to the request's own logs.
+    pub fn queue_with_context<J>(&self, job: J, trace_id: String) -> Result<(), Error>
+    where
+        J: Job,
+    {
+        let mut new_job = J::Processor::new_job(job)?;
+        new_job.set_trace_id(trace_id);
+
+        self.queue_new_job(new_job);
+        Ok(())
+    }
+
+    /// Queues a job to run once the given time has come
+    ///
+    /// The job is stored right away, but stays in its queue until it is ready, and is then
+    /// handed to the next free worker for its queue. A time in the past makes the job ready
+    /// right away.
+    ///
+    /// ```rust
+    /// use std::{
+    ///     sync::{Arc, Mutex},
+    ///     time::{Duration, Instant},
+    /// };
+    ///
+    /// use actix::System;
+    /// use background_jobs_actix::{ServerConfig, WorkerConfig};
+    /// use background_jobs_core::{clock, memory_storage, Job, Processor};
+    /// use failure::Error;
+    /// use futures::{future::ok, Future};
+    /// use serde_derive::{Deserialize, Serialize};
+    ///
+    /// #[derive(Clone, Deserialize, Serialize)]
+    /// struct Reminder;
+    ///
+    /// #[derive(Clone)]
+    /// struct ReminderProcessor;
+    ///
+    /// type RanAt = Arc<Mutex<Option<Instant>>>;
+    ///
+    /// impl Job for Reminder {
+    ///     type Processor = ReminderProcessor;
+    ///     type State = RanAt;
+    ///
+    ///     fn run(self, ran_at: RanAt) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         *ran_at.lock().unwrap() = Some(Instant::now());
+    ///         System::current().stop();
+    ///         Box::new(ok(()))
+    ///     }
+    /// }
+    ///
+    /// impl Processor for ReminderProcessor {
+    ///     type Job = Reminder;
+    ///
+    ///     const NAME: &'static str = "ReminderProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let sys = System::new("schedule");
+    ///     let ran_at = RanAt::default();
+    ///     let state = ran_at.clone();
+    ///
+    ///     let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+    ///     WorkerConfig::new(move || state.clone())
+    ///         .register(ReminderProcessor)
+    ///         .start(handle.clone());
+    ///
+    ///     let queued_at = Instant::now();
+    ///     let delay = Duration::from_millis(500);
+    ///     handle.schedule(Reminder, clock::now() + clock::TimeDelta::from_std(delay)?)?;
+    ///
+    ///     sys.run()?;
+    ///     let ran_at = ran_at.lock().unwrap().expect("The job ran");
+    ///     assert!(ran_at.duration_since(queued_at) >= delay);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn schedule<J>(&self, job: J, after: Timestamp) -> Result<(), Error>
+    where
+        J: Job,
+    {
+        self.queue_new_job(J::Processor::new_scheduled_job(job, after)?);
+        Ok(())
+    }
+
+    /// Queues a job for execution, reporting whether it was coalesced with an existing job
+    ///
+    /// Like `queue`, this waits for the server to store the job. The returned future resolves
+    /// to `EnqueueOutcome::Enqueued` with the new job's ID, or to `EnqueueOutcome::Deduplicated`
+    /// with the ID of the existing job when the job's unique, global, or idempotency key matched
+    /// one, which is useful for accurate metrics and logging on the producer's side.
+    pub fn enqueue<J>(&self, job: J) -> Box<dyn Future<Item = EnqueueOutcome, Error = Error> + Send>
+    where
+        J: Job,
+    {
+        let new_job = match J::Processor::new_job(job) {
+            Ok(new_job) => new_job,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+
+        self.enqueue_new_job(new_job)
+    }
+
+    pub(crate) fn enqueue_new_job(
+        &self,
+        new_job: NewJobInfo,
+    ) -> Box<dyn Future<Item = EnqueueOutcome, Error = Error> + Send> {
+        Box::new(self.inner.send(NewJob(new_job)).then(coerce))
+    }
+
+    /// Queues a job to run ahead of every other job in its queue
+    ///
+    /// This is meant for one-off admin actions, like running a maintenance task right away. The
+    /// job is stored with the highest possible priority and no schedule, so the next free worker
+    /// for its queue picks it up, but otherwise it is a normal job: it counts towards the stats,
+    /// its failures are retried, and it becomes dead when it runs out of retries. To run a job
+    /// synchronously without any storage, as in tests, see `run_now`.
+    pub fn run_immediate<J>(&self, job: J) -> Result<(), Error>
     where
         J: Job,
     {
-        self.inner.do_send(NewJob(J::Processor::new_job(job)?));
+        let mut new_job = J::Processor::new_job(job)?;
+        new_job.set_priority(i32::MAX);
+
+        self.queue_new_job(new_job);
         Ok(())
     }
 
+    /// Queues a job for execution, unless the server is too far behind
+    ///
+    /// When a backlog limit is set with `ServerConfig::backlog_limit`, and that many jobs queued
+    /// with this method are still waiting to be stored, the job is not sent and `ServerBusy` is
+    /// returned. Without a limit, this is the same as `queue_fire_and_forget`.
+    pub fn try_queue<J>(&self, job: J) -> Result<(), Error>
+    where
+        J: Job,
+    {
+        let new_job = J::Processor::new_job(job)?;
+
+        match self.backlog {
+            Some(ref backlog) => match backlog.acquire() {
+                Some(permit) => self.inner.do_send(QueueBounded(new_job, permit)),
+                None => return Err(ServerBusy(backlog.capacity()).into()),
+            },
+            None => self.queue_new_job(new_job),
+        }
+
+        Ok(())
+    }
+
+    /// Queues a job for execution, and waits for it to finish
+    ///
+    /// The returned future resolves once the job has completed, or has failed for the last time.
+    /// Failures that are retried do not resolve it. If the job is coalesced with an existing job
+    /// through its unique key, the future resolves with the result of that job, and if its
+    /// idempotency key shows it has already completed, the future resolves right away.
+    pub fn queue_and_watch<J>(
+        &self,
+        job: J,
+    ) -> Box<dyn Future<Item = JobResult, Error = Error> + Send>
+    where
+        J: Job,
+    {
+        let new_job = match J::Processor::new_job(job) {
+            Ok(new_job) => new_job,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+
+        let (tx, rx) = oneshot::channel();
+
+        Box::new(
+            self.inner
+                .send(QueueAndWatch(new_job, tx))
+                .then(coerce)
+                .and_then(|_| rx.from_err()),
+        )
+    }
+
+    /// Wait for an already queued job to finish
+    ///
+    /// This is like `queue_and_watch`, for jobs that were queued elsewhere and are only known by
+    /// their ID. The returned future resolves with the job's final result once it completes or
+    /// runs out of retries, or with `None` right away if no job with this ID is stored, for
+    /// example because it has already finished.
+    pub fn await_job(
+        &self,
+        id: u64,
+    ) -> Box<dyn Future<Item = Option<JobResult>, Error = Error> + Send> {
+        let (tx, rx) = oneshot::channel();
+
+        Box::new(
+            self.inner
+                .send(WatchJob(id, tx))
+                .then(coerce)
+                .and_then(move |watching| {
+                    if watching {
+                        Either::A(rx.map(Some).from_err())
+                    } else {
+                        Either::B(futures::future::ok(None))
+                    }
+                }),
+        )
+    }
+
+    /// Queues several jobs for execution
+    ///
+    /// All of the jobs are sent to the server in a single message, which is much cheaper than
+    /// calling `queue` for each job when many jobs are created at once.
+    pub fn queue_batch<J>(&self, jobs: Vec<J>) -> Result<(), Error>
+    where
+        J: Job,
+    {
+        let new_jobs = jobs
+            .into_iter()
+            .map(J::Processor::new_job)
+            .collect::<Result<_, _>>()?;
+
+        self.inner.do_send(NewJobs(new_jobs));
+        Ok(())
+    }
+
+    /// Queues several jobs, spreading their execution evenly across the given window
+    ///
+    /// The first job is scheduled for the current time, and each following job a little later
+    /// than the previous one, so that the last job becomes ready just before the window ends.
+    /// This is useful for rate-controlled work, such as sending a large number of emails over
+    /// an hour, without causing a burst of jobs at once.
+    pub fn queue_batch_scheduled<J>(&self, jobs: Vec<J>, window: Duration) -> Result<(), Error>
+    where
+        J: Job,
+    {
+        let now = clock::now();
+        let step = TimeDelta::from_std(window)? / jobs.len().max(1) as i32;
+
+        let new_jobs = jobs
+            .into_iter()
+            .enumerate()
+            .map(|(i, job)| J::Processor::new_scheduled_job(job, now + step * i as i32))
+            .collect::<Result<_, _>>()?;
+
+        self.inner.do_send(NewJobs(new_jobs));
+        Ok(())
+    }
+
+    pub(crate) fn queue_new_job(&self, new_job: NewJobInfo) {
+        self.inner.do_send(NewJob(new_job));
+    }
+
     /// Queues a job for recurring execution
     ///
     /// This job will be added to it's queue on the server once every `Duration`. It will be
-    /// processed whenever workers are free to do so.
-    pub fn every<J>(&self, duration: Duration, job: J)
+    /// processed whenever workers are free to do so, until the returned handle is stopped.
+    pub fn every<J>(&self, duration: Duration, job: J) -> EveryHandle
     where
         J: Job + Clone + 'static,
     {
-        Every::new(self.clone(), duration, job).start();
+        EveryHandle::new(Every::new(self.clone(), duration, job).start())
+    }
+
+    /// Queues a job described by a `ScheduleDescriptor` for recurring execution
+    ///
+    /// This behaves like `every`, but the job comes from data, such as a configuration file. This
+    /// fails if the descriptor has an empty processor name or queue.
+    pub fn register_from_descriptor(
+        &self,
+        descriptor: &ScheduleDescriptor,
+    ) -> Result<EveryHandle, Error> {
+        let new_job = descriptor.new_job()?;
+        let addr = Recurring::new(self.clone(), descriptor.interval(), new_job).start();
+
+        Ok(EveryHandle::new(addr))
+    }
+
+    /// Dispatch any ready jobs to waiting workers immediately
+    ///
+    /// The server normally checks for jobs that have become ready, such as scheduled jobs and
+    /// retries, once per second. This triggers that check right away, which is useful in tests
+    /// and for manual operations. The returned future resolves once the check has completed.
+    pub fn flush_scheduled(&self) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let requests: Vec<_> = (0..self.threads)
+            .map(|_| self.inner.send(FlushScheduled))
+            .collect();
+
+        Box::new(join_all(requests).map(|_| ()).from_err())
+    }
+
+    /// Stop dispatching jobs from every queue
+    ///
+    /// Jobs can still be queued while dispatch is paused, but no worker will be handed a new job
+    /// until `resume_all` is called. Jobs that are already running will finish normally. This is
+    /// useful as a circuit breaker when a dependency shared by many jobs is unavailable.
+    pub fn pause_all(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resume dispatching jobs after a call to `pause_all`
+    ///
+    /// This doesn't resume dispatch after a call to `drain`.
+    pub fn resume_all(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+
+        for _ in 0..self.threads {
+            self.inner.do_send(CheckDb);
+        }
+    }
+
+    /// Stop dispatching jobs for good, and wait for the running jobs to finish
+    ///
+    /// The server stops handing out jobs right away, even to workers that request one, but jobs
+    /// can still be queued and are kept pending. The returned future resolves once every job run
+    /// by the workers started with this handle has finished, and its result has been stored.
+    /// Jobs that were handed to a worker just before draining started count as running, so
+    /// nothing is left running when the actix `System` is stopped afterwards. This is meant for
+    /// shutting down cleanly, for example during a rolling deploy.
+    ///
+    /// ```rust
+    /// use std::{
+    ///     sync::{
+    ///         atomic::{AtomicBool, Ordering},
+    ///         Arc,
+    ///     },
+    ///     thread,
+    ///     time::{Duration, Instant},
+    /// };
+    ///
+    /// use actix::System;
+    /// use background_jobs_actix::{ServerConfig, WorkerConfig};
+    /// use background_jobs_core::{memory_storage, Job, Processor, Storage};
+    /// use failure::Error;
+    /// use futures::{sync::oneshot, Future};
+    /// use serde_derive::{Deserialize, Serialize};
+    /// use tokio_timer::Delay;
+    ///
+    /// #[derive(Clone, Deserialize, Serialize)]
+    /// struct Slow;
+    ///
+    /// #[derive(Clone)]
+    /// struct SlowProcessor;
+    ///
+    /// impl Job for Slow {
+    ///     type Processor = SlowProcessor;
+    ///     type State = Arc<AtomicBool>;
+    ///
+    ///     fn run(self, finished: Arc<AtomicBool>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         let (tx, rx) = oneshot::channel();
+    ///         thread::spawn(move || {
+    ///             thread::sleep(Duration::from_millis(500));
+    ///             finished.store(true, Ordering::SeqCst);
+    ///             let _ = tx.send(());
+    ///         });
+    ///
+    ///         Box::new(rx.from_err())
+    ///     }
+    /// }
+    ///
+    /// impl Processor for SlowProcessor {
+    ///     type Job = Slow;
+    ///
+    ///     const NAME: &'static str = "SlowProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let sys = System::new("drain");
+    ///     let finished = Arc::new(AtomicBool::new(false));
+    ///     let state = finished.clone();
+    ///
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     let handle = ServerConfig::new(storage.clone()).start();
+    ///     WorkerConfig::new(move || state.clone())
+    ///         .register(SlowProcessor)
+    ///         .start(handle.clone());
+    ///
+    ///     handle.queue_fire_and_forget(Slow)?;
+    ///
+    ///     // Drain once the job has started running
+    ///     let drained = handle.clone();
+    ///     actix::spawn(
+    ///         Delay::new(Instant::now() + Duration::from_millis(100))
+    ///             .from_err()
+    ///             .and_then(move |_| drained.drain())
+    ///             .then(|res| {
+    ///                 res.expect("Drained");
+    ///                 System::current().stop();
+    ///                 Ok(())
+    ///             }),
+    ///     );
+    ///
+    ///     sys.run()?;
+    ///
+    ///     // The running job finished, and its result was stored
+    ///     assert!(finished.load(Ordering::SeqCst));
+    ///     assert!(storage.list_jobs()?.is_empty());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn drain(&self) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        self.draining.store(true, Ordering::Relaxed);
+
+        let running = self.running.clone();
+        let returning = self.returning.clone();
+        let handing_off = self.handing_off.clone();
+
+        let drained = Interval::new_interval(DRAIN_CHECK)
+            .from_err()
+            .skip_while(move |_| {
+                Ok(!running.lock().unwrap().is_empty()
+                    || !returning.is_empty()
+                    || !handing_off.is_empty())
+            })
+            .into_future()
+            .map(|_| ())
+            .map_err(|(e, _)| e);
+
+        Box::new(drained)
+    }
+
+    /// Ask a running job to stop early
+    ///
+    /// This cancels the `CancellationToken` given to the job's `Job::run_cancellable`. The job
+    /// keeps running until it notices the token and returns, and its result is then handled as
+    /// usual. Only jobs run by workers started with this handle can be cancelled. Returns whether
+    /// the job was found running.
+    pub fn cancel_running(&self, id: u64) -> bool {
+        match self.running.lock().unwrap().get(&id) {
+            Some(job) => {
+                job.token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How long the workers started through this handle have spent running jobs, and waiting
+    ///
+    /// The durations are added up across workers, and keep growing for as long as the workers run.
+    /// Comparing two readings with `Utilization::since` gives the utilization over the time
+    /// between them. A queue that keeps growing while utilization stays low points at jobs not
+    /// being dispatched, while high utilization means more workers are needed.
+    pub fn utilization(&self) -> Utilization {
+        self.utilization.total()
+    }
+
+    /// How many workers the servers are keeping while they wait for a job
+    ///
+    /// See `WorkerCacheStats` for how this helps notice workers leaking.
+    pub fn worker_cache(&self) -> WorkerCacheStats {
+        self.worker_cache.stats()
     }
 
     /// Return an overview of the processor's statistics
     pub fn get_stats(&self) -> Box<dyn Future<Item = Stats, Error = Error> + Send> {
         Box::new(self.inner.send(GetStats).then(coerce))
     }
+
+    /// Check that the job store is usable
+    ///
+    /// The returned future fails if the storage backend reports an error, which makes it
+    /// suitable for readiness probes.
+    pub fn health_check(&self) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(self.inner.send(HealthCheck).then(coerce))
+    }
+
+    /// Delete every job from storage, and reset the pending and running counts in the stats
+    ///
+    /// This deletes pending, scheduled, running, and dead jobs alike, and cannot be undone. It is
+    /// meant for test teardown and development resets, never for a production job store. Jobs
+    /// that are running when this is called still finish, but their results are discarded, and
+    /// callers waiting on `queue_and_watch` see their result dropped.
+    pub fn danger_purge_all(&self) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(self.inner.send(PurgeAll).then(coerce))
+    }
+
+    /// Change the priority of a pending job
+    ///
+    /// The returned future resolves to whether a pending job with the given ID was found. Jobs
+    /// that have already started running are not affected.
+    pub fn set_priority(
+        &self,
+        id: u64,
+        priority: i32,
+    ) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+        Box::new(self.inner.send(SetPriority { id, priority }).then(coerce))
+    }
+
+    /// Delete a job before a worker picks it up
+    ///
+    /// This is useful when the action that queued a job is undone. Only pending jobs, including
+    /// scheduled jobs and jobs waiting for a retry, are deleted: cancelling a job that is already
+    /// running, or that doesn't exist, does nothing. The returned future resolves to whether a
+    /// pending job with the given ID was deleted. Futures watching a cancelled job, like the one
+    /// returned by `queue_and_watch`, fail.
+    pub fn cancel(&self, id: u64) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+        Box::new(self.inner.send(CancelJob(id)).then(coerce))
+    }
+
+    /// Make a pending job ready right away
+    ///
+    /// This is useful when a job is waiting out a long backoff for a dependency that has
+    /// recovered. The job's scheduled time is cleared, and it is handed to a free worker right
+    /// away if there is one. The returned future resolves to whether a pending job with the given
+    /// ID was found. A retry that runs early still counts towards the job's retries.
+    pub fn retry_now(&self, id: u64) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+        Box::new(self.inner.send(RetryNow(id)).then(coerce))
+    }
+
+    /// Make every pending job that is waiting for a scheduled time ready right away
+    ///
+    /// This is `retry_now` for every scheduled job and backed-off retry at once, limited to the
+    /// given queue if there is one, which is useful once a dependency shared by many jobs has
+    /// recovered. The returned future resolves to the number of jobs that were made ready.
+    pub fn retry_all_now(
+        &self,
+        queue: Option<&str>,
+    ) -> Box<dyn Future<Item = usize, Error = Error> + Send> {
+        let queue = queue.map(|queue| queue.to_owned());
+
+        Box::new(self.inner.send(RetryNowAll(queue)).then(coerce))
+    }
+
+    /// Return every job currently held in storage
+    ///
+    /// Each job's `history` describes the transitions it has gone through, which is useful for
+    /// debugging jobs that fail intermittently.
+    pub fn list_jobs(&self) -> Box<dyn Future<Item = Vec<JobInfo>, Error = Error> + Send> {
+        Box::new(self.inner.send(ListJobs).then(coerce))
+    }
+
+    /// Return every pending job that is waiting for a scheduled time in the future
+    ///
+    /// This includes jobs queued for a later time, as well as failed jobs waiting to be retried.
+    pub fn list_scheduled(&self) -> Box<dyn Future<Item = Vec<JobInfo>, Error = Error> + Send> {
+        Box::new(self.inner.send(ListScheduled).then(coerce))
+    }
+
+    /// Return every job that failed too many times
+    pub fn list_dead(&self) -> Box<dyn Future<Item = Vec<JobInfo>, Error = Error> + Send> {
+        Box::new(self.inner.send(ListDead).then(coerce))
+    }
+
+    /// Move a job that failed too many times back into its queue
+    ///
+    /// The job's retry count is reset. Its maximum retries and backoff strategy can be replaced,
+    /// which is useful for giving replayed jobs a more generous retry budget than the one they
+    /// already exhausted. The returned future resolves to whether a dead job with the given ID was
+    /// found.
+    pub fn requeue_dead(
+        &self,
+        id: u64,
+        max_retries: Option<MaxRetries>,
+        backoff: Option<Backoff>,
+    ) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+        let msg = RequeueDead {
+            id,
+            max_retries,
+            backoff,
+        };
+
+        Box::new(self.inner.send(msg).then(coerce))
+    }
 }
 
 fn coerce<I, E, F>(res: Result<Result<I, E>, F>) -> Result<I, E>
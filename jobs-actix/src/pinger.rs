@@ -1,16 +1,107 @@
-use actix::{Actor, Addr, AsyncContext, Context};
-use std::time::Duration;
+use actix::{
+    fut::{wrap_future, ActorFuture},
+    Actor, Addr, AsyncContext, Context,
+};
+use background_jobs_core::clock::{self, JumpDetector};
+use log::{error, warn};
+use std::time::{Duration, Instant};
 
-use crate::{CheckDb, Server};
+use crate::{CheckDb, Maintain, NextWakeup, Server, TimedOut, Watchdog};
+
+const INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Pinger {
     server: Addr<Server>,
-    threads: usize,
+    checks: usize,
+    maintenance: Option<Duration>,
+    wakeups: bool,
+    watchdog: Option<Watchdog>,
+    jumps: JumpDetector,
 }
 
 impl Pinger {
-    pub fn new(server: Addr<Server>, threads: usize) -> Self {
-        Pinger { server, threads }
+    /// Create a pinger sending the given number of checks for ready jobs on every tick
+    pub fn new(server: Addr<Server>, checks: usize, maintenance: Option<Duration>) -> Self {
+        Pinger {
+            server,
+            checks,
+            maintenance,
+            wakeups: true,
+            watchdog: None,
+            jumps: JumpDetector::new(INTERVAL),
+        }
+    }
+
+    /// Only check for ready jobs on the regular interval, and not when scheduled jobs are due
+    pub fn without_wakeups(mut self) -> Self {
+        self.wakeups = false;
+        self
+    }
+
+    /// Fail the running jobs found by the given watchdog on every check
+    pub fn with_watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Fail the running jobs that have gone over their time limit
+    fn check_overdue(&self) {
+        if let Some(ref watchdog) = self.watchdog {
+            for id in watchdog.overdue() {
+                error!("Job {} went over its time limit, failing it", id);
+                self.server.do_send(TimedOut(id));
+            }
+        }
+    }
+
+    /// Log when the system clock has been changed since the previous check
+    fn check_clock(&mut self) {
+        let jump = match self.jumps.observe(clock::now(), Instant::now()) {
+            Some(jump) => jump,
+            None => return,
+        };
+
+        if jump < clock::TimeDelta::zero() {
+            warn!(
+                "System clock was set back by {}s, jobs scheduled since are delayed by as much",
+                -jump.num_seconds()
+            );
+        } else {
+            warn!(
+                "System clock was set forward by {}s, jobs scheduled until then are ready now",
+                jump.num_seconds()
+            );
+        }
+    }
+
+    fn check_db(&self) {
+        for _ in 0..self.checks {
+            self.server.do_send(CheckDb);
+        }
+    }
+
+    /// Schedule an extra check for when the next scheduled job becomes ready, if that happens
+    /// before the next regular check
+    fn schedule_wakeup(&self, ctx: &mut Context<Self>) {
+        let fut = wrap_future::<_, Self>(self.server.send(NextWakeup)).map(|res, _, ctx| {
+            let wakeup = match res {
+                Ok(wakeup) => wakeup,
+                Err(e) => {
+                    error!("Failed to fetch next wakeup, {}", e);
+                    None
+                }
+            };
+
+            let delay = wakeup
+                .and_then(|time| time.signed_duration_since(clock::now()).to_std().ok())
+                .filter(|delay| *delay < INTERVAL);
+
+            if let Some(delay) = delay {
+                ctx.run_later(delay, |actor, _| actor.check_db());
+            }
+        });
+
+        ctx.spawn(fut.map_err(|e, _, _| error!("Server is unreachable, {}", e)));
     }
 }
 
@@ -18,10 +109,19 @@ impl Actor for Pinger {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.run_interval(Duration::from_secs(1), |actor, _| {
-            for _ in 0..actor.threads {
-                actor.server.do_send(CheckDb);
+        ctx.run_interval(INTERVAL, |actor, ctx| {
+            actor.check_clock();
+            actor.check_overdue();
+            actor.check_db();
+
+            if actor.wakeups {
+                actor.schedule_wakeup(ctx);
             }
         });
+
+        // Maintenance is handled by a single server thread, so the others keep serving workers
+        if let Some(interval) = self.maintenance {
+            ctx.run_interval(interval, |actor, _| actor.server.do_send(Maintain));
+        }
     }
 }
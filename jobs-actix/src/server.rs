@@ -1,27 +1,650 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 use actix::{Actor, Handler, Message, SyncContext};
-use background_jobs_core::{NewJobInfo, ReturnJobInfo, Stats};
+use background_jobs_core::{
+    clock::Timestamp, Backoff, EnqueueOutcome, JobInfo, JobResult, MaxRetries,
+    MissingProcessorPolicy, NewJobInfo, ReturnJobInfo, Stats,
+};
 use failure::Error;
-use log::trace;
+use futures::sync::oneshot;
+use log::{error, trace, warn};
 use serde_derive::Deserialize;
+use serde_json::Value;
 
-use crate::{ActixStorage, Worker};
+use crate::{ActixStorage, RunningJobs, Worker};
 
 pub struct Server {
     storage: Box<dyn ActixStorage + Send>,
-    cache: HashMap<String, VecDeque<Box<dyn Worker + Send>>>,
+    cache: HashMap<Arc<str>, VecDeque<Parked>>,
+    worker_cache: WorkerCache,
+    thread: usize,
+    paused: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+    storage_retries: usize,
+    budget: Option<Arc<Mutex<WeightBudget>>>,
+    watchers: Watchers,
+    retry_defaults: RetryDefaults,
+    affinity: Affinity,
+    preemption: Option<Preemption>,
+    missing_processor: MissingProcessorPolicy,
+    overdue: Overdue,
+}
+
+/// The retry settings for jobs whose job and processor don't choose any
+#[derive(Clone, Default)]
+pub(crate) struct RetryDefaults {
+    pub(crate) max_retries: MaxRetries,
+    pub(crate) backoff: Backoff,
+    pub(crate) quarantine: Option<Duration>,
+}
+
+/// A worker waiting for a job
+///
+/// A worker that takes jobs from several queues waits in each of them. The first queue to hand it
+/// a job takes the worker out, leaving empty entries behind in the other queues.
+type Parked = Arc<Mutex<Option<Box<dyn Worker + Send>>>>;
+
+/// Callers waiting for jobs to finish, keyed by job ID and shared by every server thread
+///
+/// Several callers can wait for the same job, for example when their jobs were coalesced by a
+/// unique key. They all receive the result of the single run.
+pub(crate) type Watchers = Arc<Mutex<HashMap<u64, Vec<oneshot::Sender<JobResult>>>>>;
+
+/// The affinity key of the last job each worker ran, by worker ID
+///
+/// This is shared by every server thread, but each thread only routes jobs to the workers it has
+/// parked itself.
+pub(crate) type Affinity = Arc<Mutex<HashMap<u64, String>>>;
+
+/// The jobs that were failed for going over their time limit while they were still running
+///
+/// Their result is discarded when they eventually return.
+pub(crate) type Overdue = Arc<Mutex<HashSet<u64>>>;
+
+/// The number of workers parked for each queue, by server thread index
+type CacheSizes = Arc<Mutex<HashMap<(usize, Arc<str>), usize>>>;
+
+/// The number of workers parked by each server thread, and the workers evicted to stay under the
+/// cap
+///
+/// Each server thread keeps its own cache of parked workers, so sizes are kept by thread and
+/// added up when they're read.
+#[derive(Clone, Default)]
+pub(crate) struct WorkerCache {
+    sizes: CacheSizes,
+    evicted: Arc<AtomicUsize>,
+    threads: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl WorkerCache {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        WorkerCache {
+            max: max.map(|max| max.max(1)),
+            ..WorkerCache::default()
+        }
+    }
+
+    /// Give a new server thread its index
+    fn register(&self) -> usize {
+        self.threads.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn record(&self, thread: usize, queue: &Arc<str>, size: usize) {
+        self.sizes
+            .lock()
+            .unwrap()
+            .insert((thread, queue.clone()), size);
+    }
+
+    pub(crate) fn stats(&self) -> WorkerCacheStats {
+        let mut queues = BTreeMap::new();
+
+        for ((_, queue), size) in self.sizes.lock().unwrap().iter() {
+            *queues.entry(queue.to_string()).or_insert(0) += size;
+        }
+
+        WorkerCacheStats {
+            queues,
+            evicted: self.evicted.load(Ordering::Relaxed),
+            max: self.max,
+        }
+    }
+}
+
+/// The size of the servers' caches of workers waiting for a job
+///
+/// Workers that keep being started and dying without being noticed, like in a crash loop, leave
+/// entries behind in these caches. A cache that keeps growing past the number of workers that were
+/// started points at such a leak.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WorkerCacheStats {
+    /// The number of cached workers for each queue, added up across server threads
+    ///
+    /// A worker taking jobs from several queues is counted in each of them.
+    pub queues: BTreeMap<String, usize>,
+
+    /// The number of workers evicted from a cache that went over its cap
+    pub evicted: usize,
+
+    /// The cap on the number of cached workers for each queue, if there is one
+    pub max: Option<usize>,
+}
+
+/// The running jobs that may be preempted, and the jobs that have been asked to yield
+#[derive(Clone)]
+pub(crate) struct Preemption {
+    running: RunningJobs,
+    yielding: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl Preemption {
+    pub(crate) fn new(running: RunningJobs) -> Self {
+        Preemption {
+            running,
+            yielding: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Ask the lowest priority job running on a worker for the given queue to yield, if its
+    /// priority is below the given one
+    fn preempt(&self, queue: &str, priority: i32) {
+        let running = self.running.lock().unwrap();
+
+        let victim = running
+            .iter()
+            .filter(|(_, job)| job.priority < priority && !job.token.is_cancelled())
+            .filter(|(_, job)| job.queues.iter().any(|q| &**q == queue))
+            .min_by_key(|(_, job)| job.priority);
+
+        if let Some((id, job)) = victim {
+            trace!(
+                "Asking job {} to yield to a job with priority {}",
+                id,
+                priority
+            );
+            self.yielding.lock().unwrap().insert(*id);
+            job.token.cancel();
+        }
+    }
+
+    /// Whether the given job was asked to yield, forgetting it
+    fn yielded(&self, id: u64) -> bool {
+        self.yielding.lock().unwrap().remove(&id)
+    }
+}
+
+/// The combined weight of the running jobs, shared by every server thread
+pub(crate) struct WeightBudget {
+    max: usize,
+    running: usize,
+    jobs: HashMap<u64, usize>,
+}
+
+impl WeightBudget {
+    pub(crate) fn new(max: usize) -> Self {
+        WeightBudget {
+            max,
+            running: 0,
+            jobs: HashMap::new(),
+        }
+    }
+
+    /// The heaviest job that can start right now
+    ///
+    /// When nothing is running, any job may start, so a job heavier than the whole budget can
+    /// still run on its own.
+    fn available(&self) -> usize {
+        if self.running == 0 {
+            usize::MAX
+        } else {
+            self.max.saturating_sub(self.running)
+        }
+    }
+
+    fn start(&mut self, id: u64, weight: usize) {
+        if weight > 0 {
+            self.running += weight;
+            self.jobs.insert(id, weight);
+        }
+    }
+
+    fn finish(&mut self, id: u64) -> bool {
+        match self.jobs.remove(&id) {
+            Some(weight) => {
+                self.running -= weight;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The number of new jobs sent to the server that it hasn't stored yet, with a limit
+#[derive(Clone)]
+pub(crate) struct Backlog {
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl Backlog {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Backlog {
+            queued: Arc::new(AtomicUsize::new(0)),
+            capacity,
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Reserve room for one more job, if the backlog isn't full
+    pub(crate) fn acquire(&self) -> Option<Permit> {
+        let mut queued = self.queued.load(Ordering::Relaxed);
+
+        loop {
+            if queued >= self.capacity {
+                return None;
+            }
+
+            match self.queued.compare_exchange_weak(
+                queued,
+                queued + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Permit(self.queued.clone())),
+                Err(current) => queued = current,
+            }
+        }
+    }
+}
+
+/// Room for a single job in a `Backlog`, freed when the message carrying it has been handled
+pub(crate) struct Permit(Arc<AtomicUsize>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl Server {
-    pub(crate) fn new(storage: impl ActixStorage + Send + 'static) -> Self {
+    pub(crate) fn new(
+        storage: impl ActixStorage + Send + 'static,
+        paused: Arc<AtomicBool>,
+        storage_retries: usize,
+        budget: Option<Arc<Mutex<WeightBudget>>>,
+        watchers: Watchers,
+        retry_defaults: RetryDefaults,
+        affinity: Affinity,
+    ) -> Self {
         Server {
             storage: Box::new(storage),
             cache: HashMap::new(),
+            worker_cache: WorkerCache::default(),
+            thread: 0,
+            paused,
+            draining: Arc::new(AtomicBool::new(false)),
+            storage_retries,
+            budget,
+            watchers,
+            retry_defaults,
+            affinity,
+            preemption: None,
+            missing_processor: MissingProcessorPolicy::default(),
+            overdue: Overdue::default(),
+        }
+    }
+
+    /// Stop dispatching jobs for good once the given flag is set
+    pub(crate) fn with_draining(mut self, draining: Arc<AtomicBool>) -> Self {
+        self.draining = draining;
+        self
+    }
+
+    /// Preempt running jobs for higher priority jobs that can't find a free worker
+    pub(crate) fn with_preemption(mut self, preemption: Option<Preemption>) -> Self {
+        self.preemption = preemption;
+        self
+    }
+
+    /// Handle jobs returned without a registered processor according to the given policy
+    pub(crate) fn with_missing_processor(mut self, policy: MissingProcessorPolicy) -> Self {
+        self.missing_processor = policy;
+        self
+    }
+
+    /// Count the workers this thread caches along with the other threads
+    pub(crate) fn with_worker_cache(mut self, worker_cache: WorkerCache) -> Self {
+        self.thread = worker_cache.register();
+        self.worker_cache = worker_cache;
+        self
+    }
+
+    /// Share the set of jobs failed for going over their time limit with the other threads
+    pub(crate) fn with_overdue(mut self, overdue: Overdue) -> Self {
+        self.overdue = overdue;
+        self
+    }
+
+    /// Make room for a newly stored job if it is still waiting for a worker
+    fn preempt_for(&mut self, id: u64, new_job: &NewJobInfo) -> Result<(), Error> {
+        let preemption = match self.preemption {
+            Some(ref preemption) if new_job.is_ready() => preemption.clone(),
+            _ => return Ok(()),
+        };
+
+        let waiting = self
+            .storage
+            .fetch_job(id)?
+            .is_some_and(|job| job.is_pending());
+
+        if waiting {
+            preemption.preempt(new_job.queue(), new_job.priority());
+        }
+
+        Ok(())
+    }
+
+    fn with_defaults(&self, mut new_job: NewJobInfo) -> NewJobInfo {
+        new_job.fill_retry_defaults(
+            &self.retry_defaults.max_retries,
+            &self.retry_defaults.backoff,
+        );
+        if let Some(delay) = self.retry_defaults.quarantine {
+            new_job.fill_quarantine_default(delay);
+        }
+        new_job
+    }
+
+    /// Send the result of a returned job to any callers waiting for it, if the job is finished
+    fn notify_watchers(&mut self, id: u64, result: &JobResult) -> Result<(), Error> {
+        let mut watchers = self.watchers.lock().unwrap();
+
+        if !watchers.contains_key(&id) {
+            return Ok(());
+        }
+
+        let result = match result {
+            JobResult::Success => JobResult::Success,
+            // Other jobs are only finished once they have been removed instead of requeued
+            result => {
+                if self.storage.fetch_job(id)?.is_some() {
+                    return Ok(());
+                }
+
+                if result.is_missing_processor() {
+                    JobResult::MissingProcessor
+                } else if result.is_unrecoverable() {
+                    JobResult::Unrecoverable
+                } else {
+                    JobResult::Failure
+                }
+            }
+        };
+
+        for tx in watchers.remove(&id).unwrap_or_default() {
+            let _ = tx.send(result.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Request a job for the given worker, keeping the running jobs within the weight budget
+    fn request_job(&mut self, queue: &str, worker_id: u64) -> Result<Option<JobInfo>, Error> {
+        let budget = match self.budget {
+            Some(ref budget) => budget.clone(),
+            None => {
+                return retry(&mut *self.storage, self.storage_retries, |storage| {
+                    storage.request_job(queue, worker_id)
+                });
+            }
+        };
+
+        let mut budget = budget.lock().unwrap();
+        let available = budget.available();
+
+        let job = retry(&mut *self.storage, self.storage_retries, |storage| {
+            storage.request_job_within(queue, worker_id, available)
+        })?;
+
+        if let Some(ref job) = job {
+            budget.start(job.id(), job.weight());
+        }
+
+        Ok(job)
+    }
+
+    /// Whether dispatch is paused, or stopped by `QueueHandle::drain`
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed) || self.draining.load(Ordering::Relaxed)
+    }
+
+    fn check_db(&mut self) {
+        if self.is_paused() {
+            trace!("Dispatch is paused, not checking db");
+            return;
+        }
+
+        let queues: Vec<_> = self.cache.keys().cloned().collect();
+
+        for queue in queues {
+            self.try_dispatch(&queue);
+        }
+    }
+
+    /// Hand ready jobs from the given queue to parked workers
+    ///
+    /// This keeps dispatching until the queue has no more ready jobs or no more workers are
+    /// waiting, so a burst of jobs is spread across every available worker.
+    fn try_dispatch(&mut self, queue: &str) {
+        if self.is_paused() {
+            trace!("Dispatch is paused, not dispatching from {}", queue);
+            return;
+        }
+
+        let (key, mut workers) = match self.cache.remove_entry(queue) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        while let Some(parked) = workers.pop_front() {
+            let worker = match parked.lock().unwrap().take() {
+                Some(worker) => worker,
+                None => continue,
+            };
+
+            if worker.is_stopped() {
+                trace!("Dropping stopped worker {}", worker.id());
+                continue;
+            }
+
+            let job = match self.request_job(queue, worker.id()) {
+                Ok(Some(job)) => job,
+                res => {
+                    if let Err(e) = res {
+                        error!(
+                            "Failed to fetch a job from {} for worker {}, {}",
+                            queue,
+                            worker.id(),
+                            e
+                        );
+                    }
+
+                    *parked.lock().unwrap() = Some(worker);
+                    workers.push_front(parked);
+                    break;
+                }
+            };
+
+            match take_affine(&self.affinity, &mut workers, &job) {
+                Some(affine) => {
+                    // The worker that fetched the job stays first in line for the next one
+                    let fetched_for = worker.id();
+                    *parked.lock().unwrap() = Some(worker);
+                    workers.push_front(parked);
+                    self.hand_off(affine, job, fetched_for);
+                }
+                None => {
+                    let fetched_for = worker.id();
+                    self.hand_off(worker, job, fetched_for);
+                }
+            }
+        }
+
+        self.worker_cache.record(self.thread, &key, workers.len());
+        self.cache.insert(key, workers);
+    }
+
+    /// Give a job to a worker, remembering the job's affinity key for the worker
+    ///
+    /// When the job was fetched for a different worker, the storage is told which worker runs it.
+    fn hand_off(&mut self, worker: Box<dyn Worker + Send>, job: JobInfo, fetched_for: u64) {
+        if worker.id() != fetched_for {
+            if let Err(e) = self.storage.run_job(job.id(), worker.id()) {
+                warn!(
+                    "Failed to move job {} to worker {}, {}",
+                    job.id(),
+                    worker.id(),
+                    e
+                );
+            }
+        }
+
+        if let Some(key) = job.affinity_key() {
+            self.affinity
+                .lock()
+                .unwrap()
+                .insert(worker.id(), key.to_owned());
+        }
+
+        worker.process_job(job);
+    }
+
+    /// Keep a worker until one of its queues has a job for it
+    fn park(&mut self, worker: Box<dyn Worker + Send>) {
+        let queues = worker.queues().to_vec();
+        let parked = Arc::new(Mutex::new(Some(worker)));
+
+        for queue in queues {
+            trace!("storing worker for queue {}", queue);
+            let entry = self.cache.entry(queue.clone()).or_default();
+
+            // Drop the entries of workers that were taken out through another queue, or that
+            // stopped while they were waiting
+            entry.retain(|parked| {
+                let mut worker = parked.lock().unwrap();
+
+                if worker.as_ref().is_some_and(|worker| worker.is_stopped()) {
+                    *worker = None;
+                }
+
+                worker.is_some()
+            });
+            entry.push_back(parked.clone());
+
+            if let Some(max) = self.worker_cache.max {
+                // The workers waiting the longest are the most likely to be stale
+                while entry.len() > max {
+                    let oldest = match entry.pop_front() {
+                        Some(oldest) => oldest,
+                        None => break,
+                    };
+
+                    let evicted = oldest.lock().unwrap().take();
+                    if let Some(worker) = evicted {
+                        warn!(
+                            "More than {} workers are waiting for {}, evicting worker {}",
+                            max,
+                            queue,
+                            worker.id()
+                        );
+                        self.worker_cache.evicted.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+
+            self.worker_cache.record(self.thread, &queue, entry.len());
+        }
+    }
+}
+
+/// The longest delay between two attempts of a storage operation
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Perform a storage operation, retrying it with an increasing delay if it fails
+///
+/// The delay doubles from 20ms with each attempt, up to `MAX_RETRY_DELAY`. The calling server
+/// thread sleeps in between, so it handles no other messages until the operation succeeds or
+/// the retries run out.
+///
+/// Storage errors are often transient, for example when a remote database is briefly
+/// unreachable. Retrying here keeps workers registered with the server instead of losing them to
+/// a single failed request.
+///
+/// Only operations that are safe to repeat after a partial failure may be retried. Storing or
+/// returning a job takes several writes, and running it again after one of the later writes
+/// failed would store the job twice or count one attempt twice, so those errors are reported
+/// right away.
+fn retry<T, F>(storage: &mut (dyn ActixStorage + Send), retries: usize, f: F) -> Result<T, Error>
+where
+    F: Fn(&mut (dyn ActixStorage + Send)) -> Result<T, Error>,
+{
+    let mut attempt = 0;
+
+    loop {
+        match f(storage) {
+            Ok(t) => return Ok(t),
+            Err(e) => {
+                if attempt >= retries {
+                    return Err(e);
+                }
+
+                attempt += 1;
+                warn!("Storage error, retrying ({}/{}), {}", attempt, retries, e);
+                let delay = Duration::from_millis(10u64.saturating_mul(1 << attempt.min(32)));
+                thread::sleep(delay.min(MAX_RETRY_DELAY));
+            }
         }
     }
 }
 
+/// Take the parked worker that last ran a job with the same affinity key as the given job
+///
+/// Workers that have been told to stop are skipped.
+fn take_affine(
+    affinity: &Affinity,
+    workers: &mut VecDeque<Parked>,
+    job: &JobInfo,
+) -> Option<Box<dyn Worker + Send>> {
+    let key = job.affinity_key()?;
+    let affinity = affinity.lock().unwrap();
+
+    workers.iter().find_map(|parked| {
+        let mut parked = parked.lock().unwrap();
+
+        let matches = parked.as_ref().is_some_and(|worker| {
+            !worker.is_stopped() && affinity.get(&worker.id()).map(String::as_str) == Some(key)
+        });
+
+        if matches {
+            parked.take()
+        } else {
+            None
+        }
+    })
+}
+
 impl Actor for Server {
     type Context = SyncContext<Self>;
 }
@@ -29,6 +652,15 @@ impl Actor for Server {
 #[derive(Clone, Debug, Deserialize)]
 pub struct NewJob(pub(crate) NewJobInfo);
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewJobs(pub(crate) Vec<NewJobInfo>);
+
+pub struct QueueAndWatch(pub(crate) NewJobInfo, pub(crate) oneshot::Sender<JobResult>);
+
+pub struct QueueBounded(pub(crate) NewJobInfo, pub(crate) Permit);
+
+pub struct WatchJob(pub(crate) u64, pub(crate) oneshot::Sender<JobResult>);
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct ReturningJob(pub(crate) ReturnJobInfo);
 
@@ -36,12 +668,65 @@ pub struct RequestJob(pub(crate) Box<dyn Worker + Send + 'static>);
 
 pub struct CheckDb;
 
+pub struct FlushScheduled;
+
 pub struct GetStats;
 
+pub struct PurgeAll;
+
+pub struct HealthCheck;
+
+pub struct NextWakeup;
+
+pub struct Maintain;
+
+pub struct ListJobs;
+
+pub struct ListScheduled;
+
+pub struct ListDead;
+
+pub struct RequeueDead {
+    pub(crate) id: u64,
+    pub(crate) max_retries: Option<MaxRetries>,
+    pub(crate) backoff: Option<Backoff>,
+}
+
+pub struct SetPriority {
+    pub(crate) id: u64,
+    pub(crate) priority: i32,
+}
+
+pub struct RetryNow(pub(crate) u64);
+
+pub struct CancelJob(pub(crate) u64);
+
+pub struct SaveCheckpoint(pub(crate) u64, pub(crate) Value);
+
+pub struct RetryNowAll(pub(crate) Option<String>);
+
+pub struct TimedOut(pub(crate) u64);
+
 impl Message for NewJob {
+    type Result = Result<EnqueueOutcome, Error>;
+}
+
+impl Message for NewJobs {
+    type Result = Result<(), Error>;
+}
+
+impl Message for QueueAndWatch {
     type Result = Result<(), Error>;
 }
 
+impl Message for QueueBounded {
+    type Result = Result<(), Error>;
+}
+
+impl Message for WatchJob {
+    type Result = Result<bool, Error>;
+}
+
 impl Message for ReturningJob {
     type Result = Result<(), Error>;
 }
@@ -54,31 +739,193 @@ impl Message for CheckDb {
     type Result = ();
 }
 
+impl Message for FlushScheduled {
+    type Result = ();
+}
+
 impl Message for GetStats {
     type Result = Result<Stats, Error>;
 }
 
+impl Message for PurgeAll {
+    type Result = Result<(), Error>;
+}
+
+impl Message for HealthCheck {
+    type Result = Result<(), Error>;
+}
+
+impl Message for NextWakeup {
+    type Result = Result<Option<Timestamp>, Error>;
+}
+
+impl Message for Maintain {
+    type Result = ();
+}
+
+impl Message for ListJobs {
+    type Result = Result<Vec<JobInfo>, Error>;
+}
+
+impl Message for ListScheduled {
+    type Result = Result<Vec<JobInfo>, Error>;
+}
+
+impl Message for ListDead {
+    type Result = Result<Vec<JobInfo>, Error>;
+}
+
+impl Message for RequeueDead {
+    type Result = Result<bool, Error>;
+}
+
+impl Message for SetPriority {
+    type Result = Result<bool, Error>;
+}
+
+impl Message for RetryNow {
+    type Result = Result<bool, Error>;
+}
+
+impl Message for CancelJob {
+    type Result = Result<bool, Error>;
+}
+
+impl Message for RetryNowAll {
+    type Result = Result<usize, Error>;
+}
+
+impl Message for SaveCheckpoint {
+    type Result = Result<(), Error>;
+}
+
+impl Message for TimedOut {
+    type Result = Result<(), Error>;
+}
+
 impl Handler<NewJob> for Server {
+    type Result = Result<EnqueueOutcome, Error>;
+
+    fn handle(&mut self, NewJob(new_job): NewJob, _: &mut Self::Context) -> Self::Result {
+        let new_job = self.with_defaults(new_job);
+
+        let outcome = self.storage.enqueue(new_job.clone())?;
+
+        // A coalesced job was already dispatched when it was first stored
+        if let EnqueueOutcome::Enqueued(id) = outcome {
+            if new_job.is_ready() {
+                self.try_dispatch(new_job.queue());
+            }
+
+            self.preempt_for(id, &new_job)?;
+        }
+
+        Ok(outcome)
+    }
+}
+
+impl Handler<QueueBounded> for Server {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        QueueBounded(new_job, _permit): QueueBounded,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        self.handle(NewJob(new_job), ctx).map(|_| ())
+    }
+}
+
+impl Handler<QueueAndWatch> for Server {
     type Result = Result<(), Error>;
 
-    fn handle(&mut self, msg: NewJob, _: &mut Self::Context) -> Self::Result {
-        let queue = msg.0.queue().to_owned();
-        let ready = msg.0.is_ready();
-        self.storage.new_job(msg.0)?;
+    fn handle(
+        &mut self,
+        QueueAndWatch(new_job, tx): QueueAndWatch,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let new_job = self.with_defaults(new_job);
 
-        if ready {
-            let entry = self.cache.entry(queue.clone()).or_insert(VecDeque::new());
+        // Hold the watchers while the job is stored, so a worker on another thread can't finish
+        // the job before the watcher is registered
+        let watchers = self.watchers.clone();
+        let mut watchers = watchers.lock().unwrap();
 
-            if let Some(worker) = entry.pop_front() {
-                if let Ok(Some(job)) = self.storage.request_job(&queue, worker.id()) {
-                    worker.process_job(job);
-                } else {
-                    entry.push_back(worker);
+        let id = self.storage.new_job(new_job.clone())?;
+
+        // The key matched a job that has already completed
+        if self.storage.fetch_job(id)?.is_none() {
+            let _ = tx.send(JobResult::Success);
+            return Ok(());
+        }
+
+        watchers.entry(id).or_default().push(tx);
+        drop(watchers);
+
+        if new_job.is_ready() {
+            self.try_dispatch(new_job.queue());
+        }
+
+        self.preempt_for(id, &new_job)
+    }
+}
+
+impl Handler<WatchJob> for Server {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, WatchJob(id, tx): WatchJob, _: &mut Self::Context) -> Self::Result {
+        // Hold the watchers while the job is looked up, so a worker on another thread can't
+        // finish the job between the lookup and the registration
+        let watchers = self.watchers.clone();
+        let mut watchers = watchers.lock().unwrap();
+
+        if self.storage.fetch_job(id)?.is_none() {
+            return Ok(false);
+        }
+
+        watchers.entry(id).or_default().push(tx);
+        Ok(true)
+    }
+}
+
+impl Handler<NewJobs> for Server {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, NewJobs(new_jobs): NewJobs, _: &mut Self::Context) -> Self::Result {
+        let mut ready = HashSet::new();
+        let mut stored = Vec::new();
+        let mut res = Ok(());
+
+        for new_job in new_jobs {
+            let new_job = self.with_defaults(new_job);
+
+            match self.storage.new_job(new_job.clone()) {
+                Ok(id) => {
+                    if new_job.is_ready() {
+                        ready.insert(new_job.queue().to_owned());
+                    }
+
+                    stored.push((id, new_job));
+                }
+                Err(e) => {
+                    res = Err(e);
+                    break;
                 }
             }
         }
 
-        Ok(())
+        // The jobs stored before a failure are dispatched all the same
+        for queue in ready {
+            self.try_dispatch(&queue);
+        }
+
+        for (id, new_job) in stored {
+            if let Err(e) = self.preempt_for(id, &new_job) {
+                res = res.and(Err(e));
+            }
+        }
+
+        res
     }
 }
 
@@ -86,7 +933,44 @@ impl Handler<ReturningJob> for Server {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: ReturningJob, _: &mut Self::Context) -> Self::Result {
-        self.storage.return_job(msg.0).map_err(|e| e.into())
+        let id = msg.0.id();
+
+        let yielded = match self.preemption {
+            Some(ref preemption) => preemption.yielded(id) && !msg.0.result().is_success(),
+            None => false,
+        };
+
+        let overdue = self.overdue.lock().unwrap().remove(&id);
+
+        if overdue {
+            warn!(
+                "Discarding the result of job {}, it went over its time limit",
+                id
+            );
+        } else if yielded {
+            // The job stopped early to make room, so it goes back to its queue as it was
+            self.storage.reclaim_job(id)?;
+        } else if msg.0.result().is_missing_processor() {
+            let policy = self.missing_processor;
+            self.storage.return_missing_processor(id, policy)?;
+
+            self.notify_watchers(id, msg.0.result())?;
+        } else {
+            self.storage.return_job(msg.0.clone())?;
+
+            self.notify_watchers(id, msg.0.result())?;
+        }
+
+        let freed = match self.budget {
+            Some(ref budget) => budget.lock().unwrap().finish(id),
+            None => false,
+        };
+
+        if freed {
+            self.check_db();
+        }
+
+        Ok(())
     }
 }
 
@@ -95,24 +979,58 @@ impl Handler<RequestJob> for Server {
 
     fn handle(&mut self, RequestJob(worker): RequestJob, _: &mut Self::Context) -> Self::Result {
         trace!("Worker {} requested job", worker.id());
-        let job = self.storage.request_job(worker.queue(), worker.id())?;
+        if worker.is_stopped() {
+            trace!("Dropping stopped worker {}", worker.id());
+            return Ok(());
+        }
 
-        if let Some(job) = job {
-            worker.process_job(job.clone());
-        } else {
-            trace!(
-                "storing worker {} for queue {}",
-                worker.id(),
-                worker.queue()
-            );
-            let entry = self
-                .cache
-                .entry(worker.queue().to_owned())
-                .or_insert(VecDeque::new());
-            entry.push_back(worker);
+        // The worker stays registered when the storage fails, but the first error is reported to
+        // the sender, which lets new workers notice a broken store
+        let mut failure = None;
+
+        if !self.is_paused() {
+            for queue in worker.queues() {
+                match self.request_job(queue, worker.id()) {
+                    Ok(Some(job)) => {
+                        let affinity = &self.affinity;
+                        let affine = self
+                            .cache
+                            .get_mut(&**queue)
+                            .and_then(|workers| take_affine(affinity, workers, &job));
+
+                        match affine {
+                            Some(affine) => {
+                                let queue = queue.clone();
+                                self.hand_off(affine, job, worker.id());
+
+                                // The requesting worker is still free, so it takes the next job
+                                self.park(worker);
+                                self.try_dispatch(&queue);
+                            }
+                            None => {
+                                let fetched_for = worker.id();
+                                self.hand_off(worker, job, fetched_for);
+                            }
+                        }
+
+                        return Ok(());
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        error!("Failed to request job for worker {}, {}", worker.id(), e);
+                        failure = failure.or(Some(e));
+                    }
+                }
+            }
         }
 
-        Ok(())
+        trace!("storing worker {}", worker.id());
+        self.park(worker);
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 }
 
@@ -121,19 +1039,16 @@ impl Handler<CheckDb> for Server {
 
     fn handle(&mut self, _: CheckDb, _: &mut Self::Context) -> Self::Result {
         trace!("Checkdb");
+        self.check_db();
+    }
+}
 
-        for (queue, workers) in self.cache.iter_mut() {
-            while !workers.is_empty() {
-                if let Some(worker) = workers.pop_front() {
-                    if let Ok(Some(job)) = self.storage.request_job(queue, worker.id()) {
-                        worker.process_job(job);
-                    } else {
-                        workers.push_back(worker);
-                        break;
-                    }
-                }
-            }
-        }
+impl Handler<FlushScheduled> for Server {
+    type Result = ();
+
+    fn handle(&mut self, _: FlushScheduled, _: &mut Self::Context) -> Self::Result {
+        trace!("Flushing scheduled jobs");
+        self.check_db();
     }
 }
 
@@ -144,3 +1059,172 @@ impl Handler<GetStats> for Server {
         self.storage.get_stats().map_err(|e| e.into())
     }
 }
+
+impl Handler<PurgeAll> for Server {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: PurgeAll, _: &mut Self::Context) -> Self::Result {
+        warn!("Purging every job from storage");
+        self.storage.purge_all()?;
+
+        // The watched jobs are gone, so their callers are told the result was dropped
+        self.watchers.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+impl Handler<HealthCheck> for Server {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: HealthCheck, _: &mut Self::Context) -> Self::Result {
+        self.storage.health_check()
+    }
+}
+
+impl Handler<NextWakeup> for Server {
+    type Result = Result<Option<Timestamp>, Error>;
+
+    fn handle(&mut self, _: NextWakeup, _: &mut Self::Context) -> Self::Result {
+        self.storage.next_wakeup()
+    }
+}
+
+impl Handler<Maintain> for Server {
+    type Result = ();
+
+    fn handle(&mut self, _: Maintain, _: &mut Self::Context) -> Self::Result {
+        trace!("Maintaining storage");
+
+        if let Err(e) = self.storage.maintain() {
+            error!("Error maintaining storage, {}", e);
+        }
+    }
+}
+
+impl Handler<ListJobs> for Server {
+    type Result = Result<Vec<JobInfo>, Error>;
+
+    fn handle(&mut self, _: ListJobs, _: &mut Self::Context) -> Self::Result {
+        self.storage.list_jobs()
+    }
+}
+
+impl Handler<ListScheduled> for Server {
+    type Result = Result<Vec<JobInfo>, Error>;
+
+    fn handle(&mut self, _: ListScheduled, _: &mut Self::Context) -> Self::Result {
+        self.storage.list_scheduled()
+    }
+}
+
+impl Handler<SetPriority> for Server {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, msg: SetPriority, _: &mut Self::Context) -> Self::Result {
+        self.storage.set_priority(msg.id, msg.priority)
+    }
+}
+
+impl Handler<CancelJob> for Server {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, CancelJob(id): CancelJob, _: &mut Self::Context) -> Self::Result {
+        if !self.storage.cancel_job(id)? {
+            return Ok(false);
+        }
+
+        // The job will never finish, so its watchers are dropped, which fails their futures
+        self.watchers.lock().unwrap().remove(&id);
+
+        Ok(true)
+    }
+}
+
+impl Handler<RetryNow> for Server {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, RetryNow(id): RetryNow, _: &mut Self::Context) -> Self::Result {
+        if !self.storage.retry_now(id)? {
+            return Ok(false);
+        }
+
+        if let Some(job) = self.storage.fetch_job(id)? {
+            self.try_dispatch(job.queue());
+        }
+
+        Ok(true)
+    }
+}
+
+impl Handler<SaveCheckpoint> for Server {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        SaveCheckpoint(id, checkpoint): SaveCheckpoint,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let saved = retry(&mut *self.storage, self.storage_retries, |storage| {
+            storage.save_checkpoint(id, checkpoint.clone())
+        })?;
+
+        if !saved {
+            trace!("Not saving checkpoint for job {}, it is not running", id);
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<TimedOut> for Server {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, TimedOut(id): TimedOut, _: &mut Self::Context) -> Self::Result {
+        let timed_out = self.storage.time_out_job(id)?;
+
+        if timed_out {
+            self.overdue.lock().unwrap().insert(id);
+            self.notify_watchers(id, &JobResult::Failure)?;
+
+            if let Some(job) = self.storage.fetch_job(id)? {
+                self.try_dispatch(job.queue());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<RetryNowAll> for Server {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, RetryNowAll(queue): RetryNowAll, _: &mut Self::Context) -> Self::Result {
+        let count = self.storage.retry_now_all(queue.as_deref())?;
+
+        if count > 0 {
+            match queue {
+                Some(ref queue) => self.try_dispatch(queue),
+                None => self.check_db(),
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+impl Handler<ListDead> for Server {
+    type Result = Result<Vec<JobInfo>, Error>;
+
+    fn handle(&mut self, _: ListDead, _: &mut Self::Context) -> Self::Result {
+        self.storage.fetch_dead_jobs()
+    }
+}
+
+impl Handler<RequeueDead> for Server {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, msg: RequeueDead, _: &mut Self::Context) -> Self::Result {
+        self.storage
+            .requeue_dead_job_with(msg.id, msg.max_retries, msg.backoff)
+    }
+}
@@ -0,0 +1,82 @@
+use actix::{fut::wrap_future, Actor, AsyncContext, Context};
+use background_jobs_core::Storage;
+use log::{error, info};
+
+use crate::RunningJobs;
+
+/// What happens to jobs that are still running when the actix `System` stops
+///
+/// Jobs only finish running when their worker hands their result back to the server, so a
+/// system that stops while jobs are running leaves them marked as running in the job store. This
+/// decides what is done with them instead. Only jobs run by workers started with the
+/// `QueueHandle` returned by `ServerConfig::start` are handled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ShutdownStrategy {
+    /// Leave running jobs as they are, for a custom storage or an operator to recover
+    #[default]
+    Abandon,
+
+    /// Put running jobs back in their queue without recording a result, so they run again from
+    /// the start after a restart, and count them in the `reclaimed` stat
+    RequeueOnStop,
+
+    /// Record a failed attempt for running jobs, so they are retried like any other failure, or
+    /// become dead when they are out of retries
+    FailOnStop,
+}
+
+/// An actor applying a `ShutdownStrategy` to running jobs once its arbiter stops
+///
+/// The storage is used directly rather than through the server, since the server's threads may
+/// already be gone by then.
+pub(crate) struct ShutdownGuard<S> {
+    storage: S,
+    running: RunningJobs,
+    strategy: ShutdownStrategy,
+}
+
+impl<S> ShutdownGuard<S>
+where
+    S: Storage + 'static,
+{
+    pub(crate) fn new(storage: S, running: RunningJobs, strategy: ShutdownStrategy) -> Self {
+        ShutdownGuard {
+            storage,
+            running,
+            strategy,
+        }
+    }
+
+    fn apply(&mut self, id: u64) -> Result<bool, S::Error> {
+        match self.strategy {
+            ShutdownStrategy::Abandon => Ok(false),
+            ShutdownStrategy::RequeueOnStop => self.storage.reclaim_job(id),
+            ShutdownStrategy::FailOnStop => self.storage.fail_running_job(id),
+        }
+    }
+}
+
+impl<S> Actor for ShutdownGuard<S>
+where
+    S: Storage + 'static,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // Nothing else keeps this actor alive, so it waits on a future that never completes until
+        // its arbiter stops
+        ctx.spawn(wrap_future(futures::future::empty::<(), ()>()));
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        let ids: Vec<u64> = self.running.lock().unwrap().keys().cloned().collect();
+
+        for id in ids {
+            match self.apply(id) {
+                Ok(true) => info!("Applied {:?} to job {}", self.strategy, id),
+                Ok(false) => (),
+                Err(e) => error!("Failed to apply {:?} to job {}, {}", self.strategy, id, e),
+            }
+        }
+    }
+}
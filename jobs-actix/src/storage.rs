@@ -1,13 +1,71 @@
-use background_jobs_core::{JobInfo, NewJobInfo, ReturnJobInfo, Stats, Storage};
+use background_jobs_core::{
+    clock::Timestamp, Backoff, EnqueueOutcome, JobInfo, MaxRetries, MissingProcessorPolicy,
+    NewJobInfo, ReturnJobInfo, Stats, Storage,
+};
 use failure::{Error, Fail};
+use serde_json::Value;
 
 pub(crate) trait ActixStorage {
     fn new_job(&mut self, job: NewJobInfo) -> Result<u64, Error>;
 
+    fn enqueue(&mut self, job: NewJobInfo) -> Result<EnqueueOutcome, Error>;
+
+    fn fetch_job(&mut self, id: u64) -> Result<Option<JobInfo>, Error>;
+
+    fn run_job(&mut self, id: u64, runner_id: u64) -> Result<(), Error>;
+
+    fn reclaim_job(&mut self, id: u64) -> Result<bool, Error>;
+
     fn request_job(&mut self, queue: &str, runner_id: u64) -> Result<Option<JobInfo>, Error>;
 
+    fn request_job_within(
+        &mut self,
+        queue: &str,
+        runner_id: u64,
+        max_weight: usize,
+    ) -> Result<Option<JobInfo>, Error>;
+
     fn return_job(&mut self, ret: ReturnJobInfo) -> Result<(), Error>;
 
+    fn set_priority(&mut self, id: u64, priority: i32) -> Result<bool, Error>;
+
+    fn retry_now(&mut self, id: u64) -> Result<bool, Error>;
+
+    fn cancel_job(&mut self, id: u64) -> Result<bool, Error>;
+
+    fn retry_now_all(&mut self, queue: Option<&str>) -> Result<usize, Error>;
+
+    fn time_out_job(&mut self, id: u64) -> Result<bool, Error>;
+
+    fn save_checkpoint(&mut self, id: u64, checkpoint: Value) -> Result<bool, Error>;
+
+    fn return_missing_processor(
+        &mut self,
+        id: u64,
+        policy: MissingProcessorPolicy,
+    ) -> Result<(), Error>;
+
+    fn list_jobs(&mut self) -> Result<Vec<JobInfo>, Error>;
+
+    fn list_scheduled(&mut self) -> Result<Vec<JobInfo>, Error>;
+
+    fn fetch_dead_jobs(&mut self) -> Result<Vec<JobInfo>, Error>;
+
+    fn requeue_dead_job_with(
+        &mut self,
+        id: u64,
+        max_retries: Option<MaxRetries>,
+        backoff: Option<Backoff>,
+    ) -> Result<bool, Error>;
+
+    fn purge_all(&mut self) -> Result<(), Error>;
+
+    fn health_check(&mut self) -> Result<(), Error>;
+
+    fn next_wakeup(&self) -> Result<Option<Timestamp>, Error>;
+
+    fn maintain(&mut self) -> Result<(), Error>;
+
     fn get_stats(&self) -> Result<Stats, Error>;
 }
 
@@ -25,14 +83,114 @@ where
         self.0.new_job(job).map_err(Error::from)
     }
 
+    fn enqueue(&mut self, job: NewJobInfo) -> Result<EnqueueOutcome, Error> {
+        self.0.enqueue(job).map_err(Error::from)
+    }
+
+    fn fetch_job(&mut self, id: u64) -> Result<Option<JobInfo>, Error> {
+        self.0.fetch_job(id).map_err(Error::from)
+    }
+
+    fn run_job(&mut self, id: u64, runner_id: u64) -> Result<(), Error> {
+        self.0.run_job(id, runner_id).map_err(Error::from)
+    }
+
+    fn reclaim_job(&mut self, id: u64) -> Result<bool, Error> {
+        self.0.reclaim_job(id).map_err(Error::from)
+    }
+
     fn request_job(&mut self, queue: &str, runner_id: u64) -> Result<Option<JobInfo>, Error> {
         self.0.request_job(queue, runner_id).map_err(Error::from)
     }
 
+    fn request_job_within(
+        &mut self,
+        queue: &str,
+        runner_id: u64,
+        max_weight: usize,
+    ) -> Result<Option<JobInfo>, Error> {
+        self.0
+            .request_job_within(queue, runner_id, max_weight)
+            .map_err(Error::from)
+    }
+
     fn return_job(&mut self, ret: ReturnJobInfo) -> Result<(), Error> {
         self.0.return_job(ret).map_err(Error::from)
     }
 
+    fn set_priority(&mut self, id: u64, priority: i32) -> Result<bool, Error> {
+        self.0.set_priority(id, priority).map_err(Error::from)
+    }
+
+    fn retry_now(&mut self, id: u64) -> Result<bool, Error> {
+        self.0.retry_now(id).map_err(Error::from)
+    }
+
+    fn cancel_job(&mut self, id: u64) -> Result<bool, Error> {
+        self.0.cancel_job(id).map_err(Error::from)
+    }
+
+    fn retry_now_all(&mut self, queue: Option<&str>) -> Result<usize, Error> {
+        self.0.retry_now_all(queue).map_err(Error::from)
+    }
+
+    fn time_out_job(&mut self, id: u64) -> Result<bool, Error> {
+        self.0.time_out_job(id).map_err(Error::from)
+    }
+
+    fn save_checkpoint(&mut self, id: u64, checkpoint: Value) -> Result<bool, Error> {
+        self.0.save_checkpoint(id, checkpoint).map_err(Error::from)
+    }
+
+    fn return_missing_processor(
+        &mut self,
+        id: u64,
+        policy: MissingProcessorPolicy,
+    ) -> Result<(), Error> {
+        self.0
+            .return_missing_processor(id, policy)
+            .map_err(Error::from)
+    }
+
+    fn list_jobs(&mut self) -> Result<Vec<JobInfo>, Error> {
+        self.0.list_jobs().map_err(Error::from)
+    }
+
+    fn list_scheduled(&mut self) -> Result<Vec<JobInfo>, Error> {
+        self.0.list_scheduled().map_err(Error::from)
+    }
+
+    fn fetch_dead_jobs(&mut self) -> Result<Vec<JobInfo>, Error> {
+        self.0.fetch_dead_jobs().map_err(Error::from)
+    }
+
+    fn requeue_dead_job_with(
+        &mut self,
+        id: u64,
+        max_retries: Option<MaxRetries>,
+        backoff: Option<Backoff>,
+    ) -> Result<bool, Error> {
+        self.0
+            .requeue_dead_job_with(id, max_retries, backoff)
+            .map_err(Error::from)
+    }
+
+    fn purge_all(&mut self) -> Result<(), Error> {
+        self.0.purge_all().map_err(Error::from)
+    }
+
+    fn health_check(&mut self) -> Result<(), Error> {
+        self.0.health_check().map_err(Error::from)
+    }
+
+    fn next_wakeup(&self) -> Result<Option<Timestamp>, Error> {
+        self.0.next_wakeup().map_err(Error::from)
+    }
+
+    fn maintain(&mut self) -> Result<(), Error> {
+        self.0.maintain().map_err(Error::from)
+    }
+
     fn get_stats(&self) -> Result<Stats, Error> {
         self.0.get_stats().map_err(Error::from)
     }
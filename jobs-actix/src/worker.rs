@@ -1,19 +1,223 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
 use actix::{
     dev::ToEnvelope,
-    fut::{wrap_future, ActorFuture},
-    Actor, Addr, AsyncContext, Context, Handler, Message,
+    fut::{self, wrap_future, ActorFuture},
+    Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message,
 };
-use background_jobs_core::{JobInfo, ProcessorMap};
-use log::info;
+use background_jobs_core::{CancellationToken, Checkpoint, JobInfo, ProcessorMap};
+use failure::{format_err, Error};
+use futures::{
+    future::{join_all, Shared},
+    sync::oneshot,
+    Future,
+};
+use log::{error, info};
+
+use crate::{RequestJob, ReturningJob, SaveCheckpoint};
+
+/// The jobs currently running, by job ID
+pub(crate) type RunningJobs = Arc<Mutex<HashMap<u64, RunningJob>>>;
+
+/// Messages between the server and the workers that haven't been handled yet
+///
+/// This counts the jobs handed to workers that haven't started running them, and the results
+/// sent to the server that it hasn't stored.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Count a message until the returned guard is dropped
+    fn track(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.0.clone())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
+}
+
+/// A message counted by `InFlight`, dropped once it has been handled
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A job being run by a worker
+pub(crate) struct RunningJob {
+    pub(crate) token: CancellationToken,
+    pub(crate) priority: i32,
+    pub(crate) queues: Arc<[Arc<str>]>,
+    pub(crate) started: Instant,
+    pub(crate) timeout: Option<Duration>,
+}
+
+/// Finds the running jobs that have gone over their time limit
+#[derive(Clone)]
+pub(crate) struct Watchdog {
+    running: RunningJobs,
+    max_run_time: Option<Duration>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(running: RunningJobs, max_run_time: Option<Duration>) -> Self {
+        Watchdog {
+            running,
+            max_run_time,
+        }
+    }
+
+    /// Stop tracking the running jobs that have gone over their limit, ask them to stop, and
+    /// return their IDs
+    pub(crate) fn overdue(&self) -> Vec<u64> {
+        let now = Instant::now();
+        let mut running = self.running.lock().unwrap();
+
+        let overdue: Vec<u64> = running
+            .iter()
+            .filter(|(_, job)| {
+                job.timeout
+                    .or(self.max_run_time)
+                    .is_some_and(|limit| now.saturating_duration_since(job.started) > limit)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in &overdue {
+            if let Some(job) = running.remove(id) {
+                job.token.cancel();
+            }
+        }
+
+        overdue
+    }
+}
+
+/// How long workers have spent running jobs, and waiting for them
+///
+/// Both durations only grow, so the utilization over a window is found by subtracting an earlier
+/// reading with `since`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Utilization {
+    /// The time spent running jobs
+    pub busy: Duration,
+
+    /// The time spent waiting for a job to run
+    pub idle: Duration,
+}
+
+impl Utilization {
+    /// The fraction of the time spent running jobs, between 0 and 1
+    ///
+    /// This is 0 when no time has been recorded.
+    pub fn fraction(&self) -> f64 {
+        let total = (self.busy + self.idle).as_secs_f64();
+
+        if total > 0.0 {
+            self.busy.as_secs_f64() / total
+        } else {
+            0.0
+        }
+    }
+
+    /// The utilization between an earlier reading and this one
+    pub fn since(&self, earlier: &Utilization) -> Utilization {
+        Utilization {
+            busy: self.busy.saturating_sub(earlier.busy),
+            idle: self.idle.saturating_sub(earlier.idle),
+        }
+    }
+}
+
+/// The busy and idle time of every worker, by worker ID
+#[derive(Clone, Default)]
+pub(crate) struct UtilizationMeter(Arc<Mutex<HashMap<u64, WorkerClock>>>);
+
+#[derive(Default)]
+struct WorkerClock {
+    recorded: Utilization,
+
+    /// Whether the worker is currently busy, and since when, or `None` once it has stopped
+    current: Option<(bool, Instant)>,
+}
+
+impl WorkerClock {
+    fn switch(&mut self, now: Instant, next: Option<bool>) {
+        if let Some((busy, since)) = self.current {
+            let elapsed = now.saturating_duration_since(since);
+
+            if busy {
+                self.recorded.busy += elapsed;
+            } else {
+                self.recorded.idle += elapsed;
+            }
+        }
+
+        self.current = next.map(|busy| (busy, now));
+    }
+}
+
+impl UtilizationMeter {
+    fn switch(&self, worker: u64, next: Option<bool>) {
+        let mut clocks = self.0.lock().unwrap();
+        clocks
+            .entry(worker)
+            .or_default()
+            .switch(Instant::now(), next);
+    }
+
+    /// The combined utilization of every worker so far
+    ///
+    /// Stopped workers keep counting the time they recorded before stopping.
+    pub(crate) fn total(&self) -> Utilization {
+        let now = Instant::now();
+        let clocks = self.0.lock().unwrap();
+
+        clocks
+            .values()
+            .fold(Utilization::default(), |mut total, clock| {
+                total.busy += clock.recorded.busy;
+                total.idle += clock.recorded.idle;
+
+                if let Some((busy, since)) = clock.current {
+                    let elapsed = now.saturating_duration_since(since);
+
+                    if busy {
+                        total.busy += elapsed;
+                    } else {
+                        total.idle += elapsed;
+                    }
+                }
 
-use crate::{RequestJob, ReturningJob};
+                total
+            })
+    }
+}
+
+/// A future that must resolve before a worker requests its first job
+pub(crate) type Gate = Shared<Box<dyn Future<Item = (), Error = Error> + Send>>;
 
 pub trait Worker {
     fn process_job(&self, job: JobInfo);
 
     fn id(&self) -> u64;
 
-    fn queue(&self) -> &str;
+    /// The queues this worker takes jobs from, in the order they are checked
+    fn queues(&self) -> &[Arc<str>];
+
+    /// Whether this worker has been told to stop, and should not be handed more jobs
+    fn is_stopped(&self) -> bool;
 }
 
 pub struct LocalWorkerHandle<W>
@@ -23,7 +227,9 @@ where
 {
     addr: Addr<W>,
     id: u64,
-    queue: String,
+    queues: Arc<[Arc<str>]>,
+    stop: Arc<AtomicBool>,
+    handing_off: Option<InFlight>,
 }
 
 impl<W> Worker for LocalWorkerHandle<W>
@@ -32,64 +238,207 @@ where
     W::Context: ToEnvelope<W, ProcessJob>,
 {
     fn process_job(&self, job: JobInfo) {
-        self.addr.do_send(ProcessJob(job));
+        // The job is counted until the worker has recorded it as running
+        let guard = self.handing_off.as_ref().map(InFlight::track);
+
+        self.addr.do_send(ProcessJob(job, guard));
     }
 
     fn id(&self) -> u64 {
         self.id
     }
 
-    fn queue(&self) -> &str {
-        &self.queue
+    fn queues(&self) -> &[Arc<str>] {
+        &self.queues
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
     }
 }
 
 pub struct LocalWorker<S, State>
 where
-    S: Actor + Handler<ReturningJob> + Handler<RequestJob>,
-    S::Context: ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob>,
+    S: Actor + Handler<ReturningJob> + Handler<RequestJob> + Handler<SaveCheckpoint>,
+    S::Context:
+        ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob> + ToEnvelope<S, SaveCheckpoint>,
     State: Clone + 'static,
 {
     id: u64,
-    queue: String,
+    queues: Arc<[Arc<str>]>,
     processors: ProcessorMap<State>,
     server: Addr<S>,
+    stop: Arc<AtomicBool>,
+    ready: Option<oneshot::Sender<Result<(), Error>>>,
+    running: Option<RunningJobs>,
+    returning: Option<InFlight>,
+    handing_off: Option<InFlight>,
+    utilization: Option<UtilizationMeter>,
+    gates: Vec<Gate>,
 }
 
 impl<S, State> LocalWorker<S, State>
 where
-    S: Actor + Handler<ReturningJob> + Handler<RequestJob>,
-    S::Context: ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob>,
+    S: Actor + Handler<ReturningJob> + Handler<RequestJob> + Handler<SaveCheckpoint>,
+    S::Context:
+        ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob> + ToEnvelope<S, SaveCheckpoint>,
     State: Clone + 'static,
 {
-    pub fn new(id: u64, queue: String, processors: ProcessorMap<State>, server: Addr<S>) -> Self {
+    pub fn new(
+        id: u64,
+        queue: impl Into<Arc<str>>,
+        processors: ProcessorMap<State>,
+        server: Addr<S>,
+    ) -> Self {
+        Self::for_queues(id, vec![queue.into()], processors, server)
+    }
+
+    /// Create a worker that takes jobs from several queues
+    ///
+    /// Whenever the worker is free, the queues are checked in the given order, so a queue listed
+    /// first is drained before the ones after it.
+    pub fn for_queues(
+        id: u64,
+        queues: Vec<Arc<str>>,
+        processors: ProcessorMap<State>,
+        server: Addr<S>,
+    ) -> Self {
         LocalWorker {
             id,
-            queue,
+            queues: queues.into(),
             processors,
             server,
+            stop: Arc::new(AtomicBool::new(false)),
+            ready: None,
+            running: None,
+            returning: None,
+            handing_off: None,
+            utilization: None,
+            gates: Vec::new(),
+        }
+    }
+
+    /// Use the given flag to tell this worker to stop
+    ///
+    /// Once the flag is set, the worker finishes its current job, if any, and then stops instead
+    /// of requesting another one.
+    pub fn with_stop(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Report the outcome of this worker's first job request to the given sender
+    pub(crate) fn with_ready(mut self, ready: oneshot::Sender<Result<(), Error>>) -> Self {
+        self.ready = Some(ready);
+        self
+    }
+
+    /// Record the cancellation token of each job this worker runs in the given map
+    pub(crate) fn with_running(mut self, running: RunningJobs) -> Self {
+        self.running = Some(running);
+        self
+    }
+
+    /// Count the results of this worker's jobs in the given counter until the server has handled
+    /// them
+    pub(crate) fn with_returning(mut self, returning: InFlight) -> Self {
+        self.returning = Some(returning);
+        self
+    }
+
+    /// Count the jobs handed to this worker in the given counter until it is running them
+    pub(crate) fn with_handing_off(mut self, handing_off: InFlight) -> Self {
+        self.handing_off = Some(handing_off);
+        self
+    }
+
+    /// Record the time this worker spends running jobs and waiting for them in the given meter
+    pub(crate) fn with_utilization(mut self, utilization: UtilizationMeter) -> Self {
+        self.utilization = Some(utilization);
+        self
+    }
+
+    /// Record that this worker is now running a job, waiting for one, or stopped
+    fn meter(&self, busy: Option<bool>) {
+        if let Some(ref utilization) = self.utilization {
+            utilization.switch(self.id, busy);
+        }
+    }
+
+    /// Wait for the given futures to resolve before requesting the first job
+    pub(crate) fn with_gates(mut self, gates: Vec<Gate>) -> Self {
+        self.gates = gates;
+        self
+    }
+
+    /// Make this worker's first job request
+    fn request_first(&mut self, ctx: &mut Context<Self>) {
+        self.meter(Some(false));
+        let request = RequestJob(Box::new(self.worker_handle(ctx)));
+
+        match self.ready.take() {
+            Some(ready) => {
+                let fut = self.server.send(request).then(move |res| {
+                    let _ = ready.send(res.map_err(Error::from).and_then(|res| res));
+                    Ok(())
+                });
+
+                ctx.spawn(wrap_future(fut));
+            }
+            None => self.server.do_send(request),
+        }
+    }
+
+    fn worker_handle(&self, ctx: &mut Context<Self>) -> LocalWorkerHandle<Self> {
+        LocalWorkerHandle {
+            id: self.id,
+            queues: self.queues.clone(),
+            stop: self.stop.clone(),
+            handing_off: self.handing_off.clone(),
+            addr: ctx.address(),
         }
     }
 }
 
 impl<S, State> Actor for LocalWorker<S, State>
 where
-    S: Actor + Handler<ReturningJob> + Handler<RequestJob>,
-    S::Context: ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob>,
+    S: Actor + Handler<ReturningJob> + Handler<RequestJob> + Handler<SaveCheckpoint>,
+    S::Context:
+        ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob> + ToEnvelope<S, SaveCheckpoint>,
     State: Clone + 'static,
 {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.server.do_send(RequestJob(Box::new(LocalWorkerHandle {
-            id: self.id,
-            queue: self.queue.clone(),
-            addr: ctx.address(),
-        })));
+        if self.gates.is_empty() {
+            self.request_first(ctx);
+            return;
+        }
+
+        let gates = join_all(self.gates.drain(..).collect::<Vec<_>>());
+
+        ctx.spawn(wrap_future::<_, Self>(gates).then(|res, actor, ctx| {
+            match res {
+                Ok(_) => actor.request_first(ctx),
+                Err(e) => {
+                    error!("Worker {} not starting, its gate failed: {}", actor.id, *e);
+
+                    if let Some(ready) = actor.ready.take() {
+                        let _ = ready.send(Err(format_err!("Worker gate failed: {}", *e)));
+                    }
+                }
+            }
+
+            fut::ok(())
+        }));
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.meter(None);
     }
 }
 
-pub struct ProcessJob(JobInfo);
+pub struct ProcessJob(JobInfo, Option<InFlightGuard>);
 
 impl Message for ProcessJob {
     type Result = ();
@@ -97,23 +446,89 @@ impl Message for ProcessJob {
 
 impl<S, State> Handler<ProcessJob> for LocalWorker<S, State>
 where
-    S: Actor + Handler<ReturningJob> + Handler<RequestJob>,
-    S::Context: ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob>,
+    S: Actor + Handler<ReturningJob> + Handler<RequestJob> + Handler<SaveCheckpoint>,
+    S::Context:
+        ToEnvelope<S, ReturningJob> + ToEnvelope<S, RequestJob> + ToEnvelope<S, SaveCheckpoint>,
     State: Clone + 'static,
 {
     type Result = ();
 
-    fn handle(&mut self, ProcessJob(job): ProcessJob, ctx: &mut Self::Context) -> Self::Result {
-        info!("Worker {} processing job {}", self.id, job.id());
-        let fut =
-            wrap_future::<_, Self>(self.processors.process_job(job)).map(|job, actor, ctx| {
-                actor.server.do_send(ReturningJob(job));
-                actor.server.do_send(RequestJob(Box::new(LocalWorkerHandle {
-                    id: actor.id,
-                    queue: actor.queue.clone(),
-                    addr: ctx.address(),
-                })));
-            });
+    fn handle(
+        &mut self,
+        ProcessJob(job, handing_off): ProcessJob,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        match job.trace_id() {
+            Some(trace_id) => info!(
+                "Worker {} processing job {}, trace {}",
+                self.id,
+                job.id(),
+                trace_id
+            ),
+            None => info!("Worker {} processing job {}", self.id, job.id()),
+        }
+        let id = job.id();
+        let token = CancellationToken::new();
+        let started = Instant::now();
+        self.meter(Some(true));
+
+        if let Some(ref running) = self.running {
+            let job = RunningJob {
+                token: token.clone(),
+                priority: job.priority(),
+                queues: self.queues.clone(),
+                started,
+                timeout: job.timeout(),
+            };
+
+            running.lock().unwrap().insert(id, job);
+        }
+        drop(handing_off);
+
+        // Progress is stored as soon as it is saved, so it survives a run that never returns
+        let server = self.server.clone();
+        let checkpoint = Checkpoint::new(job.checkpoint().cloned()).on_save(move |checkpoint| {
+            server.do_send(SaveCheckpoint(id, checkpoint.clone()));
+        });
+
+        let processing = self
+            .processors
+            .process_job_resumable(job, token, checkpoint);
+
+        let fut = wrap_future::<_, Self>(processing).map(move |job, actor, ctx| {
+            if let Some(ref running) = actor.running {
+                let mut running = running.lock().unwrap();
+
+                // A job that went over its time limit may be running again on another worker
+                if running.get(&id).is_some_and(|job| job.started == started) {
+                    running.remove(&id);
+                }
+            }
+
+            match actor.returning {
+                Some(ref returning) => {
+                    // The guard is dropped once the server has stored the result, or when the
+                    // message is dropped along with the server
+                    let guard = returning.track();
+
+                    actix::spawn(actor.server.send(ReturningJob(job)).then(move |_| {
+                        drop(guard);
+                        Ok(())
+                    }));
+                }
+                None => actor.server.do_send(ReturningJob(job)),
+            }
+            actor.meter(Some(false));
+
+            if actor.stop.load(Ordering::Relaxed) {
+                info!("Worker {} stopping", actor.id);
+                ctx.stop();
+            } else {
+                actor
+                    .server
+                    .do_send(RequestJob(Box::new(actor.worker_handle(ctx))));
+            }
+        });
 
         ctx.spawn(fut);
     }
@@ -0,0 +1,94 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, stream, Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+use self::common::FlakyStorage;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Touch {
+    resource: Option<String>,
+}
+
+#[derive(Clone)]
+struct TouchProcessor;
+
+impl Job for Touch {
+    type Processor = TouchProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+
+    fn affinity_key(&self) -> Option<String> {
+        self.resource.clone()
+    }
+}
+
+impl Processor for TouchProcessor {
+    type Job = Touch;
+
+    const NAME: &'static str = "TouchProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Run five jobs one after the other on four free workers, returning the worker each ran on
+fn runners(resource: Option<&str>) -> Vec<Option<u64>> {
+    let storage = FlakyStorage::new();
+    let ids = Arc::new(Mutex::new(Vec::new()));
+
+    let (store, recorded) = (storage.clone(), ids.clone());
+    let resource = resource.map(str::to_owned);
+    common::run("affinity", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(store).thread_count(1).start();
+
+        WorkerConfig::new(|| ())
+            .register(TouchProcessor)
+            .start(handle.clone());
+
+        let sequence = stream::iter_ok(0..5).for_each(move |_| {
+            let (watcher, recorded) = (handle.clone(), recorded.clone());
+            let job = Touch {
+                resource: resource.clone(),
+            };
+
+            handle.queue(job).and_then(move |id| {
+                recorded.lock().unwrap().push(id);
+                watcher.await_job(id).map(|_| ())
+            })
+        });
+
+        actix::spawn(sequence.then(|res| {
+            res.expect("Ran every job");
+            actix::System::current().stop();
+            Ok(())
+        }));
+    });
+
+    let ids = ids.lock().unwrap();
+    ids.iter().map(|id| storage.runner(*id)).collect()
+}
+
+#[test]
+fn jobs_with_the_same_affinity_key_land_on_one_worker() {
+    let with_key = runners(Some("cache"));
+    assert!(with_key[0].is_some());
+    assert!(
+        with_key.iter().all(|runner| *runner == with_key[0]),
+        "Ran on {:?}",
+        with_key
+    );
+
+    // Without a key, free workers take turns
+    let without_key = runners(None);
+    assert!(without_key.iter().any(|runner| *runner != without_key[0]));
+}
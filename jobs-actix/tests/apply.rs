@@ -0,0 +1,101 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Default)]
+struct Gate {
+    started: Arc<AtomicUsize>,
+    finished: Arc<AtomicUsize>,
+    open: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Hold;
+
+#[derive(Clone)]
+struct HoldProcessor;
+
+impl Job for Hold {
+    type Processor = HoldProcessor;
+    type State = Gate;
+
+    /// Keep a worker busy until the gate opens
+    fn run(self, gate: Gate) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        gate.started.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            while !gate.open.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            gate.finished.fetch_add(1, Ordering::SeqCst);
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+}
+
+impl Processor for HoldProcessor {
+    type Job = Hold;
+
+    const NAME: &'static str = "HoldProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+fn config(gate: &Gate, count: u64) -> WorkerConfig<Gate> {
+    let gate = gate.clone();
+
+    WorkerConfig::new(move || gate.clone())
+        .register(HoldProcessor)
+        .set_processor_count("default", count)
+}
+
+#[test]
+fn applying_a_larger_count_starts_more_workers() {
+    let gate = Gate::default();
+    let started_before = Arc::new(AtomicUsize::new(0));
+
+    let (state, before) = (gate.clone(), started_before.clone());
+    common::run("apply", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        config(&state, 2).apply(handle.clone());
+        for _ in 0..5 {
+            handle.queue_fire_and_forget(Hold).unwrap();
+        }
+
+        let larger = config(&state, 5);
+        let observed = state.clone();
+        common::after(Duration::from_millis(200), move || {
+            before.store(observed.started.load(Ordering::SeqCst), Ordering::SeqCst);
+            larger.apply(handle);
+        });
+
+        common::stop_when(move || {
+            if state.started.load(Ordering::SeqCst) == 5 {
+                state.open.store(true, Ordering::SeqCst);
+            }
+
+            state.finished.load(Ordering::SeqCst) == 5
+        });
+    });
+
+    // Only the first two workers were running jobs, and the three new ones took the rest
+    assert_eq!(started_before.load(Ordering::SeqCst), 2);
+    assert_eq!(gate.started.load(Ordering::SeqCst), 5);
+}
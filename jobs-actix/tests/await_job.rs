@@ -0,0 +1,73 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, JobResult, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Slow;
+
+#[derive(Clone)]
+struct SlowProcessor;
+
+impl Job for Slow {
+    type Processor = SlowProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+}
+
+impl Processor for SlowProcessor {
+    type Job = Slow;
+
+    const NAME: &'static str = "SlowProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn await_job_resolves_with_the_result_of_a_job_queued_elsewhere() {
+    let results = Arc::new(Mutex::new(None));
+
+    let recorded = results.clone();
+    common::run("await-job", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        WorkerConfig::new(|| ())
+            .register(SlowProcessor)
+            .start(handle.clone());
+
+        // Another part of the application only knows the job's ID
+        let elsewhere = handle.clone();
+        actix::spawn(
+            handle
+                .queue(Slow)
+                .and_then(move |id| elsewhere.await_job(id).join(elsewhere.await_job(id + 1)))
+                .then(move |res| {
+                    *recorded.lock().unwrap() = Some(res.map_err(|e| e.to_string()));
+                    actix::System::current().stop();
+                    Ok(())
+                }),
+        );
+    });
+
+    assert_eq!(
+        results.lock().unwrap().take(),
+        Some(Ok((Some(JobResult::Success), None)))
+    );
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Default)]
+struct Record {
+    ran: Arc<Mutex<Vec<u32>>>,
+    open: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Slow(u32);
+
+#[derive(Clone)]
+struct SlowProcessor;
+
+impl Job for Slow {
+    type Processor = SlowProcessor;
+    type State = Record;
+
+    /// Keep the only worker busy until the gate opens
+    fn run(self, record: Record) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        record.ran.lock().unwrap().push(self.0);
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            while !record.open.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+}
+
+impl Processor for SlowProcessor {
+    type Job = Slow;
+
+    const NAME: &'static str = "SlowProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn only_pending_jobs_are_cancelled() {
+    let record = Record::default();
+    let cancelled = Arc::new(Mutex::new(None));
+
+    let (state, recorded) = (record.clone(), cancelled.clone());
+    common::run("cancel", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .deterministic()
+            .start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(SlowProcessor)
+            .set_processor_count("default", 1)
+            .start(handle.clone());
+
+        // The first job takes the only worker, so the second one stays pending
+        let (h1, h2, h3) = (handle.clone(), handle.clone(), handle.clone());
+        let cancelling = handle.queue(Slow(1)).join(handle.queue(Slow(2))).and_then(
+            move |(running, pending)| {
+                h1.cancel(running)
+                    .join3(h2.cancel(pending), h3.cancel(pending + 100))
+            },
+        );
+
+        let observed = state.clone();
+        actix::spawn(cancelling.then(move |res| {
+            *recorded.lock().unwrap() = Some(res.map_err(|e| e.to_string()));
+            observed.open.store(true, Ordering::SeqCst);
+            Ok(())
+        }));
+
+        // Give a job that wasn't cancelled time to run after the first one
+        common::after(Duration::from_millis(300), || {
+            actix::System::current().stop()
+        });
+    });
+
+    assert_eq!(
+        cancelled.lock().unwrap().take(),
+        Some(Ok((false, true, false)))
+    );
+    assert_eq!(*record.ran.lock().unwrap(), vec![1]);
+}
@@ -0,0 +1,95 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, CancellationToken, Job, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Default)]
+struct Progress {
+    started: Arc<AtomicBool>,
+    cleaned_up: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Long;
+
+#[derive(Clone)]
+struct LongProcessor;
+
+impl Job for Long {
+    type Processor = LongProcessor;
+    type State = Progress;
+
+    fn run(self, _: Progress) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        unreachable!("Runtimes call run_cancellable")
+    }
+
+    /// Work until cancelled, then roll back
+    fn run_cancellable(
+        self,
+        progress: Progress,
+        token: CancellationToken,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        progress.started.store(true, Ordering::SeqCst);
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            progress.cleaned_up.store(true, Ordering::SeqCst);
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+}
+
+impl Processor for LongProcessor {
+    type Job = Long;
+
+    const NAME: &'static str = "LongProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn cancelled_jobs_run_their_cleanup() {
+    let progress = Progress::default();
+    let cancelled = Arc::new(Mutex::new(None));
+
+    let (state, found) = (progress.clone(), cancelled.clone());
+    common::run("cancel-running", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(LongProcessor)
+            .start(handle.clone());
+
+        let cancelling = handle.clone();
+        actix::spawn(handle.queue(Long).map_err(|_| ()).map(move |id| {
+            common::stop_when(move || {
+                if found.lock().unwrap().is_none() && state.started.load(Ordering::SeqCst) {
+                    *found.lock().unwrap() = Some(cancelling.cancel_running(id));
+                }
+
+                state.cleaned_up.load(Ordering::SeqCst)
+            });
+        }));
+    });
+
+    assert_eq!(*cancelled.lock().unwrap(), Some(true));
+    assert!(progress.cleaned_up.load(Ordering::SeqCst));
+}
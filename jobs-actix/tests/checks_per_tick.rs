@@ -0,0 +1,82 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+use self::common::FlakyStorage;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Noop;
+
+#[derive(Clone)]
+struct NoopProcessor;
+
+impl Job for Noop {
+    type Processor = NoopProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for NoopProcessor {
+    type Job = Noop;
+
+    const NAME: &'static str = "NoopProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Count the checks for ready jobs made over three ticks, with one idle worker to check for
+///
+/// With a single server thread, the worker is always parked where the checks are handled, so each
+/// check looks for a job once.
+fn checks_over_three_ticks(checks: usize) -> usize {
+    let storage = FlakyStorage::new();
+    let counted = Arc::new(Mutex::new(0));
+
+    let (store, recorded) = (storage.clone(), counted.clone());
+    common::run("checks-per-tick", Duration::from_secs(10), move || {
+        let handle = ServerConfig::new(store.clone())
+            .thread_count(1)
+            .checks_per_tick(checks)
+            .start();
+
+        WorkerConfig::new(|| ())
+            .register(NoopProcessor)
+            .set_processor_count("default", 1)
+            .start(handle);
+
+        // Start counting halfway between two ticks, once the worker's own request is done
+        let before = store.clone();
+        common::after(Duration::from_millis(500), move || {
+            let start = before.calls("fetch_job_from_queue");
+
+            common::after(Duration::from_secs(3), move || {
+                *recorded.lock().unwrap() = store.calls("fetch_job_from_queue") - start;
+                actix::System::current().stop();
+            });
+        });
+    });
+
+    let counted = *counted.lock().unwrap();
+    counted
+}
+
+#[test]
+fn several_checks_are_made_every_tick() {
+    assert_eq!(checks_over_three_ticks(5), 15);
+}
+
+#[test]
+fn a_single_check_is_made_every_tick() {
+    assert_eq!(checks_over_three_ticks(1), 3);
+}
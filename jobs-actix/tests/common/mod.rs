@@ -0,0 +1,260 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix::System;
+use background_jobs_core::{clock::Timestamp, memory_storage, JobInfo, Stats, Storage};
+use failure::Fail;
+use futures::{Future, Stream};
+use tokio_timer::{Delay, Interval};
+
+/// Run an actix system set up by `setup` until it is stopped, panicking if that takes longer
+/// than `timeout`
+pub fn run<F>(name: &str, timeout: Duration, setup: F)
+where
+    F: FnOnce(),
+{
+    let sys = System::new(name);
+    let timed_out = Arc::new(AtomicBool::new(false));
+
+    setup();
+
+    let flag = timed_out.clone();
+    actix::spawn(Delay::new(Instant::now() + timeout).then(move |_| {
+        flag.store(true, Ordering::SeqCst);
+        System::current().stop();
+        Ok(())
+    }));
+
+    sys.run().expect("Ran the system");
+    assert!(!timed_out.load(Ordering::SeqCst), "Timed out");
+}
+
+/// Stop the current system once `done` returns true, checking every 10ms
+pub fn stop_when<F>(mut done: F)
+where
+    F: FnMut() -> bool + 'static,
+{
+    actix::spawn(
+        Interval::new_interval(Duration::from_millis(10))
+            .map_err(|_| ())
+            .skip_while(move |_| Ok(!done()))
+            .into_future()
+            .then(|_| {
+                System::current().stop();
+                Ok(())
+            }),
+    );
+}
+
+/// Run `f` after `after` has passed, on the current system
+pub fn after<F>(after: Duration, f: F)
+where
+    F: FnOnce() + 'static,
+{
+    actix::spawn(Delay::new(Instant::now() + after).then(move |_| {
+        f();
+        Ok(())
+    }));
+}
+
+/// The error returned by a `FlakyStorage` operation that was told to fail
+#[derive(Debug)]
+pub struct Broken(pub &'static str);
+
+impl fmt::Display for Broken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Storage failed on purpose in {}", self.0)
+    }
+}
+
+impl Fail for Broken {}
+
+/// A memory storage whose operations can be told to fail a given number of times, and which
+/// records how often each is called and the workers jobs are handed to
+#[derive(Clone)]
+pub struct FlakyStorage {
+    pub inner: memory_storage::Storage,
+    failures: Arc<Mutex<HashMap<&'static str, usize>>>,
+    calls: Arc<Mutex<HashMap<&'static str, usize>>>,
+    runners: Arc<Mutex<HashMap<u64, u64>>>,
+}
+
+impl FlakyStorage {
+    pub fn new() -> Self {
+        FlakyStorage {
+            inner: memory_storage::Storage::new(),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            calls: Arc::new(Mutex::new(HashMap::new())),
+            runners: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Make the next `times` calls of the named operation fail
+    pub fn fail(&self, operation: &'static str, times: usize) {
+        self.failures.lock().unwrap().insert(operation, times);
+    }
+
+    /// How many failures of the named operation are still to come
+    pub fn failures_left(&self, operation: &'static str) -> usize {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(operation)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// How many times the named operation has been called
+    pub fn calls(&self, operation: &'static str) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .get(operation)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// The ID of the worker each job was last handed to
+    pub fn runner(&self, id: u64) -> Option<u64> {
+        self.runners.lock().unwrap().get(&id).cloned()
+    }
+
+    fn check(&self, operation: &'static str) -> Result<(), Broken> {
+        *self.calls.lock().unwrap().entry(operation).or_insert(0) += 1;
+
+        let mut failures = self.failures.lock().unwrap();
+
+        match failures.get_mut(operation) {
+            Some(left) if *left > 0 => {
+                *left -= 1;
+                Err(Broken(operation))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn never(e: memory_storage::Never) -> Broken {
+    match e {}
+}
+
+impl Storage for FlakyStorage {
+    type Error = Broken;
+
+    fn generate_id(&mut self) -> Result<u64, Broken> {
+        self.check("generate_id")?;
+        self.inner.generate_id().map_err(|_| Broken("generate_id"))
+    }
+
+    fn save_job(&mut self, job: JobInfo) -> Result<(), Broken> {
+        self.check("save_job")?;
+        self.inner.save_job(job).map_err(never)
+    }
+
+    fn fetch_job(&mut self, id: u64) -> Result<Option<JobInfo>, Broken> {
+        self.check("fetch_job")?;
+        self.inner.fetch_job(id).map_err(never)
+    }
+
+    fn fetch_unique_job(&mut self, queue: &str, key: &str) -> Result<Option<u64>, Broken> {
+        self.check("fetch_unique_job")?;
+        self.inner.fetch_unique_job(queue, key).map_err(never)
+    }
+
+    fn fetch_global_job(&mut self, key: &str) -> Result<Option<u64>, Broken> {
+        self.check("fetch_global_job")?;
+        self.inner.fetch_global_job(key).map_err(never)
+    }
+
+    fn save_completed_key(&mut self, queue: &str, key: &str, id: u64) -> Result<(), Broken> {
+        self.check("save_completed_key")?;
+        self.inner.save_completed_key(queue, key, id).map_err(never)
+    }
+
+    fn fetch_completed_key(
+        &mut self,
+        queue: &str,
+        key: &str,
+    ) -> Result<Option<(u64, Timestamp)>, Broken> {
+        self.check("fetch_completed_key")?;
+        self.inner.fetch_completed_key(queue, key).map_err(never)
+    }
+
+    fn delete_completed_key(&mut self, queue: &str, key: &str) -> Result<(), Broken> {
+        self.check("delete_completed_key")?;
+        self.inner.delete_completed_key(queue, key).map_err(never)
+    }
+
+    fn list_jobs(&mut self) -> Result<Vec<JobInfo>, Broken> {
+        self.check("list_jobs")?;
+        self.inner.list_jobs().map_err(never)
+    }
+
+    fn fetch_job_from_queue(&mut self, queue: &str) -> Result<Option<JobInfo>, Broken> {
+        self.check("fetch_job_from_queue")?;
+        self.inner.fetch_job_from_queue(queue).map_err(never)
+    }
+
+    fn queue_job(&mut self, queue: &str, id: u64) -> Result<(), Broken> {
+        self.check("queue_job")?;
+        self.inner.queue_job(queue, id).map_err(never)
+    }
+
+    fn run_job(&mut self, id: u64, runner_id: u64) -> Result<(), Broken> {
+        self.check("run_job")?;
+        self.runners.lock().unwrap().insert(id, runner_id);
+        self.inner.run_job(id, runner_id).map_err(never)
+    }
+
+    fn delete_job(&mut self, id: u64) -> Result<(), Broken> {
+        self.check("delete_job")?;
+        self.inner.delete_job(id).map_err(never)
+    }
+
+    fn save_dead_job(&mut self, job: JobInfo) -> Result<(), Broken> {
+        self.check("save_dead_job")?;
+        self.inner.save_dead_job(job).map_err(never)
+    }
+
+    fn fetch_dead_jobs(&mut self) -> Result<Vec<JobInfo>, Broken> {
+        self.check("fetch_dead_jobs")?;
+        self.inner.fetch_dead_jobs().map_err(never)
+    }
+
+    fn remove_dead_job(&mut self, id: u64) -> Result<Option<JobInfo>, Broken> {
+        self.check("remove_dead_job")?;
+        self.inner.remove_dead_job(id).map_err(never)
+    }
+
+    fn purge_jobs(&mut self) -> Result<(), Broken> {
+        self.check("purge_jobs")?;
+        self.inner.purge_jobs().map_err(never)
+    }
+
+    fn next_wakeup(&self) -> Result<Option<Timestamp>, Broken> {
+        self.check("next_wakeup")?;
+        self.inner.next_wakeup().map_err(never)
+    }
+
+    fn get_stats(&self) -> Result<Stats, Broken> {
+        self.check("get_stats")?;
+        self.inner.get_stats().map_err(never)
+    }
+
+    fn update_stats<F>(&mut self, f: F) -> Result<(), Broken>
+    where
+        F: Fn(Stats) -> Stats,
+    {
+        self.check("update_stats")?;
+        self.inner.update_stats(f).map_err(never)
+    }
+}
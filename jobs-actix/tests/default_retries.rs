@@ -0,0 +1,68 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Backoff, Job, JobResult, MaxRetries, Processor};
+use failure::{format_err, Error};
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Failing;
+
+#[derive(Clone)]
+struct FailingProcessor;
+
+impl Job for Failing {
+    type Processor = FailingProcessor;
+    type State = Arc<AtomicUsize>;
+
+    fn run(self, runs: Arc<AtomicUsize>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        runs.fetch_add(1, Ordering::SeqCst);
+
+        Box::new(Err(format_err!("Always fails")).into_future())
+    }
+}
+
+// No MAX_RETRIES or BACKOFF_STRATEGY, so the server's defaults apply
+impl Processor for FailingProcessor {
+    type Job = Failing;
+
+    const NAME: &'static str = "FailingProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn processors_without_a_policy_use_the_server_defaults() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let result = Arc::new(Mutex::new(None));
+
+    let (state, recorded) = (runs.clone(), result.clone());
+    common::run("default-retries", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .default_max_retries(MaxRetries::Count(2))
+            .default_backoff(Backoff::Linear(0))
+            .start();
+
+        WorkerConfig::new(move || state.clone())
+            .register(FailingProcessor)
+            .start(handle.clone());
+
+        actix::spawn(handle.queue_and_watch(Failing).then(move |res| {
+            *recorded.lock().unwrap() = Some(res.map_err(|e| e.to_string()));
+            actix::System::current().stop();
+            Ok(())
+        }));
+    });
+
+    // The built in defaults would have waited seconds between each of five retries
+    assert_eq!(result.lock().unwrap().take(), Some(Ok(JobResult::Failure)));
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
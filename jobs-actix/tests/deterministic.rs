@@ -0,0 +1,75 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Step {
+    name: String,
+    priority: i32,
+}
+
+#[derive(Clone)]
+struct StepProcessor;
+
+impl Job for Step {
+    type Processor = StepProcessor;
+    type State = Arc<Mutex<Vec<String>>>;
+
+    fn run(self, order: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        order.lock().unwrap().push(self.name);
+
+        Box::new(Ok(()).into_future())
+    }
+
+    fn priority(&self) -> Option<i32> {
+        Some(self.priority)
+    }
+}
+
+impl Processor for StepProcessor {
+    type Job = Step;
+
+    const NAME: &'static str = "StepProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn a_deterministic_server_dispatches_by_priority_then_creation() {
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    let state = order.clone();
+    common::run("deterministic", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .thread_count(4)
+            .deterministic()
+            .start();
+
+        for (name, priority) in &[("a", 0), ("b", 5), ("c", 0), ("d", 5), ("e", 10)] {
+            let step = Step {
+                name: name.to_string(),
+                priority: *priority,
+            };
+            handle.queue_fire_and_forget(step).unwrap();
+        }
+
+        // Started after the jobs were sent, so the server has stored all of them first
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(StepProcessor)
+            .set_processor_count("default", 1)
+            .start(handle);
+
+        common::stop_when(move || state.lock().unwrap().len() == 5);
+    });
+
+    assert_eq!(*order.lock().unwrap(), vec!["e", "b", "d", "a", "c"]);
+}
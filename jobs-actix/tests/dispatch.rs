@@ -0,0 +1,122 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use background_jobs_actix::{QueueHandle, ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor, Storage};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+const JOBS: usize = 3;
+
+#[derive(Clone, Default)]
+struct Runs {
+    started: Arc<AtomicUsize>,
+    finished: Arc<AtomicUsize>,
+    together: Arc<Mutex<Vec<bool>>>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Wait;
+
+#[derive(Clone)]
+struct WaitProcessor;
+
+impl Job for Wait {
+    type Processor = WaitProcessor;
+    type State = Runs;
+
+    /// Wait a little for every other job to start, recording whether they did
+    fn run(self, runs: Runs) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        runs.started.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let started = Instant::now();
+            while runs.started.load(Ordering::SeqCst) < JOBS
+                && started.elapsed() < Duration::from_millis(300)
+            {
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            let together = runs.started.load(Ordering::SeqCst) >= JOBS;
+            runs.together.lock().unwrap().push(together);
+            runs.finished.fetch_add(1, Ordering::SeqCst);
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+}
+
+impl Processor for WaitProcessor {
+    type Job = Wait;
+
+    const NAME: &'static str = "WaitProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Store all but one job behind the server's back once every worker is waiting, then let
+/// `trigger` add the last one and wake the server up
+fn dispatch_with<F>(trigger: F) -> Vec<bool>
+where
+    F: FnOnce(QueueHandle, memory_storage::Storage) + 'static,
+{
+    let runs = Runs::default();
+
+    let state = runs.clone();
+    common::run("dispatch", Duration::from_secs(5), move || {
+        let mut storage = memory_storage::Storage::new();
+        let handle = ServerConfig::new(storage.clone()).thread_count(1).start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(WaitProcessor)
+            .set_processor_count("default", JOBS as u64)
+            .start(handle.clone());
+
+        common::after(Duration::from_millis(100), move || {
+            for _ in 1..JOBS {
+                storage
+                    .new_job(WaitProcessor::new_job(Wait).unwrap())
+                    .unwrap();
+            }
+
+            trigger(handle, storage);
+        });
+
+        common::stop_when(move || state.finished.load(Ordering::SeqCst) == JOBS);
+    });
+
+    let together = runs.together.lock().unwrap().clone();
+    together
+}
+
+#[test]
+fn a_new_job_dispatches_every_ready_job() {
+    let together = dispatch_with(|handle, _| {
+        handle.queue_fire_and_forget(Wait).unwrap();
+    });
+
+    assert_eq!(together, vec![true; JOBS]);
+}
+
+#[test]
+fn a_check_dispatches_every_ready_job() {
+    let together = dispatch_with(|handle, mut storage| {
+        storage
+            .new_job(WaitProcessor::new_job(Wait).unwrap())
+            .unwrap();
+        actix::spawn(handle.flush_scheduled().map_err(|e| panic!("{}", e)));
+    });
+
+    assert_eq!(together, vec![true; JOBS]);
+}
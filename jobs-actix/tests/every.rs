@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor, Storage};
+use failure::Error;
+use futures::{future, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Report {
+    payload: String,
+}
+
+#[derive(Clone)]
+struct ReportProcessor;
+
+impl Job for Report {
+    type Processor = ReportProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        // The worker is stuck on its first copy, so every later copy stays pending
+        Box::new(future::empty())
+    }
+}
+
+impl Processor for ReportProcessor {
+    type Job = Report;
+
+    const NAME: &'static str = "ReportProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn a_stalled_recurring_job_keeps_a_single_pending_copy() {
+    let mut storage = memory_storage::Storage::new();
+
+    let server_storage = storage.clone();
+    common::run("every", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(server_storage).start();
+
+        WorkerConfig::new(|| ())
+            .register(ReportProcessor)
+            .set_processor_count("default", 1)
+            .start(handle.clone());
+
+        let every = handle.every(
+            Duration::from_millis(20),
+            Report {
+                payload: "x".repeat(100_000),
+            },
+        );
+
+        // About 25 ticks
+        common::after(Duration::from_millis(500), move || {
+            every.stop();
+            actix::System::current().stop();
+        });
+    });
+
+    let jobs = storage.list_jobs().unwrap();
+    let pending = jobs.iter().filter(|job| job.is_pending()).count();
+
+    // The copy the worker is stuck on, and the one every later tick coalesced into
+    assert_eq!(jobs.len(), 2);
+    assert_eq!(pending, 1);
+}
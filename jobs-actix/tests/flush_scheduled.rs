@@ -0,0 +1,76 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, NewJobInfo, Processor, Storage};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Ping;
+
+#[derive(Clone)]
+struct PingProcessor;
+
+impl Job for Ping {
+    type Processor = PingProcessor;
+    type State = Arc<Mutex<Option<Instant>>>;
+
+    fn run(self, ran_at: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        *ran_at.lock().unwrap() = Some(Instant::now());
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for PingProcessor {
+    type Job = Ping;
+
+    const NAME: &'static str = "PingProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn flushing_dispatches_a_ready_job_before_the_next_check() {
+    let ran_at = Arc::new(Mutex::new(None));
+    let flushed_at = Arc::new(Mutex::new(None));
+
+    let (ran, flushed) = (ran_at.clone(), flushed_at.clone());
+    common::run("flush-scheduled", Duration::from_secs(5), move || {
+        let mut storage = memory_storage::Storage::new();
+        let handle = ServerConfig::new(storage.clone()).start();
+
+        let state = ran.clone();
+        WorkerConfig::new(move || state.clone())
+            .register(PingProcessor)
+            .start(handle.clone());
+
+        // Store a ready job behind the server's back once the worker is waiting for one, so
+        // only the server's periodic check would otherwise find it
+        let flushed_at = flushed.clone();
+        common::after(Duration::from_millis(100), move || {
+            let job = NewJobInfo::builder(PingProcessor::NAME, "default")
+                .args(json!(null))
+                .build()
+                .unwrap();
+            storage.new_job(job).unwrap();
+
+            *flushed_at.lock().unwrap() = Some(Instant::now());
+            actix::spawn(handle.flush_scheduled().map_err(|e| panic!("{}", e)));
+        });
+
+        common::stop_when(move || ran.lock().unwrap().is_some());
+    });
+
+    let ran_at = ran_at.lock().unwrap().unwrap();
+    let flushed_at = flushed_at.lock().unwrap().unwrap();
+
+    // The server only checks once per second on its own
+    assert!(ran_at.duration_since(flushed_at) < Duration::from_millis(500));
+}
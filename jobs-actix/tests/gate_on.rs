@@ -0,0 +1,90 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Default)]
+struct Record {
+    gate_open: Arc<AtomicBool>,
+
+    /// Each job that ran, and whether the gate was open at the time
+    runs: Arc<Mutex<Vec<(String, bool)>>>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Task(String);
+
+#[derive(Clone)]
+struct TaskProcessor;
+
+impl Job for Task {
+    type Processor = TaskProcessor;
+    type State = Record;
+
+    fn run(self, record: Record) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let open = record.gate_open.load(Ordering::SeqCst);
+        record.runs.lock().unwrap().push((self.0, open));
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for TaskProcessor {
+    type Job = Task;
+
+    const NAME: &'static str = "TaskProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn gated_workers_wait_for_the_gate() {
+    let record = Record::default();
+
+    let state = record.clone();
+    common::run("gate-on", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let (tx, rx) = oneshot::channel();
+        let gated_state = state.clone();
+        WorkerConfig::new(move || gated_state.clone())
+            .register(TaskProcessor)
+            .set_processor_count("default", 0)
+            .set_processor_count("gated", 1)
+            .gate_on(rx.from_err())
+            .start(handle.clone());
+
+        let open_state = state.clone();
+        WorkerConfig::new(move || open_state.clone())
+            .register(TaskProcessor)
+            .set_processor_count("default", 0)
+            .set_processor_count("open", 1)
+            .start(handle.clone());
+
+        handle.queue_on("gated", Task("gated".to_owned())).unwrap();
+        handle.queue_on("open", Task("open".to_owned())).unwrap();
+
+        let gate_open = state.gate_open.clone();
+        common::after(Duration::from_millis(200), move || {
+            gate_open.store(true, Ordering::SeqCst);
+            let _ = tx.send(());
+        });
+
+        common::stop_when(move || state.runs.lock().unwrap().len() == 2);
+    });
+
+    assert_eq!(
+        *record.runs.lock().unwrap(),
+        vec![("open".to_owned(), false), ("gated".to_owned(), true)]
+    );
+}
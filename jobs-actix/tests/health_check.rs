@@ -0,0 +1,55 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::ServerConfig;
+use futures::Future;
+
+mod common;
+
+use self::common::FlakyStorage;
+
+#[test]
+fn health_check_reports_a_broken_storage() {
+    let results = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = results.clone();
+    common::run("health-check", Duration::from_secs(5), move || {
+        let storage = FlakyStorage::new();
+        let handle = ServerConfig::new(storage.clone()).start();
+
+        let broken = handle.clone();
+        let after_healthy = recorded.clone();
+        let after_broken = recorded.clone();
+        actix::spawn(
+            handle
+                .health_check()
+                .then(move |res| {
+                    after_healthy
+                        .lock()
+                        .unwrap()
+                        .push(res.map_err(|e| e.to_string()));
+
+                    // Fail every read from now on, including the server's retries
+                    storage.fail("get_stats", usize::MAX);
+                    broken.health_check()
+                })
+                .then(move |res| {
+                    after_broken
+                        .lock()
+                        .unwrap()
+                        .push(res.map_err(|e| e.to_string()));
+                    actix::System::current().stop();
+                    Ok(())
+                }),
+        );
+    });
+
+    let results = results.lock().unwrap();
+    assert_eq!(results[0], Ok(()));
+    assert_eq!(
+        results[1],
+        Err("Storage failed on purpose in get_stats".to_owned())
+    );
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{
+    memory_storage, Job, JobEventKind, MissingProcessorPolicy, NewJobInfo, Processor, Storage,
+};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Kept;
+
+#[derive(Clone)]
+struct KeptProcessor;
+
+impl Job for Kept {
+    type Processor = KeptProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for KeptProcessor {
+    type Job = Kept;
+
+    const NAME: &'static str = "KeptProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Run a job stored for a processor that no worker has, returning the storage afterwards
+fn run_removed_job(policy: MissingProcessorPolicy) -> (memory_storage::Storage, u64) {
+    let mut storage = memory_storage::Storage::new();
+    let job = NewJobInfo::builder("RemovedProcessor", "default")
+        .args(json!([]))
+        .build()
+        .unwrap();
+    let id = storage.new_job(job).unwrap();
+
+    let store = storage.clone();
+    common::run("missing-processor", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(store.clone())
+            .on_missing_processor(policy)
+            .start();
+
+        WorkerConfig::new(|| ())
+            .register(KeptProcessor)
+            .start(handle);
+
+        // Requeued jobs keep coming back, so give them a while to show that
+        match policy {
+            MissingProcessorPolicy::Requeue => common::after(Duration::from_millis(200), || {
+                actix::System::current().stop()
+            }),
+            _ => {
+                let mut store = store;
+                common::stop_when(move || store.fetch_job(id).unwrap().is_none())
+            }
+        }
+    });
+
+    (storage, id)
+}
+
+#[test]
+fn requeued_jobs_are_kept() {
+    let (mut storage, id) = run_removed_job(MissingProcessorPolicy::Requeue);
+
+    let job = storage
+        .fetch_job(id)
+        .unwrap()
+        .expect("Requeued jobs are kept");
+    assert!(job
+        .history()
+        .iter()
+        .any(|(_, event)| *event == JobEventKind::Requeued));
+    assert!(storage.fetch_dead_jobs().unwrap().is_empty());
+}
+
+#[test]
+fn dead_lettered_jobs_become_dead() {
+    let (mut storage, id) = run_removed_job(MissingProcessorPolicy::DeadLetter);
+
+    assert!(storage.fetch_job(id).unwrap().is_none());
+    let dead = storage.fetch_dead_jobs().unwrap();
+    assert_eq!(
+        dead.iter().map(|job| job.id()).collect::<Vec<_>>(),
+        vec![id]
+    );
+}
+
+#[test]
+fn dropped_jobs_are_deleted() {
+    let (mut storage, id) = run_removed_job(MissingProcessorPolicy::Drop);
+
+    assert!(storage.fetch_job(id).unwrap().is_none());
+    assert!(storage.fetch_dead_jobs().unwrap().is_empty());
+}
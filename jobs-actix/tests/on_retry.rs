@@ -0,0 +1,80 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Backoff, Job, MaxRetries, Processor};
+use failure::{format_err, Error};
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Default)]
+struct Calls {
+    runs: Arc<Mutex<u32>>,
+    retries: Arc<Mutex<Vec<u32>>>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Flaky;
+
+#[derive(Clone)]
+struct FlakyProcessor;
+
+impl Job for Flaky {
+    type Processor = FlakyProcessor;
+    type State = Calls;
+
+    fn run(self, calls: Calls) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let mut runs = calls.runs.lock().unwrap();
+        *runs += 1;
+
+        if *runs <= 2 {
+            return Box::new(Err(format_err!("Failed run {}", *runs)).into_future());
+        }
+
+        Box::new(Ok(()).into_future())
+    }
+
+    fn max_retries(&self) -> Option<MaxRetries> {
+        Some(MaxRetries::Count(5))
+    }
+
+    fn backoff_strategy(&self) -> Option<Backoff> {
+        Some(Backoff::Linear(0))
+    }
+
+    fn on_retry(&self, attempt: u32, calls: &Calls) {
+        calls.retries.lock().unwrap().push(attempt);
+    }
+}
+
+impl Processor for FlakyProcessor {
+    type Job = Flaky;
+
+    const NAME: &'static str = "FlakyProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn on_retry_is_called_with_each_upcoming_attempt() {
+    let calls = Calls::default();
+
+    let state = calls.clone();
+    common::run("on-retry", Duration::from_secs(10), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(FlakyProcessor)
+            .start(handle.clone());
+
+        handle.queue_fire_and_forget(Flaky).unwrap();
+
+        common::stop_when(move || *state.runs.lock().unwrap() == 3);
+    });
+
+    assert_eq!(*calls.retries.lock().unwrap(), vec![1, 2]);
+}
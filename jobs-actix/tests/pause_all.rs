@@ -0,0 +1,71 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Count;
+
+#[derive(Clone)]
+struct CountProcessor;
+
+impl Job for Count {
+    type Processor = CountProcessor;
+    type State = Arc<AtomicUsize>;
+
+    fn run(self, runs: Arc<AtomicUsize>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        runs.fetch_add(1, Ordering::SeqCst);
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for CountProcessor {
+    type Job = Count;
+
+    const NAME: &'static str = "CountProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn paused_jobs_run_once_dispatch_resumes() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let runs_while_paused = Arc::new(AtomicUsize::new(usize::MAX));
+
+    let (state, paused_runs) = (runs.clone(), runs_while_paused.clone());
+    common::run("pause-all", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(CountProcessor)
+            .start(handle.clone());
+
+        handle.pause_all();
+        for _ in 0..3 {
+            handle.queue_fire_and_forget(Count).unwrap();
+        }
+
+        let observed = state.clone();
+        common::after(Duration::from_millis(300), move || {
+            paused_runs.store(observed.load(Ordering::SeqCst), Ordering::SeqCst);
+            handle.resume_all();
+        });
+
+        common::stop_when(move || state.load(Ordering::SeqCst) == 3);
+    });
+
+    assert_eq!(runs_while_paused.load(Ordering::SeqCst), 0);
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "core_affinity")]
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix::Arbiter;
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Report;
+
+#[derive(Clone)]
+struct ReportProcessor;
+
+impl Job for Report {
+    type Processor = ReportProcessor;
+    type State = Arc<Mutex<Option<Vec<usize>>>>;
+
+    /// Record the cores the worker's thread may run on
+    fn run(self, cores: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let allowed = core_affinity::get_core_ids()
+            .unwrap_or_default()
+            .iter()
+            .map(|core| core.id)
+            .collect();
+        *cores.lock().unwrap() = Some(allowed);
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for ReportProcessor {
+    type Job = Report;
+
+    const NAME: &'static str = "ReportProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn pinned_workers_process_jobs() {
+    let core = core_affinity::get_core_ids().expect("Listed the cores")[0];
+    let cores = Arc::new(Mutex::new(None));
+
+    let recorded = cores.clone();
+    common::run("pinned", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let state = recorded.clone();
+        WorkerConfig::new(move || state.clone())
+            .register(ReportProcessor)
+            .set_processor_count("default", 1)
+            .start_in_arbiter_pinned(&Arbiter::new(), core, handle.clone());
+
+        handle.queue_fire_and_forget(Report).unwrap();
+
+        common::stop_when(move || recorded.lock().unwrap().is_some());
+    });
+
+    let cores = cores.lock().unwrap().take().unwrap();
+
+    // Only Linux lists the cores the current thread may run on, rather than every core
+    if cfg!(target_os = "linux") {
+        assert_eq!(cores, vec![core.id]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Default)]
+struct Record {
+    started: Arc<Mutex<Vec<String>>>,
+    finished: Arc<Mutex<Vec<String>>>,
+    open: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Task {
+    name: String,
+    hold: bool,
+}
+
+#[derive(Clone)]
+struct TaskProcessor;
+
+impl Job for Task {
+    type Processor = TaskProcessor;
+    type State = Record;
+
+    /// Record the task, keeping its worker busy until the gate opens if it holds
+    fn run(self, record: Record) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        record.started.lock().unwrap().push(self.name.clone());
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            while self.hold && !record.open.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            record.finished.lock().unwrap().push(self.name);
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+}
+
+impl Processor for TaskProcessor {
+    type Job = Task;
+
+    const NAME: &'static str = "TaskProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+fn task(name: &str, hold: bool) -> Task {
+    Task {
+        name: name.to_owned(),
+        hold,
+    }
+}
+
+#[test]
+fn pools_only_take_jobs_from_their_own_queues() {
+    let record = Record::default();
+    let started_while_held = Arc::new(Mutex::new(Vec::new()));
+
+    let (state, snapshot) = (record.clone(), started_while_held.clone());
+    common::run("pools", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(TaskProcessor)
+            .set_processor_count("default", 0)
+            .add_pool("fast", &["fast", "shared"], 1)
+            .add_pool("slow", &["slow", "shared"], 1)
+            .start(handle.clone());
+
+        handle.queue_on("slow", task("slow-held", true)).unwrap();
+        common::after(Duration::from_millis(100), move || {
+            handle
+                .queue_on("slow", task("slow-waiting", false))
+                .unwrap();
+            handle.queue_on("fast", task("fast", false)).unwrap();
+            handle.queue_on("shared", task("shared", false)).unwrap();
+        });
+
+        common::stop_when(move || {
+            let finished = state.finished.lock().unwrap().len();
+            if finished == 2 && !state.open.load(Ordering::SeqCst) {
+                *snapshot.lock().unwrap() = state.started.lock().unwrap().clone();
+                state.open.store(true, Ordering::SeqCst);
+            }
+
+            finished == 4
+        });
+    });
+
+    // The fast pool ran the fast and shared jobs, and the idle fast worker left the second slow
+    // job for the busy slow pool
+    let mut started = started_while_held.lock().unwrap().clone();
+    started.sort();
+    assert_eq!(started, vec!["fast", "shared", "slow-held"]);
+    assert_eq!(
+        record.finished.lock().unwrap().last().map(String::as_str),
+        Some("slow-waiting")
+    );
+}
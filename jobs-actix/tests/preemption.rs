@@ -0,0 +1,112 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, CancellationToken, Job, Processor};
+use failure::{format_err, Error};
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Default)]
+struct Record {
+    started: Arc<AtomicUsize>,
+    yielded: Arc<AtomicUsize>,
+    urgent_ran: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Task {
+    urgent: bool,
+}
+
+#[derive(Clone)]
+struct TaskProcessor;
+
+impl Job for Task {
+    type Processor = TaskProcessor;
+    type State = Record;
+
+    fn run(self, _: Record) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        unreachable!("Runtimes call run_cancellable")
+    }
+
+    /// Urgent tasks finish right away, and the others work until asked to yield
+    fn run_cancellable(
+        self,
+        record: Record,
+        token: CancellationToken,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        if self.urgent {
+            record.urgent_ran.store(true, Ordering::SeqCst);
+            return Box::new(futures::future::ok(()));
+        }
+        record.started.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            record.yielded.fetch_add(1, Ordering::SeqCst);
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err().and_then(|_| Err(format_err!("Yielded"))))
+    }
+
+    fn priority(&self) -> Option<i32> {
+        Some(if self.urgent { 10 } else { 0 })
+    }
+}
+
+impl Processor for TaskProcessor {
+    type Job = Task;
+
+    const NAME: &'static str = "TaskProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn an_urgent_job_preempts_a_lower_priority_one() {
+    let record = Record::default();
+
+    let state = record.clone();
+    common::run("preemption", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .preempt_lower_priority()
+            .start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(TaskProcessor)
+            .set_processor_count("default", 2)
+            .start(handle.clone());
+
+        handle
+            .queue_fire_and_forget(Task { urgent: false })
+            .unwrap();
+        handle
+            .queue_fire_and_forget(Task { urgent: false })
+            .unwrap();
+
+        // Every worker is busy by the time the urgent job arrives
+        let observed = state.clone();
+        common::after(Duration::from_millis(200), move || {
+            assert_eq!(observed.started.load(Ordering::SeqCst), 2);
+            handle.queue_fire_and_forget(Task { urgent: true }).unwrap();
+        });
+
+        common::stop_when(move || state.urgent_ran.load(Ordering::SeqCst));
+    });
+
+    assert_eq!(record.yielded.load(Ordering::SeqCst), 1);
+}
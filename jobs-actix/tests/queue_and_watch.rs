@@ -0,0 +1,81 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, JobResult, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Report;
+
+#[derive(Clone)]
+struct ReportProcessor;
+
+impl Job for Report {
+    type Processor = ReportProcessor;
+    type State = Arc<AtomicUsize>;
+
+    fn run(self, runs: Arc<AtomicUsize>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        runs.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+
+    fn unique_key(&self) -> Option<String> {
+        Some("report".to_owned())
+    }
+}
+
+impl Processor for ReportProcessor {
+    type Job = Report;
+
+    const NAME: &'static str = "ReportProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn watchers_of_a_coalesced_job_share_one_run() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let results = Arc::new(Mutex::new(None));
+
+    let (state, recorded) = (runs.clone(), results.clone());
+    common::run("queue-and-watch", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        // Both jobs are queued before any worker can take the first one
+        let first = handle.queue_and_watch(Report);
+        let second = handle.queue_and_watch(Report);
+
+        WorkerConfig::new(move || state.clone())
+            .register(ReportProcessor)
+            .start(handle);
+
+        actix::spawn(first.join(second).then(move |res| {
+            *recorded.lock().unwrap() = Some(res.map_err(|e| e.to_string()));
+            actix::System::current().stop();
+            Ok(())
+        }));
+    });
+
+    assert_eq!(
+        results.lock().unwrap().take(),
+        Some(Ok((JobResult::Success, JobResult::Success)))
+    );
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
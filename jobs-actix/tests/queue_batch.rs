@@ -0,0 +1,66 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+// The throughput example times 10,000 jobs in a release build
+const JOBS: u32 = 1_000;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Row(u32);
+
+#[derive(Clone)]
+struct RowProcessor;
+
+impl Job for Row {
+    type Processor = RowProcessor;
+    type State = Arc<Mutex<HashSet<u32>>>;
+
+    fn run(self, seen: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        assert!(
+            seen.lock().unwrap().insert(self.0),
+            "Ran row {} twice",
+            self.0
+        );
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for RowProcessor {
+    type Job = Row;
+
+    const NAME: &'static str = "RowProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn every_job_of_a_large_batch_runs_once() {
+    let seen = Arc::new(Mutex::new(HashSet::new()));
+
+    let state = seen.clone();
+    common::run("queue-batch", Duration::from_secs(10), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(RowProcessor)
+            .set_processor_count("default", 8)
+            .start(handle.clone());
+
+        handle.queue_batch((0..JOBS).map(Row).collect()).unwrap();
+
+        common::stop_when(move || state.lock().unwrap().len() == JOBS as usize);
+    });
+
+    assert_eq!(seen.lock().unwrap().len(), JOBS as usize);
+}
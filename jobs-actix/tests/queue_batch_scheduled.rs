@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use background_jobs_actix::ServerConfig;
+use background_jobs_core::{clock, memory_storage, Job, Processor, Storage};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Email;
+
+#[derive(Clone)]
+struct EmailProcessor;
+
+impl Job for Email {
+    type Processor = EmailProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for EmailProcessor {
+    type Job = Email;
+
+    const NAME: &'static str = "EmailProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn batch_jobs_are_spread_across_the_window() {
+    let mut storage = memory_storage::Storage::new();
+    let window = Duration::from_secs(60 * 60);
+    let queued_at = clock::now();
+
+    let server_storage = storage.clone();
+    common::run("queue-batch-scheduled", Duration::from_secs(5), move || {
+        let mut stored = server_storage.clone();
+        let handle = ServerConfig::new(server_storage).start();
+
+        // No workers are started, so every job stays stored
+        handle
+            .queue_batch_scheduled(vec![Email; 100], window)
+            .unwrap();
+
+        common::stop_when(move || stored.list_jobs().unwrap().len() == 100);
+    });
+
+    let mut ready_at: Vec<_> = storage
+        .list_jobs()
+        .unwrap()
+        .iter()
+        .map(|job| job.ready_at())
+        .collect();
+    ready_at.sort();
+
+    // The first job is ready right away, and the last one just before the window ends
+    assert!(ready_at[0] - queued_at < clock::TimeDelta::seconds(1));
+    assert!(ready_at[99] - queued_at < clock::TimeDelta::from_std(window).unwrap());
+    assert_eq!(storage.list_scheduled().unwrap().len(), 99);
+
+    // Each job becomes ready 36 seconds after the one before it
+    for pair in ready_at.windows(2) {
+        let gap = (pair[1] - pair[0]).num_milliseconds();
+        assert!((35_000..=37_000).contains(&gap), "Gap of {}ms", gap);
+    }
+}
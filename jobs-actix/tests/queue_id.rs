@@ -0,0 +1,66 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::ServerConfig;
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Noop;
+
+#[derive(Clone)]
+struct NoopProcessor;
+
+impl Job for Noop {
+    type Processor = NoopProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for NoopProcessor {
+    type Job = Noop;
+
+    const NAME: &'static str = "NoopProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn queue_resolves_to_the_stored_job_id() {
+    let outcome = Arc::new(Mutex::new(None));
+
+    let recorded = outcome.clone();
+    common::run("queue-id", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        // Without workers, both jobs stay stored
+        let listing = handle.clone();
+        actix::spawn(
+            handle
+                .queue(Noop)
+                .join(handle.queue(Noop))
+                .and_then(move |ids| listing.list_jobs().map(move |jobs| (ids, jobs)))
+                .then(move |res| {
+                    let (ids, jobs) = res.expect("Queued and listed the jobs");
+                    let mut stored: Vec<_> = jobs.iter().map(|job| job.id()).collect();
+                    stored.sort();
+
+                    *recorded.lock().unwrap() = Some((ids, stored));
+                    actix::System::current().stop();
+                    Ok(())
+                }),
+        );
+    });
+
+    let ((first, second), stored) = outcome.lock().unwrap().take().expect("Ran");
+    assert_ne!(first, second);
+    assert_eq!(stored, vec![first, second]);
+}
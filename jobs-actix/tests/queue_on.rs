@@ -0,0 +1,75 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{QueueHandle, ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+type Runs = Arc<Mutex<Vec<(&'static str, u32)>>>;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Invoice(u32);
+
+#[derive(Clone)]
+struct InvoiceProcessor;
+
+impl Job for Invoice {
+    type Processor = InvoiceProcessor;
+
+    /// The tenant the worker serves, and the record of which tenant ran which invoice
+    type State = (&'static str, Runs);
+
+    fn run(self, (tenant, runs): Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        runs.lock().unwrap().push((tenant, self.0));
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for InvoiceProcessor {
+    type Job = Invoice;
+
+    const NAME: &'static str = "InvoiceProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+fn serve(handle: &QueueHandle, tenant: &'static str, runs: &Runs) {
+    let runs = runs.clone();
+
+    WorkerConfig::new(move || (tenant, runs.clone()))
+        .register(InvoiceProcessor)
+        .set_processor_count("default", 0)
+        .set_processor_count(tenant, 1)
+        .start(handle.clone());
+}
+
+#[test]
+fn jobs_queued_on_runtime_named_queues_go_to_their_workers() {
+    let runs = Runs::default();
+
+    let state = runs.clone();
+    common::run("queue-on", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+        serve(&handle, "tenant-a", &state);
+        serve(&handle, "tenant-b", &state);
+
+        handle.queue_on("tenant-a", Invoice(1)).unwrap();
+        handle.queue_on("tenant-b", Invoice(2)).unwrap();
+        handle.queue_on("tenant-a", Invoice(3)).unwrap();
+
+        common::stop_when(move || state.lock().unwrap().len() == 3);
+    });
+
+    let mut runs = runs.lock().unwrap().clone();
+    runs.sort();
+    assert_eq!(
+        runs,
+        vec![("tenant-a", 1), ("tenant-a", 3), ("tenant-b", 2)]
+    );
+}
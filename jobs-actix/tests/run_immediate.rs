@@ -0,0 +1,84 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Task(String);
+
+#[derive(Clone)]
+struct TaskProcessor;
+
+impl Job for Task {
+    type Processor = TaskProcessor;
+    type State = Arc<Mutex<Vec<String>>>;
+
+    fn run(self, order: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        order.lock().unwrap().push(self.0);
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for TaskProcessor {
+    type Job = Task;
+
+    const NAME: &'static str = "TaskProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn an_immediate_job_runs_first_and_is_counted() {
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let completed = Arc::new(Mutex::new(None));
+
+    let (state, recorded) = (order.clone(), completed.clone());
+    common::run("run-immediate", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .deterministic()
+            .start();
+
+        for name in &["first", "second", "third"] {
+            handle
+                .queue_fire_and_forget(Task(name.to_string()))
+                .unwrap();
+        }
+        handle
+            .run_immediate(Task("maintenance".to_owned()))
+            .unwrap();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(TaskProcessor)
+            .set_processor_count("default", 1)
+            .start(handle.clone());
+
+        // The server may not have stored the last result yet when the last job has run
+        common::stop_when(move || {
+            if state.lock().unwrap().len() == 4 {
+                let recorded = recorded.clone();
+                actix::spawn(handle.get_stats().map_err(|_| ()).map(move |stats| {
+                    *recorded.lock().unwrap() = stats
+                        .processor_stats("TaskProcessor")
+                        .map(|stat| stat.complete.all_time());
+                }));
+            }
+
+            *recorded.lock().unwrap() == Some(4)
+        });
+    });
+
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec!["maintenance", "first", "second", "third"]
+    );
+    assert_eq!(*completed.lock().unwrap(), Some(4));
+}
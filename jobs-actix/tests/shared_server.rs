@@ -0,0 +1,68 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix::Arbiter;
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Count;
+
+#[derive(Clone)]
+struct CountProcessor;
+
+impl Job for Count {
+    type Processor = CountProcessor;
+    type State = Arc<AtomicUsize>;
+
+    fn run(self, runs: Arc<AtomicUsize>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        runs.fetch_add(1, Ordering::SeqCst);
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for CountProcessor {
+    type Job = Count;
+
+    const NAME: &'static str = "CountProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn one_server_serves_workers_on_a_provided_arbiter() {
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    let state = runs.clone();
+    common::run("shared-server", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .thread_count(2)
+            .start();
+
+        // Workers run on an arbiter provided by the application, sharing the one server
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(CountProcessor)
+            .start_in_arbiter(&Arbiter::new(), handle.clone());
+
+        // Producers use their own clones of the handle
+        for producer in [handle.clone(), handle] {
+            producer.queue_fire_and_forget(Count).unwrap();
+            producer.queue_fire_and_forget(Count).unwrap();
+        }
+
+        common::stop_when(move || state.load(Ordering::SeqCst) == 4);
+    });
+
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+}
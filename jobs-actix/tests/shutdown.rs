@@ -0,0 +1,94 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, ShutdownStrategy, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, JobInfo, MaxRetries, Processor, Storage};
+use failure::Error;
+use futures::Future;
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Endless;
+
+#[derive(Clone)]
+struct EndlessProcessor;
+
+impl Job for Endless {
+    type Processor = EndlessProcessor;
+    type State = Arc<AtomicBool>;
+
+    fn run(self, started: Arc<AtomicBool>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        started.store(true, Ordering::SeqCst);
+
+        Box::new(futures::future::empty())
+    }
+
+    fn max_retries(&self) -> Option<MaxRetries> {
+        Some(MaxRetries::Count(0))
+    }
+}
+
+impl Processor for EndlessProcessor {
+    type Job = Endless;
+
+    const NAME: &'static str = "EndlessProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Stop the system while a job is running, returning the storage and what became of the job
+fn stop_while_running(strategy: ShutdownStrategy) -> (memory_storage::Storage, Option<JobInfo>) {
+    let mut storage = memory_storage::Storage::new();
+
+    let store = storage.clone();
+    common::run("shutdown", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(store).on_shutdown(strategy).start();
+
+        let started = Arc::new(AtomicBool::new(false));
+        let worker_state = started.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(EndlessProcessor)
+            .start(handle.clone());
+
+        handle.queue_fire_and_forget(Endless).unwrap();
+        common::stop_when(move || started.load(Ordering::SeqCst));
+    });
+
+    let job = storage.list_jobs().unwrap().pop();
+    (storage, job)
+}
+
+#[test]
+fn abandoned_jobs_are_left_running() {
+    let (mut storage, job) = stop_while_running(ShutdownStrategy::Abandon);
+
+    let job = job.expect("Abandoned jobs are kept");
+    assert!(!job.is_pending());
+    assert!(storage.fetch_dead_jobs().unwrap().is_empty());
+}
+
+#[test]
+fn requeued_jobs_are_pending_again() {
+    let (mut storage, job) = stop_while_running(ShutdownStrategy::RequeueOnStop);
+
+    let job = job.expect("Requeued jobs are kept");
+    assert!(job.is_pending());
+    assert_eq!(job.retry_count(), 0);
+    assert_eq!(storage.get_stats().unwrap().reclaimed.all_time(), 1);
+    assert!(storage.fetch_dead_jobs().unwrap().is_empty());
+}
+
+#[test]
+fn failed_jobs_use_up_a_retry() {
+    let (mut storage, job) = stop_while_running(ShutdownStrategy::FailOnStop);
+
+    // Without retries left, the failure makes the job dead
+    assert!(job.is_none());
+    assert_eq!(storage.fetch_dead_jobs().unwrap().len(), 1);
+}
@@ -0,0 +1,75 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+use self::common::FlakyStorage;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Noop;
+
+#[derive(Clone)]
+struct NoopProcessor;
+
+impl Job for Noop {
+    type Processor = NoopProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for NoopProcessor {
+    type Job = Noop;
+
+    const NAME: &'static str = "NoopProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Start checked workers over `storage`, returning how their readiness resolved
+fn start_checked(storage: FlakyStorage) -> Result<(), String> {
+    let result = Arc::new(Mutex::new(None));
+
+    let recorded = result.clone();
+    common::run("start-checked", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(storage).start();
+
+        let ready = WorkerConfig::new(|| ())
+            .register(NoopProcessor)
+            .start_checked(handle);
+
+        actix::spawn(ready.then(move |res| {
+            *recorded.lock().unwrap() = Some(res.map_err(|e| e.to_string()));
+            actix::System::current().stop();
+            Ok(())
+        }));
+    });
+
+    let result = result.lock().unwrap().take();
+    result.expect("Readiness resolved")
+}
+
+#[test]
+fn start_checked_resolves_once_workers_reach_the_store() {
+    assert_eq!(start_checked(FlakyStorage::new()), Ok(()));
+}
+
+#[test]
+fn start_checked_fails_on_a_broken_store() {
+    let storage = FlakyStorage::new();
+    storage.fail("fetch_job_from_queue", usize::MAX);
+
+    assert_eq!(
+        start_checked(storage),
+        Err("Storage failed on purpose in fetch_job_from_queue".to_owned())
+    );
+}
@@ -0,0 +1,124 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{Job, Processor, Storage};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+use self::common::FlakyStorage;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Ping;
+
+#[derive(Clone)]
+struct PingProcessor;
+
+impl Job for Ping {
+    type Processor = PingProcessor;
+    type State = Arc<Mutex<Option<Instant>>>;
+
+    fn run(self, ran_at: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        *ran_at.lock().unwrap() = Some(Instant::now());
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for PingProcessor {
+    type Job = Ping;
+
+    const NAME: &'static str = "PingProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Queue a job once the worker is waiting for one, with the storage failing the next `failures`
+/// fetches, and return how long the job took to run
+fn run_with_failures(retries: usize, failures: usize) -> (Duration, FlakyStorage) {
+    let storage = FlakyStorage::new();
+    let ran_at = Arc::new(Mutex::new(None));
+    let queued_at = Arc::new(Mutex::new(None));
+
+    let (server_storage, ran, queued) = (storage.clone(), ran_at.clone(), queued_at.clone());
+    common::run("storage-retries", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(server_storage.clone())
+            .thread_count(1)
+            .storage_retries(retries)
+            .start();
+
+        let state = ran.clone();
+        WorkerConfig::new(move || state.clone())
+            .register(PingProcessor)
+            .set_processor_count("default", 1)
+            .start(handle.clone());
+
+        common::after(Duration::from_millis(100), move || {
+            server_storage.fail("fetch_job_from_queue", failures);
+
+            *queued.lock().unwrap() = Some(Instant::now());
+            handle.queue_fire_and_forget(Ping).unwrap();
+        });
+
+        common::stop_when(move || ran.lock().unwrap().is_some());
+    });
+
+    let ran_at = ran_at.lock().unwrap().unwrap();
+    let queued_at = queued_at.lock().unwrap().unwrap();
+
+    (ran_at.duration_since(queued_at), storage)
+}
+
+#[test]
+fn a_failed_fetch_is_retried_for_the_waiting_worker() {
+    let (waited, storage) = run_with_failures(3, 1);
+
+    assert_eq!(storage.failures_left("fetch_job_from_queue"), 0);
+
+    // The job didn't wait for the server's next periodic check
+    assert!(waited < Duration::from_millis(500));
+}
+
+#[test]
+fn a_worker_stays_registered_when_retries_run_out() {
+    let (waited, storage) = run_with_failures(0, 1);
+
+    assert_eq!(storage.failures_left("fetch_job_from_queue"), 0);
+
+    // The worker was kept, and got the job from the server's next periodic check
+    assert!(waited < Duration::from_secs(2));
+}
+
+#[test]
+fn a_failed_enqueue_is_not_stored_twice() {
+    let storage = FlakyStorage::new();
+    let result = Arc::new(Mutex::new(None));
+
+    let (server_storage, recorded) = (storage.clone(), result.clone());
+    common::run("storage-retries", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(server_storage.clone())
+            .thread_count(1)
+            .storage_retries(3)
+            .start();
+
+        // The job is stored and queued before its stats fail to update
+        server_storage.fail("update_stats", 1);
+
+        actix::spawn(handle.queue(Ping).then(move |res| {
+            *recorded.lock().unwrap() = Some(res.map_err(|e| e.to_string()));
+            actix::System::current().stop();
+            Ok(())
+        }));
+    });
+
+    assert_eq!(
+        result.lock().unwrap().take(),
+        Some(Err("Storage failed on purpose in update_stats".to_owned()))
+    );
+    assert_eq!(storage.calls("generate_id"), 1);
+    assert_eq!(storage.clone().list_jobs().unwrap().len(), 1);
+}
@@ -0,0 +1,105 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use log::{Level, Log, Metadata, Record};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+/// Keeps every info line logged while the test runs
+struct Capture(Mutex<Vec<String>>);
+
+impl Log for Capture {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGS: Capture = Capture(Mutex::new(Vec::new()));
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Notify;
+
+#[derive(Clone)]
+struct NotifyProcessor;
+
+impl Job for Notify {
+    type Processor = NotifyProcessor;
+    type State = Arc<AtomicBool>;
+
+    fn run(self, ran: Arc<AtomicBool>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        ran.store(true, Ordering::SeqCst);
+
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for NotifyProcessor {
+    type Job = Notify;
+
+    const NAME: &'static str = "NotifyProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+fn logged(pattern: &str) -> Vec<String> {
+    let logs = LOGS.0.lock().unwrap();
+
+    logs.iter()
+        .filter(|line| line.contains(pattern))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn the_trace_id_is_logged_with_the_job() {
+    log::set_logger(&LOGS).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    common::run("trace-id", Duration::from_secs(5), || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let state = ran.clone();
+        WorkerConfig::new(move || state.clone())
+            .register(NotifyProcessor)
+            .start(handle.clone());
+
+        handle
+            .queue_with_context(Notify, "request-42".to_owned())
+            .unwrap();
+
+        // The completed line is logged right after the job's future resolves
+        common::stop_when(move || {
+            ran.load(Ordering::SeqCst) && !logged("completed, NotifyProcessor").is_empty()
+        });
+    });
+
+    let traced = logged("trace request-42");
+    assert!(
+        traced.iter().any(|line| line.contains("processing job")),
+        "Logged {:?}",
+        traced
+    );
+    assert!(
+        traced.iter().any(|line| line.contains("completed")),
+        "Logged {:?}",
+        traced
+    );
+}
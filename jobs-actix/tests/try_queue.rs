@@ -0,0 +1,78 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerBusy, ServerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+const LIMIT: usize = 10;
+const FLOOD: usize = 10_000;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Noop;
+
+#[derive(Clone)]
+struct NoopProcessor;
+
+impl Job for Noop {
+    type Processor = NoopProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for NoopProcessor {
+    type Job = Noop;
+
+    const NAME: &'static str = "NoopProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn flooding_the_server_is_refused_past_the_backlog_limit() {
+    let outcome = Arc::new(Mutex::new((0, 0, None)));
+
+    let recorded = outcome.clone();
+    common::run("try-queue", Duration::from_secs(10), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .thread_count(1)
+            .backlog_limit(LIMIT)
+            .start();
+
+        // No workers run, so every accepted job stays pending
+        let (mut accepted, mut busy) = (0, 0);
+        for _ in 0..FLOOD {
+            match handle.try_queue(Noop) {
+                Ok(()) => accepted += 1,
+                Err(e) => {
+                    assert_eq!(e.downcast_ref::<ServerBusy>().map(|b| b.0), Some(LIMIT));
+                    busy += 1;
+                }
+            }
+        }
+
+        actix::spawn(handle.get_stats().then(move |stats| {
+            *recorded.lock().unwrap() = (accepted, busy, stats.ok().map(|s| s.pending));
+            actix::System::current().stop();
+            Ok(())
+        }));
+    });
+
+    let (accepted, busy, pending) = *outcome.lock().unwrap();
+    assert_eq!(accepted + busy, FLOOD);
+    assert!(
+        busy > 0,
+        "The server kept up with a flood of {} jobs",
+        FLOOD
+    );
+    assert!(accepted >= LIMIT);
+    assert_eq!(pending, Some(accepted));
+}
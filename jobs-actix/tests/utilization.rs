@@ -0,0 +1,81 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, Utilization, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Sleep;
+
+#[derive(Clone)]
+struct SleepProcessor;
+
+impl Job for Sleep {
+    type Processor = SleepProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+}
+
+impl Processor for SleepProcessor {
+    type Job = Sleep;
+
+    const NAME: &'static str = "SleepProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn utilization_matches_the_time_spent_on_jobs() {
+    let window = Arc::new(Mutex::new(None));
+
+    let recorded = window.clone();
+    common::run("utilization", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new()).start();
+
+        WorkerConfig::new(|| ())
+            .register(SleepProcessor)
+            .set_processor_count("default", 2)
+            .start(handle.clone());
+
+        // Over 400ms, each of the two workers runs one 200ms job
+        let start = Arc::new(Mutex::new(Utilization::default()));
+        let (queuing, started) = (handle.clone(), start.clone());
+        common::after(Duration::from_millis(100), move || {
+            *started.lock().unwrap() = queuing.utilization();
+            queuing.queue_fire_and_forget(Sleep).unwrap();
+            queuing.queue_fire_and_forget(Sleep).unwrap();
+        });
+
+        common::after(Duration::from_millis(500), move || {
+            let earlier = *start.lock().unwrap();
+            *recorded.lock().unwrap() = Some(handle.utilization().since(&earlier));
+            actix::System::current().stop();
+        });
+    });
+
+    let window = window.lock().unwrap().take().expect("Measured utilization");
+    assert!(
+        window.busy >= Duration::from_millis(400),
+        "Measured {:?}",
+        window
+    );
+
+    let fraction = window.fraction();
+    assert!(fraction > 0.4 && fraction < 0.6, "Measured {}", fraction);
+}
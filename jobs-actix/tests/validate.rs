@@ -0,0 +1,83 @@
+use background_jobs_actix::{ConfigIssue, WorkerConfig};
+use background_jobs_core::{Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, Serialize)]
+struct SendEmail;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct ResizeImage;
+
+#[derive(Clone)]
+struct EmailProcessor;
+
+#[derive(Clone)]
+struct ImageProcessor;
+
+impl Job for SendEmail {
+    type Processor = EmailProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Job for ResizeImage {
+    type Processor = ImageProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for EmailProcessor {
+    type Job = SendEmail;
+
+    const NAME: &'static str = "EmailProcessor";
+    const QUEUE: &'static str = "email";
+}
+
+impl Processor for ImageProcessor {
+    type Job = ResizeImage;
+
+    const NAME: &'static str = "ImageProcessor";
+    const QUEUE: &'static str = "images";
+}
+
+#[test]
+fn a_matching_config_is_valid() {
+    let config = WorkerConfig::new(|| ())
+        .register(EmailProcessor)
+        .register(ImageProcessor)
+        .add_pool("media", &["images"], 2);
+
+    assert_eq!(config.validate(), Ok(()));
+}
+
+#[test]
+fn every_mismatch_is_reported() {
+    let config = WorkerConfig::new(|| ())
+        .register(EmailProcessor)
+        .register(ImageProcessor)
+        // The email workers were turned off, stranding EmailProcessor's jobs
+        .set_processor_count("email", 0)
+        // Nothing is ever queued in these
+        .set_processor_count("reports", 2)
+        .add_pool("batch", &["exports"], 1);
+
+    assert_eq!(
+        config.validate(),
+        Err(vec![
+            ConfigIssue::QueueWithoutProcessor("exports".to_owned()),
+            ConfigIssue::QueueWithoutProcessor("reports".to_owned()),
+            ConfigIssue::ProcessorWithoutWorkers {
+                processor: "EmailProcessor".to_owned(),
+                queue: "email".to_owned(),
+            },
+        ])
+    );
+}
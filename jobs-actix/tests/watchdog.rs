@@ -0,0 +1,90 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use actix::Arbiter;
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, MaxRetries, Processor, Storage};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+const BLOCKS_FOR: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Blocking;
+
+#[derive(Clone)]
+struct BlockingProcessor;
+
+impl Job for Blocking {
+    type Processor = BlockingProcessor;
+    type State = Arc<AtomicBool>;
+
+    /// Block the worker's thread, so the job can't be interrupted
+    fn run(self, returned: Arc<AtomicBool>) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        thread::sleep(BLOCKS_FOR);
+        returned.store(true, Ordering::SeqCst);
+
+        Box::new(Ok(()).into_future())
+    }
+
+    fn max_retries(&self) -> Option<MaxRetries> {
+        Some(MaxRetries::Count(0))
+    }
+}
+
+impl Processor for BlockingProcessor {
+    type Job = Blocking;
+
+    const NAME: &'static str = "BlockingProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn the_watchdog_fails_a_blocking_job_past_its_limit() {
+    let returned = Arc::new(AtomicBool::new(false));
+    let flagged = Arc::new(Mutex::new(None));
+
+    let mut storage = memory_storage::Storage::new();
+    let (state, recorded, store) = (returned.clone(), flagged.clone(), storage.clone());
+    common::run("watchdog", Duration::from_secs(5), move || {
+        let handle = ServerConfig::new(store.clone())
+            .max_run_time(Duration::from_millis(200))
+            .start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(BlockingProcessor)
+            .set_processor_count("default", 1)
+            .start_in_arbiter(&Arbiter::new(), handle.clone());
+
+        handle.queue_fire_and_forget(Blocking).unwrap();
+
+        let (started, mut store) = (Instant::now(), store);
+        common::stop_when(move || {
+            if store.fetch_dead_jobs().unwrap().is_empty() {
+                return false;
+            }
+
+            *recorded.lock().unwrap() = Some((started.elapsed(), state.load(Ordering::SeqCst)));
+            true
+        });
+    });
+
+    // The job was failed while it was still blocking its worker
+    let (elapsed, had_returned) = flagged.lock().unwrap().expect("Flagged the job");
+    assert!(!had_returned, "Flagged after the job returned");
+    assert!(elapsed < BLOCKS_FOR, "Flagged after {:?}", elapsed);
+
+    let stats = storage.get_stats().unwrap();
+    assert_eq!(stats.running, 0);
+    assert!(storage.list_jobs().unwrap().is_empty());
+}
@@ -0,0 +1,95 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{sync::oneshot, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+const BUDGET: usize = 10;
+
+#[derive(Clone, Default)]
+struct Weights {
+    running: Arc<AtomicUsize>,
+    max_running: Arc<AtomicUsize>,
+    finished: Arc<AtomicUsize>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Heavy {
+    weight: usize,
+}
+
+#[derive(Clone)]
+struct HeavyProcessor;
+
+impl Job for Heavy {
+    type Processor = HeavyProcessor;
+    type State = Weights;
+
+    fn run(self, weights: Weights) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let running = weights.running.fetch_add(self.weight, Ordering::SeqCst) + self.weight;
+        weights.max_running.fetch_max(running, Ordering::SeqCst);
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            weights.running.fetch_sub(self.weight, Ordering::SeqCst);
+            weights.finished.fetch_add(1, Ordering::SeqCst);
+            let _ = tx.send(());
+        });
+
+        Box::new(rx.from_err())
+    }
+
+    fn weight(&self) -> usize {
+        self.weight
+    }
+}
+
+impl Processor for HeavyProcessor {
+    type Job = Heavy;
+
+    const NAME: &'static str = "HeavyProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+#[test]
+fn running_weight_stays_within_the_budget() {
+    let weights = Weights::default();
+    let jobs = vec![6, 4, 4, 3, 5, 2, 4, 6];
+    let count = jobs.len();
+
+    let state = weights.clone();
+    common::run("weight-budget", Duration::from_secs(10), move || {
+        let handle = ServerConfig::new(memory_storage::Storage::new())
+            .weight_budget(BUDGET)
+            .start();
+
+        let worker_state = state.clone();
+        WorkerConfig::new(move || worker_state.clone())
+            .register(HeavyProcessor)
+            .set_processor_count("default", 8)
+            .start(handle.clone());
+
+        for weight in jobs {
+            handle.queue_fire_and_forget(Heavy { weight }).unwrap();
+        }
+
+        common::stop_when(move || state.finished.load(Ordering::SeqCst) == count);
+    });
+
+    // Eight workers would otherwise have run every job at once, for a weight of 34
+    let max_running = weights.max_running.load(Ordering::SeqCst);
+    assert!(max_running <= BUDGET, "Ran jobs weighing {}", max_running);
+    assert!(max_running > 6, "Jobs never ran side by side");
+}
@@ -0,0 +1,79 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use background_jobs_actix::{ServerConfig, WorkerCacheStats, WorkerConfig};
+use background_jobs_core::{memory_storage, Job, Processor};
+use failure::Error;
+use futures::{future::IntoFuture, Future};
+use serde_derive::{Deserialize, Serialize};
+
+mod common;
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Noop;
+
+#[derive(Clone)]
+struct NoopProcessor;
+
+impl Job for Noop {
+    type Processor = NoopProcessor;
+    type State = ();
+
+    fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(Ok(()).into_future())
+    }
+}
+
+impl Processor for NoopProcessor {
+    type Job = Noop;
+
+    const NAME: &'static str = "NoopProcessor";
+    const QUEUE: &'static str = "default";
+}
+
+/// Start five idle workers, returning the worker cache stats once they are all waiting
+fn cache_with_five_workers(max: Option<usize>) -> WorkerCacheStats {
+    let stats = Arc::new(Mutex::new(None));
+
+    let recorded = stats.clone();
+    common::run("worker-cache", Duration::from_secs(5), move || {
+        let mut config = ServerConfig::new(memory_storage::Storage::new()).thread_count(1);
+        if let Some(max) = max {
+            config = config.max_cached_workers(max);
+        }
+        let handle = config.start();
+
+        WorkerConfig::new(|| ())
+            .register(NoopProcessor)
+            .set_processor_count("default", 5)
+            .start(handle.clone());
+
+        common::after(Duration::from_millis(200), move || {
+            *recorded.lock().unwrap() = Some(handle.worker_cache());
+            actix::System::current().stop();
+        });
+    });
+
+    let stats = stats.lock().unwrap().take();
+    stats.expect("Read the worker cache")
+}
+
+#[test]
+fn workers_over_the_cap_are_evicted_and_counted() {
+    let stats = cache_with_five_workers(Some(3));
+
+    assert_eq!(stats.queues.get("default"), Some(&3));
+    assert_eq!(stats.evicted, 2);
+    assert_eq!(stats.max, Some(3));
+}
+
+#[test]
+fn every_worker_is_cached_without_a_cap() {
+    let stats = cache_with_five_workers(None);
+
+    assert_eq!(stats.queues.get("default"), Some(&5));
+    assert_eq!(stats.evicted, 0);
+    assert_eq!(stats.max, None);
+}
@@ -0,0 +1,134 @@
+/*
+ * This file is part of Background Jobs.
+ *
+ * Copyright © 2019 Riley Trautman
+ *
+ * Background Jobs is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Background Jobs is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use failure::Error;
+use serde::{de::DeserializeOwned, ser::Serialize};
+use serde_json::Value;
+
+/// A function called with every progress saved through a `Checkpoint`
+type SaveFn = Arc<dyn Fn(&Value) + Send + Sync>;
+
+/// A handle a running job uses to record how far it has gotten
+///
+/// Every run of a job gets its own handle, which is passed to `Job::run_resumable`, holding the
+/// progress saved by previous runs. Progress is an opaque value chosen by the job. Each save
+/// replaces the previous one, and the last one saved is kept on the job's `JobInfo` when the run
+/// fails, so the next retry can pick up from there instead of starting over. Progress is kept
+/// while the job is retried or dead, and removed along with the job once it completes.
+///
+/// Runtimes may also persist every save as soon as it is made, so progress survives a run that
+/// never returns, like one whose worker died. Clones share the same progress.
+///
+/// ```rust
+/// use background_jobs_core::Checkpoint;
+///
+/// let checkpoint = Checkpoint::new(None);
+/// assert_eq!(checkpoint.last::<usize>(), None);
+///
+/// checkpoint.save(&10usize).unwrap();
+/// checkpoint.save(&20usize).unwrap();
+/// assert_eq!(checkpoint.last::<usize>(), Some(20));
+/// ```
+#[derive(Clone)]
+pub struct Checkpoint {
+    progress: Arc<Mutex<Progress>>,
+    on_save: Option<SaveFn>,
+}
+
+struct Progress {
+    last: Option<Value>,
+    saved: bool,
+}
+
+impl Checkpoint {
+    /// Create a handle holding the progress saved by previous runs, if any
+    pub fn new(last: Option<Value>) -> Self {
+        Checkpoint {
+            progress: Arc::new(Mutex::new(Progress { last, saved: false })),
+            on_save: None,
+        }
+    }
+
+    /// Call the given function with every progress saved through this handle
+    ///
+    /// This is intended for runtimes persisting progress while the job is still running, for
+    /// example with `Storage::save_checkpoint`.
+    pub fn on_save(mut self, f: impl Fn(&Value) + Send + Sync + 'static) -> Self {
+        self.on_save = Some(Arc::new(f));
+        self
+    }
+
+    /// The most recently saved progress, if there is some and it has the expected type
+    pub fn last<T>(&self) -> Option<T>
+    where
+        T: DeserializeOwned,
+    {
+        let progress = self.progress.lock().unwrap();
+
+        progress
+            .last
+            .clone()
+            .and_then(|last| serde_json::from_value(last).ok())
+    }
+
+    /// Save the job's progress, replacing whatever was saved before
+    pub fn save<T>(&self, progress: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let value = serde_json::to_value(progress)?;
+
+        if let Some(ref on_save) = self.on_save {
+            on_save(&value);
+        }
+
+        let mut progress = self.progress.lock().unwrap();
+        progress.last = Some(value);
+        progress.saved = true;
+
+        Ok(())
+    }
+
+    /// The progress saved during this run, if any was
+    pub(crate) fn saved(&self) -> Option<Value> {
+        let progress = self.progress.lock().unwrap();
+
+        if progress.saved {
+            progress.last.clone()
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let progress = self.progress.lock().unwrap();
+
+        f.debug_struct("Checkpoint")
+            .field("last", &progress.last)
+            .field("saved", &progress.saved)
+            .finish()
+    }
+}
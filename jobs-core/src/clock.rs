@@ -0,0 +1,168 @@
+/*
+ * This file is part of Background Jobs.
+ *
+ * Copyright © 2019 Riley Trautman
+ *
+ * Background Jobs is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Background Jobs is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The date and time types used by background-jobs
+//!
+//! Every use of `chrono` goes through this module, including in the actix and sled crates, so no
+//! other module names it directly. `chrono` is the only supported time library: there is no
+//! feature that selects another one, such as the `time` crate, since the stats buckets and the
+//! stored format of timestamps still rely on `chrono` behind these types.
+//!
+//! Scheduled jobs and retries are stored with the wall-clock time they become ready at, since
+//! that is the only time that means the same thing across restarts and machines. This ties them
+//! to the system clock: when it is set back, for example by an NTP correction, jobs that were due
+//! during the skipped span wait until the clock catches up again, and when it is set forward,
+//! jobs scheduled during the skipped span become ready all at once. Jobs are never lost either
+//! way. `JumpDetector` can be used to notice such corrections, and the actix runtime logs them.
+//!
+//! ```rust
+//! use std::time::{Duration, Instant};
+//!
+//! use background_jobs_core::{
+//!     clock::{self, JumpDetector, TimeDelta},
+//!     memory_storage, NewJobInfo, Storage,
+//! };
+//! use serde_json::json;
+//!
+//! let (start, monotonic) = (clock::now(), Instant::now());
+//! let mut detector = JumpDetector::new(Duration::from_secs(5));
+//! detector.observe(start, monotonic);
+//!
+//! let mut storage = memory_storage::Storage::new();
+//! let job = NewJobInfo::builder("ReportProcessor", "default")
+//!     .args(json!([]))
+//!     .schedule(start + TimeDelta::seconds(10))
+//!     .build()
+//!     .unwrap();
+//! let id = storage.new_job(job).unwrap();
+//! let job = storage.fetch_job(id).unwrap().unwrap();
+//!
+//! // One second in, the clock is set back by a minute
+//! let jumped = start - TimeDelta::seconds(59);
+//! let second = Duration::from_secs(1);
+//! assert_eq!(
+//!     detector.observe(jumped, monotonic + second),
+//!     Some(TimeDelta::seconds(-60))
+//! );
+//! assert!(!job.is_ready(jumped));
+//!
+//! // Ten seconds in, the job would have been due, but it waits for the clock to catch up
+//! assert!(!job.is_ready(jumped + TimeDelta::seconds(9)));
+//!
+//! // Seventy-one seconds in, the clock has passed the scheduled time again, and the job is ready
+//! assert!(job.is_ready(jumped + TimeDelta::seconds(70)));
+//! ```
+
+use std::time::{Duration, Instant};
+
+use chrono::offset::Utc;
+
+pub(crate) use chrono::{Datelike, Timelike};
+
+/// A point in time, in UTC
+pub type Timestamp = chrono::DateTime<Utc>;
+
+/// A signed span of time between two timestamps
+pub type TimeDelta = chrono::Duration;
+
+/// The current time
+pub fn now() -> Timestamp {
+    Utc::now()
+}
+
+/// Notices when the wall clock jumps, by comparing it with a monotonic clock
+///
+/// ```rust
+/// use std::time::{Duration, Instant};
+///
+/// use background_jobs_core::clock::{self, JumpDetector, TimeDelta};
+///
+/// let mut detector = JumpDetector::new(Duration::from_secs(5));
+/// let (wall, monotonic) = (clock::now(), Instant::now());
+///
+/// assert_eq!(detector.observe(wall, monotonic), None);
+///
+/// // One second later, the wall clock follows along
+/// let second = Duration::from_secs(1);
+/// let wall = wall + TimeDelta::seconds(1);
+/// assert_eq!(detector.observe(wall, monotonic + second), None);
+///
+/// // One more second later, the wall clock has been set back by a minute
+/// let jumped = wall - TimeDelta::seconds(59);
+/// assert_eq!(
+///     detector.observe(jumped, monotonic + second * 2),
+///     Some(TimeDelta::seconds(-60))
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct JumpDetector {
+    last: Option<(Timestamp, Instant)>,
+    tolerance: TimeDelta,
+}
+
+impl JumpDetector {
+    /// Create a detector that ignores differences up to the given tolerance
+    pub fn new(tolerance: Duration) -> Self {
+        JumpDetector {
+            last: None,
+            tolerance: TimeDelta::from_std(tolerance).unwrap_or_else(|_| TimeDelta::max_value()),
+        }
+    }
+
+    /// Record a reading of both clocks
+    ///
+    /// This returns how far the wall clock has moved from where the monotonic clock says it
+    /// should be since the previous reading, when that is more than the tolerance. A negative
+    /// value means the wall clock was set back.
+    pub fn observe(&mut self, wall: Timestamp, monotonic: Instant) -> Option<TimeDelta> {
+        let last = self.last.replace((wall, monotonic));
+        let (last_wall, last_monotonic) = last?;
+
+        let elapsed =
+            TimeDelta::from_std(monotonic.saturating_duration_since(last_monotonic)).ok()?;
+        let jump = wall.signed_duration_since(last_wall) - elapsed;
+
+        if jump > self.tolerance || -jump > self.tolerance {
+            Some(jump)
+        } else {
+            None
+        }
+    }
+}
+
+/// Add a number of milliseconds to a timestamp, stopping at the latest representable time instead
+/// of overflowing
+pub(crate) fn saturating_add_millis(timestamp: Timestamp, millis: u64) -> Timestamp {
+    let millis = millis.min(i64::MAX as u64) as i64;
+
+    timestamp
+        .checked_add_signed(TimeDelta::milliseconds(millis))
+        .unwrap_or(chrono::MAX_DATETIME)
+}
+
+/// Add a number of seconds to a timestamp, stopping at the latest representable time instead of
+/// overflowing
+pub(crate) fn saturating_add_secs(timestamp: Timestamp, secs: u64) -> Timestamp {
+    // TimeDelta can't hold more than i64::MAX milliseconds
+    let secs = secs.min((i64::MAX / 1000) as u64) as i64;
+
+    timestamp
+        .checked_add_signed(TimeDelta::seconds(secs))
+        .unwrap_or(chrono::MAX_DATETIME)
+}
@@ -17,11 +17,14 @@
  * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::time::Duration;
+
 use failure::Error;
-use futures::Future;
+use futures::{future::IntoFuture, Future};
 use serde::{de::DeserializeOwned, ser::Serialize};
+use serde_json::Value;
 
-use crate::{Backoff, MaxRetries, Processor};
+use crate::{Backoff, CancellationToken, Checkpoint, MaxRetries, Processor};
 
 /// The Job trait defines parameters pertaining to an instance of background job
 pub trait Job: Serialize + DeserializeOwned + 'static {
@@ -43,6 +46,120 @@ pub trait Job: Serialize + DeserializeOwned + 'static {
     /// an actor in an actix-based system.
     fn run(self, state: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send>;
 
+    /// Run the job with a token telling it when to stop early
+    ///
+    /// Runtimes call this method instead of `run`. By default, the token is ignored and `run` is
+    /// called. Long jobs can override this to check `CancellationToken::is_cancelled` while they
+    /// work, and clean up before returning once it is set, rather than leaving partial work
+    /// behind. A cancelled job's result is handled like any other, so returning an error makes it
+    /// eligible for a retry, and returning Ok marks it as complete.
+    fn run_cancellable(
+        self,
+        state: Self::State,
+        token: CancellationToken,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let _ = token;
+        self.run(state)
+    }
+
+    /// Run the job with a handle for saving its progress
+    ///
+    /// Runtimes call this method, which calls `run_cancellable` by default. Long jobs that would
+    /// rather not start over when they are retried can override this to resume from
+    /// `Checkpoint::last`, and call `Checkpoint::save` as they make progress.
+    ///
+    /// ```rust
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// use background_jobs_core::{
+    ///     memory_storage, Backoff, CancellationToken, Checkpoint, Job, Processor, ProcessorMap,
+    ///     Storage,
+    /// };
+    /// use failure::{format_err, Error};
+    /// use futures::{future::IntoFuture, Future};
+    /// use serde_derive::{Deserialize, Serialize};
+    ///
+    /// #[derive(Clone, Deserialize, Serialize)]
+    /// struct Import {
+    ///     rows: u32,
+    /// }
+    ///
+    /// #[derive(Clone)]
+    /// struct ImportProcessor;
+    ///
+    /// impl Job for Import {
+    ///     type Processor = ImportProcessor;
+    ///     type State = Arc<Mutex<Vec<u32>>>;
+    ///
+    ///     fn run(self, _: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         unreachable!("Runtimes call run_resumable")
+    ///     }
+    ///
+    ///     // The first run is interrupted after row 5, and the retry picks up from there
+    ///     fn run_resumable(
+    ///         self,
+    ///         imported: Self::State,
+    ///         _: CancellationToken,
+    ///         checkpoint: Checkpoint,
+    ///     ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         let resumed = checkpoint.last::<u32>();
+    ///
+    ///         for row in resumed.unwrap_or(0)..self.rows {
+    ///             imported.lock().unwrap().push(row);
+    ///             let saved = checkpoint.save(&row);
+    ///
+    ///             if row == 5 && resumed.is_none() {
+    ///                 return Box::new(saved.and(Err(format_err!("Interrupted"))).into_future());
+    ///             }
+    ///         }
+    ///
+    ///         Box::new(Ok(()).into_future())
+    ///     }
+    ///
+    ///     fn backoff_strategy(&self) -> Option<Backoff> {
+    ///         Some(Backoff::Linear(0))
+    ///     }
+    /// }
+    ///
+    /// impl Processor for ImportProcessor {
+    ///     type Job = Import;
+    ///
+    ///     const NAME: &'static str = "ImportProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let imported = Arc::new(Mutex::new(Vec::new()));
+    ///     let state = imported.clone();
+    ///
+    ///     let mut processors = ProcessorMap::new(Arc::new(move || state.clone()));
+    ///     processors.register_processor(ImportProcessor);
+    ///
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     storage.new_job(ImportProcessor::new_job(Import { rows: 8 })?)?;
+    ///
+    ///     for _ in 0..2 {
+    ///         let job = storage.request_job("default", 1)?.unwrap();
+    ///         let returned = processors.process_job(job).wait().unwrap();
+    ///         storage.return_job(returned)?;
+    ///     }
+    ///
+    ///     // Row 5 was saved as the checkpoint, so it is the only row imported twice
+    ///     assert_eq!(*imported.lock().unwrap(), vec![0, 1, 2, 3, 4, 5, 5, 6, 7]);
+    ///     assert!(storage.list_jobs()?.is_empty());
+    ///     Ok(())
+    /// }
+    /// ```
+    fn run_resumable(
+        self,
+        state: Self::State,
+        token: CancellationToken,
+        checkpoint: Checkpoint,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let _ = checkpoint;
+        self.run_cancellable(state, token)
+    }
+
     /// If this job should not use the default queue for its processor, this can be overridden in
     /// user-code.
     ///
@@ -64,4 +181,315 @@ pub trait Job: Serialize + DeserializeOwned + 'static {
     fn backoff_strategy(&self) -> Option<Backoff> {
         None
     }
+
+    /// If this job should not use the default priority for its processor, this can be overridden
+    /// in user-code.
+    fn priority(&self) -> Option<i32> {
+        None
+    }
+
+    /// If retries of this job should go to a different queue, this can be overridden in user-code
+    /// to return the name of that queue.
+    ///
+    /// This is useful for keeping failing jobs from delaying fresh work. Make sure a worker
+    /// serves the retry queue with this job's processor registered, otherwise retries will never
+    /// run.
+    fn retry_queue(&self) -> Option<&str> {
+        None
+    }
+
+    /// If this job should be coalesced with identical pending jobs, this can be overridden in
+    /// user-code to return a key identifying it.
+    ///
+    /// While a pending job with the same key exists in this job's queue, queuing this job will not
+    /// store a new copy. Keys are only compared within a single queue.
+    fn unique_key(&self) -> Option<String> {
+        None
+    }
+
+    /// If this job should be coalesced with identical pending jobs queued for other processors or
+    /// queues, this can be overridden in user-code to return a key identifying it.
+    ///
+    /// This is like `unique_key`, but keys are compared across the whole storage, so the same
+    /// piece of work queued from different places only runs once. When a pending job with the
+    /// same key exists, it is kept as it is, with its own processor, queue, and arguments, and
+    /// this job is discarded.
+    fn global_key(&self) -> Option<String> {
+        None
+    }
+
+    /// If producers may resubmit this job after it has already completed, this can be overridden
+    /// in user-code to return a key identifying it.
+    ///
+    /// Once a job with this key has completed successfully, queuing another job with the same key
+    /// in the same queue will not run it again, for as long as the storage backend retains
+    /// completed keys. Unlike `unique_key`, this does not coalesce jobs that are still pending.
+    fn idempotency_key(&self) -> Option<String> {
+        None
+    }
+
+    /// If this job's arguments contain secrets, this can be overridden in user-code to return
+    /// the arguments with those fields masked.
+    ///
+    /// The masked arguments are stored next to the real ones, and are shown in place of them
+    /// wherever jobs are logged or listed, such as in the `Debug` output of `JobInfo`. The job
+    /// always runs with its real arguments. By default, nothing is masked.
+    ///
+    /// ```rust
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// use background_jobs_core::{memory_storage, Job, Processor, ProcessorMap, Storage};
+    /// use failure::Error;
+    /// use futures::{future::IntoFuture, Future};
+    /// use serde_derive::{Deserialize, Serialize};
+    /// use serde_json::{json, Value};
+    ///
+    /// #[derive(Deserialize, Serialize)]
+    /// struct Refresh {
+    ///     user_id: u64,
+    ///     token: String,
+    /// }
+    ///
+    /// impl Job for Refresh {
+    ///     type Processor = RefreshProcessor;
+    ///     type State = Arc<Mutex<Option<String>>>;
+    ///
+    ///     fn run(self, used: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         *used.lock().unwrap() = Some(self.token);
+    ///         Box::new(Ok(()).into_future())
+    ///     }
+    ///
+    ///     fn redacted_args(&self) -> Option<Value> {
+    ///         Some(json!({ "user_id": self.user_id, "token": "<redacted>" }))
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone)]
+    /// struct RefreshProcessor;
+    ///
+    /// impl Processor for RefreshProcessor {
+    ///     type Job = Refresh;
+    ///
+    ///     const NAME: &'static str = "RefreshProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let job = Refresh {
+    ///         user_id: 7,
+    ///         token: "hunter2".to_owned(),
+    ///     };
+    ///     let new_job = RefreshProcessor::new_job(job)?;
+    ///     assert!(format!("{:?}", new_job).contains("<redacted>"));
+    ///     assert!(!format!("{:?}", new_job).contains("hunter2"));
+    ///
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     storage.new_job(new_job)?;
+    ///
+    ///     let listed = storage.list_jobs()?;
+    ///     assert_eq!(listed[0].redacted_args()["token"], "<redacted>");
+    ///     assert!(!format!("{:?}", listed).contains("hunter2"));
+    ///
+    ///     let used = Arc::new(Mutex::new(None));
+    ///     let state = used.clone();
+    ///     let mut processors = ProcessorMap::new(Arc::new(move || state.clone()));
+    ///     processors.register_processor(RefreshProcessor);
+    ///
+    ///     let running = storage.request_job("default", 1)?.unwrap();
+    ///     assert!(!format!("{:?}", running).contains("hunter2"));
+    ///     processors.process_job(running).wait().unwrap();
+    ///
+    ///     // The job itself got the real token
+    ///     assert_eq!(used.lock().unwrap().as_deref(), Some("hunter2"));
+    ///     Ok(())
+    /// }
+    /// ```
+    fn redacted_args(&self) -> Option<Value> {
+        None
+    }
+
+    /// If this job uses a significant amount of memory while running, this can be overridden in
+    /// user-code to return an estimate of it.
+    ///
+    /// Runtimes can limit the combined weight of the jobs running at once, holding back jobs that
+    /// would go over the limit until others finish. By default, jobs weigh nothing.
+    fn weight(&self) -> usize {
+        0
+    }
+
+    /// If this job works on a resource that related jobs also work on, this can be overridden in
+    /// user-code to return a key identifying that resource.
+    ///
+    /// Runtimes may run jobs with the same key on the same worker, so state the worker keeps
+    /// around, such as a cache, can be reused. This is only a preference: when the worker that
+    /// last ran a job with the key is busy, another worker runs the job.
+    fn affinity_key(&self) -> Option<String> {
+        None
+    }
+
+    /// If a run of this job should not take longer than some time, this can be overridden in
+    /// user-code to return that limit.
+    ///
+    /// `ProcessorMap` stops waiting on a run that goes over the limit, drops its future, and
+    /// fails it with `JobError::TimedOut`, so it is retried like any other failure. This needs a
+    /// tokio timer, which runtimes built on tokio provide. Runtimes may also fail a run whose
+    /// future can't be interrupted, for example because it blocks, and discard whatever it
+    /// returns afterwards. By default, runs have no limit besides the runtime's own.
+    ///
+    /// ```rust
+    /// use std::{
+    ///     sync::Arc,
+    ///     time::{Duration, Instant},
+    /// };
+    ///
+    /// use background_jobs_core::{memory_storage, Job, Processor, ProcessorMap, Storage};
+    /// use failure::Error;
+    /// use futures::{future::empty, Future};
+    /// use serde_derive::{Deserialize, Serialize};
+    /// use tokio::runtime::current_thread::Runtime;
+    ///
+    /// #[derive(Clone, Deserialize, Serialize)]
+    /// struct Hang;
+    ///
+    /// #[derive(Clone)]
+    /// struct HangProcessor;
+    ///
+    /// impl Job for Hang {
+    ///     type Processor = HangProcessor;
+    ///     type State = ();
+    ///
+    ///     // This run never resolves
+    ///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         Box::new(empty())
+    ///     }
+    ///
+    ///     fn timeout(&self) -> Option<Duration> {
+    ///         Some(Duration::from_millis(100))
+    ///     }
+    /// }
+    ///
+    /// impl Processor for HangProcessor {
+    ///     type Job = Hang;
+    ///
+    ///     const NAME: &'static str = "HangProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let mut processors = ProcessorMap::new(Arc::new(|| ()));
+    ///     processors.register_processor(HangProcessor);
+    ///
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     let id = storage.new_job(HangProcessor::new_job(Hang)?)?;
+    ///     let job = storage.fetch_job(id)?.expect("The job is stored");
+    ///
+    ///     // The runtime provides the timer the limit is measured with
+    ///     let started = Instant::now();
+    ///     let returned = Runtime::new()?
+    ///         .block_on(processors.process_job(job))
+    ///         .expect("Processing doesn't fail");
+    ///
+    ///     assert!(returned.result().is_failure());
+    ///     assert!(started.elapsed() >= Duration::from_millis(100));
+    ///     assert!(started.elapsed() < Duration::from_secs(5));
+    ///     Ok(())
+    /// }
+    /// ```
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Decide whether an error returned by a run of this job may go away on a retry
+    ///
+    /// This can be overridden in user-code to return `false` for errors that will happen again
+    /// however many times the job is retried, like a malformed input or an HTTP 400 response.
+    /// Jobs failing with such an error become dead right away, without using up their retries.
+    /// By default, every error is recoverable.
+    fn is_recoverable(error: &Error) -> bool {
+        let _ = error;
+        true
+    }
+
+    /// This method is called when a failed run of this job is about to be retried
+    ///
+    /// The `attempt` argument is the number of the upcoming retry, starting at 1 for the first
+    /// retry. This can be overridden in user-code to perform side effects such as recording the
+    /// failure in an audit log. It is not called when a job fails for the last time.
+    fn on_retry(&self, attempt: u32, state: &Self::State) {
+        let _ = (attempt, state);
+    }
+
+    /// This method is called when a failed run of this job is about to be retried, and the job
+    /// is close to running out of retries
+    ///
+    /// It is called after `on_retry`, for retries that leave the job with no more retries than the
+    /// threshold set with `ProcessorMap::warn_when_retries_left`, and `retries_left` is the
+    /// number of retries the job has after the upcoming one. This can be overridden in user-code
+    /// to alert an operator while there is still time to fix whatever makes the job fail.
+    fn on_nearing_exhaustion(&self, attempt: u32, retries_left: u64, state: &Self::State) {
+        let _ = (attempt, retries_left, state);
+    }
+}
+
+/// Run a job immediately, bypassing queues, storage, and workers entirely
+///
+/// This is intended for developing and testing jobs. The job is passed through the same
+/// serialization it would go through when stored, and then run with the provided state. Any error
+/// returned by the job's `run` method is returned unchanged.
+///
+/// Hooks such as `on_retry` are not called, and no retries are performed.
+///
+/// ```rust
+/// use background_jobs_core::{run_now, Job, Processor};
+/// use failure::{Error, Fail};
+/// use futures::{future::IntoFuture, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Debug, Fail)]
+/// #[fail(display = "Mailbox {} is full", _0)]
+/// struct MailboxFull(String);
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct SendEmail {
+///     to: String,
+/// }
+///
+/// #[derive(Clone)]
+/// struct EmailProcessor;
+///
+/// impl Job for SendEmail {
+///     type Processor = EmailProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(Err(MailboxFull(self.to).into()).into_future())
+///     }
+/// }
+///
+/// impl Processor for EmailProcessor {
+///     type Job = SendEmail;
+///
+///     const NAME: &'static str = "EmailProcessor";
+///     const QUEUE: &'static str = "email";
+/// }
+///
+/// let job = SendEmail {
+///     to: "someone@example.com".to_owned(),
+/// };
+/// let e = run_now(job, ()).wait().unwrap_err();
+///
+/// // The job's own error comes back, not a wrapper around it
+/// assert_eq!(e.to_string(), "Mailbox someone@example.com is full");
+/// assert!(e.downcast_ref::<MailboxFull>().is_some());
+/// ```
+pub fn run_now<J>(job: J, state: J::State) -> Box<dyn Future<Item = (), Error = Error> + Send>
+where
+    J: Job,
+{
+    let res = serde_json::to_value(job).and_then(serde_json::from_value::<J>);
+
+    match res {
+        Ok(job) => job.run(state),
+        Err(e) => Box::new(Err(Error::from(e)).into_future()),
+    }
 }
@@ -17,82 +17,308 @@
  * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use chrono::{offset::Utc, DateTime, Duration as OldDuration};
+use std::{convert::TryFrom, fmt, sync::Arc, time::Duration};
+
+use failure::Fail;
 use log::trace;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{Backoff, JobResult, JobStatus, MaxRetries, ShouldStop};
+use crate::{
+    clock::{self, TimeDelta, Timestamp},
+    schedule, Backoff, JobResult, JobStatus, MaxRetries, ShouldStop,
+};
+
+/// The number of transitions kept in a job's history
+const MAX_HISTORY: usize = 32;
 
+/// The outcome of a run of a job, as reported to `Storage::return_job`
+///
+/// `ProcessorMap` builds these for the runtimes using it. Runtimes that run jobs some other way
+/// build them with the constructors below.
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use background_jobs_core::{
+///     memory_storage, Backoff, MaxRetries, NewJobInfo, ReturnJobInfo, Storage,
+/// };
+/// use serde_json::json;
+///
+/// fn main() -> Result<(), failure::Error> {
+///     let mut storage = memory_storage::Storage::new();
+///     let mut run = |storage: &mut memory_storage::Storage| -> Result<u64, failure::Error> {
+///         let job = NewJobInfo::builder("MyProcessor", "default")
+///             .args(json!([]))
+///             .max_retries(MaxRetries::Count(1))
+///             .backoff(Backoff::Linear(0))
+///             .build()?;
+///         storage.new_job(job)?;
+///
+///         let job = storage.request_job("default", 1)?.expect("The job is ready");
+///         Ok(job.id())
+///     };
+///
+///     let id = run(&mut storage)?;
+///     storage.return_job(ReturnJobInfo::pass(id))?;
+///     assert!(storage.fetch_job(id)?.is_none());
+///
+///     let id = run(&mut storage)?;
+///     storage.return_job(ReturnJobInfo::fail(id))?;
+///     assert!(storage.fetch_job(id)?.expect("The job is retried").is_pending());
+///     storage.delete_job(id)?;
+///
+///     let id = run(&mut storage)?;
+///     let later = ReturnJobInfo::retry_after(id, Duration::from_secs(3600))
+///         .with_args(json!(["changed"]))
+///         .with_checkpoint(Some(json!({ "done": 10 })));
+///     storage.return_job(later)?;
+///     let job = storage.fetch_job(id)?.expect("The job is retried");
+///     assert!(job.scheduled_for().is_some());
+///     assert_eq!(job.checkpoint(), Some(&json!({ "done": 10 })));
+///     storage.delete_job(id)?;
+///
+///     let id = run(&mut storage)?;
+///     storage.return_job(ReturnJobInfo::unrecoverable(id))?;
+///     assert!(storage.fetch_job(id)?.is_none());
+///     assert!(storage.fetch_dead_jobs()?.iter().any(|job| job.id() == id));
+///
+///     let id = run(&mut storage)?;
+///     storage.return_job(ReturnJobInfo::missing_processor(id))?;
+///     assert!(storage.fetch_job(id)?.expect("The job is requeued").is_pending());
+///     Ok(())
+/// }
+/// ```
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct ReturnJobInfo {
     pub(crate) id: u64,
     pub(crate) result: JobResult,
+
+    /// Arguments to store on the job before it is retried
+    #[serde(default)]
+    pub(crate) args: Option<Value>,
+
+    /// Progress saved by the job during this run
+    #[serde(default)]
+    pub(crate) checkpoint: Option<Value>,
 }
 
 impl ReturnJobInfo {
-    pub(crate) fn fail(id: u64) -> Self {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn result(&self) -> &JobResult {
+        &self.result
+    }
+
+    /// The run failed, so the job is retried or becomes dead
+    pub fn fail(id: u64) -> Self {
         ReturnJobInfo {
             id,
             result: JobResult::Failure,
+            args: None,
+            checkpoint: None,
         }
     }
 
-    pub(crate) fn pass(id: u64) -> Self {
+    /// The run succeeded, so the job is complete
+    pub fn pass(id: u64) -> Self {
         ReturnJobInfo {
             id,
             result: JobResult::Success,
+            args: None,
+            checkpoint: None,
+        }
+    }
+
+    /// The run asked to be retried after the given delay, instead of following the backoff
+    pub fn retry_after(id: u64, after: Duration) -> Self {
+        ReturnJobInfo {
+            id,
+            result: JobResult::Retry { after },
+            args: None,
+            checkpoint: None,
+        }
+    }
+
+    /// Replace the job's arguments before it is retried
+    ///
+    /// This is ignored when the job isn't retried.
+    pub fn with_args(mut self, args: Value) -> Self {
+        self.args = Some(args);
+        self
+    }
+
+    /// Keep the progress the job saved during the run, for the next run to resume from
+    pub fn with_checkpoint(mut self, checkpoint: Option<Value>) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// The run failed with an error retrying won't fix, so the job becomes dead right away
+    pub fn unrecoverable(id: u64) -> Self {
+        ReturnJobInfo {
+            id,
+            result: JobResult::Unrecoverable,
+            args: None,
+            checkpoint: None,
         }
     }
 
-    pub(crate) fn missing_processor(id: u64) -> Self {
+    /// No processor was registered for the job, so it is put back in its queue without counting
+    /// as a failure
+    pub fn missing_processor(id: u64) -> Self {
         ReturnJobInfo {
             id,
             result: JobResult::MissingProcessor,
+            args: None,
+            checkpoint: None,
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
 pub struct NewJobInfo {
     /// Name of the processor that should handle this job
     processor: String,
 
     /// Name of the queue that this job is a part of
-    queue: String,
+    queue: Arc<str>,
 
     /// Arguments for a given job
     args: Value,
 
-    /// the initial MaxRetries value, for comparing to the current retry count
-    max_retries: MaxRetries,
+    /// The arguments to show instead of `args`, if the job masks some of them
+    #[serde(default)]
+    redacted_args: Option<Value>,
 
-    /// How often retries should be scheduled
-    backoff_strategy: Backoff,
+    /// the initial MaxRetries value, if the job or its processor chose one
+    max_retries: Option<MaxRetries>,
+
+    /// How often retries should be scheduled, if the job or its processor chose a strategy
+    backoff_strategy: Option<Backoff>,
 
     /// The time this job should be dequeued
-    next_queue: Option<DateTime<Utc>>,
+    next_queue: Option<Timestamp>,
+
+    /// The queue this job should be moved to when it is retried
+    #[serde(default)]
+    retry_queue: Option<String>,
+
+    /// A key used to coalesce this job with an identical pending job in the same queue
+    #[serde(default)]
+    unique_key: Option<String>,
+
+    /// A key used to coalesce this job with an identical pending job anywhere in the storage
+    #[serde(default)]
+    global_key: Option<String>,
+
+    /// A key used to recognize this job as already completed when it is resubmitted
+    #[serde(default)]
+    idempotency_key: Option<String>,
+
+    /// The priority of this job within its queue, higher runs first
+    #[serde(default)]
+    priority: i32,
+
+    /// An estimate of the memory this job uses while running
+    #[serde(default)]
+    weight: usize,
+
+    /// A key shared by related jobs that should preferably run on the same worker
+    #[serde(default)]
+    affinity_key: Option<String>,
+
+    /// The correlation ID of the request that created this job
+    #[serde(default)]
+    trace_id: Option<String>,
+
+    /// How long a run of this job may take before it is failed
+    #[serde(default)]
+    timeout: Option<Duration>,
+
+    /// How long this job waits in quarantine before its last replay, once it's out of retries
+    #[serde(default)]
+    quarantine: Option<Duration>,
 }
 
 impl NewJobInfo {
-    pub(crate) fn schedule(&mut self, time: DateTime<Utc>) {
+    pub(crate) fn schedule(&mut self, time: Timestamp) {
         self.next_queue = Some(time);
     }
 
+    /// Start building a NewJobInfo from raw parts
+    ///
+    /// This is intended for tools that need to create jobs without having the concrete `Job` and
+    /// `Processor` types available, such as an admin tool replaying stored jobs. In most cases,
+    /// `Processor::new_job` should be preferred, since it guarantees the arguments match the
+    /// processor.
+    ///
+    /// ```rust
+    /// use background_jobs_core::{memory_storage, Backoff, MaxRetries, NewJobInfo, Storage};
+    /// use failure::Error;
+    /// use serde_json::json;
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let job = NewJobInfo::builder("MyProcessor", "default")
+    ///         .args(json!({ "count": 1234 }))
+    ///         .max_retries(MaxRetries::Count(1))
+    ///         .backoff(Backoff::Exponential(2))
+    ///         .build()?;
+    ///
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     storage.new_job(job)?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn builder(processor: &str, queue: &str) -> NewJobInfoBuilder {
+        NewJobInfoBuilder {
+            processor: processor.to_owned(),
+            queue: queue.to_owned(),
+            args: None,
+            max_retries: None,
+            backoff_strategy: None,
+            next_queue: None,
+            retry_queue: None,
+            unique_key: None,
+            global_key: None,
+            idempotency_key: None,
+            priority: None,
+            weight: None,
+            affinity_key: None,
+            trace_id: None,
+            timeout: None,
+            quarantine: None,
+        }
+    }
+
     pub(crate) fn new(
         processor: String,
         queue: String,
         args: Value,
-        max_retries: MaxRetries,
-        backoff_strategy: Backoff,
+        max_retries: Option<MaxRetries>,
+        backoff_strategy: Option<Backoff>,
     ) -> Self {
         NewJobInfo {
             processor,
-            queue,
+            queue: queue.into(),
             args,
+            redacted_args: None,
             max_retries,
             next_queue: None,
             backoff_strategy,
+            retry_queue: None,
+            unique_key: None,
+            global_key: None,
+            idempotency_key: None,
+            priority: 0,
+            weight: 0,
+            affinity_key: None,
+            trace_id: None,
+            timeout: None,
+            quarantine: None,
         }
     }
 
@@ -100,27 +326,407 @@ impl NewJobInfo {
         &self.queue
     }
 
+    #[cfg(feature = "jsonschema")]
+    pub(crate) fn processor(&self) -> &str {
+        &self.processor
+    }
+
+    pub(crate) fn args(&self) -> &Value {
+        &self.args
+    }
+
+    /// Set the arguments to show in place of this job's arguments in logs and listings
+    ///
+    /// The job still runs with its real arguments.
+    pub fn set_redacted_args(&mut self, args: Value) {
+        self.redacted_args = Some(args);
+    }
+
+    /// The arguments of this job that are safe to show, with any masked fields masked
+    pub fn redacted_args(&self) -> &Value {
+        self.redacted_args.as_ref().unwrap_or(&self.args)
+    }
+
+    /// Set the queue this job is stored in, replacing the one chosen by its processor
+    ///
+    /// No worker will pick up the job unless a processor for this job is registered with a
+    /// worker serving the new queue.
+    pub fn set_queue(&mut self, queue: String) {
+        self.queue = queue.into();
+    }
+
+    /// Set the queue this job should be moved to when it is retried
+    ///
+    /// No worker will pick up the retry unless a processor for this job is registered with a
+    /// worker serving the retry queue.
+    pub fn set_retry_queue(&mut self, queue: String) {
+        self.retry_queue = Some(queue);
+    }
+
+    pub fn retry_queue(&self) -> Option<&str> {
+        self.retry_queue.as_deref()
+    }
+
+    /// Set a key that identifies this job within its queue
+    ///
+    /// When a job is created while a pending job with the same key exists in the same queue, no
+    /// new job is stored, and the existing job's ID is returned instead. Once a job starts
+    /// running, new jobs with its key are accepted again.
+    pub fn set_unique_key(&mut self, key: String) {
+        self.unique_key = Some(key);
+    }
+
+    pub fn unique_key(&self) -> Option<&str> {
+        self.unique_key.as_deref()
+    }
+
+    /// Set a key that identifies this job across every queue
+    ///
+    /// When a job is created while a pending job with the same key exists anywhere in the
+    /// storage, whatever its queue or processor, no new job is stored, and the existing job's ID
+    /// is returned instead. The existing job keeps its own arguments. Once a job starts running,
+    /// new jobs with its key are accepted again.
+    pub fn set_global_key(&mut self, key: String) {
+        self.global_key = Some(key);
+    }
+
+    pub fn global_key(&self) -> Option<&str> {
+        self.global_key.as_deref()
+    }
+
+    /// Set a key that identifies this job across completion
+    ///
+    /// When a job is created after a job with the same key in the same queue has completed, no
+    /// new job is stored, and the completed job's ID is returned instead. Completed keys are
+    /// remembered for the storage backend's idempotency retention window.
+    pub fn set_idempotency_key(&mut self, key: String) {
+        self.idempotency_key = Some(key);
+    }
+
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    /// Set the priority of this job within its queue
+    ///
+    /// When several jobs in a queue are ready, the one with the highest priority is dispatched
+    /// first. Jobs with equal priority are dispatched oldest first.
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Set an estimate of the memory this job uses while running
+    ///
+    /// This is only meaningful relative to the weight of other jobs and the budget configured
+    /// for the runtime, so any unit can be used as long as it is used consistently.
+    pub fn set_weight(&mut self, weight: usize) {
+        self.weight = weight;
+    }
+
+    /// Set a key shared by related jobs that should preferably run on the same worker
+    ///
+    /// This is a hint for runtimes that keep state in their workers, such as a cache of the
+    /// resource the jobs operate on. The actix runtime hands a job with a key to the free worker
+    /// that last ran a job with the same key, if there is one, and to any worker otherwise.
+    pub fn set_affinity_key(&mut self, key: String) {
+        self.affinity_key = Some(key);
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn affinity_key(&self) -> Option<&str> {
+        self.affinity_key.as_deref()
+    }
+
+    /// Set the correlation ID of the request that created this job
+    ///
+    /// The ID is stored with the job, and included in the log lines written while the job is
+    /// processed, so a job's logs can be linked back to the request that queued it.
+    pub fn set_trace_id(&mut self, trace_id: String) {
+        self.trace_id = Some(trace_id);
+    }
+
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    /// Set how long a run of this job may take before it is failed
+    ///
+    /// A run that is still going after this long is failed as if it had returned an error, even
+    /// if its future can't be interrupted. The run is also asked to stop through its
+    /// `CancellationToken`. Whatever the run returns afterwards is discarded.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Quarantine this job once it's out of retries, instead of making it dead right away
+    ///
+    /// A quarantined job is pending again, scheduled to run once more after the given delay. If
+    /// that run fails too, the job becomes dead as usual.
+    pub fn set_quarantine(&mut self, delay: Duration) {
+        self.quarantine = Some(delay);
+    }
+
+    pub fn quarantine(&self) -> Option<Duration> {
+        self.quarantine
+    }
+
     pub fn is_ready(&self) -> bool {
         self.next_queue.is_none()
     }
 
+    /// Use the given retry settings where neither the job nor its processor chose one
+    ///
+    /// Jobs that still have no retry settings when they are stored use the `Default` values of
+    /// `MaxRetries` and `Backoff`.
+    pub fn fill_retry_defaults(&mut self, max_retries: &MaxRetries, backoff_strategy: &Backoff) {
+        if self.max_retries.is_none() {
+            self.max_retries = Some(max_retries.clone());
+        }
+
+        if self.backoff_strategy.is_none() {
+            self.backoff_strategy = Some(backoff_strategy.clone());
+        }
+    }
+
+    /// Use the given quarantine delay if neither the job nor its processor chose one
+    pub fn fill_quarantine_default(&mut self, delay: Duration) {
+        if self.quarantine.is_none() {
+            self.quarantine = Some(delay);
+        }
+    }
+
     pub(crate) fn with_id(self, id: u64) -> JobInfo {
+        let now = clock::now();
+
         JobInfo {
             id,
             processor: self.processor,
             queue: self.queue,
             status: JobStatus::Pending,
             args: self.args,
+            redacted_args: self.redacted_args,
             retry_count: 0,
-            max_retries: self.max_retries,
+            max_retries: self.max_retries.unwrap_or_default(),
             next_queue: self.next_queue,
-            backoff_strategy: self.backoff_strategy,
-            updated_at: Utc::now(),
+            backoff_strategy: self.backoff_strategy.unwrap_or_default(),
+            retry_queue: self.retry_queue,
+            unique_key: self.unique_key,
+            global_key: self.global_key,
+            idempotency_key: self.idempotency_key,
+            priority: self.priority,
+            weight: self.weight,
+            affinity_key: self.affinity_key,
+            trace_id: self.trace_id,
+            timeout: self.timeout,
+            quarantine: self.quarantine,
+            quarantined: false,
+            checkpoint: None,
+            updated_at: now,
+            created_at: now,
+            history: vec![(now, JobEventKind::Created)],
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+impl fmt::Debug for NewJobInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NewJobInfo")
+            .field("processor", &self.processor)
+            .field("queue", &self.queue)
+            .field("args", self.redacted_args())
+            .field("max_retries", &self.max_retries)
+            .field("backoff_strategy", &self.backoff_strategy)
+            .field("next_queue", &self.next_queue)
+            .field("retry_queue", &self.retry_queue)
+            .field("unique_key", &self.unique_key)
+            .field("global_key", &self.global_key)
+            .field("idempotency_key", &self.idempotency_key)
+            .field("priority", &self.priority)
+            .field("weight", &self.weight)
+            .field("affinity_key", &self.affinity_key)
+            .field("trace_id", &self.trace_id)
+            .field("timeout", &self.timeout)
+            .field("quarantine", &self.quarantine)
+            .finish()
+    }
+}
+
+/// A builder for creating a NewJobInfo from raw parts
+///
+/// The processor name, queue, and arguments are required. When the maximum retries or backoff
+/// strategy are left out, the server's defaults are used, as for processors that don't set them.
+#[derive(Clone, Debug)]
+pub struct NewJobInfoBuilder {
+    processor: String,
+    queue: String,
+    args: Option<Value>,
+    max_retries: Option<MaxRetries>,
+    backoff_strategy: Option<Backoff>,
+    next_queue: Option<Timestamp>,
+    retry_queue: Option<String>,
+    unique_key: Option<String>,
+    global_key: Option<String>,
+    idempotency_key: Option<String>,
+    priority: Option<i32>,
+    weight: Option<usize>,
+    affinity_key: Option<String>,
+    trace_id: Option<String>,
+    timeout: Option<Duration>,
+    quarantine: Option<Duration>,
+}
+
+impl NewJobInfoBuilder {
+    /// Set the arguments for the job
+    pub fn args(mut self, args: Value) -> Self {
+        self.args = Some(args);
+        self
+    }
+
+    /// Set the maximum number of retries for the job
+    pub fn max_retries(mut self, max_retries: MaxRetries) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Set the backoff strategy for the job
+    pub fn backoff(mut self, backoff_strategy: Backoff) -> Self {
+        self.backoff_strategy = Some(backoff_strategy);
+        self
+    }
+
+    /// Schedule the job to be performed after a certain time
+    pub fn schedule(mut self, after: Timestamp) -> Self {
+        self.next_queue = Some(after);
+        self
+    }
+
+    /// Set the queue the job should be moved to when it is retried
+    pub fn retry_queue(mut self, queue: &str) -> Self {
+        self.retry_queue = Some(queue.to_owned());
+        self
+    }
+
+    /// Set a key that identifies the job within its queue
+    pub fn unique_key(mut self, key: &str) -> Self {
+        self.unique_key = Some(key.to_owned());
+        self
+    }
+
+    /// Set a key that identifies the job across every queue
+    pub fn global_key(mut self, key: &str) -> Self {
+        self.global_key = Some(key.to_owned());
+        self
+    }
+
+    /// Set a key that identifies the job across completion
+    pub fn idempotency_key(mut self, key: &str) -> Self {
+        self.idempotency_key = Some(key.to_owned());
+        self
+    }
+
+    /// Set the priority of the job within its queue
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Set an estimate of the memory the job uses while running
+    pub fn weight(mut self, weight: usize) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// Set a key shared by related jobs that should preferably run on the same worker
+    pub fn affinity_key(mut self, key: &str) -> Self {
+        self.affinity_key = Some(key.to_owned());
+        self
+    }
+
+    /// Set the correlation ID of the request that created the job
+    pub fn trace_id(mut self, trace_id: &str) -> Self {
+        self.trace_id = Some(trace_id.to_owned());
+        self
+    }
+
+    /// Set how long a run of the job may take before it is failed
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Quarantine the job for the given delay once it's out of retries
+    pub fn quarantine(mut self, delay: Duration) -> Self {
+        self.quarantine = Some(delay);
+        self
+    }
+
+    /// Validate the provided parts and create the NewJobInfo
+    pub fn build(self) -> Result<NewJobInfo, NewJobInfoError> {
+        NewJobInfo::try_from(self)
+    }
+}
+
+impl TryFrom<NewJobInfoBuilder> for NewJobInfo {
+    type Error = NewJobInfoError;
+
+    fn try_from(builder: NewJobInfoBuilder) -> Result<Self, Self::Error> {
+        if builder.processor.is_empty() {
+            return Err(NewJobInfoError::Empty("processor"));
+        }
+
+        if builder.queue.is_empty() {
+            return Err(NewJobInfoError::Empty("queue"));
+        }
+
+        let args = builder.args.ok_or(NewJobInfoError::Missing("args"))?;
+
+        let mut job = NewJobInfo::new(
+            builder.processor,
+            builder.queue,
+            args,
+            builder.max_retries,
+            builder.backoff_strategy,
+        );
+        job.next_queue = builder.next_queue;
+        job.retry_queue = builder.retry_queue;
+        job.unique_key = builder.unique_key;
+        job.global_key = builder.global_key;
+        job.idempotency_key = builder.idempotency_key;
+        job.priority = builder.priority.unwrap_or(0);
+        job.weight = builder.weight.unwrap_or(0);
+        job.affinity_key = builder.affinity_key;
+        job.trace_id = builder.trace_id;
+        job.timeout = builder.timeout;
+        job.quarantine = builder.quarantine;
+
+        Ok(job)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+/// The error type returned when a NewJobInfo cannot be built
+pub enum NewJobInfoError {
+    /// A required field was not provided
+    #[fail(display = "Missing required field {}", _0)]
+    Missing(&'static str),
+
+    /// A field was provided, but it was empty
+    #[fail(display = "Field {} must not be empty", _0)]
+    Empty(&'static str),
+}
+
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
 /// Metadata pertaining to a job that exists within the background_jobs system
 ///
 /// Although exposed publically, this type should only really be handled by the library itself, and
@@ -135,11 +741,15 @@ pub struct JobInfo {
     processor: String,
 
     /// Name of the queue that this job is a part of
-    queue: String,
+    queue: Arc<str>,
 
     /// Arguments for a given job
     args: Value,
 
+    /// The arguments to show instead of `args`, if the job masks some of them
+    #[serde(default)]
+    redacted_args: Option<Value>,
+
     /// Status of the job
     status: JobStatus,
 
@@ -153,10 +763,66 @@ pub struct JobInfo {
     backoff_strategy: Backoff,
 
     /// The time this job should be dequeued
-    next_queue: Option<DateTime<Utc>>,
+    next_queue: Option<Timestamp>,
+
+    /// The queue this job should be moved to when it is retried
+    #[serde(default)]
+    retry_queue: Option<String>,
+
+    /// A key used to coalesce this job with an identical pending job in the same queue
+    #[serde(default)]
+    unique_key: Option<String>,
+
+    /// A key used to coalesce this job with an identical pending job anywhere in the storage
+    #[serde(default)]
+    global_key: Option<String>,
+
+    /// A key used to recognize this job as already completed when it is resubmitted
+    #[serde(default)]
+    idempotency_key: Option<String>,
+
+    /// The priority of this job within its queue, higher runs first
+    #[serde(default)]
+    priority: i32,
+
+    /// An estimate of the memory this job uses while running
+    #[serde(default)]
+    weight: usize,
+
+    /// A key shared by related jobs that should preferably run on the same worker
+    #[serde(default)]
+    affinity_key: Option<String>,
+
+    /// The correlation ID of the request that created this job
+    #[serde(default)]
+    trace_id: Option<String>,
+
+    /// How long a run of this job may take before it is failed
+    #[serde(default)]
+    timeout: Option<Duration>,
+
+    /// How long this job waits in quarantine before its last replay, once it's out of retries
+    #[serde(default)]
+    quarantine: Option<Duration>,
+
+    /// Whether this job has already been quarantined
+    #[serde(default)]
+    quarantined: bool,
+
+    /// The progress last saved by a run of this job
+    #[serde(default)]
+    checkpoint: Option<Value>,
 
     /// The time this job was last updated
-    updated_at: DateTime<Utc>,
+    updated_at: Timestamp,
+
+    /// The time this job was created
+    #[serde(default = "clock::now")]
+    created_at: Timestamp,
+
+    /// The most recent transitions this job has gone through
+    #[serde(default)]
+    history: Vec<(Timestamp, JobEventKind)>,
 }
 
 impl JobInfo {
@@ -164,8 +830,81 @@ impl JobInfo {
         &self.queue
     }
 
+    pub fn unique_key(&self) -> Option<&str> {
+        self.unique_key.as_deref()
+    }
+
+    pub fn global_key(&self) -> Option<&str> {
+        self.global_key.as_deref()
+    }
+
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn affinity_key(&self) -> Option<&str> {
+        self.affinity_key.as_deref()
+    }
+
+    /// The correlation ID of the request that created this job, if it was queued with one
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    /// How long a run of this job may take before it is failed, if it has a limit
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// How long this job waits in quarantine once it's out of retries, if it is quarantined at all
+    pub fn quarantine(&self) -> Option<Duration> {
+        self.quarantine
+    }
+
+    /// Whether this job is in quarantine, or already was and failed its replay
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined
+    }
+
+    /// The progress last saved by a run of this job, if any run saved some
+    pub fn checkpoint(&self) -> Option<&Value> {
+        self.checkpoint.as_ref()
+    }
+
+    pub(crate) fn set_checkpoint(&mut self, checkpoint: Value) {
+        self.updated();
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// A suffix for log lines about this job, naming its correlation ID if it has one
+    pub(crate) fn trace_suffix(&self) -> String {
+        self.trace_id
+            .as_ref()
+            .map(|id| format!(", trace {}", id))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn set_priority(&mut self, priority: i32) {
+        self.updated();
+        self.priority = priority;
+    }
+
+    /// Make this job ready right away, instead of at its scheduled time
+    pub(crate) fn clear_schedule(&mut self) {
+        self.updated();
+        self.next_queue = None;
+    }
+
     fn updated(&mut self) {
-        self.updated_at = Utc::now();
+        self.updated_at = clock::now();
     }
 
     pub(crate) fn processor(&self) -> &str {
@@ -176,26 +915,89 @@ impl JobInfo {
         self.args.clone()
     }
 
+    /// The arguments of this job that are safe to show, with any masked fields masked
+    ///
+    /// Masked arguments are computed when the job is created. When a retry replaces the job's
+    /// arguments, the original masked arguments keep being shown, so secrets are never revealed.
+    pub fn redacted_args(&self) -> &Value {
+        self.redacted_args.as_ref().unwrap_or(&self.args)
+    }
+
+    pub(crate) fn set_args(&mut self, args: Value) {
+        self.updated();
+        self.args = args;
+    }
+
     pub fn id(&self) -> u64 {
         self.id
     }
 
+    /// The number of failed runs of this job so far
+    ///
+    /// This is reset when a dead job is requeued.
+    ///
+    /// ```rust
+    /// use background_jobs_core::{memory_storage, Backoff, NewJobInfo, ReturnJobInfo, Storage};
+    /// use serde_json::json;
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     let new_job = NewJobInfo::builder("Processor", "default")
+    ///         .args(json!([]))
+    ///         .backoff(Backoff::Linear(0))
+    ///         .build()?;
+    ///     let id = storage.new_job(new_job)?;
+    ///
+    ///     let stored = storage.fetch_job(id)?.unwrap();
+    ///     assert_eq!(stored.retry_count(), 0);
+    ///
+    ///     for _ in 0..2 {
+    ///         storage.request_job("default", 1)?.unwrap();
+    ///         storage.return_job(ReturnJobInfo::fail(id))?;
+    ///     }
+    ///
+    ///     // Each failed run is counted, and the job keeps the time it was first stored
+    ///     let retried = storage.fetch_job(id)?.unwrap();
+    ///     assert_eq!(retried.retry_count(), 2);
+    ///     assert_eq!(retried.created_at(), stored.created_at());
+    ///     assert!(retried.updated_at() > stored.updated_at());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn retry_count(&self) -> u32 {
+        self.retry_count
+    }
+
+    /// The time this job last changed state
+    pub fn updated_at(&self) -> Timestamp {
+        self.updated_at
+    }
+
+    /// The time this job was first stored
+    ///
+    /// Jobs stored by versions that didn't record this report the time they were loaded instead.
+    pub fn created_at(&self) -> Timestamp {
+        self.created_at
+    }
+
+    /// Whether this job is running and hasn't changed state for longer than the given duration
+    pub fn is_stale(&self, now: Timestamp, older_than: Duration) -> bool {
+        let age = now.signed_duration_since(self.updated_at);
+
+        self.status == JobStatus::Running && age.to_std().is_ok_and(|age| age > older_than)
+    }
+
     pub(crate) fn increment(&mut self) -> ShouldStop {
         self.updated();
-        self.retry_count += 1;
-        self.max_retries.compare(self.retry_count)
+        self.record(JobEventKind::Failed);
+        self.retry_count = self.retry_count.saturating_add(1);
+        schedule::should_retry(&self.max_retries, self.retry_count)
     }
 
     fn next_queue(&mut self) {
-        let now = Utc::now();
+        let now = clock::now();
 
-        let next_queue = match self.backoff_strategy {
-            Backoff::Linear(secs) => now + OldDuration::seconds(secs as i64),
-            Backoff::Exponential(base) => {
-                let secs = base.pow(self.retry_count);
-                now + OldDuration::seconds(secs as i64)
-            }
-        };
+        let next_queue = schedule::next_attempt(&self.backoff_strategy, self.retry_count, now);
 
         self.next_queue = Some(next_queue);
 
@@ -207,39 +1009,248 @@ impl JobInfo {
         );
     }
 
-    pub fn is_ready(&self, now: DateTime<Utc>) -> bool {
-        match self.next_queue {
-            Some(ref time) => now > *time,
-            None => true,
+    /// The time this job will next become ready, if it has been scheduled
+    pub fn scheduled_for(&self) -> Option<Timestamp> {
+        self.next_queue
+    }
+
+    /// Whether this job is waiting for a scheduled time that has not yet arrived
+    pub fn is_scheduled(&self, now: Timestamp) -> bool {
+        self.is_pending() && !self.is_ready(now)
+    }
+
+    pub fn is_ready(&self, now: Timestamp) -> bool {
+        schedule::is_ready(self.next_queue, now)
+    }
+
+    /// The number of the retry that will follow a failure of the current run, if the job is
+    /// allowed to be retried
+    pub(crate) fn next_retry(&self) -> Option<u32> {
+        let attempt = self.retry_count.checked_add(1)?;
+
+        if schedule::should_retry(&self.max_retries, attempt).should_requeue() {
+            Some(attempt)
+        } else {
+            None
         }
     }
 
+    /// The number of retries left once the given retry has started, or `None` when retries are
+    /// unlimited
+    pub(crate) fn retries_left_after(&self, attempt: u32) -> Option<u64> {
+        schedule::retries_left(&self.max_retries, attempt)
+    }
+
     pub(crate) fn needs_retry(&mut self) -> bool {
         let should_retry = self.increment().should_requeue();
 
         if should_retry {
             self.pending();
             self.next_queue();
+
+            if let Some(ref queue) = self.retry_queue {
+                self.queue = queue.as_str().into();
+            }
+        }
+
+        should_retry
+    }
+
+    /// Like `needs_retry`, but schedules the retry after the given duration instead of following
+    /// the backoff strategy
+    pub(crate) fn needs_retry_after(&mut self, after: Duration) -> bool {
+        let should_retry = self.needs_retry();
+
+        if should_retry {
+            if let Some(next_queue) = schedule::retry_after(after, clock::now()) {
+                self.next_queue = Some(next_queue);
+            }
         }
 
         should_retry
     }
 
+    /// Move a job that is out of retries into quarantine, if it has a quarantine delay and hasn't
+    /// been quarantined before
+    ///
+    /// This returns whether the job was quarantined. A quarantined job is pending again, and
+    /// scheduled to be replayed once the delay has passed.
+    pub(crate) fn needs_quarantine(&mut self) -> bool {
+        let delay = match self.quarantine {
+            Some(delay) if !self.quarantined => delay,
+            _ => return false,
+        };
+
+        self.quarantined = true;
+        self.pending();
+        self.record(JobEventKind::Quarantined);
+        self.next_queue = schedule::retry_after(delay, clock::now())
+            .or_else(|| Some(clock::saturating_add_secs(clock::now(), delay.as_secs())));
+
+        if let Some(ref queue) = self.retry_queue {
+            self.queue = queue.as_str().into();
+        }
+
+        true
+    }
+
+    /// The moment this job became, or will become, ready
+    ///
+    /// This is its creation time for fresh jobs, and its scheduled time for scheduled jobs and
+    /// retries.
+    pub fn ready_at(&self) -> Timestamp {
+        match self.next_queue {
+            Some(time) if time > self.created_at => time,
+            _ => self.created_at,
+        }
+    }
+
+    /// How long this job has been waiting to be picked up, as of `now`
+    pub(crate) fn wait_time(&self, now: Timestamp) -> TimeDelta {
+        now.signed_duration_since(self.ready_at())
+    }
+
     pub fn is_pending(&self) -> bool {
         self.status == JobStatus::Pending
     }
 
     pub(crate) fn is_in_queue(&self, queue: &str) -> bool {
-        self.queue == queue
+        &*self.queue == queue
     }
 
     pub(crate) fn run(&mut self) {
         self.updated();
+        self.record(JobEventKind::Started);
         self.status = JobStatus::Running;
     }
 
+    /// Prepare a dead job to be run again, with a fresh retry count and quarantine
+    pub(crate) fn revive(&mut self, max_retries: Option<MaxRetries>, backoff: Option<Backoff>) {
+        if let Some(max_retries) = max_retries {
+            self.max_retries = max_retries;
+        }
+
+        if let Some(backoff) = backoff {
+            self.backoff_strategy = backoff;
+        }
+
+        self.retry_count = 0;
+        self.quarantined = false;
+        self.next_queue = None;
+        self.pending();
+    }
+
     pub(crate) fn pending(&mut self) {
         self.updated();
+        self.record(JobEventKind::Requeued);
         self.status = JobStatus::Pending;
     }
+
+    /// The transitions this job has gone through, oldest first
+    ///
+    /// Only the most recent 32 transitions are kept.
+    ///
+    /// ```rust
+    /// use background_jobs_core::{
+    ///     memory_storage, Backoff, JobEventKind, MaxRetries, NewJobInfo, ReturnJobInfo, Storage,
+    /// };
+    /// use serde_json::json;
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     let id = storage.new_job(
+    ///         NewJobInfo::builder("Processor", "default")
+    ///             .args(json!([]))
+    ///             .max_retries(MaxRetries::Count(2))
+    ///             .backoff(Backoff::Linear(0))
+    ///             .build()?,
+    ///     )?;
+    ///
+    ///     for _ in 0..2 {
+    ///         storage.request_job("default", 1)?.unwrap();
+    ///         storage.return_job(ReturnJobInfo::fail(id))?;
+    ///     }
+    ///
+    ///     let job = storage.list_jobs()?.into_iter().find(|job| job.id() == id).unwrap();
+    ///     let kinds: Vec<_> = job.history().iter().map(|(_, kind)| kind.clone()).collect();
+    ///     assert_eq!(
+    ///         kinds,
+    ///         vec![
+    ///             JobEventKind::Created,
+    ///             JobEventKind::Started,
+    ///             JobEventKind::Failed,
+    ///             JobEventKind::Requeued,
+    ///             JobEventKind::Started,
+    ///             JobEventKind::Failed,
+    ///             JobEventKind::Requeued,
+    ///         ]
+    ///     );
+    ///
+    ///     // Each transition happened no earlier than the one before it
+    ///     assert!(job.history().windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn history(&self) -> &[(Timestamp, JobEventKind)] {
+        &self.history
+    }
+
+    fn record(&mut self, kind: JobEventKind) {
+        if self.history.len() >= MAX_HISTORY {
+            self.history.remove(0);
+        }
+
+        self.history.push((self.updated_at, kind));
+    }
+}
+
+// Only the redacted arguments are shown, so jobs can be logged without revealing secrets
+impl fmt::Debug for JobInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JobInfo")
+            .field("id", &self.id)
+            .field("processor", &self.processor)
+            .field("queue", &self.queue)
+            .field("args", self.redacted_args())
+            .field("status", &self.status)
+            .field("retry_count", &self.retry_count)
+            .field("max_retries", &self.max_retries)
+            .field("backoff_strategy", &self.backoff_strategy)
+            .field("next_queue", &self.next_queue)
+            .field("retry_queue", &self.retry_queue)
+            .field("unique_key", &self.unique_key)
+            .field("global_key", &self.global_key)
+            .field("idempotency_key", &self.idempotency_key)
+            .field("priority", &self.priority)
+            .field("weight", &self.weight)
+            .field("affinity_key", &self.affinity_key)
+            .field("trace_id", &self.trace_id)
+            .field("timeout", &self.timeout)
+            .field("quarantine", &self.quarantine)
+            .field("quarantined", &self.quarantined)
+            .field("has_checkpoint", &self.checkpoint.is_some())
+            .field("updated_at", &self.updated_at)
+            .field("created_at", &self.created_at)
+            .field("history", &self.history)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// A transition recorded in a job's history
+pub enum JobEventKind {
+    /// The job was stored for the first time
+    Created,
+
+    /// The job was handed to a worker
+    Started,
+
+    /// A run of the job failed
+    Failed,
+
+    /// The job was put back into its queue
+    Requeued,
+
+    /// The job ran out of retries and was scheduled for one last replay after its quarantine
+    Quarantined,
 }
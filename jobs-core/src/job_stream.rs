@@ -0,0 +1,152 @@
+/*
+ * This file is part of Background Jobs.
+ *
+ * Copyright © 2019 Riley Trautman
+ *
+ * Background Jobs is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Background Jobs is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use futures::{Async, Poll, Stream};
+use log::trace;
+
+use crate::{JobInfo, Storage};
+
+/// A `Stream` of jobs requested from a single queue
+///
+/// This is intended for custom runtimes built directly on `background-jobs-core`. Whenever the
+/// queue has a ready job, the stream yields it right away. Otherwise, it waits for the next item
+/// from the provided ticker, such as a timer interval, before asking the storage again. The
+/// stream ends when the ticker ends or fails.
+///
+/// Jobs are delivered at least once. Every job yielded by the stream is marked as running, and
+/// must be handed back with `Storage::return_job` once it has been processed, usually with the
+/// `ReturnJobInfo` produced by `ProcessorMap::process_job`. Jobs that are never returned stay in
+/// the running state.
+///
+/// ```rust
+/// use std::{
+///     collections::HashSet,
+///     sync::{Arc, Mutex},
+/// };
+///
+/// use background_jobs_core::{memory_storage, Job, JobStream, Processor, ProcessorMap, Storage};
+/// use failure::Error;
+/// use futures::{future::IntoFuture, stream, Future, Stream};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Deserialize, Serialize)]
+/// struct Count {
+///     count: usize,
+/// }
+///
+/// impl Job for Count {
+///     type Processor = CountProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(Ok(()).into_future())
+///     }
+/// }
+///
+/// #[derive(Clone)]
+/// struct CountProcessor;
+///
+/// impl Processor for CountProcessor {
+///     type Job = Count;
+///
+///     const NAME: &'static str = "CountProcessor";
+///     const QUEUE: &'static str = "default";
+/// }
+///
+/// fn main() -> Result<(), Error> {
+///     let mut storage = memory_storage::Storage::new();
+///
+///     for count in 0..3 {
+///         storage.new_job(CountProcessor::new_job(Count { count })?)?;
+///     }
+///
+///     let mut processors = ProcessorMap::new(Arc::new(|| ()));
+///     processors.register_processor(CountProcessor);
+///
+///     let yielded = Arc::new(Mutex::new(Vec::new()));
+///     let (ids, mut returns) = (yielded.clone(), storage.clone());
+///
+///     JobStream::new(storage.clone(), "default", 1, stream::repeat::<(), ()>(()))
+///         .take(3)
+///         .map_err(Error::from)
+///         .for_each(move |job| {
+///             ids.lock().unwrap().push(job.id());
+///             let ret = processors.process_job(job).wait().unwrap();
+///             returns.return_job(ret).map_err(Error::from)
+///         })
+///         .wait()?;
+///
+///     let yielded = yielded.lock().unwrap();
+///     assert_eq!(yielded.iter().collect::<HashSet<_>>().len(), 3);
+///     assert_eq!(storage.get_stats()?.complete.all_time(), 3);
+///     assert!(storage.list_jobs()?.is_empty());
+///     Ok(())
+/// }
+/// ```
+pub struct JobStream<S, T> {
+    storage: S,
+    queue: String,
+    runner_id: u64,
+    ticker: T,
+}
+
+impl<S, T> JobStream<S, T>
+where
+    S: Storage,
+    T: Stream,
+{
+    /// Create a stream of jobs from the given queue
+    ///
+    /// The `runner_id` is recorded by the storage as the runner of every job yielded by this
+    /// stream.
+    pub fn new(storage: S, queue: &str, runner_id: u64, ticker: T) -> Self {
+        JobStream {
+            storage,
+            queue: queue.to_owned(),
+            runner_id,
+            ticker,
+        }
+    }
+}
+
+impl<S, T> Stream for JobStream<S, T>
+where
+    S: Storage,
+    T: Stream,
+{
+    type Item = JobInfo;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(job) = self.storage.request_job(&self.queue, self.runner_id)? {
+                return Ok(Async::Ready(Some(job)));
+            }
+
+            match self.ticker.poll() {
+                Ok(Async::Ready(Some(_))) => continue,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) | Err(_) => {
+                    trace!("Ticker for queue {} stopped, ending stream", self.queue);
+                    return Ok(Async::Ready(None));
+                }
+            }
+        }
+    }
+}
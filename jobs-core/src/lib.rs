@@ -17,25 +17,45 @@
  * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::{fmt, time::Duration};
+
 use failure::{Error, Fail};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 
+mod cancellation;
+mod checkpoint;
+pub mod clock;
 mod job;
 mod job_info;
+mod job_stream;
 mod processor;
 mod processor_map;
+pub mod schedule;
 mod stats;
 mod storage;
+mod test_storage;
 
 pub use crate::{
-    job::Job,
-    job_info::{JobInfo, NewJobInfo, ReturnJobInfo},
-    processor::Processor,
-    processor_map::ProcessorMap,
-    stats::{JobStat, Stats},
-    storage::{memory_storage, Storage},
+    cancellation::CancellationToken,
+    checkpoint::Checkpoint,
+    job::{run_now, Job},
+    job_info::{
+        JobEventKind, JobInfo, NewJobInfo, NewJobInfoBuilder, NewJobInfoError, ReturnJobInfo,
+    },
+    job_stream::JobStream,
+    processor::{test_round_trip, ArgsTooLarge, Processor, ToJson},
+    processor_map::{DuplicateProcessor, ProcessorMap},
+    stats::{
+        AtomicStats, JobStat, ProcessorStat, QueueStat, Stats, StatsEvent, StatsSink, WaitStat,
+    },
+    storage::{memory_storage, DispatchOrder, EnqueueOutcome, MissingProcessorPolicy, Storage},
+    test_storage::test_storage,
 };
 
+#[cfg(feature = "jsonschema")]
+pub use crate::processor::InvalidArgs;
+
 #[derive(Debug, Fail)]
 /// The error type returned by a `Processor`'s `process` method
 pub enum JobError {
@@ -43,6 +63,14 @@ pub enum JobError {
     #[fail(display = "Error performing job: {}", _0)]
     Processing(#[cause] Error),
 
+    /// An error occurred while processing the job that retrying won't fix
+    #[fail(display = "Unrecoverable error performing job: {}", _0)]
+    Unrecoverable(#[cause] Error),
+
+    /// The job ran for longer than its `Job::timeout`
+    #[fail(display = "Job timed out after {:?}", _0)]
+    TimedOut(Duration),
+
     /// Creating a `Job` type from the provided `serde_json::Value` failed
     #[fail(display = "Could not make JSON value from arguments")]
     Json,
@@ -52,11 +80,289 @@ pub enum JobError {
     MissingProcessor,
 }
 
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "Job should be retried after {:?}", _0)]
+/// An error a job can return to choose when it is retried
+///
+/// When a job's `run` method fails with this error, its next retry is scheduled after the given
+/// duration instead of following the backoff strategy. This still counts as a failed attempt, so
+/// the job is not retried if it has no retries left. This is useful when the job knows exactly
+/// when to try again, like after an HTTP 429 response with a `Retry-After` header.
+///
+/// ```rust
+/// use std::{sync::Arc, time::Duration};
+///
+/// use background_jobs_core::{
+///     clock::{self, TimeDelta},
+///     memory_storage, Backoff, Job, Processor, ProcessorMap, RetryAfter, Storage,
+/// };
+/// use failure::Error;
+/// use futures::{future::IntoFuture, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct CallApi;
+///
+/// #[derive(Clone)]
+/// struct ApiProcessor;
+///
+/// impl Job for CallApi {
+///     type Processor = ApiProcessor;
+///     type State = ();
+///
+///     // The API answered with a 429 and `Retry-After: 30`
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(Err(RetryAfter(Duration::from_secs(30)).into()).into_future())
+///     }
+///
+///     fn backoff_strategy(&self) -> Option<Backoff> {
+///         Some(Backoff::Linear(5))
+///     }
+/// }
+///
+/// impl Processor for ApiProcessor {
+///     type Job = CallApi;
+///
+///     const NAME: &'static str = "ApiProcessor";
+///     const QUEUE: &'static str = "default";
+/// }
+///
+/// fn main() -> Result<(), Error> {
+///     let mut processors = ProcessorMap::new(Arc::new(|| ()));
+///     processors.register_processor(ApiProcessor);
+///
+///     let mut storage = memory_storage::Storage::new();
+///     let id = storage.new_job(ApiProcessor::new_job(CallApi)?)?;
+///     let job = storage.request_job("default", 1)?.unwrap();
+///
+///     let failed_at = clock::now();
+///     let returned = processors.process_job(job).wait().unwrap();
+///     storage.return_job(returned)?;
+///
+///     // The retry waits for 30 seconds, rather than the 5 of the backoff strategy
+///     let retry_at = storage.fetch_job(id)?.unwrap().scheduled_for().unwrap();
+///     assert!(retry_at - failed_at >= TimeDelta::seconds(29));
+///     assert!(retry_at - failed_at <= TimeDelta::seconds(31));
+///     Ok(())
+/// }
+/// ```
+pub struct RetryAfter(pub Duration);
+
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "Job should be retried with updated arguments")]
+/// An error a job can return to change its arguments before it is retried
+///
+/// When a job's `run` method fails with this error, the given arguments replace the stored ones,
+/// and the next attempt is deserialized from them. This is useful for jobs that can resume where
+/// they left off, for example by recording a cursor. The arguments are stored like a new job's
+/// arguments, so large values make every failed attempt more expensive.
+///
+/// ```rust
+/// use std::sync::{Arc, Mutex};
+///
+/// use background_jobs_core::{
+///     memory_storage, Backoff, Job, Processor, ProcessorMap, RetryWith, Storage,
+/// };
+/// use failure::Error;
+/// use futures::{future::IntoFuture, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct Import {
+///     cursor: u32,
+/// }
+///
+/// #[derive(Clone)]
+/// struct ImportProcessor;
+///
+/// impl Job for Import {
+///     type Processor = ImportProcessor;
+///     type State = Arc<Mutex<Vec<u32>>>;
+///
+///     // The first attempt gets through 10 rows before failing, and the retry resumes from there
+///     fn run(self, seen: Self::State) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         seen.lock().unwrap().push(self.cursor);
+///
+///         if self.cursor > 0 {
+///             return Box::new(Ok(()).into_future());
+///         }
+///
+///         let res = RetryWith::new(&Import { cursor: 10 }).and_then(|retry| Err(retry.into()));
+///         Box::new(res.into_future())
+///     }
+///
+///     fn backoff_strategy(&self) -> Option<Backoff> {
+///         Some(Backoff::Linear(0))
+///     }
+/// }
+///
+/// impl Processor for ImportProcessor {
+///     type Job = Import;
+///
+///     const NAME: &'static str = "ImportProcessor";
+///     const QUEUE: &'static str = "default";
+/// }
+///
+/// fn main() -> Result<(), Error> {
+///     let seen = Arc::new(Mutex::new(Vec::new()));
+///     let state = seen.clone();
+///
+///     let mut processors = ProcessorMap::new(Arc::new(move || state.clone()));
+///     processors.register_processor(ImportProcessor);
+///
+///     let mut storage = memory_storage::Storage::new();
+///     storage.new_job(ImportProcessor::new_job(Import { cursor: 0 })?)?;
+///
+///     for _ in 0..2 {
+///         let job = storage.request_job("default", 1)?.unwrap();
+///         let returned = processors.process_job(job).wait().unwrap();
+///         storage.return_job(returned)?;
+///     }
+///
+///     assert_eq!(*seen.lock().unwrap(), vec![0, 10]);
+///     Ok(())
+/// }
+/// ```
+pub struct RetryWith(pub Value);
+
+impl RetryWith {
+    /// Create a `RetryWith` from the job that should be run on the next attempt
+    pub fn new<J>(job: &J) -> Result<Self, Error>
+    where
+        J: Job,
+    {
+        Ok(RetryWith(serde_json::to_value(job)?))
+    }
+}
+
+/// An error from code that doesn't use `failure`, so it can be returned from a job
+///
+/// `Job::run` fails with a `failure::Error`. Errors implementing `std::error::Error` already
+/// convert into it, but boxed errors and `anyhow::Error` don't, so they can be wrapped in this type
+/// first. With the `anyhow` feature enabled, `anyhow::Error` converts into it as well. The
+/// wrapped error's message is kept, and is what gets logged when the job fails.
+///
+/// ```rust
+/// use background_jobs_core::StdError;
+/// use failure::Error;
+///
+/// fn lookup() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+///     Err("record not found".into())
+/// }
+///
+/// let error: Error = lookup().map_err(StdError::from).unwrap_err().into();
+/// assert_eq!(error.to_string(), "record not found");
+/// ```
+pub struct StdError(Box<dyn std::error::Error + Send + Sync>);
+
+impl StdError {
+    /// The wrapped error
+    pub fn get_ref(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        &*self.0
+    }
+
+    /// Take the wrapped error back out
+    pub fn into_inner(self) -> Box<dyn std::error::Error + Send + Sync> {
+        self.0
+    }
+}
+
+impl Fail for StdError {}
+
+impl fmt::Display for StdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for StdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl From<Box<dyn std::error::Error + Send + Sync>> for StdError {
+    fn from(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        StdError(error)
+    }
+}
+
+/// A job that fails with an `anyhow::Error` is recorded as a failure, and the error keeps its
+/// message
+///
+/// ```rust
+/// use std::sync::Arc;
+///
+/// use background_jobs_core::{
+///     memory_storage, Job, JobError, JobResult, Processor, ProcessorMap, StdError, Storage,
+/// };
+/// use failure::Error;
+/// use futures::{future::IntoFuture, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Deserialize, Serialize)]
+/// struct Lookup;
+///
+/// impl Job for Lookup {
+///     type Processor = LookupProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         let res: Result<(), anyhow::Error> = Err(anyhow::anyhow!("record not found"));
+///         Box::new(res.map_err(|e| StdError::from(e).into()).into_future())
+///     }
+/// }
+///
+/// #[derive(Clone)]
+/// struct LookupProcessor;
+///
+/// impl Processor for LookupProcessor {
+///     type Job = Lookup;
+///
+///     const NAME: &'static str = "LookupProcessor";
+///     const QUEUE: &'static str = "default";
+/// }
+///
+/// fn main() -> Result<(), Error> {
+///     let mut processors = ProcessorMap::new(Arc::new(|| ()));
+///     processors.register_processor(LookupProcessor);
+///
+///     let mut storage = memory_storage::Storage::new();
+///     storage.new_job(LookupProcessor::new_job(Lookup)?)?;
+///     storage.new_job(LookupProcessor::new_job(Lookup)?)?;
+///
+///     let job = storage.request_job("default", 1)?.unwrap();
+///     let returned = processors.process_job(job).wait().unwrap();
+///     assert_eq!(*returned.result(), JobResult::Failure);
+///
+///     let job = storage.request_job("default", 1)?.unwrap();
+///     match processors.process_job_output(job).wait() {
+///         Err(JobError::Processing(e)) => assert_eq!(e.to_string(), "record not found"),
+///         other => panic!("Unexpected output {:?}", other),
+///     }
+///     Ok(())
+/// }
+/// ```
+#[cfg(feature = "anyhow")]
+impl From<anyhow::Error> for StdError {
+    fn from(error: anyhow::Error) -> Self {
+        StdError(error.into())
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum JobResult {
     Success,
     Failure,
     MissingProcessor,
+
+    /// The job failed, and asked to be retried after the given duration
+    Retry {
+        after: Duration,
+    },
+
+    /// The job failed with an error it can't recover from, so it isn't retried
+    Unrecoverable,
 }
 
 impl JobResult {
@@ -72,6 +378,14 @@ impl JobResult {
         JobResult::MissingProcessor
     }
 
+    pub fn retry(after: Duration) -> Self {
+        JobResult::Retry { after }
+    }
+
+    pub fn unrecoverable() -> Self {
+        JobResult::Unrecoverable
+    }
+
     pub fn is_failure(&self) -> bool {
         *self == JobResult::Failure
     }
@@ -83,6 +397,17 @@ impl JobResult {
     pub fn is_missing_processor(&self) -> bool {
         *self == JobResult::MissingProcessor
     }
+
+    pub fn is_unrecoverable(&self) -> bool {
+        *self == JobResult::Unrecoverable
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            JobResult::Retry { after } => Some(after),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
@@ -120,6 +445,29 @@ pub enum Backoff {
 
     /// Base for seconds between execution
     Exponential(usize),
+
+    /// Seconds between execution, the same for every retry
+    ///
+    /// This suits APIs with a fixed rate-limit window between attempts.
+    Fixed(usize),
+
+    /// Base for seconds between execution, waiting at most `max_seconds`
+    ExponentialCapped { base: usize, max_seconds: usize },
+
+    /// Base for seconds between execution, moved randomly by up to `jitter_percent` percent
+    /// either way
+    ///
+    /// Jobs that failed together, for example because a service they depend on went down, are
+    /// then retried over a window instead of all at once. The jitter is capped at 100 percent.
+    ExponentialJitter { base: usize, jitter_percent: u8 },
+}
+
+impl Default for Backoff {
+    /// The backoff used when neither a job, its processor, nor the server chose one,
+    /// `Exponential(2)`
+    fn default() -> Self {
+        Backoff::Exponential(2)
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
@@ -131,18 +479,10 @@ pub enum MaxRetries {
     Count(usize),
 }
 
-impl MaxRetries {
-    fn compare(&self, retry_count: u32) -> ShouldStop {
-        match *self {
-            MaxRetries::Infinite => ShouldStop::Requeue,
-            MaxRetries::Count(ref count) => {
-                if (retry_count as usize) <= *count {
-                    ShouldStop::Requeue
-                } else {
-                    ShouldStop::LimitReached
-                }
-            }
-        }
+impl Default for MaxRetries {
+    /// The retries used when neither a job, its processor, nor the server chose them, `Count(5)`
+    fn default() -> Self {
+        MaxRetries::Count(5)
     }
 }
 
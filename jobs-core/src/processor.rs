@@ -17,7 +17,8 @@
  * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use chrono::{offset::Utc, DateTime};
+use std::time::Duration;
+
 use failure::{Error, Fail};
 use futures::{
     future::{Either, IntoFuture},
@@ -25,7 +26,9 @@ use futures::{
 };
 use serde_json::Value;
 
-use crate::{Backoff, Job, JobError, MaxRetries, NewJobInfo};
+use crate::{
+    clock::Timestamp, Backoff, CancellationToken, Checkpoint, Job, JobError, MaxRetries, NewJobInfo,
+};
 
 /// ## The Processor trait
 ///
@@ -55,7 +58,10 @@ use crate::{Backoff, Job, JobError, MaxRetries, NewJobInfo};
 ///     count: i32,
 /// }
 ///
-/// impl Job<()> for MyJob {
+/// impl Job for MyJob {
+///     type Processor = MyProcessor;
+///     type State = ();
+///
 ///     fn run(self, _state: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
 ///         info!("Processing {}", self.count);
 ///
@@ -66,13 +72,13 @@ use crate::{Backoff, Job, JobError, MaxRetries, NewJobInfo};
 /// #[derive(Clone)]
 /// struct MyProcessor;
 ///
-/// impl Processor<()> for MyProcessor {
+/// impl Processor for MyProcessor {
 ///     type Job = MyJob;
 ///
 ///     const NAME: &'static str = "IncrementProcessor";
 ///     const QUEUE: &'static str = "default";
-///     const MAX_RETRIES: MaxRetries = MaxRetries::Count(1);
-///     const BACKOFF_STRATEGY: Backoff = Backoff::Exponential(2);
+///     const MAX_RETRIES: Option<MaxRetries> = Some(MaxRetries::Count(1));
+///     const BACKOFF_STRATEGY: Option<Backoff> = Some(Backoff::Exponential(2));
 /// }
 ///
 /// fn main() -> Result<(), Error> {
@@ -97,13 +103,53 @@ pub trait Processor: Clone {
 
     /// Define the default number of retries for a given processor
     ///
-    /// Jobs can override
-    const MAX_RETRIES: MaxRetries;
+    /// Jobs can override this by implementing `Job::max_retries`. When this is `None`, the
+    /// server's default from `ServerConfig::default_max_retries` is used.
+    const MAX_RETRIES: Option<MaxRetries> = None;
 
     /// Define the default backoff strategy for a given processor
     ///
-    /// Jobs can override
-    const BACKOFF_STRATEGY: Backoff;
+    /// Jobs can override this by implementing `Job::backoff_strategy`. When this is `None`, the
+    /// server's default from `ServerConfig::default_backoff` is used.
+    const BACKOFF_STRATEGY: Option<Backoff> = None;
+
+    /// Define the default priority for jobs created with this processor
+    ///
+    /// When several jobs in a queue are ready, jobs with a higher priority are dispatched first.
+    /// Jobs can override this by implementing `Job::priority`.
+    const PRIORITY: i32 = 0;
+
+    /// Define how long jobs created with this processor are quarantined once they're out of
+    /// retries
+    ///
+    /// Instead of becoming dead as soon as its last retry fails, a quarantined job is replayed
+    /// once more after this delay, which is meant to be long enough for an outage to be fixed,
+    /// like a few hours. It only becomes dead if that replay fails as well.
+    ///
+    /// By default, jobs aren't quarantined.
+    const QUARANTINE: Option<Duration> = None;
+
+    /// Define the largest serialized size, in bytes, allowed for the arguments of a job
+    ///
+    /// Creating a job whose arguments are larger than this fails with an `ArgsTooLarge` error.
+    /// Large payloads bloat the job store and slow down dispatch, so jobs should carry references
+    /// to large data rather than the data itself. Setting this to `None` disables the check.
+    ///
+    /// By default, arguments may be up to 1 MiB.
+    const MAX_ARGS_BYTES: Option<usize> = Some(1024 * 1024);
+
+    /// Define a JSON Schema the arguments of this processor's jobs must match
+    ///
+    /// This is meant for jobs whose arguments also come from producers that aren't written in
+    /// Rust, and so can't rely on the `Job` type to get them right. Creating a job whose arguments
+    /// don't match fails with an `InvalidArgs` error, and `ProcessorMap::validate_args` checks
+    /// jobs built by other means, such as from a request to an HTTP API, before they are stored.
+    ///
+    /// By default, there is no schema.
+    #[cfg(feature = "jsonschema")]
+    fn args_schema() -> Option<Value> {
+        None
+    }
 
     /// A provided method to create a new JobInfo from provided arguments
     ///
@@ -111,22 +157,77 @@ pub trait Processor: Clone {
     /// the Processor that should handle it.
     fn new_job(job: Self::Job) -> Result<NewJobInfo, Error> {
         let queue = job.queue().unwrap_or(Self::QUEUE).to_owned();
-        let max_retries = job.max_retries().unwrap_or(Self::MAX_RETRIES);
-        let backoff_strategy = job.backoff_strategy().unwrap_or(Self::BACKOFF_STRATEGY);
+        let max_retries = job.max_retries().or(Self::MAX_RETRIES);
+        let backoff_strategy = job.backoff_strategy().or(Self::BACKOFF_STRATEGY);
+        let priority = job.priority().unwrap_or(Self::PRIORITY);
+        let retry_queue = job.retry_queue().map(|queue| queue.to_owned());
+        let unique_key = job.unique_key();
+        let global_key = job.global_key();
+        let idempotency_key = job.idempotency_key();
+        let affinity_key = job.affinity_key();
+        let timeout = job.timeout();
+        let weight = job.weight();
+
+        let args = serde_json::to_value(&job).map_err(|e| ToJson(e.to_string()))?;
+        let redacted_args = job.redacted_args();
+
+        if let Some(max) = Self::MAX_ARGS_BYTES {
+            let size = serde_json::to_vec(&args)
+                .map_err(|e| ToJson(e.to_string()))?
+                .len();
 
-        let job = NewJobInfo::new(
+            if size > max {
+                return Err(ArgsTooLarge { size, max }.into());
+            }
+        }
+
+        #[cfg(feature = "jsonschema")]
+        {
+            if let Some(schema) = Self::args_schema() {
+                validate_args(Self::NAME, &schema, &args)?;
+            }
+        }
+
+        let mut job = NewJobInfo::new(
             Self::NAME.to_owned(),
             queue,
-            serde_json::to_value(job).map_err(|_| ToJson)?,
+            args,
             max_retries,
             backoff_strategy,
         );
 
+        job.set_priority(priority);
+        job.set_weight(weight);
+        if let Some(args) = redacted_args {
+            job.set_redacted_args(args);
+        }
+        if let Some(queue) = retry_queue {
+            job.set_retry_queue(queue);
+        }
+        if let Some(key) = unique_key {
+            job.set_unique_key(key);
+        }
+        if let Some(key) = global_key {
+            job.set_global_key(key);
+        }
+        if let Some(key) = idempotency_key {
+            job.set_idempotency_key(key);
+        }
+        if let Some(key) = affinity_key {
+            job.set_affinity_key(key);
+        }
+        if let Some(timeout) = timeout {
+            job.set_timeout(timeout);
+        }
+        if let Some(delay) = Self::QUARANTINE {
+            job.set_quarantine(delay);
+        }
+
         Ok(job)
     }
 
     /// Create a JobInfo to schedule a job to be performed after a certain time
-    fn new_scheduled_job(job: Self::Job, after: DateTime<Utc>) -> Result<NewJobInfo, Error> {
+    fn new_scheduled_job(job: Self::Job, after: Timestamp) -> Result<NewJobInfo, Error> {
         let mut job = Self::new_job(job)?;
         job.schedule(after);
 
@@ -140,20 +241,26 @@ pub trait Processor: Clone {
     ///
     /// The state passed into this method is initialized at the start of the application. The state
     /// argument could be useful for containing a hook into something like r2d2, or the address of
-    /// an actor in an actix-based system.
+    /// an actor in an actix-based system. The token is cancelled when the runtime asks the job to
+    /// stop early, and should be passed on to `Job::run_resumable`, along with the checkpoint.
     ///
     /// ```rust,ignore
     /// fn process(
     ///     &self,
     ///     args: Value,
-    ///     state: S
+    ///     state: S,
+    ///     token: CancellationToken,
+    ///     checkpoint: Checkpoint,
     /// ) -> Box<dyn Future<Item = (), Error = JobError> + Send> {
     ///     let res = serde_json::from_value::<Self::Job>(args);
     ///
     ///     let fut = match res {
     ///         Ok(job) => {
     ///             // Perform some custom pre-job logic
-    ///             Either::A(job.run(state).map_err(JobError::Processing))
+    ///             Either::A(
+    ///                 job.run_resumable(state, token, checkpoint)
+    ///                     .map_err(JobError::Processing),
+    ///             )
     ///         },
     ///         Err(_) => Either::B(Err(JobError::Json).into_future()),
     ///     };
@@ -173,11 +280,19 @@ pub trait Processor: Clone {
         &self,
         args: Value,
         state: <Self::Job as Job>::State,
+        token: CancellationToken,
+        checkpoint: Checkpoint,
     ) -> Box<dyn Future<Item = (), Error = JobError> + Send> {
         let res = serde_json::from_value::<Self::Job>(args);
 
         let fut = match res {
-            Ok(job) => Either::A(job.run(state).map_err(JobError::Processing)),
+            Ok(job) => Either::A(job.run_resumable(state, token, checkpoint).map_err(|e| {
+                if Self::Job::is_recoverable(&e) {
+                    JobError::Processing(e)
+                } else {
+                    JobError::Unrecoverable(e)
+                }
+            })),
             Err(_) => Either::B(Err(JobError::Json).into_future()),
         };
 
@@ -185,6 +300,217 @@ pub trait Processor: Clone {
     }
 }
 
+/// Check that a job's arguments can be read back after `Processor::new_job` stores them
+///
+/// This is meant to be called from the tests of an application's jobs. Arguments that serialize
+/// but don't deserialize, for example because of a field marked `skip_deserializing` or a custom
+/// serde impl, are stored without complaint, and only fail once a worker runs the job.
+///
+/// # Panics
+/// This panics if the job can't be serialized, or if its serialized arguments can't be turned
+/// back into a job.
+///
+/// ```rust
+/// use background_jobs_core::{test_round_trip, Job, Processor};
+/// use failure::Error;
+/// use futures::{future::IntoFuture, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Deserialize, Serialize)]
+/// struct SendEmail {
+///     to: String,
+///     #[serde(skip_serializing)]
+///     body: String,
+/// }
+///
+/// impl Job for SendEmail {
+///     type Processor = EmailProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(Ok(()).into_future())
+///     }
+/// }
+///
+/// #[derive(Clone)]
+/// struct EmailProcessor;
+///
+/// impl Processor for EmailProcessor {
+///     type Job = SendEmail;
+///
+///     const NAME: &'static str = "EmailProcessor";
+///     const QUEUE: &'static str = "default";
+/// }
+///
+/// let res = std::panic::catch_unwind(|| {
+///     test_round_trip::<EmailProcessor>(SendEmail {
+///         to: "user@example.com".to_owned(),
+///         body: "Hello".to_owned(),
+///     })
+/// });
+/// assert!(res.is_err(), "The body is never stored, so the job can't be read back");
+/// ```
+pub fn test_round_trip<P>(job: P::Job)
+where
+    P: Processor,
+{
+    let new_job = P::new_job(job)
+        .unwrap_or_else(|e| panic!("Failed to create a job for processor {}, {}", P::NAME, e));
+
+    if let Err(e) = serde_json::from_value::<P::Job>(new_job.args().clone()) {
+        panic!(
+            "Arguments for processor {} cannot be deserialized after serializing, {}",
+            P::NAME,
+            e
+        );
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "Failed to turn job into value, {}", _0)]
+/// The error returned when a job could not be serialized
+///
+/// This contains the message from the underlying serialization error. A common cause is a map
+/// keyed by a type that cannot be written as a JSON string, such as a struct or a tuple.
+///
+/// ```rust
+/// use std::collections::HashMap;
+///
+/// use background_jobs_core::{Job, Processor, ToJson};
+/// use failure::Error;
+/// use futures::{future::IntoFuture, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct PaintTiles {
+///     // JSON object keys are strings, and a tuple can't be written as one
+///     colors: HashMap<(i32, i32), String>,
+/// }
+///
+/// #[derive(Clone)]
+/// struct PaintProcessor;
+///
+/// impl Job for PaintTiles {
+///     type Processor = PaintProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(Ok(()).into_future())
+///     }
+/// }
+///
+/// impl Processor for PaintProcessor {
+///     type Job = PaintTiles;
+///
+///     const NAME: &'static str = "PaintProcessor";
+///     const QUEUE: &'static str = "default";
+/// }
+///
+/// let mut colors = HashMap::new();
+/// colors.insert((0, 0), "red".to_owned());
+///
+/// let e = PaintProcessor::new_job(PaintTiles { colors }).unwrap_err();
+/// assert!(e.downcast_ref::<ToJson>().is_some());
+/// assert!(e.to_string().contains("key must be a string"), "{}", e);
+/// ```
+pub struct ToJson(pub String);
+
+#[derive(Clone, Debug, Fail)]
+#[fail(
+    display = "Job arguments are {} bytes, larger than the maximum of {} bytes",
+    size, max
+)]
+/// The error returned when a job's serialized arguments exceed `Processor::MAX_ARGS_BYTES`
+///
+/// ```rust
+/// use background_jobs_core::{ArgsTooLarge, Job, Processor};
+/// use failure::Error;
+/// use futures::{future::IntoFuture, Future};
+/// use serde_derive::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Deserialize, Serialize)]
+/// struct Upload {
+///     body: String,
+/// }
+///
+/// #[derive(Clone)]
+/// struct UploadProcessor;
+///
+/// impl Job for Upload {
+///     type Processor = UploadProcessor;
+///     type State = ();
+///
+///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+///         Box::new(Ok(()).into_future())
+///     }
+/// }
+///
+/// impl Processor for UploadProcessor {
+///     type Job = Upload;
+///
+///     const NAME: &'static str = "UploadProcessor";
+///     const QUEUE: &'static str = "default";
+///     const MAX_ARGS_BYTES: Option<usize> = Some(100);
+/// }
+///
+/// let small = Upload {
+///     body: "a reference to the file".to_owned(),
+/// };
+/// assert!(UploadProcessor::new_job(small).is_ok());
+///
+/// // The arguments are written as {"body":"..."}, 211 bytes in all
+/// let large = Upload {
+///     body: "x".repeat(200),
+/// };
+/// let e = UploadProcessor::new_job(large).unwrap_err();
+/// assert_eq!(
+///     e.to_string(),
+///     "Job arguments are 211 bytes, larger than the maximum of 100 bytes"
+/// );
+///
+/// let too_large = e.downcast_ref::<ArgsTooLarge>().unwrap();
+/// assert_eq!((too_large.size, too_large.max), (211, 100));
+/// ```
+pub struct ArgsTooLarge {
+    /// The serialized size of the arguments
+    pub size: usize,
+
+    /// The maximum allowed size
+    pub max: usize,
+}
+
+#[cfg(feature = "jsonschema")]
 #[derive(Clone, Debug, Fail)]
-#[fail(display = "Failed to to turn job into value")]
-pub struct ToJson;
+#[fail(display = "Invalid arguments for processor {}, {}", processor, reason)]
+/// The error returned when a job's arguments don't match its processor's
+/// `Processor::args_schema`
+pub struct InvalidArgs {
+    /// The name of the job's processor
+    pub processor: String,
+
+    /// What is wrong with the arguments, or with the schema itself
+    pub reason: String,
+}
+
+/// Check the arguments of a job for the given processor against a JSON Schema
+#[cfg(feature = "jsonschema")]
+pub(crate) fn validate_args(
+    processor: &str,
+    schema: &Value,
+    args: &Value,
+) -> Result<(), InvalidArgs> {
+    let invalid = |reason| InvalidArgs {
+        processor: processor.to_owned(),
+        reason,
+    };
+
+    let validator =
+        jsonschema::validator_for(schema).map_err(|e| invalid(format!("bad schema, {}", e)))?;
+
+    validator.validate(args).map_err(|e| {
+        invalid(match e.instance_path.to_string() {
+            ref path if path.is_empty() => e.to_string(),
+            path => format!("{} at {}", e, path),
+        })
+    })
+}
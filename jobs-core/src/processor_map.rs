@@ -19,11 +19,21 @@
 
 use std::{collections::HashMap, sync::Arc};
 
+use failure::{format_err, Fail};
 use futures::future::{Either, Future, IntoFuture};
-use log::{error, info};
+use log::{error, info, warn};
 use serde_json::Value;
+use tokio_timer::Timeout;
 
-use crate::{Job, JobError, JobInfo, Processor, ReturnJobInfo};
+#[cfg(feature = "jsonschema")]
+use crate::{
+    processor::{validate_args, InvalidArgs},
+    NewJobInfo,
+};
+use crate::{
+    CancellationToken, Checkpoint, Job, JobError, JobInfo, Processor, RetryAfter, RetryWith,
+    ReturnJobInfo,
+};
 
 /// A generic function that processes a job
 ///
@@ -32,11 +42,25 @@ use crate::{Job, JobError, JobInfo, Processor, ReturnJobInfo};
 /// directly, the
 /// [`ProcessorMap`](https://docs.rs/background-jobs-core/0.4.0/background_jobs_core/struct.ProcessorMap.html)
 /// struct stores these `ProcessFn` types that don't expose differences in Job types.
-pub type ProcessFn<S> =
-    Arc<dyn Fn(Value, S) -> Box<dyn Future<Item = (), Error = JobError> + Send> + Send + Sync>;
+pub type ProcessFn<S> = Arc<
+    dyn Fn(
+            Value,
+            S,
+            CancellationToken,
+            Checkpoint,
+        ) -> Box<dyn Future<Item = (), Error = JobError> + Send>
+        + Send
+        + Sync,
+>;
 
 pub type StateFn<S> = Arc<dyn Fn() -> S + Send + Sync>;
 
+/// A generic function that notifies a job that it is about to be retried
+///
+/// Besides the number of the upcoming retry, it is given the number of retries left after it when
+/// that is within the warning threshold of the `ProcessorMap`.
+pub type RetryFn<S> = Arc<dyn Fn(Value, u32, Option<u64>, &S) + Send + Sync>;
+
 /// A type for storing the relationships between processor names and the processor itself
 ///
 /// [`Processor`s](https://docs.rs/background-jobs/0.4.0/background_jobs/trait.Processor.html) must
@@ -48,7 +72,11 @@ where
     S: Clone,
 {
     inner: HashMap<String, ProcessFn<S>>,
+    retry_fns: HashMap<String, RetryFn<S>>,
     state_fn: StateFn<S>,
+    exhaustion_warning: Option<u64>,
+    #[cfg(feature = "jsonschema")]
+    schemas: HashMap<String, Value>,
 }
 
 impl<S> ProcessorMap<S>
@@ -63,36 +91,373 @@ where
     pub fn new(state_fn: StateFn<S>) -> Self {
         ProcessorMap {
             inner: HashMap::new(),
+            retry_fns: HashMap::new(),
             state_fn,
+            exhaustion_warning: None,
+            #[cfg(feature = "jsonschema")]
+            schemas: HashMap::new(),
         }
     }
 
+    /// Call `Job::on_nearing_exhaustion` for retries that leave the job with this many retries or
+    /// fewer
+    ///
+    /// This gives some warning before a job fails for the last time. Jobs that are retried forever
+    /// never trigger the warning. By default, no warning is given.
+    pub fn warn_when_retries_left(&mut self, retries: u64) {
+        self.exhaustion_warning = Some(retries);
+    }
+
     /// Register a
     /// [`Processor`](https://docs.rs/background-jobs/0.4.0/background_jobs/trait.Processor.html) with
     /// this `ProcessorMap`.
     ///
     /// `ProcessorMap`s are useless if no processors are registerd before workers are spawned, so
     /// make sure to register all your processors up-front.
+    ///
+    /// If a processor with the same `NAME` has already been registered, it is replaced and a
+    /// warning is logged. Use `try_register_processor` to treat this as an error instead.
     pub fn register_processor<P, J>(&mut self, processor: P)
     where
         P: Processor<Job = J> + Sync + Send + 'static,
         J: Job<State = S>,
     {
+        if self.inner.contains_key(P::NAME) {
+            warn!(
+                "Processor {} was registered more than once, replacing the existing processor",
+                P::NAME
+            );
+        }
+
+        self.insert_processor(processor);
+    }
+
+    /// Register a processor, failing if one with the same `NAME` has already been registered
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    ///
+    /// use background_jobs_core::{Job, Processor, ProcessorMap};
+    /// use failure::Error;
+    /// use futures::Future;
+    /// use serde_derive::{Deserialize, Serialize};
+    ///
+    /// #[derive(Deserialize, Serialize)]
+    /// struct SendEmail;
+    ///
+    /// #[derive(Deserialize, Serialize)]
+    /// struct SendSms;
+    ///
+    /// impl Job for SendEmail {
+    ///     type Processor = EmailProcessor;
+    ///     type State = ();
+    ///
+    ///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         Box::new(futures::future::ok(()))
+    ///     }
+    /// }
+    ///
+    /// impl Job for SendSms {
+    ///     type Processor = SmsProcessor;
+    ///     type State = ();
+    ///
+    ///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         Box::new(futures::future::ok(()))
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone)]
+    /// struct EmailProcessor;
+    ///
+    /// #[derive(Clone)]
+    /// struct SmsProcessor;
+    ///
+    /// impl Processor for EmailProcessor {
+    ///     type Job = SendEmail;
+    ///
+    ///     const NAME: &'static str = "NotificationProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// // Copied from the processor above, without changing its name
+    /// impl Processor for SmsProcessor {
+    ///     type Job = SendSms;
+    ///
+    ///     const NAME: &'static str = "NotificationProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// let mut processors = ProcessorMap::new(Arc::new(|| ()));
+    /// assert!(processors.try_register_processor(EmailProcessor).is_ok());
+    ///
+    /// let e = processors.try_register_processor(SmsProcessor).unwrap_err();
+    /// assert_eq!(e.0, "NotificationProcessor");
+    /// assert_eq!(e.to_string(), "Processor NotificationProcessor is already registered");
+    /// ```
+    pub fn try_register_processor<P, J>(&mut self, processor: P) -> Result<(), DuplicateProcessor>
+    where
+        P: Processor<Job = J> + Sync + Send + 'static,
+        J: Job<State = S>,
+    {
+        if self.inner.contains_key(P::NAME) {
+            return Err(DuplicateProcessor(P::NAME.to_owned()));
+        }
+
+        self.insert_processor(processor);
+        Ok(())
+    }
+
+    fn insert_processor<P, J>(&mut self, processor: P)
+    where
+        P: Processor<Job = J> + Sync + Send + 'static,
+        J: Job<State = S>,
+    {
+        #[cfg(feature = "jsonschema")]
+        {
+            match P::args_schema() {
+                Some(schema) => self.schemas.insert(P::NAME.to_owned(), schema),
+                None => self.schemas.remove(P::NAME),
+            };
+        }
+
         self.inner.insert(
             P::NAME.to_owned(),
-            Arc::new(move |value, state| processor.process(value, state)),
+            Arc::new(move |value, state, token, checkpoint| {
+                processor.process(value, state, token, checkpoint)
+            }),
+        );
+        self.retry_fns.insert(
+            P::NAME.to_owned(),
+            Arc::new(|value, attempt, retries_left, state| {
+                if let Ok(job) = serde_json::from_value::<J>(value) {
+                    job.on_retry(attempt, state);
+
+                    if let Some(retries_left) = retries_left {
+                        job.on_nearing_exhaustion(attempt, retries_left, state);
+                    }
+                }
+            }),
         );
     }
 
+    /// Check a job's arguments against the `Processor::args_schema` of its processor
+    ///
+    /// This is meant for enqueue paths that build jobs without a `Job` value, such as from a
+    /// request to an HTTP API, so malformed jobs are rejected before they are stored rather than
+    /// failing once they run. Jobs whose processor has no schema pass, and jobs whose processor
+    /// isn't registered fail.
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    ///
+    /// use background_jobs_core::{Job, NewJobInfo, Processor, ProcessorMap};
+    /// use failure::Error;
+    /// use futures::Future;
+    /// use serde_derive::{Deserialize, Serialize};
+    /// use serde_json::{json, Value};
+    ///
+    /// #[derive(Deserialize, Serialize)]
+    /// struct Reindex {
+    ///     index: String,
+    /// }
+    ///
+    /// impl Job for Reindex {
+    ///     type Processor = ReindexProcessor;
+    ///     type State = ();
+    ///
+    ///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         Box::new(futures::future::ok(()))
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone)]
+    /// struct ReindexProcessor;
+    ///
+    /// impl Processor for ReindexProcessor {
+    ///     type Job = Reindex;
+    ///
+    ///     const NAME: &'static str = "ReindexProcessor";
+    ///     const QUEUE: &'static str = "default";
+    ///
+    ///     fn args_schema() -> Option<Value> {
+    ///         Some(json!({
+    ///             "type": "object",
+    ///             "properties": { "index": { "type": "string" } },
+    ///             "required": ["index"],
+    ///         }))
+    ///     }
+    /// }
+    ///
+    /// let mut processors = ProcessorMap::new(Arc::new(|| ()));
+    /// processors.register_processor(ReindexProcessor);
+    ///
+    /// let valid = NewJobInfo::builder("ReindexProcessor", "default")
+    ///     .args(json!({ "index": "users" }))
+    ///     .build()
+    ///     .unwrap();
+    /// assert!(processors.validate_args(&valid).is_ok());
+    ///
+    /// let invalid = NewJobInfo::builder("ReindexProcessor", "default")
+    ///     .args(json!({ "index": 7 }))
+    ///     .build()
+    ///     .unwrap();
+    /// assert!(processors.validate_args(&invalid).is_err());
+    /// ```
+    #[cfg(feature = "jsonschema")]
+    pub fn validate_args(&self, job: &NewJobInfo) -> Result<(), InvalidArgs> {
+        if !self.inner.contains_key(job.processor()) {
+            return Err(InvalidArgs {
+                processor: job.processor().to_owned(),
+                reason: "processor is not registered".to_owned(),
+            });
+        }
+
+        match self.schemas.get(job.processor()) {
+            Some(schema) => validate_args(job.processor(), schema, job.args()),
+            None => Ok(()),
+        }
+    }
+
+    /// Run a given job right away, outside of any worker
+    ///
+    /// This behaves exactly like `process_job`, and is intended for developing new processors. For
+    /// running a `Job` directly without creating a `JobInfo`, see `run_now`.
+    pub fn run_now(&self, job: JobInfo) -> impl Future<Item = ReturnJobInfo, Error = ()> {
+        self.process_job(job)
+    }
+
+    /// Run a given job, returning the outcome of its `run` method as is
+    ///
+    /// Unlike `process_job`, which turns the outcome into a `ReturnJobInfo` for the storage, this
+    /// gives callers driving the core directly the job's own error, such as a `RetryWith` to
+    /// inspect. Jobs don't produce a value beyond that, so a successful run yields `()`. No
+    /// retry hooks are called, and a job whose processor isn't registered fails with
+    /// `JobError::MissingProcessor`.
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    ///
+    /// use background_jobs_core::{
+    ///     memory_storage, JobError, Job, NewJobInfo, Processor, ProcessorMap, RetryWith, Storage,
+    /// };
+    /// use failure::Error;
+    /// use futures::{future::IntoFuture, Future};
+    /// use serde_derive::{Deserialize, Serialize};
+    /// use serde_json::json;
+    ///
+    /// #[derive(Deserialize, Serialize)]
+    /// struct Resize {
+    ///     width: u32,
+    /// }
+    ///
+    /// impl Job for Resize {
+    ///     type Processor = ResizeProcessor;
+    ///     type State = ();
+    ///
+    ///     // Images that are too wide are retried at a width that fits
+    ///     fn run(self, _: ()) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    ///         if self.width <= 1000 {
+    ///             return Box::new(Ok(()).into_future());
+    ///         }
+    ///
+    ///         let res = RetryWith::new(&Resize { width: 1000 }).and_then(|retry| Err(retry.into()));
+    ///         Box::new(res.into_future())
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone)]
+    /// struct ResizeProcessor;
+    ///
+    /// impl Processor for ResizeProcessor {
+    ///     type Job = Resize;
+    ///
+    ///     const NAME: &'static str = "ResizeProcessor";
+    ///     const QUEUE: &'static str = "default";
+    /// }
+    ///
+    /// fn main() -> Result<(), Error> {
+    ///     let mut processors = ProcessorMap::new(Arc::new(|| ()));
+    ///     processors.register_processor(ResizeProcessor);
+    ///
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     storage.new_job(ResizeProcessor::new_job(Resize { width: 800 })?)?;
+    ///     storage.new_job(ResizeProcessor::new_job(Resize { width: 4000 })?)?;
+    ///     storage.new_job(
+    ///         NewJobInfo::builder("CropProcessor", "default")
+    ///             .args(json!([]))
+    ///             .build()?,
+    ///     )?;
+    ///
+    ///     let mut outputs = Vec::new();
+    ///     while let Some(job) = storage.request_job("default", 1)? {
+    ///         outputs.push(processors.process_job_output(job).wait());
+    ///     }
+    ///
+    ///     assert!(outputs[0].is_ok());
+    ///     match outputs[1] {
+    ///         Err(JobError::Processing(ref e)) => {
+    ///             let retry = e.downcast_ref::<RetryWith>().unwrap();
+    ///             assert_eq!(retry.0, json!({ "width": 1000 }));
+    ///         }
+    ///         ref other => panic!("Unexpected output {:?}", other),
+    ///     }
+    ///     assert!(matches!(outputs[2], Err(JobError::MissingProcessor)));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn process_job_output(
+        &self,
+        job: JobInfo,
+    ) -> Box<dyn Future<Item = (), Error = JobError> + Send> {
+        match self.inner.get(job.processor()) {
+            Some(process_fn) => process_fn(
+                job.args(),
+                (self.state_fn)(),
+                CancellationToken::new(),
+                Checkpoint::new(job.checkpoint().cloned()),
+            ),
+            None => Box::new(Err(JobError::MissingProcessor).into_future()),
+        }
+    }
+
     /// Process a given job
     ///
     /// This should not be called from outside implementations of a backgoround-jobs runtime. It is
     /// intended for internal use.
     pub fn process_job(&self, job: JobInfo) -> impl Future<Item = ReturnJobInfo, Error = ()> {
-        let opt = self
-            .inner
-            .get(job.processor())
-            .map(|processor| process(processor, (self.state_fn)(), job.clone()));
+        self.process_job_cancellable(job, CancellationToken::new())
+    }
+
+    /// Process a given job, allowing it to be asked to stop early through the given token
+    ///
+    /// Like `process_job`, this is intended for implementations of a runtime.
+    pub fn process_job_cancellable(
+        &self,
+        job: JobInfo,
+        token: CancellationToken,
+    ) -> impl Future<Item = ReturnJobInfo, Error = ()> {
+        let checkpoint = Checkpoint::new(job.checkpoint().cloned());
+
+        self.process_job_resumable(job, token, checkpoint)
+    }
+
+    /// Process a given job, giving it the provided handle to save its progress with
+    ///
+    /// The handle should hold the job's last checkpoint. Runtimes that persist progress while the
+    /// job runs can pass a handle with `Checkpoint::on_save` set, and like `process_job`, this is
+    /// intended for implementations of a runtime.
+    pub fn process_job_resumable(
+        &self,
+        job: JobInfo,
+        token: CancellationToken,
+        checkpoint: Checkpoint,
+    ) -> impl Future<Item = ReturnJobInfo, Error = ()> {
+        let retry_fn = self.retry_fns.get(job.processor()).cloned();
+        let opt = self.inner.get(job.processor()).map(|processor| {
+            let state = (self.state_fn)();
+            let retry = retry_fn.map(|retry_fn| (retry_fn, self.exhaustion_warning));
+            process(processor, retry, state, job.clone(), token, checkpoint)
+        });
 
         if let Some(fut) = opt {
             Either::A(fut)
@@ -103,23 +468,92 @@ where
     }
 }
 
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "Processor {} is already registered", _0)]
+/// The error returned when two processors are registered with the same name
+pub struct DuplicateProcessor(pub String);
+
 fn process<S>(
     process_fn: &ProcessFn<S>,
+    retry: Option<(RetryFn<S>, Option<u64>)>,
     state: S,
     job: JobInfo,
-) -> impl Future<Item = ReturnJobInfo, Error = ()> {
+    token: CancellationToken,
+    checkpoint: Checkpoint,
+) -> impl Future<Item = ReturnJobInfo, Error = ()>
+where
+    S: Clone,
+{
     let args = job.args();
     let id = job.id();
-    let processor = job.processor().to_owned();
+    let retry_state = state.clone();
+
+    let fut = process_fn(args, state, token.clone(), checkpoint.clone());
+    let fut = match job.timeout() {
+        Some(limit) => Either::A(Timeout::new(fut, limit).map_err(move |e| {
+            if e.is_elapsed() {
+                JobError::TimedOut(limit)
+            } else if e.is_inner() {
+                e.into_inner()
+                    .expect("Checked that the error is from the job")
+            } else {
+                JobError::Processing(format_err!("Failed to time the job, {}", e))
+            }
+        })),
+        None => Either::B(fut),
+    };
 
-    process_fn(args, state).then(move |res| match res {
+    fut.then(move |res| match res {
         Ok(_) => {
-            info!("Job {} completed, {}", id, processor);
+            info!(
+                "Job {} completed, {}{}",
+                id,
+                job.processor(),
+                job.trace_suffix()
+            );
             Ok(ReturnJobInfo::pass(id))
         }
         Err(e) => {
-            error!("Job {} errored, {}, {}", id, processor, e);
-            Ok(ReturnJobInfo::fail(id))
+            error!(
+                "Job {} errored, {}, {}{}",
+                id,
+                job.processor(),
+                e,
+                job.trace_suffix()
+            );
+
+            // The job's future was dropped, but anything it started should stop as well
+            if let JobError::TimedOut(_) = e {
+                token.cancel();
+            }
+
+            if let JobError::Unrecoverable(_) = e {
+                return Ok(ReturnJobInfo::unrecoverable(id).with_checkpoint(checkpoint.saved()));
+            }
+
+            if let (Some((retry_fn, warning)), Some(attempt)) = (retry, job.next_retry()) {
+                let retries_left = job
+                    .retries_left_after(attempt)
+                    .filter(|left| warning.is_some_and(|warning| *left <= warning));
+
+                retry_fn(job.args(), attempt, retries_left, &retry_state);
+            }
+
+            if let JobError::Processing(ref e) = e {
+                if let Some(RetryAfter(after)) = e.downcast_ref() {
+                    return Ok(
+                        ReturnJobInfo::retry_after(id, *after).with_checkpoint(checkpoint.saved())
+                    );
+                }
+
+                if let Some(RetryWith(args)) = e.downcast_ref() {
+                    return Ok(ReturnJobInfo::fail(id)
+                        .with_args(args.clone())
+                        .with_checkpoint(checkpoint.saved()));
+                }
+            }
+
+            Ok(ReturnJobInfo::fail(id).with_checkpoint(checkpoint.saved()))
         }
     })
 }
@@ -0,0 +1,286 @@
+/*
+ * This file is part of Background Jobs.
+ *
+ * Copyright © 2019 Riley Trautman
+ *
+ * Background Jobs is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Background Jobs is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The retry and scheduling rules followed by every job
+//!
+//! These are plain functions of their inputs and the `clock` types, with no storage or runtime
+//! involved, so they can be reused by custom runtimes or checked on their own. `JobInfo` uses them
+//! to decide when a failed job is retried and when a scheduled job becomes ready.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::{
+    clock::{self, TimeDelta, Timestamp},
+    Backoff, MaxRetries, ShouldStop,
+};
+
+/// The number of seconds to wait before the given retry
+///
+/// `retry_count` is the number of failed attempts so far, including the one being retried. For
+/// `ExponentialJitter`, this is the delay before jitter is applied.
+/// Exponential backoff stops growing at `u64::MAX` seconds instead of overflowing, or at its cap
+/// for `ExponentialCapped`.
+///
+/// ```rust
+/// use background_jobs_core::{schedule::backoff_secs, Backoff};
+///
+/// assert_eq!(backoff_secs(&Backoff::Linear(5), 1), 5);
+/// assert_eq!(backoff_secs(&Backoff::Linear(5), 4), 5);
+///
+/// assert_eq!(backoff_secs(&Backoff::Exponential(2), 1), 2);
+/// assert_eq!(backoff_secs(&Backoff::Exponential(2), 4), 16);
+///
+/// assert_eq!(backoff_secs(&Backoff::Exponential(2), 100), u64::MAX);
+///
+/// assert_eq!(backoff_secs(&Backoff::Fixed(30), 1), 30);
+/// assert_eq!(backoff_secs(&Backoff::Fixed(30), 100), 30);
+///
+/// let capped = Backoff::ExponentialCapped {
+///     base: 2,
+///     max_seconds: 3600,
+/// };
+/// assert_eq!(backoff_secs(&capped, 4), 16);
+/// for retry_count in 0..=64 {
+///     assert!(backoff_secs(&capped, retry_count) <= 3600);
+/// }
+/// assert_eq!(backoff_secs(&capped, 64), 3600);
+/// ```
+pub fn backoff_secs(backoff: &Backoff, retry_count: u32) -> u64 {
+    match *backoff {
+        Backoff::Linear(secs) => secs as u64,
+        Backoff::Exponential(base) => (base as u64).saturating_pow(retry_count),
+        Backoff::Fixed(secs) => secs as u64,
+        Backoff::ExponentialCapped { base, max_seconds } => (base as u64)
+            .saturating_pow(retry_count)
+            .min(max_seconds as u64),
+        Backoff::ExponentialJitter { base, .. } => (base as u64).saturating_pow(retry_count),
+    }
+}
+
+/// The time at which the given retry becomes ready, following the backoff strategy
+///
+/// Retries that would be scheduled past the latest representable time are scheduled at that time.
+/// With `ExponentialJitter`, each call picks a new random time within the jitter window, which
+/// never starts before `now`.
+///
+/// ```rust
+/// use background_jobs_core::{
+///     clock::{self, TimeDelta},
+///     schedule::next_attempt,
+///     Backoff,
+/// };
+///
+/// let now = clock::now();
+///
+/// assert_eq!(next_attempt(&Backoff::Linear(5), 3, now), now + TimeDelta::seconds(5));
+/// assert_eq!(next_attempt(&Backoff::Exponential(3), 3, now), now + TimeDelta::seconds(27));
+///
+/// // Consecutive retries with a fixed backoff all wait the same time
+/// for retry_count in 1..=3 {
+///     assert_eq!(
+///         next_attempt(&Backoff::Fixed(30), retry_count, now),
+///         now + TimeDelta::seconds(30)
+///     );
+/// }
+///
+/// let capped = next_attempt(&Backoff::Exponential(2), 100, now);
+/// assert_eq!(capped, next_attempt(&Backoff::Exponential(10), 100, now));
+/// assert!(capped > now);
+///
+/// // 8 seconds, give or take 20 percent
+/// let jitter = Backoff::ExponentialJitter {
+///     base: 2,
+///     jitter_percent: 20,
+/// };
+/// for _ in 0..100 {
+///     let next = next_attempt(&jitter, 3, now);
+///     assert!(next >= now + TimeDelta::milliseconds(6400));
+///     assert!(next <= now + TimeDelta::milliseconds(9600));
+/// }
+///
+/// let wide = Backoff::ExponentialJitter {
+///     base: 2,
+///     jitter_percent: 255,
+/// };
+/// for _ in 0..100 {
+///     assert!(next_attempt(&wide, 1, now) >= now);
+/// }
+///
+/// // The largest retry count is scheduled without overflowing, at the latest time or before it
+/// for backoff in vec![
+///     Backoff::Linear(u32::MAX as usize),
+///     Backoff::Exponential(2),
+///     Backoff::ExponentialCapped { base: 2, max_seconds: 60 },
+///     jitter,
+/// ] {
+///     assert!(next_attempt(&backoff, u32::MAX, now) > now);
+/// }
+/// assert_eq!(
+///     next_attempt(
+///         &Backoff::ExponentialCapped { base: 2, max_seconds: 60 },
+///         u32::MAX,
+///         now
+///     ),
+///     now + TimeDelta::seconds(60)
+/// );
+/// ```
+pub fn next_attempt(backoff: &Backoff, retry_count: u32, now: Timestamp) -> Timestamp {
+    let secs = backoff_secs(backoff, retry_count);
+
+    match *backoff {
+        Backoff::ExponentialJitter { jitter_percent, .. } => jitter(now, secs, jitter_percent),
+        _ => clock::saturating_add_secs(now, secs),
+    }
+}
+
+/// Add the given number of seconds to `now`, moved randomly by up to `percent` percent
+fn jitter(now: Timestamp, secs: u64, percent: u8) -> Timestamp {
+    let spread = f64::from(percent.min(100)) / 100.0;
+
+    if spread == 0.0 {
+        return clock::saturating_add_secs(now, secs);
+    }
+
+    let factor = rand::thread_rng().gen_range(1.0 - spread, 1.0 + spread);
+
+    // Float to integer casts saturate, and the result is never negative
+    clock::saturating_add_millis(now, (secs as f64 * 1000.0 * factor) as u64)
+}
+
+/// The time at which a retry becomes ready when the job asked to wait for `after`
+///
+/// This returns `None` when the delay can't be represented, in which case the backoff strategy
+/// should be used instead.
+pub fn retry_after(after: Duration, now: Timestamp) -> Option<Timestamp> {
+    TimeDelta::from_std(after)
+        .ok()
+        .and_then(|after| now.checked_add_signed(after))
+}
+
+/// Whether a job that has failed `retry_count` times is allowed another attempt
+///
+/// ```rust
+/// use background_jobs_core::{schedule::should_retry, MaxRetries};
+///
+/// assert!(should_retry(&MaxRetries::Count(2), 2).should_requeue());
+/// assert!(!should_retry(&MaxRetries::Count(2), 3).should_requeue());
+/// assert!(should_retry(&MaxRetries::Infinite, u32::MAX).should_requeue());
+///
+/// // No retry count goes past the largest limit, whatever the width of usize
+/// assert!(should_retry(&MaxRetries::Count(usize::MAX), u32::MAX).should_requeue());
+/// ```
+///
+/// A job's retry count stops at `u32::MAX`, so a job allowed that many retries keeps being
+/// retried once it gets there:
+///
+/// ```rust
+/// use background_jobs_core::{memory_storage, JobInfo, MaxRetries, NewJobInfo, ReturnJobInfo, Storage};
+/// use serde_json::json;
+///
+/// fn main() -> Result<(), failure::Error> {
+///     let mut storage = memory_storage::Storage::new();
+///
+///     for max_retries in vec![MaxRetries::Infinite, MaxRetries::Count(usize::MAX)] {
+///         let id = storage.new_job(
+///             NewJobInfo::builder("Processor", "default")
+///                 .args(json!([]))
+///                 .max_retries(max_retries)
+///                 .build()?,
+///         )?;
+///         let job = storage.request_job("default", 1)?.unwrap();
+///
+///         // Pretend the job has already failed almost as often as can be counted
+///         let mut value = serde_json::to_value(&job)?;
+///         value["retry_count"] = json!(u32::MAX - 1);
+///         storage.save_job(serde_json::from_value::<JobInfo>(value)?)?;
+///
+///         for _ in 0..2 {
+///             storage.return_job(ReturnJobInfo::fail(id))?;
+///
+///             let job = storage.fetch_job(id)?.unwrap();
+///             assert_eq!(job.retry_count(), u32::MAX);
+///             assert!(job.is_pending());
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn should_retry(max_retries: &MaxRetries, retry_count: u32) -> ShouldStop {
+    match *max_retries {
+        MaxRetries::Infinite => ShouldStop::Requeue,
+        MaxRetries::Count(count) => {
+            // Compare as u64 so the result doesn't depend on the width of usize
+            if u64::from(retry_count) <= count as u64 {
+                ShouldStop::Requeue
+            } else {
+                ShouldStop::LimitReached
+            }
+        }
+    }
+}
+
+/// The number of retries a job has left after its `retry_count`th failure, or `None` when it is
+/// retried forever
+///
+/// ```rust
+/// use background_jobs_core::{schedule::retries_left, MaxRetries};
+///
+/// assert_eq!(retries_left(&MaxRetries::Count(3), 1), Some(2));
+/// assert_eq!(retries_left(&MaxRetries::Count(3), 3), Some(0));
+/// assert_eq!(retries_left(&MaxRetries::Count(3), 5), Some(0));
+/// assert_eq!(retries_left(&MaxRetries::Infinite, 5), None);
+///
+/// assert_eq!(
+///     retries_left(&MaxRetries::Count(usize::MAX), u32::MAX),
+///     Some(usize::MAX as u64 - u64::from(u32::MAX))
+/// );
+/// assert_eq!(retries_left(&MaxRetries::Count(0), u32::MAX), Some(0));
+/// ```
+pub fn retries_left(max_retries: &MaxRetries, retry_count: u32) -> Option<u64> {
+    match *max_retries {
+        MaxRetries::Infinite => None,
+        MaxRetries::Count(count) => Some((count as u64).saturating_sub(u64::from(retry_count))),
+    }
+}
+
+/// Whether a job scheduled for `next_queue` can run at `now`
+///
+/// Jobs without a scheduled time are always ready. Scheduled jobs are ready strictly after their
+/// scheduled time.
+///
+/// ```rust
+/// use background_jobs_core::{
+///     clock::{self, TimeDelta},
+///     schedule::is_ready,
+/// };
+///
+/// let now = clock::now();
+///
+/// assert!(is_ready(None, now));
+/// assert!(is_ready(Some(now - TimeDelta::seconds(1)), now));
+/// assert!(!is_ready(Some(now), now));
+/// ```
+pub fn is_ready(next_queue: Option<Timestamp>, now: Timestamp) -> bool {
+    match next_queue {
+        Some(time) => now > time,
+        None => true,
+    }
+}
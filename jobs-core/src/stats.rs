@@ -17,15 +17,66 @@
  * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use chrono::{offset::Utc, DateTime, Datelike, Timelike};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
+
 use serde_derive::{Deserialize, Serialize};
 
+use crate::clock::{self, Datelike, TimeDelta, Timelike, Timestamp};
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Stats {
     pub pending: usize,
     pub running: usize,
     pub dead: JobStat,
     pub complete: JobStat,
+    #[serde(default)]
+    pub wait_time: WaitStat,
+
+    /// Running jobs that were moved back to pending without finishing, such as after their
+    /// worker died
+    #[serde(default)]
+    pub reclaimed: JobStat,
+
+    /// Jobs that ran out of retries and were quarantined for one last replay
+    #[serde(default)]
+    pub quarantined: JobStat,
+
+    /// Pending and running counts for each queue, sorted by queue name
+    ///
+    /// ```rust
+    /// use background_jobs_core::{memory_storage, NewJobInfo, Storage};
+    /// use serde_json::json;
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     for queue in &["zeta", "alpha", "mid"] {
+    ///         storage.new_job(NewJobInfo::builder("Processor", queue).args(json!([])).build()?)?;
+    ///     }
+    ///
+    ///     let stats = storage.get_stats()?;
+    ///     let queues: Vec<_> = stats.queues.keys().map(String::as_str).collect();
+    ///     assert_eq!(queues, vec!["alpha", "mid", "zeta"]);
+    ///
+    ///     // Serialized stats list the queues in the same order
+    ///     let json = serde_json::to_string(&stats.queues)?;
+    ///     let alpha = json.find("alpha").unwrap();
+    ///     let mid = json.find("mid").unwrap();
+    ///     assert!(alpha < mid && mid < json.find("zeta").unwrap());
+    ///     Ok(())
+    /// }
+    /// ```
+    #[serde(default)]
+    pub queues: BTreeMap<String, QueueStat>,
+
+    /// Completed and dead job counts for each processor, sorted by processor name
+    #[serde(default)]
+    pub processors: BTreeMap<String, ProcessorStat>,
 }
 
 impl Stats {
@@ -33,42 +84,132 @@ impl Stats {
         Self::default()
     }
 
-    pub(crate) fn new_job(mut self) -> Self {
+    pub(crate) fn new_job(mut self, queue: &str) -> Self {
         self.pending += 1;
+        self.queue(queue).pending += 1;
         self
     }
 
-    pub(crate) fn run_job(mut self) -> Self {
-        if self.pending > 0 {
-            self.pending -= 1;
-        }
+    pub(crate) fn run_job(mut self, queue: &str, wait_time: TimeDelta) -> Self {
+        self.pending = self.pending.saturating_sub(1);
         self.running += 1;
+        self.wait_time.record(wait_time);
+
+        let stat = self.queue(queue);
+        stat.pending = stat.pending.saturating_sub(1);
+        stat.running += 1;
         self
     }
 
-    pub(crate) fn retry_job(mut self) -> Self {
+    pub(crate) fn retry_job(mut self, from: &str, to: &str) -> Self {
         self.pending += 1;
-        if self.running > 0 {
-            self.running -= 1;
-        }
+        self.running = self.running.saturating_sub(1);
+
+        let stat = self.queue(from);
+        stat.running = stat.running.saturating_sub(1);
+        self.queue(to).pending += 1;
         self
     }
 
-    pub(crate) fn fail_job(mut self) -> Self {
-        if self.running > 0 {
-            self.running -= 1;
-        }
+    pub(crate) fn quarantine_job(mut self, from: &str, to: &str) -> Self {
+        self.quarantined.increment();
+        self.retry_job(from, to)
+    }
+
+    pub(crate) fn reclaim_job(mut self, queue: &str) -> Self {
+        self.pending += 1;
+        self.running = self.running.saturating_sub(1);
+        self.reclaimed.increment();
+
+        let stat = self.queue(queue);
+        stat.running = stat.running.saturating_sub(1);
+        stat.pending += 1;
+        self
+    }
+
+    pub(crate) fn fail_job(mut self, queue: &str, processor: &str) -> Self {
+        self.running = self.running.saturating_sub(1);
         self.dead.increment();
+        self.processor(processor).dead.increment();
+
+        let stat = self.queue(queue);
+        stat.running = stat.running.saturating_sub(1);
         self
     }
 
-    pub(crate) fn complete_job(mut self) -> Self {
-        if self.running > 0 {
-            self.running -= 1;
-        }
+    pub(crate) fn complete_job(mut self, queue: &str, processor: &str) -> Self {
+        self.running = self.running.saturating_sub(1);
         self.complete.increment();
+        self.processor(processor).complete.increment();
+
+        let stat = self.queue(queue);
+        stat.running = stat.running.saturating_sub(1);
         self
     }
+
+    /// Stop counting a running job that was deleted without a result
+    pub(crate) fn drop_job(mut self, queue: &str) -> Self {
+        self.running = self.running.saturating_sub(1);
+
+        let stat = self.queue(queue);
+        stat.running = stat.running.saturating_sub(1);
+        self
+    }
+
+    /// Stop counting a pending job that was deleted before it ran
+    pub(crate) fn cancel_job(mut self, queue: &str) -> Self {
+        self.pending = self.pending.saturating_sub(1);
+
+        let stat = self.queue(queue);
+        stat.pending = stat.pending.saturating_sub(1);
+        self
+    }
+
+    /// Reset the counts of pending and running jobs, after every job was deleted
+    pub(crate) fn purge(mut self) -> Self {
+        self.pending = 0;
+        self.running = 0;
+        self.queues.clear();
+        self
+    }
+
+    /// Apply a single change to the stats
+    pub fn apply(self, event: &StatsEvent) -> Self {
+        match *event {
+            StatsEvent::New { queue } => self.new_job(queue),
+            StatsEvent::Run { queue, wait_time } => self.run_job(queue, wait_time),
+            StatsEvent::Retry { from, to } => self.retry_job(from, to),
+            StatsEvent::Quarantine { from, to } => self.quarantine_job(from, to),
+            StatsEvent::Reclaim { queue } => self.reclaim_job(queue),
+            StatsEvent::Fail { queue, processor } => self.fail_job(queue, processor),
+            StatsEvent::Complete { queue, processor } => self.complete_job(queue, processor),
+            StatsEvent::Drop { queue } => self.drop_job(queue),
+            StatsEvent::Cancel { queue } => self.cancel_job(queue),
+            StatsEvent::Purge => self.purge(),
+        }
+    }
+
+    /// The completed and dead job counts of a processor, if any of its jobs have finished
+    pub fn processor_stats(&self, name: &str) -> Option<&ProcessorStat> {
+        self.processors.get(name)
+    }
+
+    fn queue(&mut self, queue: &str) -> &mut QueueStat {
+        if !self.queues.contains_key(queue) {
+            self.queues.insert(queue.to_owned(), QueueStat::default());
+        }
+
+        self.queues.get_mut(queue).unwrap()
+    }
+
+    fn processor(&mut self, processor: &str) -> &mut ProcessorStat {
+        if !self.processors.contains_key(processor) {
+            self.processors
+                .insert(processor.to_owned(), ProcessorStat::default());
+        }
+
+        self.processors.get_mut(processor).unwrap()
+    }
 }
 
 impl Default for Stats {
@@ -78,17 +219,328 @@ impl Default for Stats {
             running: 0,
             dead: JobStat::default(),
             complete: JobStat::default(),
+            wait_time: WaitStat::default(),
+            reclaimed: JobStat::default(),
+            quarantined: JobStat::default(),
+            queues: BTreeMap::new(),
+            processors: BTreeMap::new(),
         }
     }
 }
 
+/// A change to the stats, recorded as a job moves from one state to another
+#[derive(Clone, Copy, Debug)]
+pub enum StatsEvent<'a> {
+    /// A job was stored in the given queue
+    New { queue: &'a str },
+
+    /// A job was handed to a worker after waiting for the given time
+    Run {
+        queue: &'a str,
+        wait_time: TimeDelta,
+    },
+
+    /// A running job failed and was put back, possibly in another queue
+    Retry { from: &'a str, to: &'a str },
+
+    /// A running job ran out of retries and was quarantined, possibly in another queue
+    Quarantine { from: &'a str, to: &'a str },
+
+    /// A running job was put back in its queue without finishing
+    Reclaim { queue: &'a str },
+
+    /// A running job of the given processor ran out of retries and became dead
+    Fail { queue: &'a str, processor: &'a str },
+
+    /// A running job of the given processor completed
+    Complete { queue: &'a str, processor: &'a str },
+
+    /// A running job was deleted without a result
+    Drop { queue: &'a str },
+
+    /// A pending job was deleted before it ran
+    Cancel { queue: &'a str },
+
+    /// Every job was deleted
+    Purge,
+}
+
+/// Where the stats of a storage are kept
+///
+/// By default, storages keep their stats in a single `Stats` value, which is read, changed, and
+/// written back with `Storage::update_stats` for every change. Under high throughput, every
+/// job that completes contends for that one value, which for a persistent storage also means
+/// serializing and writing it. Storages accepting a sink, like the memory storage, record each
+/// change with `record` instead, and answer `Storage::get_stats` with `snapshot`, so a sink can
+/// count with atomics, as `AtomicStats` does, or forward changes to an external aggregator.
+pub trait StatsSink: Send + Sync {
+    /// Record a change to the stats
+    fn record(&self, event: &StatsEvent);
+
+    /// The current stats
+    fn snapshot(&self) -> Stats;
+}
+
+/// A `StatsSink` that counts with atomics instead of locking a shared `Stats`
+///
+/// Recording a change to a queue that has been seen before only takes a shared lock, so
+/// concurrent changes don't wait on each other. To stay lock-free, it keeps fewer details than
+/// `Stats`: the job counts only have their all-time totals, so their hourly, daily, and monthly
+/// counts are always 0, and the average wait time is over every job rather than the last 100.
+/// Counts start at 0 when the sink is created.
+///
+/// ```rust
+/// use background_jobs_core::{AtomicStats, StatsEvent, StatsSink};
+///
+/// let sink = AtomicStats::new();
+/// sink.record(&StatsEvent::New { queue: "default" });
+/// sink.record(&StatsEvent::Complete {
+///     queue: "default",
+///     processor: "SendEmail",
+/// });
+///
+/// let stats = sink.snapshot();
+/// assert_eq!(stats.complete.all_time(), 1);
+/// assert_eq!(stats.queues["default"].pending, 1);
+///
+/// let processor = stats.processor_stats("SendEmail").unwrap();
+/// assert_eq!(processor.complete.all_time(), 1);
+/// assert_eq!(processor.dead.all_time(), 0);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct AtomicStats {
+    inner: Arc<AtomicInner>,
+}
+
+#[derive(Debug, Default)]
+struct AtomicInner {
+    pending: AtomicUsize,
+    running: AtomicUsize,
+    dead: AtomicUsize,
+    complete: AtomicUsize,
+    reclaimed: AtomicUsize,
+    quarantined: AtomicUsize,
+    waits: AtomicUsize,
+    wait_total_ms: AtomicU64,
+    wait_last_ms: AtomicU64,
+    wait_max_ms: AtomicU64,
+    queues: RwLock<HashMap<String, Arc<AtomicQueue>>>,
+    processors: RwLock<HashMap<String, Arc<AtomicProcessor>>>,
+}
+
+#[derive(Debug, Default)]
+struct AtomicQueue {
+    pending: AtomicUsize,
+    running: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct AtomicProcessor {
+    complete: AtomicUsize,
+    dead: AtomicUsize,
+}
+
+impl AtomicStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AtomicInner {
+    fn queue(&self, queue: &str) -> Arc<AtomicQueue> {
+        if let Some(stat) = self.queues.read().unwrap().get(queue) {
+            return stat.clone();
+        }
+
+        self.queues
+            .write()
+            .unwrap()
+            .entry(queue.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    fn processor(&self, processor: &str) -> Arc<AtomicProcessor> {
+        if let Some(stat) = self.processors.read().unwrap().get(processor) {
+            return stat.clone();
+        }
+
+        self.processors
+            .write()
+            .unwrap()
+            .entry(processor.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    fn record_wait(&self, wait_time: TimeDelta) {
+        let ms = wait_time.num_milliseconds().max(0) as u64;
+
+        self.waits.fetch_add(1, Ordering::Relaxed);
+        self.wait_total_ms.fetch_add(ms, Ordering::Relaxed);
+        self.wait_last_ms.store(ms, Ordering::Relaxed);
+        self.wait_max_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+}
+
+impl StatsSink for AtomicStats {
+    fn record(&self, event: &StatsEvent) {
+        let inner = &self.inner;
+
+        match *event {
+            StatsEvent::New { queue } => {
+                increment(&inner.pending);
+                increment(&inner.queue(queue).pending);
+            }
+            StatsEvent::Run { queue, wait_time } => {
+                decrement(&inner.pending);
+                increment(&inner.running);
+                inner.record_wait(wait_time);
+
+                let stat = inner.queue(queue);
+                decrement(&stat.pending);
+                increment(&stat.running);
+            }
+            StatsEvent::Retry { from, to } | StatsEvent::Quarantine { from, to } => {
+                if let StatsEvent::Quarantine { .. } = *event {
+                    increment(&inner.quarantined);
+                }
+
+                increment(&inner.pending);
+                decrement(&inner.running);
+                decrement(&inner.queue(from).running);
+                increment(&inner.queue(to).pending);
+            }
+            StatsEvent::Reclaim { queue } => {
+                increment(&inner.pending);
+                decrement(&inner.running);
+                increment(&inner.reclaimed);
+
+                let stat = inner.queue(queue);
+                decrement(&stat.running);
+                increment(&stat.pending);
+            }
+            StatsEvent::Fail { queue, processor } | StatsEvent::Complete { queue, processor } => {
+                let stat = inner.processor(processor);
+
+                match *event {
+                    StatsEvent::Fail { .. } => {
+                        increment(&inner.dead);
+                        increment(&stat.dead);
+                    }
+                    _ => {
+                        increment(&inner.complete);
+                        increment(&stat.complete);
+                    }
+                }
+
+                decrement(&inner.running);
+                decrement(&inner.queue(queue).running);
+            }
+            StatsEvent::Drop { queue } => {
+                decrement(&inner.running);
+                decrement(&inner.queue(queue).running);
+            }
+            StatsEvent::Cancel { queue } => {
+                decrement(&inner.pending);
+                decrement(&inner.queue(queue).pending);
+            }
+            StatsEvent::Purge => {
+                inner.pending.store(0, Ordering::Relaxed);
+                inner.running.store(0, Ordering::Relaxed);
+                inner.queues.write().unwrap().clear();
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Stats {
+        let inner = &self.inner;
+        let waits = inner.waits.load(Ordering::Relaxed);
+        let total_ms = inner.wait_total_ms.load(Ordering::Relaxed);
+
+        let queues = inner
+            .queues
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(queue, stat)| {
+                let stat = QueueStat {
+                    pending: stat.pending.load(Ordering::Relaxed),
+                    running: stat.running.load(Ordering::Relaxed),
+                };
+
+                (queue.clone(), stat)
+            })
+            .collect();
+
+        let processors = inner
+            .processors
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(processor, stat)| {
+                let stat = ProcessorStat {
+                    complete: JobStat::all_time_only(stat.complete.load(Ordering::Relaxed)),
+                    dead: JobStat::all_time_only(stat.dead.load(Ordering::Relaxed)),
+                };
+
+                (processor.clone(), stat)
+            })
+            .collect();
+
+        Stats {
+            pending: inner.pending.load(Ordering::Relaxed),
+            running: inner.running.load(Ordering::Relaxed),
+            dead: JobStat::all_time_only(inner.dead.load(Ordering::Relaxed)),
+            complete: JobStat::all_time_only(inner.complete.load(Ordering::Relaxed)),
+            wait_time: WaitStat {
+                count: waits,
+                last_ms: inner.wait_last_ms.load(Ordering::Relaxed),
+                average_ms: if waits > 0 {
+                    total_ms as f64 / waits as f64
+                } else {
+                    0.0
+                },
+                max_ms: inner.wait_max_ms.load(Ordering::Relaxed),
+            },
+            reclaimed: JobStat::all_time_only(inner.reclaimed.load(Ordering::Relaxed)),
+            quarantined: JobStat::all_time_only(inner.quarantined.load(Ordering::Relaxed)),
+            queues,
+            processors,
+        }
+    }
+}
+
+fn increment(count: &AtomicUsize) {
+    count.fetch_add(1, Ordering::Relaxed);
+}
+
+// Counts never go below 0, like the saturating subtractions in `Stats`
+fn decrement(count: &AtomicUsize) {
+    let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+/// The number of jobs in a single queue
+pub struct QueueStat {
+    pub pending: usize,
+    pub running: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+/// The number of completed and dead jobs of a single processor
+pub struct ProcessorStat {
+    pub complete: JobStat,
+    pub dead: JobStat,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct JobStat {
     this_hour: usize,
     today: usize,
     this_month: usize,
     all_time: usize,
-    updated_at: DateTime<Utc>,
+    updated_at: Timestamp,
 }
 
 impl JobStat {
@@ -96,6 +548,13 @@ impl JobStat {
         Self::default()
     }
 
+    fn all_time_only(all_time: usize) -> Self {
+        JobStat {
+            all_time,
+            ..JobStat::default()
+        }
+    }
+
     fn increment(&mut self) {
         self.tick();
 
@@ -106,7 +565,7 @@ impl JobStat {
     }
 
     fn tick(&mut self) {
-        let now = Utc::now();
+        let now = clock::now();
 
         if now.month() != self.updated_at.month() {
             self.next_month();
@@ -157,7 +616,80 @@ impl Default for JobStat {
             today: 0,
             this_month: 0,
             all_time: 0,
-            updated_at: Utc::now(),
+            updated_at: clock::now(),
         }
     }
 }
+
+/// How long jobs spend waiting in a queue before a worker picks them up
+///
+/// A job's wait time is measured from when it became ready to run until the moment it is
+/// dispatched. Consistently high wait times usually mean there are not enough workers for a queue.
+///
+/// ```rust
+/// use background_jobs_core::{memory_storage, NewJobInfo, Storage};
+/// use serde_json::json;
+/// use std::{thread, time::Duration};
+///
+/// fn main() -> Result<(), failure::Error> {
+///     let mut storage = memory_storage::Storage::new();
+///     storage.new_job(NewJobInfo::builder("Processor", "default").args(json!([])).build()?)?;
+///
+///     // Keep the job waiting before a worker asks for it
+///     thread::sleep(Duration::from_millis(50));
+///     storage.request_job("default", 1)?.unwrap();
+///
+///     let wait_time = storage.get_stats()?.wait_time;
+///     assert_eq!(wait_time.count(), 1);
+///     assert!(wait_time.last_ms() >= 50 && wait_time.last_ms() < 5000);
+///     assert_eq!(wait_time.max_ms(), wait_time.last_ms());
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WaitStat {
+    count: usize,
+    last_ms: u64,
+    average_ms: f64,
+    max_ms: u64,
+}
+
+impl WaitStat {
+    /// The number of samples considered by the rolling average
+    const WINDOW: usize = 100;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&mut self, wait_time: TimeDelta) {
+        let ms = wait_time.num_milliseconds().max(0) as u64;
+
+        self.count += 1;
+        self.last_ms = ms;
+        self.max_ms = self.max_ms.max(ms);
+
+        let samples = self.count.min(Self::WINDOW) as f64;
+        self.average_ms += (ms as f64 - self.average_ms) / samples;
+    }
+
+    /// The number of jobs that have been dispatched
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The wait time of the most recently dispatched job, in milliseconds
+    pub fn last_ms(&self) -> u64 {
+        self.last_ms
+    }
+
+    /// The rolling average wait time over roughly the last 100 jobs, in milliseconds
+    pub fn average_ms(&self) -> f64 {
+        self.average_ms
+    }
+
+    /// The longest wait time seen so far, in milliseconds
+    pub fn max_ms(&self) -> u64 {
+        self.max_ms
+    }
+}
@@ -17,11 +17,154 @@
  * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use chrono::offset::Utc;
+use std::time::Duration;
+
 use failure::Fail;
-use log::error;
+use log::{error, trace, warn};
+use serde_json::Value;
+
+use crate::{
+    clock::{self, TimeDelta, Timestamp},
+    Backoff, JobInfo, MaxRetries, NewJobInfo, ReturnJobInfo, Stats, StatsEvent,
+};
+
+/// What storing a new job did
+///
+/// ```rust
+/// use background_jobs_core::{memory_storage, EnqueueOutcome, NewJobInfo, Storage};
+/// use serde_json::json;
+///
+/// let mut storage = memory_storage::Storage::new();
+/// let job = NewJobInfo::builder("MyProcessor", "default")
+///     .args(json!([]))
+///     .unique_key("nightly-report")
+///     .build()
+///     .unwrap();
+///
+/// let first = storage.enqueue(job.clone()).unwrap();
+/// assert_eq!(first, EnqueueOutcome::Enqueued(first.id()));
+///
+/// let second = storage.enqueue(job).unwrap();
+/// assert_eq!(second, EnqueueOutcome::Deduplicated(first.id()));
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EnqueueOutcome {
+    /// The job was stored with this ID
+    Enqueued(u64),
+
+    /// The job matched an existing job by its unique, global, or idempotency key, so it wasn't
+    /// stored, and this is the ID of the existing job
+    Deduplicated(u64),
+}
+
+impl EnqueueOutcome {
+    /// The ID of the stored job, or of the job it was coalesced with
+    pub fn id(&self) -> u64 {
+        match *self {
+            EnqueueOutcome::Enqueued(id) | EnqueueOutcome::Deduplicated(id) => id,
+        }
+    }
+
+    /// Whether the job was coalesced with an existing job instead of being stored
+    pub fn is_deduplicated(&self) -> bool {
+        matches!(self, EnqueueOutcome::Deduplicated(_))
+    }
+}
+
+/// How a storage chooses between ready jobs of the same priority in a queue
+///
+/// Storage backends hand out the ready job with the highest priority first. This decides which
+/// of several such jobs goes first.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DispatchOrder {
+    /// The job that was created first runs first
+    ///
+    /// Scheduled jobs and retries keep their place from when they were created, so an overdue
+    /// job created before a backlog of fresh jobs still runs ahead of them, but one created
+    /// after the backlog waits behind it. This is the default.
+    #[default]
+    Created,
+
+    /// The job that has been ready the longest runs first
+    ///
+    /// A job is ready from its creation for fresh jobs, and from its scheduled time for
+    /// scheduled jobs and retries, so an overdue job is never passed over by jobs that became
+    /// ready after it.
+    ///
+    /// ```rust
+    /// use std::{thread, time::Duration};
+    ///
+    /// use background_jobs_core::{
+    ///     clock::{self, TimeDelta},
+    ///     memory_storage, DispatchOrder, NewJobInfo, NewJobInfoBuilder, Storage,
+    /// };
+    /// use serde_json::json;
+    ///
+    /// fn job() -> NewJobInfoBuilder {
+    ///     NewJobInfo::builder("Processor", "default").args(json!([]))
+    /// }
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let mut storage =
+    ///         memory_storage::Storage::new().with_dispatch_order(DispatchOrder::Ready);
+    ///
+    ///     let early = storage.new_job(job().build()?)?;
+    ///     let overdue = storage.new_job(
+    ///         job()
+    ///             .schedule(clock::now() + TimeDelta::milliseconds(20))
+    ///             .build()?,
+    ///     )?;
+    ///     thread::sleep(Duration::from_millis(40));
+    ///
+    ///     // A stream of fresh jobs arrives after the scheduled job became ready
+    ///     let fresh = storage.new_jobs(vec![job().build()?, job().build()?, job().build()?])?;
+    ///
+    ///     let mut order = Vec::new();
+    ///     while let Some(job) = storage.request_job("default", 1)? {
+    ///         order.push(job.id());
+    ///     }
+    ///     assert_eq!(order[..2], [early, overdue]);
+    ///     assert_eq!(order[2..], fresh[..]);
+    ///     Ok(())
+    /// }
+    /// ```
+    Ready,
+}
+
+impl DispatchOrder {
+    /// The key that orders ready jobs of the same priority, where the smallest key runs first
+    pub fn key(self, job: &JobInfo) -> (Option<Timestamp>, u64) {
+        self.key_for(job.id(), job.ready_at())
+    }
 
-use crate::{JobInfo, NewJobInfo, ReturnJobInfo, Stats};
+    /// The key of a job with the given ID that is ready at the given time
+    ///
+    /// This is for storages that index queued jobs, so they can order jobs without loading them.
+    pub fn key_for(self, id: u64, ready_at: Timestamp) -> (Option<Timestamp>, u64) {
+        match self {
+            DispatchOrder::Created => (None, id),
+            DispatchOrder::Ready => (Some(ready_at), id),
+        }
+    }
+}
+
+/// What happens to a job that was returned because no processor for it is registered
+///
+/// This usually means the processor was removed in a deploy while jobs for it were still stored.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MissingProcessorPolicy {
+    /// Put the job back in its queue, in case a worker with its processor picks it up later
+    ///
+    /// This doesn't use up a retry. It is the default.
+    #[default]
+    Requeue,
+
+    /// Move the job to the dead jobs, where it can still be inspected or requeued
+    DeadLetter,
+
+    /// Delete the job
+    Drop,
+}
 
 /// Define a storage backend for jobs
 ///
@@ -45,10 +188,70 @@ pub trait Storage: Clone + Send {
     /// This method should return the job with the given ID regardless of what state the job is in.
     fn fetch_job(&mut self, id: u64) -> Result<Option<JobInfo>, Self::Error>;
 
+    /// This method should return the ID of the pending job in the given queue with the given
+    /// unique key, if one exists
+    ///
+    /// Implementations should track jobs with a unique key when they are saved while pending, and
+    /// stop tracking them once they are saved in any other state or deleted.
+    fn fetch_unique_job(&mut self, queue: &str, key: &str) -> Result<Option<u64>, Self::Error>;
+
+    /// This method should return the ID of the pending job with the given global key, in any
+    /// queue, if one exists
+    ///
+    /// Implementations should track jobs with a global key when they are saved while pending, and
+    /// stop tracking them once they are saved in any other state or deleted.
+    fn fetch_global_job(&mut self, key: &str) -> Result<Option<u64>, Self::Error>;
+
+    /// This method should remember that the job with the given ID and idempotency key completed
+    ///
+    /// The time of completion should be stored alongside the ID, so it can be returned from
+    /// `fetch_completed_key`.
+    fn save_completed_key(&mut self, queue: &str, key: &str, id: u64) -> Result<(), Self::Error>;
+
+    /// This method should return the ID and completion time of the job in the given queue that
+    /// completed with the given idempotency key, if one exists
+    fn fetch_completed_key(
+        &mut self,
+        queue: &str,
+        key: &str,
+    ) -> Result<Option<(u64, Timestamp)>, Self::Error>;
+
+    /// This method should forget a completed idempotency key
+    fn delete_completed_key(&mut self, queue: &str, key: &str) -> Result<(), Self::Error>;
+
+    /// How long completed idempotency keys are honored
+    ///
+    /// Jobs resubmitted with a completed key are not run again until this much time has passed
+    /// since the original job completed. By default, keys are retained for one day.
+    fn idempotency_retention(&self) -> TimeDelta {
+        TimeDelta::days(1)
+    }
+
+    /// This method should return every stored job, regardless of what state the jobs are in.
+    fn list_jobs(&mut self) -> Result<Vec<JobInfo>, Self::Error>;
+
+    /// This method returns every pending job whose scheduled time is still in the future
+    ///
+    /// This includes jobs queued for a later time, as well as failed jobs waiting for their next
+    /// retry.
+    fn list_scheduled(&mut self) -> Result<Vec<JobInfo>, Self::Error> {
+        let now = clock::now();
+
+        Ok(self
+            .list_jobs()?
+            .into_iter()
+            .filter(|job| job.is_scheduled(now))
+            .collect())
+    }
+
     /// This should fetch a job ready to be processed from the queue
     ///
     /// If a job is not ready, is currently running, or is not in the requested queue, this method
     /// should not return it. If no jobs meet these criteria, this method should return Ok(None)
+    ///
+    /// When several jobs meet these criteria, the job with the highest priority should be
+    /// returned, with ties broken by a `DispatchOrder`, which defaults to the job with the lowest
+    /// ID.
     fn fetch_job_from_queue(&mut self, queue: &str) -> Result<Option<JobInfo>, Self::Error>;
 
     /// This method tells the storage mechanism to mark the given job as being in the provided
@@ -63,6 +266,50 @@ pub trait Storage: Clone + Send {
     /// This happens when a job has been completed or has failed too many times
     fn delete_job(&mut self, id: u64) -> Result<(), Self::Error>;
 
+    /// This method should store a job that has failed too many times
+    ///
+    /// Dead jobs are kept separately from other jobs, so they are never fetched from a queue, and
+    /// should not be returned by `fetch_job` or `list_jobs`.
+    fn save_dead_job(&mut self, job: JobInfo) -> Result<(), Self::Error>;
+
+    /// This method should return every dead job
+    fn fetch_dead_jobs(&mut self) -> Result<Vec<JobInfo>, Self::Error>;
+
+    /// This method should remove the dead job with the given ID, returning it if it existed
+    fn remove_dead_job(&mut self, id: u64) -> Result<Option<JobInfo>, Self::Error>;
+
+    /// This method should delete every job, whether it is pending, scheduled, running, or dead
+    ///
+    /// The unique and idempotency keys of the jobs, and the records of which runners hold which
+    /// jobs, should be deleted as well. Stats are reset separately by `purge_all`.
+    fn purge_jobs(&mut self) -> Result<(), Self::Error>;
+
+    /// This method returns the time the earliest scheduled job becomes ready, if the backend can
+    /// compute it
+    ///
+    /// Runtimes use this to wake up exactly when a scheduled job or retry becomes ready, rather
+    /// than waiting for their next poll. Returning `None` means the time is unknown, and the
+    /// runtime should keep polling. This is the default.
+    fn next_wakeup(&self) -> Result<Option<Timestamp>, Self::Error> {
+        Ok(None)
+    }
+
+    /// This method performs periodic upkeep, such as reclaiming the space left behind by deleted
+    /// jobs
+    ///
+    /// Runtimes call this every `maintenance_interval`, alongside normal job processing, so it
+    /// should not hold locks that block requesting jobs for long. By default, nothing is done.
+    fn maintain(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// How often `maintain` should be called, or `None` if it shouldn't be called at all
+    ///
+    /// By default, this returns `None`.
+    fn maintenance_interval(&self) -> Option<Duration> {
+        None
+    }
+
     /// This method returns the current statistics, or Stats::default() if none exists.
     fn get_stats(&self) -> Result<Stats, Self::Error>;
 
@@ -72,7 +319,122 @@ pub trait Storage: Clone + Send {
     where
         F: Fn(Stats) -> Stats;
 
+    /// Record a change to the statistics
+    ///
+    /// Every change made by the provided methods goes through here. By default, this applies the
+    /// change with `update_stats`. Implementations keeping their statistics elsewhere, like in a
+    /// `StatsSink`, should override this along with `get_stats`.
+    fn record_stats(&mut self, event: StatsEvent) -> Result<(), Self::Error> {
+        self.update_stats(|stats| stats.apply(&event))
+    }
+
+    /// Check that the storage backend is usable
+    ///
+    /// This is meant for readiness probes. By default, it reads the current statistics.
+    /// Implementations backed by a disk or a remote service should override this to check that
+    /// they can also be written to.
+    fn health_check(&mut self) -> Result<(), Self::Error> {
+        self.get_stats().map(|_| ())
+    }
+
+    /// Delete every job and reset the pending and running counts
+    ///
+    /// This is meant for test teardown and development resets. The completed and dead job counts
+    /// are history rather than gauges, so they are kept.
+    ///
+    /// ```rust
+    /// use background_jobs_core::{
+    ///     clock::{self, TimeDelta},
+    ///     memory_storage, MaxRetries, NewJobInfo, NewJobInfoBuilder, ReturnJobInfo, Storage,
+    /// };
+    /// use serde_json::json;
+    ///
+    /// fn job() -> NewJobInfoBuilder {
+    ///     NewJobInfo::builder("Processor", "default")
+    ///         .args(json!([]))
+    ///         .max_retries(MaxRetries::Count(0))
+    /// }
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let mut storage = memory_storage::Storage::new();
+    ///
+    ///     // One dead job, one running, one pending, and one scheduled
+    ///     let dead = storage.new_job(job().build()?)?;
+    ///     storage.request_job("default", 1)?.unwrap();
+    ///     storage.return_job(ReturnJobInfo::fail(dead))?;
+    ///     storage.new_job(job().build()?)?;
+    ///     storage.request_job("default", 1)?.unwrap();
+    ///     storage.new_job(job().build()?)?;
+    ///     storage.new_job(job().schedule(clock::now() + TimeDelta::hours(1)).build()?)?;
+    ///
+    ///     storage.purge_all()?;
+    ///
+    ///     assert!(storage.list_jobs()?.is_empty());
+    ///     assert!(storage.fetch_dead_jobs()?.is_empty());
+    ///     assert!(storage.request_job("default", 1)?.is_none());
+    ///
+    ///     let stats = storage.get_stats()?;
+    ///     assert_eq!((stats.pending, stats.running), (0, 0));
+    ///     assert!(stats.queues.values().all(|q| q.pending == 0 && q.running == 0));
+    ///     assert_eq!(stats.dead.all_time(), 1);
+    ///     Ok(())
+    /// }
+    /// ```
+    fn purge_all(&mut self) -> Result<(), Self::Error> {
+        self.purge_jobs()?;
+        self.record_stats(StatsEvent::Purge)
+    }
+
+    /// Store a new job, returning its ID
+    ///
+    /// When the job is coalesced with an existing job by its unique, global, or idempotency key,
+    /// the ID of the existing job is returned instead.
     fn new_job(&mut self, job: NewJobInfo) -> Result<u64, Self::Error> {
+        self.enqueue(job).map(|outcome| outcome.id())
+    }
+
+    /// Store a new job, reporting whether it was coalesced with an existing job
+    fn enqueue(&mut self, job: NewJobInfo) -> Result<EnqueueOutcome, Self::Error> {
+        if let Some(key) = job.global_key() {
+            if let Some(id) = self.fetch_global_job(key)? {
+                if let Some(existing) = self.fetch_job(id)? {
+                    if existing.is_pending() {
+                        if existing.args() != *job.args() {
+                            warn!(
+                                "Job {} already pending with global key {}, discarding new arguments",
+                                id, key
+                            );
+                        } else {
+                            trace!("Job {} already pending with global key {}", id, key);
+                        }
+                        return Ok(EnqueueOutcome::Deduplicated(id));
+                    }
+                }
+            }
+        }
+
+        if let Some(key) = job.unique_key() {
+            if let Some(id) = self.fetch_unique_job(job.queue(), key)? {
+                if let Some(existing) = self.fetch_job(id)? {
+                    if existing.is_pending() {
+                        trace!("Job {} already pending with key {}", id, key);
+                        return Ok(EnqueueOutcome::Deduplicated(id));
+                    }
+                }
+            }
+        }
+
+        if let Some(key) = job.idempotency_key() {
+            if let Some((id, completed_at)) = self.fetch_completed_key(job.queue(), key)? {
+                if completed_at + self.idempotency_retention() > clock::now() {
+                    trace!("Job {} already completed with key {}", id, key);
+                    return Ok(EnqueueOutcome::Deduplicated(id));
+                }
+
+                self.delete_completed_key(job.queue(), key)?;
+            }
+        }
+
         let id = self.generate_id()?;
 
         let job = job.with_id(id);
@@ -80,19 +442,334 @@ pub trait Storage: Clone + Send {
         let queue = job.queue().to_owned();
         self.save_job(job)?;
         self.queue_job(&queue, id)?;
-        self.update_stats(Stats::new_job)?;
+        self.record_stats(StatsEvent::New { queue: &queue })?;
+
+        Ok(EnqueueOutcome::Enqueued(id))
+    }
 
-        Ok(id)
+    /// Store several new jobs at once
+    ///
+    /// This returns the IDs of the jobs in the order they were provided. By default, each job is
+    /// stored with `new_job`, but implementations may override this to batch their writes.
+    fn new_jobs(&mut self, jobs: Vec<NewJobInfo>) -> Result<Vec<u64>, Self::Error> {
+        jobs.into_iter().map(|job| self.new_job(job)).collect()
+    }
+
+    /// Change the priority of a pending job
+    ///
+    /// This returns whether a pending job with the given ID was found. Jobs that are running are
+    /// not changed.
+    fn set_priority(&mut self, id: u64, priority: i32) -> Result<bool, Self::Error> {
+        match self.fetch_job(id)? {
+            Some(mut job) => {
+                if job.is_pending() {
+                    job.set_priority(priority);
+                    self.save_job(job)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Make a pending job ready right away
+    ///
+    /// This clears the time a scheduled job or a retry waiting out its backoff would otherwise
+    /// run at, so it is handed out as soon as a worker is free. This returns whether a pending
+    /// job with the given ID was found. Jobs that are running are not changed.
+    fn retry_now(&mut self, id: u64) -> Result<bool, Self::Error> {
+        match self.fetch_job(id)? {
+            Some(mut job) => {
+                if job.is_pending() {
+                    job.clear_schedule();
+                    self.save_job(job)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Make every pending job that is waiting for a scheduled time ready right away
+    ///
+    /// This is `retry_now` for every scheduled job and retry waiting out its backoff, limited to
+    /// the given queue if there is one. This returns the number of jobs that were made ready.
+    fn retry_now_all(&mut self, queue: Option<&str>) -> Result<usize, Self::Error> {
+        let mut count = 0;
+
+        for mut job in self.list_scheduled()? {
+            if queue.is_none_or(|queue| job.queue() == queue) {
+                job.clear_schedule();
+                self.save_job(job)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Handle a job that was returned because no processor for it is registered
+    ///
+    /// `return_job` follows `MissingProcessorPolicy::Requeue` for these jobs. Servers that let
+    /// operators choose the policy call this instead.
+    fn return_missing_processor(
+        &mut self,
+        id: u64,
+        policy: MissingProcessorPolicy,
+    ) -> Result<(), Self::Error> {
+        let mut job = match self.fetch_job(id)? {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+        let queue = job.queue().to_owned();
+        let processor = job.processor().to_owned();
+
+        match policy {
+            MissingProcessorPolicy::Requeue => {
+                job.pending();
+                self.queue_job(&queue, id)?;
+                self.save_job(job)?;
+                self.record_stats(StatsEvent::Retry {
+                    from: &queue,
+                    to: &queue,
+                })
+            }
+            MissingProcessorPolicy::DeadLetter => {
+                self.delete_job(id)?;
+                self.save_dead_job(job)?;
+                self.record_stats(StatsEvent::Fail {
+                    queue: &queue,
+                    processor: &processor,
+                })
+            }
+            MissingProcessorPolicy::Drop => {
+                self.delete_job(id)?;
+                self.record_stats(StatsEvent::Drop { queue: &queue })
+            }
+        }
+    }
+
+    /// Fail a running job that took longer than it was allowed to
+    ///
+    /// This records a failed attempt, as if the job had returned an error, so the job is retried
+    /// or moved to the dead jobs like any other failure. This returns whether a running job with
+    /// the given ID was found.
+    fn time_out_job(&mut self, id: u64) -> Result<bool, Self::Error> {
+        self.fail_running_job(id)
+    }
+
+    /// Record a failed attempt for a running job whose result will never arrive
+    ///
+    /// The job is retried or moved to the dead jobs like any other failure. This returns whether
+    /// a running job with the given ID was found.
+    fn fail_running_job(&mut self, id: u64) -> Result<bool, Self::Error> {
+        match self.fetch_job(id)? {
+            Some(ref job) if !job.is_pending() => {
+                self.return_job(ReturnJobInfo::fail(id))?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Store the progress saved by a running job
+    ///
+    /// This replaces any progress saved before, and is kept until the job completes, so a retry
+    /// of the job can resume from it. This returns whether a running job with the given ID was
+    /// found.
+    fn save_checkpoint(&mut self, id: u64, checkpoint: Value) -> Result<bool, Self::Error> {
+        match self.fetch_job(id)? {
+            Some(mut job) => {
+                if job.is_pending() {
+                    return Ok(false);
+                }
+
+                job.set_checkpoint(checkpoint);
+                self.save_job(job)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Delete a job that hasn't started running yet
+    ///
+    /// This is meant for jobs made unnecessary before a worker picks them up, like one queued by
+    /// an action a user then undid. Scheduled jobs and jobs waiting for a retry are pending, so
+    /// they can be cancelled too. This returns whether a pending job with the given ID was found
+    /// and deleted, and does nothing for running, dead, or unknown jobs.
+    fn cancel_job(&mut self, id: u64) -> Result<bool, Self::Error> {
+        match self.fetch_job(id)? {
+            Some(job) => {
+                if !job.is_pending() {
+                    return Ok(false);
+                }
+
+                self.delete_job(id)?;
+                self.record_stats(StatsEvent::Cancel { queue: job.queue() })?;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Move a running job back into its queue without recording a result
+    ///
+    /// This is meant for jobs whose runner is gone, so they would otherwise stay running forever.
+    /// The job keeps its retry count, and the `reclaimed` stat is incremented so lost jobs can be
+    /// noticed. This returns whether a running job with the given ID was found.
+    fn reclaim_job(&mut self, id: u64) -> Result<bool, Self::Error> {
+        match self.fetch_job(id)? {
+            Some(mut job) => {
+                if job.is_pending() {
+                    return Ok(false);
+                }
+
+                job.pending();
+
+                let queue = job.queue().to_owned();
+                self.save_job(job)?;
+                self.queue_job(&queue, id)?;
+                self.record_stats(StatsEvent::Reclaim { queue: &queue })?;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Move running jobs that haven't changed state for a while back into their queue
+    ///
+    /// This is meant to be called when a server starts, to recover jobs that were running when a
+    /// previous process died, which would otherwise stay running forever. Every running job that
+    /// was last updated longer ago than `older_than` is reclaimed like with `reclaim_job`. Jobs
+    /// still running in another process are reclaimed too once they are that old, so the
+    /// threshold should be longer than any job takes. This returns the number of jobs that were
+    /// requeued.
+    fn requeue_stale_jobs(&mut self, older_than: Duration) -> Result<usize, Self::Error> {
+        let now = clock::now();
+        let mut count = 0;
+
+        for job in self.list_jobs()? {
+            if job.is_stale(now, older_than) && self.reclaim_job(job.id())? {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Move a dead job back into its queue
+    ///
+    /// This returns whether a dead job with the given ID was found.
+    fn requeue_dead_job(&mut self, id: u64) -> Result<bool, Self::Error> {
+        self.requeue_dead_job_with(id, None, None)
+    }
+
+    /// Move a dead job back into its queue, optionally giving it new retry settings
+    ///
+    /// The job's retry count is reset, so it gets its full retry budget again. This returns
+    /// whether a dead job with the given ID was found.
+    ///
+    /// ```rust
+    /// use background_jobs_core::{
+    ///     memory_storage, Backoff, MaxRetries, NewJobInfo, ReturnJobInfo, Storage,
+    /// };
+    /// use serde_json::json;
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let mut storage = memory_storage::Storage::new();
+    ///     let id = storage.new_job(
+    ///         NewJobInfo::builder("Processor", "default")
+    ///             .args(json!([]))
+    ///             .max_retries(MaxRetries::Count(0))
+    ///             .build()?,
+    ///     )?;
+    ///
+    ///     // Without retries, the first failure is the last one
+    ///     storage.request_job("default", 1)?.unwrap();
+    ///     storage.return_job(ReturnJobInfo::fail(id))?;
+    ///     assert_eq!(storage.fetch_dead_jobs()?.len(), 1);
+    ///
+    ///     let replayed = storage.requeue_dead_job_with(
+    ///         id,
+    ///         Some(MaxRetries::Count(5)),
+    ///         Some(Backoff::Linear(0)),
+    ///     )?;
+    ///     assert!(replayed);
+    ///     assert_eq!(storage.fetch_job(id)?.unwrap().retry_count(), 0);
+    ///
+    ///     // The first attempt and its 5 retries fail, and only then is the job dead again
+    ///     for attempt in 0..6 {
+    ///         assert!(storage.fetch_dead_jobs()?.is_empty(), "Dead after {} attempts", attempt);
+    ///         storage.request_job("default", 1)?.unwrap();
+    ///         storage.return_job(ReturnJobInfo::fail(id))?;
+    ///     }
+    ///     assert_eq!(storage.fetch_dead_jobs()?[0].id(), id);
+    ///     Ok(())
+    /// }
+    /// ```
+    fn requeue_dead_job_with(
+        &mut self,
+        id: u64,
+        max_retries: Option<MaxRetries>,
+        backoff: Option<Backoff>,
+    ) -> Result<bool, Self::Error> {
+        match self.remove_dead_job(id)? {
+            Some(mut job) => {
+                job.revive(max_retries, backoff);
+
+                let queue = job.queue().to_owned();
+                self.save_job(job)?;
+                self.queue_job(&queue, id)?;
+                self.record_stats(StatsEvent::New { queue: &queue })?;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
     fn request_job(&mut self, queue: &str, runner_id: u64) -> Result<Option<JobInfo>, Self::Error> {
+        self.request_job_within(queue, runner_id, usize::MAX)
+    }
+
+    /// Request a job like `request_job`, but only if its weight is at most `max_weight`
+    ///
+    /// If the job that would be returned next is heavier, it is left in its queue and Ok(None) is
+    /// returned.
+    fn request_job_within(
+        &mut self,
+        queue: &str,
+        runner_id: u64,
+        max_weight: usize,
+    ) -> Result<Option<JobInfo>, Self::Error> {
         match self.fetch_job_from_queue(queue)? {
             Some(mut job) => {
-                if job.is_pending() && job.is_ready(Utc::now()) && job.is_in_queue(queue) {
+                let now = clock::now();
+
+                if job.weight() > max_weight {
+                    trace!("Job {} is too heavy to run right now", job.id());
+                    self.queue_job(queue, job.id())?;
+                    Ok(None)
+                } else if job.is_pending() && job.is_ready(now) && job.is_in_queue(queue) {
+                    let wait_time = job.wait_time(now);
+                    trace!(
+                        "Job {} waited {}ms in queue {}",
+                        job.id(),
+                        wait_time.num_milliseconds(),
+                        queue
+                    );
+
                     job.run();
                     self.run_job(job.id(), runner_id)?;
                     self.save_job(job.clone())?;
-                    self.update_stats(Stats::run_job)?;
+                    self.record_stats(StatsEvent::Run { queue, wait_time })?;
 
                     Ok(Some(job))
                 } else {
@@ -109,41 +786,104 @@ pub trait Storage: Clone + Send {
 
     fn return_job(
         &mut self,
-        ReturnJobInfo { id, result }: ReturnJobInfo,
+        ReturnJobInfo {
+            id,
+            result,
+            args,
+            checkpoint,
+        }: ReturnJobInfo,
     ) -> Result<(), Self::Error> {
-        if result.is_failure() {
+        if result.is_failure() || result.retry_after().is_some() {
             if let Some(mut job) = self.fetch_job(id)? {
-                if job.needs_retry() {
-                    self.queue_job(job.queue(), id)?;
+                if let Some(checkpoint) = checkpoint {
+                    job.set_checkpoint(checkpoint);
+                }
+
+                let queue = job.queue().to_owned();
+                let processor = job.processor().to_owned();
+                let should_retry = match result.retry_after() {
+                    Some(after) => job.needs_retry_after(after),
+                    None => job.needs_retry(),
+                };
+
+                if should_retry {
+                    if let Some(args) = args {
+                        job.set_args(args);
+                    }
+
+                    let retry_queue = job.queue().to_owned();
+                    self.queue_job(&retry_queue, id)?;
                     self.save_job(job)?;
-                    self.update_stats(Stats::retry_job)
+                    self.record_stats(StatsEvent::Retry {
+                        from: &queue,
+                        to: &retry_queue,
+                    })
+                } else if job.needs_quarantine() {
+                    let retry_queue = job.queue().to_owned();
+                    self.queue_job(&retry_queue, id)?;
+                    self.save_job(job)?;
+                    self.record_stats(StatsEvent::Quarantine {
+                        from: &queue,
+                        to: &retry_queue,
+                    })
                 } else {
                     self.delete_job(id)?;
-                    self.update_stats(Stats::fail_job)
+                    self.save_dead_job(job)?;
+                    self.record_stats(StatsEvent::Fail {
+                        queue: &queue,
+                        processor: &processor,
+                    })
                 }
             } else {
                 Ok(())
             }
-        } else if result.is_missing_processor() {
+        } else if result.is_unrecoverable() {
             if let Some(mut job) = self.fetch_job(id)? {
-                job.pending();
-                self.queue_job(job.queue(), id)?;
-                self.save_job(job)?;
-                self.update_stats(Stats::retry_job)
+                if let Some(checkpoint) = checkpoint {
+                    job.set_checkpoint(checkpoint);
+                }
+
+                // The failed attempt is still recorded, but retries and quarantine are skipped
+                job.increment();
+
+                let queue = job.queue().to_owned();
+                let processor = job.processor().to_owned();
+                self.delete_job(id)?;
+                self.save_dead_job(job)?;
+                self.record_stats(StatsEvent::Fail {
+                    queue: &queue,
+                    processor: &processor,
+                })
             } else {
                 Ok(())
             }
+        } else if result.is_missing_processor() {
+            self.return_missing_processor(id, MissingProcessorPolicy::Requeue)
         } else {
-            self.delete_job(id)?;
-            self.update_stats(Stats::complete_job)
+            if let Some(job) = self.fetch_job(id)? {
+                if let Some(key) = job.idempotency_key() {
+                    self.save_completed_key(job.queue(), key, id)?;
+                }
+
+                self.delete_job(id)?;
+                self.record_stats(StatsEvent::Complete {
+                    queue: job.queue(),
+                    processor: job.processor(),
+                })
+            } else {
+                Ok(())
+            }
         }
     }
 }
 
 pub mod memory_storage {
-    use super::{JobInfo, Stats};
+    use super::{DispatchOrder, JobInfo, Stats, StatsEvent};
+    use crate::clock::{self, Timestamp};
+    use crate::StatsSink;
     use failure::Fail;
     use std::{
+        cmp::Reverse,
         collections::HashMap,
         fmt,
         sync::{Arc, Mutex},
@@ -152,6 +892,8 @@ pub mod memory_storage {
     #[derive(Clone)]
     pub struct Storage {
         inner: Arc<Mutex<Inner>>,
+        order: DispatchOrder,
+        stats_sink: Option<Arc<dyn StatsSink>>,
     }
 
     #[derive(Clone)]
@@ -159,6 +901,10 @@ pub mod memory_storage {
         count: u64,
         jobs: HashMap<u64, JobInfo>,
         queues: HashMap<u64, String>,
+        unique: HashMap<(String, String), u64>,
+        global: HashMap<String, u64>,
+        completed: HashMap<(String, String), (u64, Timestamp)>,
+        dead: HashMap<u64, JobInfo>,
         worker_ids: HashMap<u64, u64>,
         worker_ids_inverse: HashMap<u64, u64>,
         stats: Stats,
@@ -171,12 +917,30 @@ pub mod memory_storage {
                     count: 0,
                     jobs: HashMap::new(),
                     queues: HashMap::new(),
+                    unique: HashMap::new(),
+                    global: HashMap::new(),
+                    completed: HashMap::new(),
+                    dead: HashMap::new(),
                     worker_ids: HashMap::new(),
                     worker_ids_inverse: HashMap::new(),
                     stats: Stats::default(),
                 })),
+                order: DispatchOrder::default(),
+                stats_sink: None,
             }
         }
+
+        /// Choose the order in which ready jobs of the same priority are handed out
+        pub fn with_dispatch_order(mut self, order: DispatchOrder) -> Self {
+            self.order = order;
+            self
+        }
+
+        /// Keep the stats in the given sink instead of behind the storage's lock
+        pub fn with_stats_sink(mut self, sink: impl StatsSink + 'static) -> Self {
+            self.stats_sink = Some(Arc::new(sink));
+            self
+        }
     }
 
     impl super::Storage for Storage {
@@ -190,7 +954,27 @@ pub mod memory_storage {
         }
 
         fn save_job(&mut self, job: JobInfo) -> Result<(), Self::Error> {
-            self.inner.lock().unwrap().jobs.insert(job.id(), job);
+            let mut inner = self.inner.lock().unwrap();
+
+            if let Some(key) = job.unique_key() {
+                let key = (job.queue().to_owned(), key.to_owned());
+
+                if job.is_pending() {
+                    inner.unique.insert(key, job.id());
+                } else if inner.unique.get(&key) == Some(&job.id()) {
+                    inner.unique.remove(&key);
+                }
+            }
+
+            if let Some(key) = job.global_key() {
+                if job.is_pending() {
+                    inner.global.insert(key.to_owned(), job.id());
+                } else if inner.global.get(key) == Some(&job.id()) {
+                    inner.global.remove(key);
+                }
+            }
+
+            inner.jobs.insert(job.id(), job);
 
             Ok(())
         }
@@ -201,8 +985,56 @@ pub mod memory_storage {
             Ok(j)
         }
 
+        fn list_jobs(&mut self) -> Result<Vec<JobInfo>, Self::Error> {
+            Ok(self.inner.lock().unwrap().jobs.values().cloned().collect())
+        }
+
+        fn fetch_unique_job(&mut self, queue: &str, key: &str) -> Result<Option<u64>, Self::Error> {
+            let key = (queue.to_owned(), key.to_owned());
+
+            Ok(self.inner.lock().unwrap().unique.get(&key).cloned())
+        }
+
+        fn fetch_global_job(&mut self, key: &str) -> Result<Option<u64>, Self::Error> {
+            Ok(self.inner.lock().unwrap().global.get(key).cloned())
+        }
+
+        fn save_completed_key(
+            &mut self,
+            queue: &str,
+            key: &str,
+            id: u64,
+        ) -> Result<(), Self::Error> {
+            let key = (queue.to_owned(), key.to_owned());
+
+            self.inner
+                .lock()
+                .unwrap()
+                .completed
+                .insert(key, (id, clock::now()));
+            Ok(())
+        }
+
+        fn fetch_completed_key(
+            &mut self,
+            queue: &str,
+            key: &str,
+        ) -> Result<Option<(u64, Timestamp)>, Self::Error> {
+            let key = (queue.to_owned(), key.to_owned());
+
+            Ok(self.inner.lock().unwrap().completed.get(&key).cloned())
+        }
+
+        fn delete_completed_key(&mut self, queue: &str, key: &str) -> Result<(), Self::Error> {
+            let key = (queue.to_owned(), key.to_owned());
+
+            self.inner.lock().unwrap().completed.remove(&key);
+            Ok(())
+        }
+
         fn fetch_job_from_queue(&mut self, queue: &str) -> Result<Option<JobInfo>, Self::Error> {
             let mut inner = self.inner.lock().unwrap();
+            let now = clock::now();
 
             let j = inner
                 .queues
@@ -214,7 +1046,8 @@ pub mod memory_storage {
                         None
                     }
                 })
-                .next();
+                .filter(|j| j.is_ready(now))
+                .max_by_key(|j| (j.priority(), Reverse(self.order.key(j))));
 
             if let Some(ref j) = j {
                 inner.queues.remove(&j.id());
@@ -242,7 +1075,21 @@ pub mod memory_storage {
 
         fn delete_job(&mut self, id: u64) -> Result<(), Self::Error> {
             let mut inner = self.inner.lock().unwrap();
-            inner.jobs.remove(&id);
+            if let Some(job) = inner.jobs.remove(&id) {
+                if let Some(key) = job.unique_key() {
+                    let key = (job.queue().to_owned(), key.to_owned());
+
+                    if inner.unique.get(&key) == Some(&id) {
+                        inner.unique.remove(&key);
+                    }
+                }
+
+                if let Some(key) = job.global_key() {
+                    if inner.global.get(key) == Some(&id) {
+                        inner.global.remove(key);
+                    }
+                }
+            }
             inner.queues.remove(&id);
             if let Some(worker_id) = inner.worker_ids.remove(&id) {
                 inner.worker_ids_inverse.remove(&worker_id);
@@ -250,10 +1097,83 @@ pub mod memory_storage {
             Ok(())
         }
 
+        fn save_dead_job(&mut self, job: JobInfo) -> Result<(), Self::Error> {
+            self.inner.lock().unwrap().dead.insert(job.id(), job);
+            Ok(())
+        }
+
+        fn fetch_dead_jobs(&mut self) -> Result<Vec<JobInfo>, Self::Error> {
+            Ok(self.inner.lock().unwrap().dead.values().cloned().collect())
+        }
+
+        fn remove_dead_job(&mut self, id: u64) -> Result<Option<JobInfo>, Self::Error> {
+            Ok(self.inner.lock().unwrap().dead.remove(&id))
+        }
+
+        fn purge_jobs(&mut self) -> Result<(), Self::Error> {
+            let mut inner = self.inner.lock().unwrap();
+
+            inner.jobs.clear();
+            inner.queues.clear();
+            inner.unique.clear();
+            inner.global.clear();
+            inner.completed.clear();
+            inner.dead.clear();
+            inner.worker_ids.clear();
+            inner.worker_ids_inverse.clear();
+            Ok(())
+        }
+
+        fn health_check(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn retry_now_all(&mut self, queue: Option<&str>) -> Result<usize, Self::Error> {
+            let now = clock::now();
+            let mut inner = self.inner.lock().unwrap();
+
+            let mut count = 0;
+            for job in inner.jobs.values_mut() {
+                if job.is_scheduled(now) && queue.is_none_or(|queue| job.queue() == queue) {
+                    job.clear_schedule();
+                    count += 1;
+                }
+            }
+
+            Ok(count)
+        }
+
+        fn next_wakeup(&self) -> Result<Option<Timestamp>, Self::Error> {
+            let now = clock::now();
+
+            Ok(self
+                .inner
+                .lock()
+                .unwrap()
+                .jobs
+                .values()
+                .filter(|job| job.is_scheduled(now))
+                .filter_map(|job| job.scheduled_for())
+                .min())
+        }
+
         fn get_stats(&self) -> Result<Stats, Self::Error> {
+            if let Some(ref sink) = self.stats_sink {
+                return Ok(sink.snapshot());
+            }
+
             Ok(self.inner.lock().unwrap().stats.clone())
         }
 
+        fn record_stats(&mut self, event: StatsEvent) -> Result<(), Self::Error> {
+            if let Some(ref sink) = self.stats_sink {
+                sink.record(&event);
+                return Ok(());
+            }
+
+            self.update_stats(|stats| stats.apply(&event))
+        }
+
         fn update_stats<F>(&mut self, f: F) -> Result<(), Self::Error>
         where
             F: Fn(Stats) -> Stats,
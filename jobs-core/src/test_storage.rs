@@ -0,0 +1,731 @@
+/*
+ * This file is part of Background Jobs.
+ *
+ * Copyright © 2019 Riley Trautman
+ *
+ * Background Jobs is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Background Jobs is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Background Jobs.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::{thread, time::Duration};
+
+use failure::Fail;
+use serde_json::json;
+
+use crate::{
+    clock::{self, TimeDelta},
+    Backoff, EnqueueOutcome, JobInfo, MaxRetries, NewJobInfo, NewJobInfoBuilder, ReturnJobInfo,
+    Storage,
+};
+
+const PROCESSOR: &str = "TestStorageProcessor";
+
+/// Check that a `Storage` implementation behaves the way the job servers expect
+///
+/// This is meant to be called from the tests of a custom storage backend. Each check uses its own
+/// queue, named with a `test-storage-` prefix, so the storage doesn't need to be empty, but it
+/// shouldn't be shared with running workers, and jobs it already has running are requeued by the
+/// stale job check. The checks cover:
+/// - jobs are handed out in priority order, then in the order they were created
+/// - a pending job's priority can be changed, and a running job's can't
+/// - a job that is running isn't handed out again
+/// - retried jobs return to their queue, and jobs without retries left become dead
+/// - retried jobs with a retry queue are moved to it
+/// - reclaimed running jobs return to their queue, and are counted as reclaimed
+/// - running jobs are requeued once they are stale, and not before
+/// - pending jobs can be cancelled, and running jobs can't
+/// - scheduled jobs stay queued until they're ready, without blocking ready jobs behind them
+/// - a retry waiting out a long backoff can be made ready right away, and a running job can't
+/// - scheduled jobs can all be made ready at once
+/// - only jobs scheduled for later are listed as scheduled, and they can be cancelled
+/// - the next wakeup, if the storage computes one, is no later than the earliest scheduled job
+/// - pending jobs with the same global key are coalesced, across processors and queues
+/// - jobs resubmitted with the idempotency key of a completed job aren't run again
+/// - quarantined jobs are replayed once after their delay, then become dead
+/// - the per-queue stats follow each of these transitions, and the per-processor stats count
+///   completed and dead jobs
+///
+/// The scheduled and quarantined job checks wait for a short while, so this takes about a
+/// second.
+///
+/// # Panics
+/// This panics with a description of the first broken expectation, or of the first error
+/// returned by the storage.
+///
+/// ```rust
+/// use background_jobs_core::{memory_storage, test_storage};
+///
+/// test_storage(memory_storage::Storage::new());
+/// ```
+pub fn test_storage<S>(storage: S)
+where
+    S: Storage,
+{
+    check_order(storage.clone());
+    check_set_priority(storage.clone());
+    check_running(storage.clone());
+    check_retries(storage.clone());
+    check_retry_queue(storage.clone());
+    check_reclaim(storage.clone());
+    check_stale(storage.clone());
+    check_cancel(storage.clone());
+    check_retry_now(storage.clone());
+    check_retry_now_all(storage.clone());
+    check_list_scheduled(storage.clone());
+    check_next_wakeup(storage.clone());
+    check_global_key(storage.clone());
+    check_idempotency_key(storage.clone());
+    check_scheduled(storage.clone());
+    check_quarantine(storage);
+}
+
+fn check_order<S: Storage>(mut storage: S) {
+    let queue = "test-storage-order";
+
+    let first = new_job(&mut storage, builder(queue));
+    let second = new_job(&mut storage, builder(queue));
+    let urgent = new_job(&mut storage, builder(queue).priority(1));
+    assert_queue_stat(&storage, queue, 3, 0);
+
+    for (runner_id, expected) in [urgent, first, second].iter().enumerate() {
+        let job = request_job(&mut storage, queue, runner_id as u64)
+            .unwrap_or_else(|| panic!("Expected job {} to be ready in {}", expected, queue));
+
+        assert_eq!(
+            job.id(),
+            *expected,
+            "Jobs should be handed out by priority, then by creation order"
+        );
+    }
+    assert_queue_stat(&storage, queue, 0, 3);
+
+    for id in &[urgent, first, second] {
+        return_job(&mut storage, ReturnJobInfo::pass(*id));
+    }
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_set_priority<S: Storage>(mut storage: S) {
+    let queue = "test-storage-set-priority";
+
+    let first = new_job(&mut storage, builder(queue));
+    let second = new_job(&mut storage, builder(queue));
+    let bumped = new_job(&mut storage, builder(queue));
+
+    assert!(
+        check(storage.set_priority(bumped, 1), "set_priority"),
+        "Pending jobs should have their priority changed"
+    );
+    assert_eq!(
+        fetch_job(&mut storage, bumped).map(|job| job.priority()),
+        Some(1),
+        "Changed priorities should be stored"
+    );
+
+    for (runner_id, expected) in [bumped, first, second].iter().enumerate() {
+        let job = request_job(&mut storage, queue, runner_id as u64)
+            .unwrap_or_else(|| panic!("Expected job {} to be ready in {}", expected, queue));
+
+        assert_eq!(
+            job.id(),
+            *expected,
+            "Jobs should be handed out by their changed priority"
+        );
+    }
+
+    assert!(
+        !check(storage.set_priority(first, 2), "set_priority"),
+        "Running jobs should not have their priority changed"
+    );
+    assert_eq!(
+        fetch_job(&mut storage, first).map(|job| job.priority()),
+        Some(0),
+        "Running jobs should keep their priority"
+    );
+
+    for id in &[bumped, first, second] {
+        return_job(&mut storage, ReturnJobInfo::pass(*id));
+    }
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_running<S: Storage>(mut storage: S) {
+    let queue = "test-storage-running";
+
+    let id = new_job(&mut storage, builder(queue));
+
+    let job = request_job(&mut storage, queue, 1);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(id),
+        "New jobs should be ready"
+    );
+    assert_queue_stat(&storage, queue, 0, 1);
+
+    assert!(
+        request_job(&mut storage, queue, 2).is_none(),
+        "Running jobs should not be handed out again"
+    );
+    let job = fetch_job(&mut storage, id).expect("Running jobs should be stored");
+    assert!(
+        !job.is_pending(),
+        "Requested jobs should be marked as running"
+    );
+
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+    assert!(
+        fetch_job(&mut storage, id).is_none(),
+        "Completed jobs should be deleted"
+    );
+    assert!(
+        request_job(&mut storage, queue, 1).is_none(),
+        "Completed jobs should not be handed out again"
+    );
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_retries<S: Storage>(mut storage: S) {
+    let queue = "test-storage-retries";
+
+    let builder = builder(queue)
+        .max_retries(MaxRetries::Count(1))
+        .backoff(Backoff::Linear(0));
+    let id = new_job(&mut storage, builder);
+    let (completed, died) = processor_counts(&storage);
+
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+    return_job(&mut storage, ReturnJobInfo::fail(id));
+    assert_queue_stat(&storage, queue, 1, 0);
+
+    let job = fetch_job(&mut storage, id).expect("Retried jobs should be stored");
+    assert!(job.is_pending(), "Retried jobs should be pending");
+
+    // Retries are ready strictly after their backoff has passed
+    thread::sleep(Duration::from_millis(10));
+
+    let job = request_job(&mut storage, queue, 1);
+    assert_eq!(job.map(|job| job.id()), Some(id), "Retries should be ready");
+    return_job(&mut storage, ReturnJobInfo::fail(id));
+    assert_queue_stat(&storage, queue, 0, 0);
+    assert_eq!(
+        processor_counts(&storage),
+        (completed, died + 1),
+        "Dead jobs should be counted for their processor"
+    );
+
+    assert!(
+        fetch_job(&mut storage, id).is_none(),
+        "Jobs without retries left should be deleted"
+    );
+    assert!(
+        request_job(&mut storage, queue, 1).is_none(),
+        "Dead jobs should not be handed out"
+    );
+    let dead = check(storage.fetch_dead_jobs(), "fetch_dead_jobs");
+    assert!(
+        dead.iter().any(|job| job.id() == id),
+        "Jobs without retries left should be kept as dead jobs"
+    );
+
+    assert!(
+        check(storage.requeue_dead_job(id), "requeue_dead_job"),
+        "Dead jobs should be found by requeue_dead_job"
+    );
+    assert_queue_stat(&storage, queue, 1, 0);
+
+    request_job(&mut storage, queue, 1).expect("Requeued dead jobs should be ready");
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+    assert_queue_stat(&storage, queue, 0, 0);
+    assert_eq!(
+        processor_counts(&storage),
+        (completed + 1, died + 1),
+        "Completed jobs should be counted for their processor"
+    );
+}
+
+fn check_retry_queue<S: Storage>(mut storage: S) {
+    let (queue, retry_queue) = ("test-storage-retry-from", "test-storage-retry-to");
+
+    let id = new_job(
+        &mut storage,
+        builder(queue)
+            .max_retries(MaxRetries::Count(1))
+            .retry_queue(retry_queue),
+    );
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+    return_job(&mut storage, ReturnJobInfo::fail(id));
+
+    let job = fetch_job(&mut storage, id).expect("Retried jobs should be stored");
+    assert_eq!(
+        job.queue(),
+        retry_queue,
+        "Retried jobs should be moved to their retry queue"
+    );
+    assert!(
+        request_job(&mut storage, queue, 1).is_none(),
+        "Retried jobs should not be handed out from their original queue"
+    );
+    assert_queue_stat(&storage, queue, 0, 0);
+    assert_queue_stat(&storage, retry_queue, 1, 0);
+
+    let job = request_job(&mut storage, retry_queue, 1);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(id),
+        "Retried jobs should be handed out from their retry queue"
+    );
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+    assert_queue_stat(&storage, retry_queue, 0, 0);
+}
+
+fn check_reclaim<S: Storage>(mut storage: S) {
+    let queue = "test-storage-reclaim";
+
+    let id = new_job(&mut storage, builder(queue));
+    assert!(
+        !check(storage.reclaim_job(id), "reclaim_job"),
+        "Pending jobs should not be reclaimed"
+    );
+
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+    let before = reclaimed(&storage);
+
+    assert!(
+        check(storage.reclaim_job(id), "reclaim_job"),
+        "Running jobs should be reclaimed"
+    );
+    assert_queue_stat(&storage, queue, 1, 0);
+    assert_eq!(
+        reclaimed(&storage),
+        before + 1,
+        "Reclaimed jobs should be counted"
+    );
+
+    let job = request_job(&mut storage, queue, 2);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(id),
+        "Reclaimed jobs should be ready again"
+    );
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_stale<S: Storage>(mut storage: S) {
+    let queue = "test-storage-stale";
+    let age = Duration::from_millis(50);
+
+    let id = new_job(&mut storage, builder(queue));
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+
+    check(
+        storage.requeue_stale_jobs(Duration::from_secs(3600)),
+        "requeue_stale_jobs",
+    );
+    let job = fetch_job(&mut storage, id).expect("Running jobs should be stored");
+    assert!(
+        !job.is_pending(),
+        "Recent running jobs should not be requeued"
+    );
+
+    thread::sleep(age + Duration::from_millis(10));
+    let before = reclaimed(&storage);
+
+    let requeued = check(storage.requeue_stale_jobs(age), "requeue_stale_jobs");
+    assert!(requeued >= 1, "Stale running jobs should be requeued");
+    assert_queue_stat(&storage, queue, 1, 0);
+    assert!(
+        reclaimed(&storage) > before,
+        "Requeued stale jobs should be counted as reclaimed"
+    );
+
+    let job = request_job(&mut storage, queue, 2);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(id),
+        "Requeued stale jobs should be ready again"
+    );
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_cancel<S: Storage>(mut storage: S) {
+    let queue = "test-storage-cancel";
+
+    let pending = new_job(&mut storage, builder(queue));
+    let running = new_job(&mut storage, builder(queue).priority(1));
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+
+    assert!(
+        check(storage.cancel_job(pending), "cancel_job"),
+        "Pending jobs should be cancelled"
+    );
+    assert!(
+        fetch_job(&mut storage, pending).is_none(),
+        "Cancelled jobs should be deleted"
+    );
+    assert!(
+        !check(storage.cancel_job(running), "cancel_job"),
+        "Running jobs should not be cancelled"
+    );
+    assert!(
+        !check(storage.cancel_job(pending), "cancel_job"),
+        "Missing jobs should not be cancelled"
+    );
+    assert_queue_stat(&storage, queue, 0, 1);
+
+    return_job(&mut storage, ReturnJobInfo::pass(running));
+    assert!(
+        request_job(&mut storage, queue, 1).is_none(),
+        "Cancelled jobs should not be handed out"
+    );
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_retry_now<S: Storage>(mut storage: S) {
+    let queue = "test-storage-retry-now";
+
+    let builder = builder(queue)
+        .max_retries(MaxRetries::Count(1))
+        .backoff(Backoff::Linear(2 * 60 * 60));
+    let id = new_job(&mut storage, builder);
+
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+    assert!(
+        !check(storage.retry_now(id), "retry_now"),
+        "Running jobs should not be made ready"
+    );
+    return_job(&mut storage, ReturnJobInfo::fail(id));
+    assert!(
+        request_job(&mut storage, queue, 1).is_none(),
+        "Retries should wait out their backoff"
+    );
+
+    assert!(
+        check(storage.retry_now(id), "retry_now"),
+        "Retries should be found by retry_now"
+    );
+    let job = request_job(&mut storage, queue, 1);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(id),
+        "Retries made ready should be handed out"
+    );
+
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_retry_now_all<S: Storage>(mut storage: S) {
+    let queue = "test-storage-retry-now-all";
+
+    let run_at = clock::now() + TimeDelta::hours(1);
+    let ids: Vec<_> = (0..3)
+        .map(|_| new_job(&mut storage, builder(queue).schedule(run_at)))
+        .collect();
+
+    assert_eq!(
+        check(storage.retry_now_all(Some(queue)), "retry_now_all"),
+        ids.len(),
+        "Every scheduled job in the queue should be made ready"
+    );
+
+    for (runner_id, id) in ids.iter().enumerate() {
+        let job = request_job(&mut storage, queue, runner_id as u64);
+        assert_eq!(
+            job.map(|job| job.id()),
+            Some(*id),
+            "Jobs made ready should be handed out"
+        );
+    }
+
+    for id in ids {
+        return_job(&mut storage, ReturnJobInfo::pass(id));
+    }
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_list_scheduled<S: Storage>(mut storage: S) {
+    let queue = "test-storage-list-scheduled";
+
+    let run_at = clock::now() + TimeDelta::hours(1);
+    let later = new_job(&mut storage, builder(queue).schedule(run_at));
+    let much_later = new_job(
+        &mut storage,
+        builder(queue).schedule(run_at + TimeDelta::hours(1)),
+    );
+    let ready = new_job(&mut storage, builder(queue));
+
+    let scheduled = |storage: &mut S| {
+        let mut ids: Vec<_> = check(storage.list_scheduled(), "list_scheduled")
+            .into_iter()
+            .filter(|job| job.queue() == queue)
+            .map(|job| job.id())
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    assert_eq!(
+        scheduled(&mut storage),
+        vec![later, much_later],
+        "Only jobs scheduled for later should be listed as scheduled"
+    );
+
+    assert!(
+        check(storage.cancel_job(later), "cancel_job"),
+        "Scheduled jobs should be cancelled"
+    );
+    assert_eq!(
+        scheduled(&mut storage),
+        vec![much_later],
+        "Cancelled jobs should not be listed as scheduled"
+    );
+
+    check(storage.retry_now_all(Some(queue)), "retry_now_all");
+    for (runner_id, id) in [much_later, ready].iter().enumerate() {
+        let job = request_job(&mut storage, queue, runner_id as u64);
+        assert_eq!(
+            job.map(|job| job.id()),
+            Some(*id),
+            "Jobs made ready should be handed out"
+        );
+        return_job(&mut storage, ReturnJobInfo::pass(*id));
+    }
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_next_wakeup<S: Storage>(mut storage: S) {
+    let queue = "test-storage-next-wakeup";
+
+    let soon = clock::now() + TimeDelta::minutes(1);
+    let later = new_job(
+        &mut storage,
+        builder(queue).schedule(soon + TimeDelta::hours(1)),
+    );
+    let earliest = new_job(&mut storage, builder(queue).schedule(soon));
+
+    // Backends may round to the millisecond, and other scheduled jobs may wake up earlier
+    if let Some(wakeup) = check(storage.next_wakeup(), "next_wakeup") {
+        assert!(
+            wakeup <= soon + TimeDelta::milliseconds(1),
+            "The next wakeup should be no later than the earliest scheduled job"
+        );
+    }
+
+    check(storage.retry_now_all(Some(queue)), "retry_now_all");
+    for (runner_id, id) in [later, earliest].iter().enumerate() {
+        request_job(&mut storage, queue, runner_id as u64)
+            .expect("Jobs made ready should be ready");
+        return_job(&mut storage, ReturnJobInfo::pass(*id));
+    }
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_global_key<S: Storage>(mut storage: S) {
+    let (queue, other_queue) = ("test-storage-global", "test-storage-global-other");
+    let key = "test-storage-global-key";
+
+    let id = new_job(&mut storage, builder(queue).global_key(key));
+    let other = NewJobInfo::builder("OtherTestStorageProcessor", other_queue)
+        .args(json!(["different"]))
+        .global_key(key);
+    assert_eq!(
+        new_job(&mut storage, other),
+        id,
+        "Jobs with the global key of a pending job should be coalesced with it"
+    );
+    assert_queue_stat(&storage, queue, 1, 0);
+    assert_queue_stat(&storage, other_queue, 0, 0);
+
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+    let again = new_job(&mut storage, builder(other_queue).global_key(key));
+    assert_ne!(
+        again, id,
+        "Jobs with the global key of a running job should be stored"
+    );
+
+    request_job(&mut storage, other_queue, 2).expect("New jobs should be ready");
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+    return_job(&mut storage, ReturnJobInfo::pass(again));
+    assert_queue_stat(&storage, queue, 0, 0);
+    assert_queue_stat(&storage, other_queue, 0, 0);
+}
+
+fn check_idempotency_key<S: Storage>(mut storage: S) {
+    let queue = "test-storage-idempotency";
+
+    // Completed keys outlive the jobs, so each run of the check needs its own key
+    let key = format!(
+        "test-storage-idempotency-{}",
+        check(storage.generate_id(), "generate_id")
+    );
+    let keyed = || builder(queue).idempotency_key(&key);
+
+    let id = new_job(&mut storage, keyed());
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+    return_job(&mut storage, ReturnJobInfo::pass(id));
+
+    let job = keyed().build().expect("Test jobs are valid");
+    assert_eq!(
+        check(storage.enqueue(job), "enqueue"),
+        EnqueueOutcome::Deduplicated(id),
+        "Jobs with the idempotency key of a completed job should be recognized as complete"
+    );
+    assert!(
+        request_job(&mut storage, queue, 1).is_none(),
+        "Jobs recognized as complete should not be run again"
+    );
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_scheduled<S: Storage>(mut storage: S) {
+    let queue = "test-storage-scheduled";
+    let delay = Duration::from_millis(500);
+
+    let run_at = clock::now() + TimeDelta::from_std(delay).expect("Delay fits in a TimeDelta");
+    let scheduled = new_job(&mut storage, builder(queue).schedule(run_at));
+    let ready = new_job(&mut storage, builder(queue));
+
+    let job = request_job(&mut storage, queue, 1);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(ready),
+        "Scheduled jobs should not block ready jobs in the same queue"
+    );
+    assert!(
+        request_job(&mut storage, queue, 2).is_none(),
+        "Scheduled jobs should not be handed out early"
+    );
+    return_job(&mut storage, ReturnJobInfo::pass(ready));
+
+    thread::sleep(delay + Duration::from_millis(10));
+
+    let job = request_job(&mut storage, queue, 2);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(scheduled),
+        "Scheduled jobs should stay queued until they are ready"
+    );
+    return_job(&mut storage, ReturnJobInfo::pass(scheduled));
+    assert_queue_stat(&storage, queue, 0, 0);
+}
+
+fn check_quarantine<S: Storage>(mut storage: S) {
+    let queue = "test-storage-quarantine";
+    let delay = Duration::from_millis(500);
+
+    let id = new_job(&mut storage, builder(queue).quarantine(delay));
+    let before = quarantined(&storage);
+
+    request_job(&mut storage, queue, 1).expect("New jobs should be ready");
+    return_job(&mut storage, ReturnJobInfo::fail(id));
+    assert_queue_stat(&storage, queue, 1, 0);
+    assert_eq!(
+        quarantined(&storage),
+        before + 1,
+        "Quarantined jobs should be counted"
+    );
+
+    let job = fetch_job(&mut storage, id).expect("Quarantined jobs should be stored");
+    assert!(
+        job.is_pending() && job.is_quarantined(),
+        "Jobs out of retries with a quarantine should be quarantined"
+    );
+    assert!(
+        request_job(&mut storage, queue, 1).is_none(),
+        "Quarantined jobs should not be replayed before their delay"
+    );
+
+    thread::sleep(delay + Duration::from_millis(10));
+
+    let job = request_job(&mut storage, queue, 1);
+    assert_eq!(
+        job.map(|job| job.id()),
+        Some(id),
+        "Quarantined jobs should be replayed after their delay"
+    );
+    return_job(&mut storage, ReturnJobInfo::fail(id));
+    assert_queue_stat(&storage, queue, 0, 0);
+
+    assert!(
+        fetch_job(&mut storage, id).is_none(),
+        "Jobs failing their replay should be deleted"
+    );
+    let dead = check(storage.fetch_dead_jobs(), "fetch_dead_jobs");
+    assert!(
+        dead.iter().any(|job| job.id() == id),
+        "Jobs failing their replay should be kept as dead jobs"
+    );
+}
+
+fn builder(queue: &str) -> NewJobInfoBuilder {
+    NewJobInfo::builder(PROCESSOR, queue)
+        .args(json!([]))
+        .max_retries(MaxRetries::Count(0))
+        .backoff(Backoff::Linear(0))
+}
+
+fn new_job<S: Storage>(storage: &mut S, builder: NewJobInfoBuilder) -> u64 {
+    let job = builder.build().expect("Test jobs are valid");
+
+    check(storage.new_job(job), "new_job")
+}
+
+fn request_job<S: Storage>(storage: &mut S, queue: &str, runner_id: u64) -> Option<JobInfo> {
+    check(storage.request_job(queue, runner_id), "request_job")
+}
+
+fn return_job<S: Storage>(storage: &mut S, job: ReturnJobInfo) {
+    check(storage.return_job(job), "return_job")
+}
+
+fn fetch_job<S: Storage>(storage: &mut S, id: u64) -> Option<JobInfo> {
+    check(storage.fetch_job(id), "fetch_job")
+}
+
+fn assert_queue_stat<S: Storage>(storage: &S, queue: &str, pending: usize, running: usize) {
+    let stats = check(storage.get_stats(), "get_stats");
+    let stat = stats.queues.get(queue).cloned().unwrap_or_default();
+
+    assert_eq!(
+        (stat.pending, stat.running),
+        (pending, running),
+        "Unexpected (pending, running) stats for {}",
+        queue
+    );
+}
+
+fn reclaimed<S: Storage>(storage: &S) -> usize {
+    check(storage.get_stats(), "get_stats").reclaimed.all_time()
+}
+
+fn processor_counts<S: Storage>(storage: &S) -> (usize, usize) {
+    let stats = check(storage.get_stats(), "get_stats");
+    let stat = stats
+        .processor_stats(PROCESSOR)
+        .cloned()
+        .unwrap_or_default();
+
+    (stat.complete.all_time(), stat.dead.all_time())
+}
+
+fn quarantined<S: Storage>(storage: &S) -> usize {
+    check(storage.get_stats(), "get_stats")
+        .quarantined
+        .all_time()
+}
+
+fn check<T, E: Fail>(res: Result<T, E>, method: &str) -> T {
+    res.unwrap_or_else(|e| panic!("Storage::{} failed: {}", method, e))
+}
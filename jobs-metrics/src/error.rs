@@ -0,0 +1,15 @@
+use failure::Fail;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "Error in prometheus: {}", _0)]
+    Prometheus(#[cause] prometheus::Error),
+}
+
+impl From<prometheus::Error> for Error {
+    fn from(e: prometheus::Error) -> Self {
+        Error::Prometheus(e)
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use background_jobs_core::{AtomicStats, Stats, StatsEvent, StatsSink};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+mod error;
+
+pub use self::error::Error;
+
+use self::error::Result;
+
+/// Prometheus metrics for the stats of a storage
+///
+/// The metrics are registered in their own `Registry` unless another one is given, and set from
+/// a `Stats` value with `observe`. They are:
+/// - `background_jobs_pending` and `background_jobs_running`, the number of pending and running
+///   jobs
+/// - `background_jobs_complete_total` and `background_jobs_dead_total`, the number of jobs that
+///   completed or became dead
+/// - `background_jobs_queue_pending` and `background_jobs_queue_running`, the same counts for
+///   each queue, labelled with `queue`
+/// - `background_jobs_processor_complete_total` and `background_jobs_processor_dead_total`, the
+///   same totals for each processor, labelled with `processor`
+///
+/// Every clone shares the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registry: Registry,
+    pending: IntGauge,
+    running: IntGauge,
+    complete: IntCounter,
+    dead: IntCounter,
+    queue_pending: IntGaugeVec,
+    queue_running: IntGaugeVec,
+    processor_complete: IntCounterVec,
+    processor_dead: IntCounterVec,
+    // The queues that were set by the last observation, so queues that went away can be zeroed.
+    // Holding this lock also keeps observations from interleaving.
+    queues: Mutex<BTreeSet<String>>,
+}
+
+impl Metrics {
+    /// Create the metrics in a new registry
+    pub fn new() -> Result<Self> {
+        Self::register(Registry::new())
+    }
+
+    /// Create the metrics in the given registry, next to the application's own metrics
+    pub fn register(registry: Registry) -> Result<Self> {
+        let inner = Inner {
+            pending: IntGauge::new("background_jobs_pending", "Jobs waiting to run")?,
+            running: IntGauge::new("background_jobs_running", "Jobs being run")?,
+            complete: IntCounter::new("background_jobs_complete_total", "Jobs that completed")?,
+            dead: IntCounter::new("background_jobs_dead_total", "Jobs that ran out of retries")?,
+            queue_pending: IntGaugeVec::new(
+                Opts::new(
+                    "background_jobs_queue_pending",
+                    "Jobs waiting to run in a queue",
+                ),
+                &["queue"],
+            )?,
+            queue_running: IntGaugeVec::new(
+                Opts::new(
+                    "background_jobs_queue_running",
+                    "Jobs being run from a queue",
+                ),
+                &["queue"],
+            )?,
+            processor_complete: IntCounterVec::new(
+                Opts::new(
+                    "background_jobs_processor_complete_total",
+                    "Jobs of a processor that completed",
+                ),
+                &["processor"],
+            )?,
+            processor_dead: IntCounterVec::new(
+                Opts::new(
+                    "background_jobs_processor_dead_total",
+                    "Jobs of a processor that ran out of retries",
+                ),
+                &["processor"],
+            )?,
+            queues: Mutex::new(BTreeSet::new()),
+            registry,
+        };
+
+        inner.registry.register(Box::new(inner.pending.clone()))?;
+        inner.registry.register(Box::new(inner.running.clone()))?;
+        inner.registry.register(Box::new(inner.complete.clone()))?;
+        inner.registry.register(Box::new(inner.dead.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.queue_pending.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.queue_running.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.processor_complete.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.processor_dead.clone()))?;
+
+        Ok(Metrics {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// The registry the metrics are in
+    pub fn registry(&self) -> &Registry {
+        &self.inner.registry
+    }
+
+    /// Set the metrics from the given stats
+    ///
+    /// Totals only ever go up, as Prometheus counters do, so stats with a lower total than an
+    /// earlier observation leave it as it is.
+    pub fn observe(&self, stats: &Stats) {
+        let mut queues = self.inner.queues.lock().unwrap();
+
+        self.update(&mut queues, stats);
+    }
+
+    /// Every metric in the registry, in the Prometheus text format
+    ///
+    /// This is what a `/metrics` endpoint should respond with.
+    pub fn gather(&self) -> Result<String> {
+        let families = self.inner.registry.gather();
+
+        Ok(TextEncoder::new().encode_to_string(&families)?)
+    }
+
+    fn update(&self, queues: &mut BTreeSet<String>, stats: &Stats) {
+        let inner = &self.inner;
+
+        inner.pending.set(stats.pending as i64);
+        inner.running.set(stats.running as i64);
+        advance(&inner.complete, stats.complete.all_time());
+        advance(&inner.dead, stats.dead.all_time());
+
+        for queue in queues.iter() {
+            if !stats.queues.contains_key(queue) {
+                inner.queue_pending.with_label_values(&[queue]).set(0);
+                inner.queue_running.with_label_values(&[queue]).set(0);
+            }
+        }
+
+        for (queue, stat) in &stats.queues {
+            let labels = [queue.as_str()];
+
+            inner
+                .queue_pending
+                .with_label_values(&labels)
+                .set(stat.pending as i64);
+            inner
+                .queue_running
+                .with_label_values(&labels)
+                .set(stat.running as i64);
+        }
+
+        for (processor, stat) in &stats.processors {
+            let labels = [processor.as_str()];
+
+            advance(
+                &inner.processor_complete.with_label_values(&labels),
+                stat.complete.all_time(),
+            );
+            advance(
+                &inner.processor_dead.with_label_values(&labels),
+                stat.dead.all_time(),
+            );
+        }
+
+        *queues = stats.queues.keys().cloned().collect();
+    }
+}
+
+/// A `StatsSink` that keeps a set of `Metrics` up to date with the sink it wraps
+///
+/// Every change is recorded by the wrapped sink, and the metrics are then set from its
+/// snapshot, so they follow the stats without anything polling the storage. Recording a change
+/// takes a lock shared by every clone for as long as this takes, which serializes changes that
+/// `AtomicStats` would otherwise record concurrently.
+///
+/// ```rust
+/// use background_jobs_core::{memory_storage, AtomicStats, StatsEvent, StatsSink};
+/// use background_jobs_metrics::MetricsSink;
+///
+/// fn main() -> Result<(), failure::Error> {
+///     let sink = MetricsSink::new(AtomicStats::new())?;
+///     let metrics = sink.metrics();
+///
+///     let _storage = memory_storage::Storage::new().with_stats_sink(sink.clone());
+///
+///     // The storage records a change like this one whenever a job is stored
+///     sink.record(&StatsEvent::New { queue: "default" });
+///
+///     let text = metrics.gather()?;
+///     assert!(text.contains("background_jobs_pending 1"));
+///     assert!(text.contains("background_jobs_queue_pending{queue=\"default\"} 1"));
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MetricsSink<S = AtomicStats> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S> MetricsSink<S>
+where
+    S: StatsSink,
+{
+    /// Wrap a sink, with the metrics in a new registry
+    pub fn new(inner: S) -> Result<Self> {
+        Ok(Self::with_metrics(inner, Metrics::new()?))
+    }
+
+    /// Wrap a sink, keeping the given metrics up to date
+    ///
+    /// The metrics are set from the sink's current stats right away, so a sink that already has
+    /// counts doesn't report zeroes until its next change.
+    pub fn with_metrics(inner: S, metrics: Metrics) -> Self {
+        metrics.observe(&inner.snapshot());
+
+        MetricsSink { inner, metrics }
+    }
+
+    /// The metrics kept up to date by this sink
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+}
+
+impl<S> StatsSink for MetricsSink<S>
+where
+    S: StatsSink,
+{
+    fn record(&self, event: &StatsEvent) {
+        // The snapshot is taken under the lock, so an older snapshot can't overwrite a newer one
+        let mut queues = self.metrics.inner.queues.lock().unwrap();
+
+        self.inner.record(event);
+        self.metrics.update(&mut queues, &self.inner.snapshot());
+    }
+
+    fn snapshot(&self) -> Stats {
+        self.inner.snapshot()
+    }
+}
+
+fn advance(counter: &IntCounter, total: usize) {
+    let total = total as u64;
+    let current = counter.get();
+
+    if total > current {
+        counter.inc_by(total - current);
+    }
+}
@@ -0,0 +1,21 @@
+use failure::Fail;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "Error in redis: {}", _0)]
+    Redis(#[cause] redis::RedisError),
+
+    #[fail(display = "Failed to deserialize data")]
+    Deserialize,
+
+    #[fail(display = "Failed to serialize data")]
+    Serialize,
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(e: redis::RedisError) -> Self {
+        Error::Redis(e)
+    }
+}
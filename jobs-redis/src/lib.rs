@@ -0,0 +1,521 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use background_jobs_core::{
+    clock::{self, TimeDelta, Timestamp},
+    DispatchOrder, JobInfo, Stats, StatsEvent, StatsSink, Storage,
+};
+use redis::Commands;
+use serde::{de::DeserializeOwned, ser::Serialize};
+
+mod error;
+mod scripts;
+
+pub use self::error::Error;
+
+use self::{error::Result, scripts::Scripts};
+
+/// The prefix of every key written by the storage, unless another one is chosen
+const DEFAULT_PREFIX: &str = "background-jobs";
+
+/// The most scheduled jobs that are moved to their queue's ready jobs when a job is requested
+///
+/// This bounds the work done by each request when many scheduled jobs become ready at once. The
+/// ones left behind are moved by the following requests, so a higher priority job among them may
+/// run after lower priority jobs that were ready sooner.
+const PROMOTE_LIMIT: usize = 100;
+
+/// A `Storage` implementation backed by a Redis server
+///
+/// Unlike a Sled database, a Redis server can be shared by several processes, so every instance
+/// of an application running behind a load balancer can use the same job store. Jobs are handed
+/// out by a Lua script that Redis runs atomically, so two workers are never given the same job,
+/// even from different processes, and queues don't need a separate lock.
+///
+/// Each queue keeps its ready jobs in a sorted set ordered by priority, so handing out a job
+/// reads only that job, however many are queued. Scheduled jobs are kept apart by the time they
+/// become ready, and moved to the ready jobs once that time has passed.
+///
+/// Every clone of the storage shares a single connection. The scripts build the keys of each
+/// queue from the storage's prefix, so this needs a single Redis server rather than Redis
+/// Cluster.
+///
+/// ```rust,no_run
+/// use background_jobs_core::test_storage;
+/// use background_jobs_redis_storage::RedisStorage;
+///
+/// fn main() -> Result<(), failure::Error> {
+///     let client = redis::Client::open("redis://127.0.0.1/")?;
+///
+///     test_storage(RedisStorage::new(client)?);
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct RedisStorage {
+    conn: Arc<Mutex<redis::Connection>>,
+    scripts: Arc<Scripts>,
+    prefix: Arc<str>,
+    stats_sink: Option<Arc<dyn StatsSink>>,
+    order: DispatchOrder,
+}
+
+impl Storage for RedisStorage {
+    type Error = Error;
+
+    fn generate_id(&mut self) -> Result<u64> {
+        let key = self.key("id");
+
+        Ok(self.conn().incr(key, 1)?)
+    }
+
+    fn save_job(&mut self, job: JobInfo) -> Result<()> {
+        if let Some(key) = job.unique_key() {
+            let key = unique_key(job.queue(), key);
+
+            if job.is_pending() {
+                self.set_unique(&key, job.id())?;
+            } else {
+                self.remove_unique(&key, job.id())?;
+            }
+        }
+
+        // Global keys share the unique hash, under their own prefix
+        if let Some(key) = job.global_key() {
+            let key = global_key(key);
+
+            if job.is_pending() {
+                self.set_unique(&key, job.id())?;
+            } else {
+                self.remove_unique(&key, job.id())?;
+            }
+        }
+
+        let raw = encode(&job)?;
+
+        self.scripts
+            .save
+            .key(self.key("jobs"))
+            .key(self.key("queued"))
+            .arg(self.key(""))
+            .arg(job.id())
+            .arg(raw)
+            .arg(ready_score(&job))
+            .arg(job.priority())
+            .arg(millis(clock::now()))
+            .arg(self.order())
+            .invoke(&mut *self.conn())
+            .map_err(Error::from)
+    }
+
+    fn fetch_job(&mut self, id: u64) -> Result<Option<JobInfo>> {
+        let key = self.key("jobs");
+        let raw: Option<String> = self.conn().hget(key, id)?;
+
+        raw.map(|raw| decode(&raw)).transpose()
+    }
+
+    fn list_jobs(&mut self) -> Result<Vec<JobInfo>> {
+        let key = self.key("jobs");
+        let raw: Vec<String> = self.conn().hvals(key)?;
+
+        let mut jobs = raw
+            .iter()
+            .map(|raw| decode(raw))
+            .collect::<Result<Vec<JobInfo>>>()?;
+
+        // Hashes have no order, so jobs are sorted by ID like in the other storages
+        jobs.sort_by_key(|job| job.id());
+
+        Ok(jobs)
+    }
+
+    fn fetch_unique_job(&mut self, queue: &str, key: &str) -> Result<Option<u64>> {
+        let hash = self.key("unique");
+
+        Ok(self.conn().hget(hash, unique_key(queue, key))?)
+    }
+
+    fn fetch_global_job(&mut self, key: &str) -> Result<Option<u64>> {
+        let hash = self.key("unique");
+
+        Ok(self.conn().hget(hash, global_key(key))?)
+    }
+
+    fn save_completed_key(&mut self, queue: &str, key: &str, id: u64) -> Result<()> {
+        let hash = self.key("completed");
+        let raw = encode(&(id, clock::now()))?;
+
+        Ok(self.conn().hset(hash, completed_key(queue, key), raw)?)
+    }
+
+    fn fetch_completed_key(&mut self, queue: &str, key: &str) -> Result<Option<(u64, Timestamp)>> {
+        let hash = self.key("completed");
+        let raw: Option<String> = self.conn().hget(hash, completed_key(queue, key))?;
+
+        raw.map(|raw| decode(&raw)).transpose()
+    }
+
+    fn delete_completed_key(&mut self, queue: &str, key: &str) -> Result<()> {
+        let hash = self.key("completed");
+
+        Ok(self.conn().hdel(hash, completed_key(queue, key))?)
+    }
+
+    fn fetch_job_from_queue(&mut self, queue: &str) -> Result<Option<JobInfo>> {
+        let raw: Option<String> = self
+            .scripts
+            .fetch
+            .key(self.key("jobs"))
+            .key(self.key("queued"))
+            .arg(self.key(""))
+            .arg(queue)
+            .arg(millis(clock::now()))
+            .arg(self.order())
+            .arg(PROMOTE_LIMIT)
+            .invoke(&mut *self.conn())?;
+
+        raw.map(|raw| decode(&raw)).transpose()
+    }
+
+    fn queue_job(&mut self, queue: &str, id: u64) -> Result<()> {
+        let now = millis(clock::now());
+        let (ready_at, priority) = match self.fetch_job(id)? {
+            Some(job) => (ready_score(&job), job.priority()),
+            None => (now, 0),
+        };
+
+        self.scripts
+            .queue
+            .key(self.key("queued"))
+            .key(self.key("running"))
+            .key(self.key("queues"))
+            .arg(self.key(""))
+            .arg(id)
+            .arg(queue)
+            .arg(ready_at)
+            .arg(priority)
+            .arg(now)
+            .arg(self.order())
+            .invoke(&mut *self.conn())
+            .map_err(Error::from)
+    }
+
+    fn run_job(&mut self, id: u64, runner_id: u64) -> Result<()> {
+        let key = self.key("running");
+
+        Ok(self.conn().hset(key, id, runner_id)?)
+    }
+
+    fn delete_job(&mut self, id: u64) -> Result<()> {
+        if let Some(job) = self.fetch_job(id)? {
+            if let Some(key) = job.unique_key() {
+                self.remove_unique(&unique_key(job.queue(), key), id)?;
+            }
+            if let Some(key) = job.global_key() {
+                self.remove_unique(&global_key(key), id)?;
+            }
+        }
+
+        self.scripts
+            .delete
+            .key(self.key("jobs"))
+            .key(self.key("queued"))
+            .key(self.key("running"))
+            .arg(self.key(""))
+            .arg(id)
+            .invoke(&mut *self.conn())
+            .map_err(Error::from)
+    }
+
+    fn save_dead_job(&mut self, job: JobInfo) -> Result<()> {
+        let key = self.key("dead");
+        let raw = encode(&job)?;
+
+        Ok(self.conn().hset(key, job.id(), raw)?)
+    }
+
+    fn fetch_dead_jobs(&mut self) -> Result<Vec<JobInfo>> {
+        let key = self.key("dead");
+        let raw: Vec<String> = self.conn().hvals(key)?;
+
+        let mut jobs = raw
+            .iter()
+            .map(|raw| decode(raw))
+            .collect::<Result<Vec<JobInfo>>>()?;
+        jobs.sort_by_key(|job| job.id());
+
+        Ok(jobs)
+    }
+
+    fn remove_dead_job(&mut self, id: u64) -> Result<Option<JobInfo>> {
+        let raw: Option<String> = self
+            .scripts
+            .take
+            .key(self.key("dead"))
+            .arg(id)
+            .invoke(&mut *self.conn())?;
+
+        raw.map(|raw| decode(&raw)).transpose()
+    }
+
+    fn purge_jobs(&mut self) -> Result<()> {
+        let mut conn = self.conn();
+        let queues: Vec<String> = conn.smembers(self.key("queues"))?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for queue in queues {
+            pipe.del(self.queue_key(&queue)).ignore();
+            pipe.del(self.ready_key(&queue)).ignore();
+        }
+
+        for name in &[
+            "jobs",
+            "queued",
+            "promoted",
+            "queues",
+            "running",
+            "unique",
+            "completed",
+            "dead",
+        ] {
+            pipe.del(self.key(name)).ignore();
+        }
+
+        Ok(pipe.query(&mut *conn)?)
+    }
+
+    fn requeue_stale_jobs(&mut self, older_than: Duration) -> Result<usize> {
+        let now = clock::now();
+        let key = self.key("running");
+
+        // Only running jobs have an entry in this hash, so pending jobs aren't read at all
+        let running: Vec<u64> = self.conn().hkeys(key)?;
+
+        let mut count = 0;
+        for id in running {
+            let stale = self
+                .fetch_job(id)?
+                .is_some_and(|job| job.is_stale(now, older_than));
+
+            if stale && self.reclaim_job(id)? {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn health_check(&mut self) -> Result<()> {
+        Ok(redis::cmd("PING").query(&mut *self.conn())?)
+    }
+
+    fn next_wakeup(&self) -> Result<Option<Timestamp>> {
+        let now = clock::now();
+        let mut conn = self.conn();
+        let queues: Vec<String> = conn.smembers(self.key("queues"))?;
+
+        let mut next: Option<f64> = None;
+        for queue in queues {
+            let first: Vec<(u64, f64)> = conn.zrangebyscore_limit_withscores(
+                self.queue_key(&queue),
+                format!("({}", millis(now)),
+                "+inf",
+                0,
+                1,
+            )?;
+
+            if let Some((_, ready_at)) = first.first() {
+                next = Some(next.map_or(*ready_at, |next| next.min(*ready_at)));
+            }
+        }
+
+        Ok(next.map(|ready_at| now + TimeDelta::milliseconds(ready_at as i64 - millis(now))))
+    }
+
+    fn get_stats(&self) -> Result<Stats> {
+        if let Some(ref sink) = self.stats_sink {
+            return Ok(sink.snapshot());
+        }
+
+        let raw: Option<String> = self.conn().get(self.key("stats"))?;
+
+        match raw {
+            Some(raw) => decode(&raw),
+            None => Ok(Stats::default()),
+        }
+    }
+
+    fn update_stats<F>(&mut self, f: F) -> Result<()>
+    where
+        F: Fn(Stats) -> Stats,
+    {
+        let key = self.key("stats");
+        let mut conn = self.conn();
+
+        // Other processes may update the stats at the same time, so the write is only applied if
+        // the stats haven't changed since they were read, and retried otherwise
+        loop {
+            redis::cmd("WATCH").arg(&key).query::<()>(&mut *conn)?;
+
+            let raw: Option<String> = conn.get(&key)?;
+            let stats = match raw.map(|raw| decode(&raw)).transpose() {
+                Ok(stats) => stats.unwrap_or_default(),
+                Err(e) => {
+                    redis::cmd("UNWATCH").query::<()>(&mut *conn)?;
+                    return Err(e);
+                }
+            };
+
+            let raw = encode(&(f)(stats))?;
+            let written: Option<()> = redis::pipe()
+                .atomic()
+                .set(&key, raw)
+                .ignore()
+                .query(&mut *conn)?;
+
+            if written.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn record_stats(&mut self, event: StatsEvent) -> Result<()> {
+        if let Some(ref sink) = self.stats_sink {
+            sink.record(&event);
+            return Ok(());
+        }
+
+        self.update_stats(|stats| stats.apply(&event))
+    }
+}
+
+impl RedisStorage {
+    /// Create a RedisStorage connected to the server the client points to
+    pub fn new(client: redis::Client) -> Result<Self> {
+        let conn = client.get_connection()?;
+
+        Ok(RedisStorage {
+            conn: Arc::new(Mutex::new(conn)),
+            scripts: Arc::new(Scripts::new()),
+            prefix: Arc::from(DEFAULT_PREFIX),
+            stats_sink: None,
+            order: DispatchOrder::default(),
+        })
+    }
+
+    /// Prefix every key written by the storage with the given string
+    ///
+    /// This lets several applications share a Redis server without sharing their jobs. By
+    /// default, keys start with `background-jobs`.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Arc::from(prefix);
+        self
+    }
+
+    /// Choose the order in which ready jobs of the same priority are handed out
+    pub fn with_dispatch_order(mut self, order: DispatchOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Keep the stats in the given sink instead of the Redis server
+    ///
+    /// Every change to the stats is otherwise written to the server as a whole new `Stats`,
+    /// which processes sharing the server retry when they write at the same time. Stats kept in
+    /// a sink are only those of the current process.
+    pub fn with_stats_sink(mut self, sink: impl StatsSink + 'static) -> Self {
+        self.stats_sink = Some(Arc::new(sink));
+        self
+    }
+
+    fn conn(&self) -> MutexGuard<'_, redis::Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{}", self.prefix, name)
+    }
+
+    /// The sorted set of the queue's jobs that weren't ready when they were queued
+    fn queue_key(&self, queue: &str) -> String {
+        format!("{}:queue:{}", self.prefix, queue)
+    }
+
+    /// The sorted set of the queue's ready jobs
+    fn ready_key(&self, queue: &str) -> String {
+        format!("{}:ready:{}", self.prefix, queue)
+    }
+
+    /// The dispatch order, as the scripts name it
+    fn order(&self) -> &'static str {
+        match self.order {
+            DispatchOrder::Created => "created",
+            DispatchOrder::Ready => "ready",
+        }
+    }
+
+    fn set_unique(&self, key: &str, id: u64) -> Result<()> {
+        Ok(self.conn().hset(self.key("unique"), key, id)?)
+    }
+
+    fn remove_unique(&self, key: &str, id: u64) -> Result<()> {
+        self.scripts
+            .remove_if
+            .key(self.key("unique"))
+            .arg(key)
+            .arg(id)
+            .invoke(&mut *self.conn())
+            .map(|_: u64| ())
+            .map_err(Error::from)
+    }
+}
+
+fn unique_key(queue: &str, key: &str) -> String {
+    format!("unique-{}-{}", queue, key)
+}
+
+fn global_key(key: &str) -> String {
+    format!("global-{}", key)
+}
+
+fn completed_key(queue: &str, key: &str) -> String {
+    format!("completed-{}-{}", queue, key)
+}
+
+/// Timestamps are stored as milliseconds since the epoch, which is what sorted set scores hold
+fn millis(timestamp: Timestamp) -> i64 {
+    timestamp.timestamp_millis()
+}
+
+/// The score of a queued job, the first whole millisecond at which it may be handed out
+///
+/// Jobs are fetched once their score is at most the current time, in whole milliseconds.
+/// Scheduled jobs are only ready strictly after their scheduled time, so their score is the
+/// millisecond after it, and a job scheduled part way through a millisecond isn't handed out
+/// before its time within that same millisecond. Jobs that aren't scheduled are ready from the
+/// moment they are created.
+fn ready_score(job: &JobInfo) -> i64 {
+    match job.scheduled_for() {
+        Some(_) => millis(job.ready_at()).saturating_add(1),
+        None => millis(job.ready_at()),
+    }
+}
+
+fn encode<T>(value: &T) -> Result<String>
+where
+    T: Serialize,
+{
+    serde_json::to_string(value).map_err(|_| Error::Serialize)
+}
+
+fn decode<T>(raw: &str) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_str(raw).map_err(|_| Error::Deserialize)
+}
@@ -0,0 +1,198 @@
+//! The Lua scripts run by `RedisStorage`
+//!
+//! Redis runs each script atomically, so their steps can't interleave with another client's.
+//! Queue keys are built inside the scripts from the storage's prefix, passed as the first
+//! argument, which means they only work with a single Redis server, and not with Redis Cluster.
+//!
+//! Each queue keeps its jobs in two sorted sets. `queue:<name>` holds the jobs that weren't ready
+//! when they were queued, scored by the millisecond they become ready at. `ready:<name>` holds
+//! the ready jobs, scored by their negated priority, so the job that runs next is always the
+//! first one. Jobs with the same priority are ordered by their member, which is the job's ID,
+//! or its ready time and ID for `DispatchOrder::Ready`, both padded to a fixed width so they
+//! sort by their numeric value. The `promoted` hash holds the member of every ready job, by ID.
+
+use redis::Script;
+
+/// The functions shared by the scripts that queue and unqueue jobs
+///
+/// ARGV[1] is always the prefix of the storage's keys.
+const SHARED: &str = r#"
+local prefix = ARGV[1]
+
+local function pad(value)
+    return string.rep('0', 20 - #value) .. value
+end
+
+-- Put a queued job in the set it is found in, depending on whether it is ready
+local function place(queue, id, ready, priority, now, order)
+    if tonumber(ready) > tonumber(now) then
+        redis.call('ZADD', prefix .. 'queue:' .. queue, ready, id)
+        return
+    end
+
+    local member = pad(id)
+    if order == 'ready' then
+        member = pad(string.format('%d', tonumber(ready))) .. ':' .. member
+    end
+
+    redis.call('ZADD', prefix .. 'ready:' .. queue, -tonumber(priority), member)
+    redis.call('HSET', prefix .. 'promoted', id, member)
+end
+
+-- Take a queued job out of whichever set it is in
+local function unplace(queue, id)
+    redis.call('ZREM', prefix .. 'queue:' .. queue, id)
+
+    local member = redis.call('HGET', prefix .. 'promoted', id)
+    if member then
+        redis.call('ZREM', prefix .. 'ready:' .. queue, member)
+        redis.call('HDEL', prefix .. 'promoted', id)
+    end
+end
+"#;
+
+/// Take the ready job that should run next from a queue
+///
+/// Scheduled jobs that have become ready are moved to the ready set first, at most `ARGV[5]` of
+/// them, so a call never reads more than that many jobs.
+///
+/// KEYS: the jobs hash, the queued hash.
+/// ARGV: the prefix, the queue, the current time in milliseconds, then `ready` to break priority
+/// ties by ready time instead of by ID, and the most scheduled jobs to move.
+const FETCH: &str = r#"
+local queue, now, order = ARGV[2], ARGV[3], ARGV[4]
+local scheduled = prefix .. 'queue:' .. queue
+local due = redis.call('ZRANGEBYSCORE', scheduled, '-inf', now, 'WITHSCORES', 'LIMIT', 0, ARGV[5])
+
+for i = 1, #due, 2 do
+    local id = due[i]
+    local raw = redis.call('HGET', KEYS[1], id)
+
+    redis.call('ZREM', scheduled, id)
+    if raw then
+        place(queue, id, due[i + 1], cjson.decode(raw).priority or 0, now, order)
+    else
+        redis.call('HDEL', KEYS[2], id)
+    end
+end
+
+local ready = prefix .. 'ready:' .. queue
+
+while true do
+    local best = redis.call('ZRANGE', ready, 0, 0)[1]
+    if not best then
+        return false
+    end
+
+    local id = (string.gsub(string.sub(best, -20), '^0+', ''))
+    redis.call('ZREM', ready, best)
+    redis.call('HDEL', prefix .. 'promoted', id)
+    redis.call('HDEL', KEYS[2], id)
+
+    local raw = redis.call('HGET', KEYS[1], id)
+    if raw then
+        return raw
+    end
+end
+"#;
+
+/// Put a job in a queue, taking it out of any other queue and of the running jobs
+///
+/// KEYS: the queued hash, the running hash, the queue names set.
+/// ARGV: the prefix, the job's ID, the queue, the time the job is ready at in milliseconds, the
+/// job's priority, the current time in milliseconds, the dispatch order.
+const QUEUE: &str = r#"
+local id, queue = ARGV[2], ARGV[3]
+
+local previous = redis.call('HGET', KEYS[1], id)
+if previous then
+    unplace(previous, id)
+end
+
+redis.call('HSET', KEYS[1], id, queue)
+place(queue, id, ARGV[4], ARGV[5], ARGV[6], ARGV[7])
+redis.call('HDEL', KEYS[2], id)
+redis.call('SADD', KEYS[3], queue)
+"#;
+
+/// Store a job, moving it to its new ready time and priority if it is queued
+///
+/// KEYS: the jobs hash, the queued hash.
+/// ARGV: the prefix, the job's ID, the job, the time the job is ready at in milliseconds, the
+/// job's priority, the current time in milliseconds, the dispatch order.
+const SAVE: &str = r#"
+local id = ARGV[2]
+redis.call('HSET', KEYS[1], id, ARGV[3])
+
+local queue = redis.call('HGET', KEYS[2], id)
+if queue then
+    unplace(queue, id)
+    place(queue, id, ARGV[4], ARGV[5], ARGV[6], ARGV[7])
+end
+"#;
+
+/// Delete a job from every place it may be kept, except for its keys
+///
+/// KEYS: the jobs hash, the queued hash, the running hash.
+/// ARGV: the prefix, the job's ID.
+const DELETE: &str = r#"
+local id = ARGV[2]
+
+local queue = redis.call('HGET', KEYS[2], id)
+if queue then
+    unplace(queue, id)
+end
+
+redis.call('HDEL', KEYS[1], id)
+redis.call('HDEL', KEYS[2], id)
+redis.call('HDEL', KEYS[3], id)
+"#;
+
+/// Remove a field from a hash and return its value, if it has one
+///
+/// KEYS: the hash. ARGV: the field.
+const TAKE: &str = r#"
+local value = redis.call('HGET', KEYS[1], ARGV[1])
+if value then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return value
+"#;
+
+/// Remove a field from a hash, but only if it still has the given value
+///
+/// KEYS: the hash. ARGV: the field, the value.
+const REMOVE_IF: &str = r#"
+if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+    return redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return 0
+"#;
+
+/// The scripts, loaded once so their hashes aren't computed again for every call
+pub(crate) struct Scripts {
+    pub(crate) fetch: Script,
+    pub(crate) queue: Script,
+    pub(crate) save: Script,
+    pub(crate) delete: Script,
+    pub(crate) take: Script,
+    pub(crate) remove_if: Script,
+}
+
+impl Scripts {
+    pub(crate) fn new() -> Self {
+        Scripts {
+            fetch: shared(FETCH),
+            queue: shared(QUEUE),
+            save: shared(SAVE),
+            delete: shared(DELETE),
+            take: Script::new(TAKE),
+            remove_if: Script::new(REMOVE_IF),
+        }
+    }
+}
+
+/// A script that uses the shared functions
+fn shared(script: &str) -> Script {
+    Script::new(&format!("{}{}", SHARED, script))
+}
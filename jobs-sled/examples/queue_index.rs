@@ -0,0 +1,52 @@
+//! Measure how long requesting jobs takes from a database with many queued jobs
+//!
+//! 50,000 jobs are stored across 5 queues, then 500 jobs are requested and returned, taking turns
+//! between the queues. Run it with a release build:
+//!
+//! ```text
+//! cargo run --release -p background-jobs-sled-storage --example queue_index
+//! ```
+
+use std::time::Instant;
+
+use background_jobs_core::{NewJobInfo, ReturnJobInfo, Storage};
+use background_jobs_sled_storage::SledStorage;
+use failure::Error;
+use serde_json::json;
+use sled::{ConfigBuilder, Db};
+
+const QUEUES: &[&str] = &["one", "two", "three", "four", "five"];
+const JOBS: usize = 50_000;
+const REQUESTS: usize = 500;
+
+fn main() -> Result<(), Error> {
+    let db = Db::start(ConfigBuilder::new().temporary(true).build())?;
+    let mut storage = SledStorage::new(db)?;
+
+    let start = Instant::now();
+    for i in 0..JOBS {
+        let queue = QUEUES[i % QUEUES.len()];
+
+        storage.new_job(
+            NewJobInfo::builder("Processor", queue)
+                .args(json!([i]))
+                .build()?,
+        )?;
+    }
+    println!("Stored {} jobs in {:?}", JOBS, start.elapsed());
+
+    let start = Instant::now();
+    for i in 0..REQUESTS {
+        let queue = QUEUES[i % QUEUES.len()];
+        let job = storage.request_job(queue, 1)?.expect("A queued job");
+
+        storage.return_job(ReturnJobInfo::pass(job.id()))?;
+    }
+    println!(
+        "Requested and returned {} jobs in {:?}",
+        REQUESTS,
+        start.elapsed()
+    );
+
+    Ok(())
+}
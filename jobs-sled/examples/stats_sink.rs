@@ -0,0 +1,77 @@
+//! Compare recording stats in the stored `Stats` value with recording them in `AtomicStats`
+//!
+//! Several threads record job completions at the same time, through clones of one storage, as
+//! the threads of a job server do. Run it with a release build:
+//!
+//! ```text
+//! cargo run --release -p background-jobs-sled-storage --example stats_sink
+//! ```
+
+use std::{thread, time::Instant};
+
+use background_jobs_core::{memory_storage, AtomicStats, StatsEvent, Storage};
+use background_jobs_sled_storage::SledStorage;
+use failure::Error;
+use sled::{ConfigBuilder, Db};
+
+const THREADS: usize = 8;
+const COMPLETIONS: usize = 5000;
+
+fn main() -> Result<(), Error> {
+    let db = || Db::start(ConfigBuilder::new().temporary(true).build());
+
+    measure("sled, stored stats", SledStorage::new(db()?)?)?;
+    measure(
+        "sled, AtomicStats",
+        SledStorage::new(db()?)?.with_stats_sink(AtomicStats::new()),
+    )?;
+    measure("memory, stored stats", memory_storage::Storage::new())?;
+    measure(
+        "memory, AtomicStats",
+        memory_storage::Storage::new().with_stats_sink(AtomicStats::new()),
+    )?;
+
+    Ok(())
+}
+
+/// Record `COMPLETIONS` completions from each of `THREADS` threads, and print how long it took
+fn measure<S>(name: &str, storage: S) -> Result<(), Error>
+where
+    S: Storage + Clone + Send + 'static,
+{
+    let start = Instant::now();
+
+    let threads = (0..THREADS)
+        .map(|_| {
+            let mut storage = storage.clone();
+
+            thread::spawn(move || -> Result<(), S::Error> {
+                for _ in 0..COMPLETIONS {
+                    storage.record_stats(StatsEvent::Complete {
+                        queue: "default",
+                        processor: "Processor",
+                    })?;
+                }
+
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread
+            .join()
+            .expect("Recording thread panicked")
+            .map_err(Error::from)?;
+    }
+
+    let elapsed = start.elapsed();
+    let complete = storage
+        .get_stats()
+        .map_err(Error::from)?
+        .complete
+        .all_time();
+    println!("{}: {} completions in {:?}", name, complete, elapsed);
+
+    Ok(())
+}
@@ -12,6 +12,19 @@ pub enum Error {
 
     #[fail(display = "Failed to serialize data")]
     Serialize,
+
+    #[cfg(feature = "encryption")]
+    #[fail(display = "Failed to encrypt data")]
+    Encrypt,
+
+    /// The stored data could not be decrypted, usually because it was written with another key or
+    /// without encryption
+    #[cfg(feature = "encryption")]
+    #[fail(display = "Failed to decrypt data")]
+    Decrypt,
+
+    #[fail(display = "Error in queue lock: {}", _0)]
+    Lock(String),
 }
 
 impl From<sled::Error> for Error {
@@ -1,21 +1,58 @@
-use background_jobs_core::{JobInfo, Stats, Storage};
-use chrono::offset::Utc;
+use std::{cmp::Reverse, sync::Arc, time::Duration};
+
+use background_jobs_core::{
+    clock::{self, TimeDelta, Timestamp},
+    DispatchOrder, JobInfo, Stats, StatsEvent, StatsSink, Storage,
+};
 
 mod error;
+mod lock;
 mod sled_wrappers;
 
-pub use error::Error;
-
-use self::{error::Result, sled_wrappers::Tree};
-
+pub use self::{
+    error::Error,
+    lock::{NoopLock, QueueLock, SledLock},
+};
+
+use self::{error::Result, lock::LockGuard, sled_wrappers::Tree};
+
+/// The key written by `health_check`, which is also locked as if it were a queue
+const HEALTH_KEY: &str = "background-jobs-health";
+
+/// The priority and ready time of a queued job, which the queue index keeps so that jobs can be
+/// chosen without loading them
+type Queued = (i32, Timestamp);
+
+/// A `Storage` implementation backed by a Sled database
+///
+/// ```rust
+/// use background_jobs_core::test_storage;
+/// use background_jobs_sled_storage::SledStorage;
+/// use sled::{ConfigBuilder, Db};
+///
+/// fn main() -> Result<(), failure::Error> {
+///     let db = Db::start(ConfigBuilder::new().temporary(true).build())?;
+///
+///     test_storage(SledStorage::new(db)?);
+///     Ok(())
+/// }
+/// ```
 #[derive(Clone)]
 pub struct SledStorage {
     jobinfo: Tree<JobInfo>,
     running: Tree<u64>,
     running_inverse: Tree<u64>,
     queue: Tree<String>,
+    queue_index: Tree<Queued>,
+    unique: Tree<u64>,
+    completed: Tree<(u64, Timestamp)>,
+    dead: Tree<JobInfo>,
     stats: Tree<Stats>,
-    lock: Tree<u64>,
+    stats_sink: Option<Arc<dyn StatsSink>>,
+    lock: Arc<dyn QueueLock>,
+    order: DispatchOrder,
+    gc_interval: Option<Duration>,
+    idempotency_retention: TimeDelta,
     db: sled::Db,
 }
 
@@ -27,65 +64,229 @@ impl Storage for SledStorage {
     }
 
     fn save_job(&mut self, job: JobInfo) -> Result<()> {
-        self.jobinfo.set(&job_key(job.id()), job).map(|_| ())
+        if let Some(key) = job.unique_key() {
+            let key = unique_key(job.queue(), key);
+
+            if job.is_pending() {
+                self.unique.set(&key, job.id())?;
+            } else {
+                self.remove_unique(&key, job.id())?;
+            }
+        }
+
+        // Global keys share the unique tree, under their own prefix
+        if let Some(key) = job.global_key() {
+            let key = global_key(key);
+
+            if job.is_pending() {
+                self.unique.set(&key, job.id())?;
+            } else {
+                self.remove_unique(&key, job.id())?;
+            }
+        }
+
+        // Queued jobs are indexed by their priority and ready time, which may have just changed
+        if let Some(queue) = self.queue.get(job_key(job.id()))? {
+            self.queue_index
+                .set(queue_index_key(&queue, job.id()), queued(&job))?;
+        }
+
+        self.jobinfo.set(job_key(job.id()), job).map(|_| ())
     }
 
     fn fetch_job(&mut self, id: u64) -> Result<Option<JobInfo>> {
-        self.jobinfo.get(&job_key(id))
+        self.jobinfo.get(job_key(id))
+    }
+
+    fn list_jobs(&mut self) -> Result<Vec<JobInfo>> {
+        self.jobinfo
+            .iter()
+            .map(|res| res.map(|(_, job)| job))
+            .collect()
+    }
+
+    fn fetch_unique_job(&mut self, queue: &str, key: &str) -> Result<Option<u64>> {
+        self.unique.get(unique_key(queue, key))
+    }
+
+    fn fetch_global_job(&mut self, key: &str) -> Result<Option<u64>> {
+        self.unique.get(global_key(key))
+    }
+
+    fn save_completed_key(&mut self, queue: &str, key: &str, id: u64) -> Result<()> {
+        self.completed
+            .set(completed_key(queue, key), (id, clock::now()))
+            .map(|_| ())
+    }
+
+    fn fetch_completed_key(&mut self, queue: &str, key: &str) -> Result<Option<(u64, Timestamp)>> {
+        self.completed.get(completed_key(queue, key))
+    }
+
+    fn delete_completed_key(&mut self, queue: &str, key: &str) -> Result<()> {
+        self.completed.del(completed_key(queue, key)).map(|_| ())
     }
 
     fn fetch_job_from_queue(&mut self, queue: &str) -> Result<Option<JobInfo>> {
-        let queue_tree = self.queue.clone();
-        let job_tree = self.jobinfo.clone();
+        let storage = self.clone();
 
         self.lock_queue(queue, move || {
-            let now = Utc::now();
+            let now = clock::now();
+            let prefix = queue_prefix(queue);
 
-            let job = queue_tree
-                .iter()
+            // Only the index entries of this queue are read, and only the chosen job is loaded
+            let id = storage
+                .queue_index
+                .scan_prefix(&prefix)
                 .filter_map(|res| res.ok())
-                .filter_map(|(id, in_queue)| if queue == in_queue { Some(id) } else { None })
-                .filter_map(|id| job_tree.get(id).ok())
-                .filter_map(|opt| opt)
-                .filter(|job| job.is_ready(now))
-                .next();
-
-            if let Some(ref job) = job {
-                queue_tree.del(&job_key(job.id()))?;
+                .filter(|(key, _)| key.len() == prefix.len() + 8)
+                .filter(|(_, (_, ready_at))| *ready_at <= now)
+                .map(|(key, (priority, ready_at))| (index_id(&key), priority, ready_at))
+                .max_by_key(|&(id, priority, ready_at)| {
+                    (priority, Reverse(storage.order.key_for(id, ready_at)))
+                })
+                .map(|(id, _, _)| id);
+
+            match id {
+                Some(id) => {
+                    storage.unqueue(id)?;
+                    storage.jobinfo.get(job_key(id))
+                }
+                None => Ok(None),
             }
-
-            Ok(job)
         })
     }
 
     fn queue_job(&mut self, queue: &str, id: u64) -> Result<()> {
-        if let Some(runner_id) = self.running_inverse.del(&job_key(id))? {
-            self.running.del(&runner_key(runner_id))?;
+        if let Some(runner_id) = self.running_inverse.del(job_key(id))? {
+            self.running.del(runner_key(runner_id))?;
         }
 
-        self.queue.set(&job_key(id), queue.to_owned()).map(|_| ())
+        self.unqueue(id)?;
+
+        let queued = match self.jobinfo.get(job_key(id))? {
+            Some(job) => queued(&job),
+            None => (0, clock::now()),
+        };
+        self.queue_index.set(queue_index_key(queue, id), queued)?;
+
+        self.queue.set(job_key(id), queue.to_owned()).map(|_| ())
     }
 
     fn run_job(&mut self, id: u64, runner_id: u64) -> Result<()> {
-        self.queue.del(&job_key(id))?;
-        self.running.set(&runner_key(runner_id), id)?;
-        self.running_inverse.set(&job_key(id), runner_id)?;
+        self.unqueue(id)?;
+        self.running.set(runner_key(runner_id), id)?;
+        self.running_inverse.set(job_key(id), runner_id)?;
 
         Ok(())
     }
 
     fn delete_job(&mut self, id: u64) -> Result<()> {
-        self.jobinfo.del(&job_key(id))?;
-        self.queue.del(&job_key(id))?;
+        if let Some(job) = self.jobinfo.del(job_key(id))? {
+            if let Some(key) = job.unique_key() {
+                self.remove_unique(&unique_key(job.queue(), key), id)?;
+            }
+            if let Some(key) = job.global_key() {
+                self.remove_unique(&global_key(key), id)?;
+            }
+        }
+        self.unqueue(id)?;
+
+        if let Some(runner_id) = self.running_inverse.del(job_key(id))? {
+            self.running.del(runner_key(runner_id))?;
+        }
+
+        Ok(())
+    }
 
-        if let Some(runner_id) = self.running_inverse.del(&job_key(id))? {
-            self.running.del(&runner_key(runner_id))?;
+    fn requeue_stale_jobs(&mut self, older_than: Duration) -> Result<usize> {
+        let now = clock::now();
+
+        // Only running jobs have an entry in this tree, so pending jobs aren't read at all
+        let stale = self
+            .running_inverse
+            .iter()
+            .filter_map(|res| res.ok())
+            .filter_map(|(id, _)| self.jobinfo.get(id).ok().flatten())
+            .filter(|job| job.is_stale(now, older_than))
+            .map(|job| job.id())
+            .collect::<Vec<_>>();
+
+        let mut count = 0;
+        for id in stale {
+            if self.reclaim_job(id)? {
+                count += 1;
+            }
         }
 
+        Ok(count)
+    }
+
+    fn save_dead_job(&mut self, job: JobInfo) -> Result<()> {
+        self.dead.set(job_key(job.id()), job).map(|_| ())
+    }
+
+    fn fetch_dead_jobs(&mut self) -> Result<Vec<JobInfo>> {
+        self.dead
+            .iter()
+            .map(|res| res.map(|(_, job)| job))
+            .collect()
+    }
+
+    fn remove_dead_job(&mut self, id: u64) -> Result<Option<JobInfo>> {
+        self.dead.del(job_key(id))
+    }
+
+    fn purge_jobs(&mut self) -> Result<()> {
+        self.jobinfo.clear()?;
+        self.running.clear()?;
+        self.running_inverse.clear()?;
+        self.queue.clear()?;
+        self.queue_index.clear()?;
+        self.unique.clear()?;
+        self.completed.clear()?;
+        self.dead.clear()
+    }
+
+    fn health_check(&mut self) -> Result<()> {
+        self.lock.lock(HEALTH_KEY)?;
+        self.lock.unlock(HEALTH_KEY)?;
+
+        self.db.set(HEALTH_KEY, vec![1])?;
+        self.db.flush()?;
+
         Ok(())
     }
 
+    fn maintain(&mut self) -> Result<()> {
+        self.gc()
+    }
+
+    fn maintenance_interval(&self) -> Option<Duration> {
+        self.gc_interval
+    }
+
+    fn idempotency_retention(&self) -> TimeDelta {
+        self.idempotency_retention
+    }
+
+    fn next_wakeup(&self) -> Result<Option<Timestamp>> {
+        let now = clock::now();
+
+        Ok(self
+            .jobinfo
+            .iter()
+            .filter_map(|res| res.ok())
+            .filter(|(_, job)| job.is_scheduled(now))
+            .filter_map(|(_, job)| job.scheduled_for())
+            .min())
+    }
+
     fn get_stats(&self) -> Result<Stats> {
+        if let Some(ref sink) = self.stats_sink {
+            return Ok(sink.snapshot());
+        }
+
         Ok(self.stats.get("stats")?.unwrap_or(Stats::default()))
     }
 
@@ -104,51 +305,441 @@ impl Storage for SledStorage {
 
         Ok(())
     }
+
+    fn record_stats(&mut self, event: StatsEvent) -> Result<()> {
+        if let Some(ref sink) = self.stats_sink {
+            sink.record(&event);
+            return Ok(());
+        }
+
+        self.update_stats(|stats| stats.apply(&event))
+    }
 }
 
 impl SledStorage {
     pub fn new(db: sled::Db) -> Result<Self> {
-        Ok(SledStorage {
+        let lock = SledLock::open(&db)?;
+
+        Self::with_lock(db, lock)
+    }
+
+    /// Create a SledStorage that guards its queues with the provided lock
+    ///
+    /// Databases written by versions that stored IDs as strings are migrated with `migrate_keys`
+    /// when they are opened.
+    ///
+    /// ```rust
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// use background_jobs_core::{NewJobInfo, Storage};
+    /// use background_jobs_sled_storage::{Error, QueueLock, SledStorage};
+    /// use serde_json::json;
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct RecordingLock {
+    ///     calls: Arc<Mutex<Vec<String>>>,
+    /// }
+    ///
+    /// impl QueueLock for RecordingLock {
+    ///     fn lock(&self, queue: &str) -> Result<(), Error> {
+    ///         self.calls.lock().unwrap().push(format!("lock {}", queue));
+    ///         Ok(())
+    ///     }
+    ///
+    ///     fn unlock(&self, queue: &str) -> Result<(), Error> {
+    ///         self.calls.lock().unwrap().push(format!("unlock {}", queue));
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let db = Db::start(ConfigBuilder::new().temporary(true).build())?;
+    ///     let lock = RecordingLock::default();
+    ///     let mut storage = SledStorage::with_lock(db, lock.clone())?;
+    ///
+    ///     let id = storage.new_job(NewJobInfo::builder("Processor", "default").args(json!([])).build()?)?;
+    ///     lock.calls.lock().unwrap().clear();
+    ///
+    ///     let job = storage.fetch_job_from_queue("default")?.unwrap();
+    ///     assert_eq!(job.id(), id);
+    ///     assert_eq!(*lock.calls.lock().unwrap(), vec!["lock default", "unlock default"]);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_lock(db: sled::Db, lock: impl QueueLock + 'static) -> Result<Self> {
+        Self::open(db, Arc::new(lock), |_| ())
+    }
+
+    /// Create a SledStorage that encrypts stored jobs with the given 256-bit key
+    ///
+    /// Jobs, including dead jobs, are encrypted with ChaCha20-Poly1305 before they are written,
+    /// so their arguments don't sit on disk in plaintext. Other data, such as queue names, unique
+    /// keys, and stats, is not encrypted. Keeping the key safe is up to the application, and a
+    /// database must always be opened with the key it was written with, since jobs that can't
+    /// be decrypted fail to load with `Error::Decrypt`.
+    ///
+    /// ```rust
+    /// use background_jobs_core::{NewJobInfo, Storage};
+    /// use background_jobs_sled_storage::SledStorage;
+    /// use serde_json::json;
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let db = Db::start(ConfigBuilder::new().temporary(true).build())?;
+    ///     let key = [7; 32];
+    ///
+    ///     let mut storage = SledStorage::with_encryption(db.clone(), &key)?;
+    ///     let id = storage.new_job(
+    ///         NewJobInfo::builder("Processor", "default").args(json!(["secret"])).build()?,
+    ///     )?;
+    ///
+    ///     // The stored job is not the JSON it was serialized to
+    ///     let tree = db.open_tree("background-jobs-jobinfo".to_string())?;
+    ///     let (_, value) = tree.iter().next().unwrap()?;
+    ///     assert!(serde_json::from_slice::<serde_json::Value>(&value).is_err());
+    ///     assert!(!String::from_utf8_lossy(&value).contains("secret"));
+    ///     assert_eq!(storage.fetch_job(id)?.unwrap().redacted_args(), &json!(["secret"]));
+    ///
+    ///     // Opening the database again with the key reads the jobs back, and indexes the queued
+    ///     // ones so they are handed out
+    ///     let mut storage = SledStorage::with_encryption(db, &key)?;
+    ///     assert_eq!(storage.fetch_job(id)?.unwrap().redacted_args(), &json!(["secret"]));
+    ///     assert_eq!(storage.request_job("default", 1)?.map(|job| job.id()), Some(id));
+    ///     Ok(())
+    /// }
+    /// ```
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(db: sled::Db, key: &[u8; 32]) -> Result<Self> {
+        let lock = SledLock::open(&db)?;
+
+        Self::with_lock_and_encryption(db, lock, key)
+    }
+
+    /// Create a SledStorage that guards its queues with the provided lock, and encrypts stored
+    /// jobs with the given 256-bit key
+    ///
+    /// See `with_encryption`.
+    #[cfg(feature = "encryption")]
+    pub fn with_lock_and_encryption(
+        db: sled::Db,
+        lock: impl QueueLock + 'static,
+        key: &[u8; 32],
+    ) -> Result<Self> {
+        Self::open(db, Arc::new(lock), |storage| {
+            storage.jobinfo.encrypt(key);
+            storage.dead.encrypt(key);
+        })
+    }
+
+    /// Open the trees, then migrate and index them once `configure` has set them up
+    ///
+    /// Migrating and indexing read stored jobs, so anything that changes how jobs are decoded
+    /// has to happen first.
+    fn open<F>(db: sled::Db, lock: Arc<dyn QueueLock>, configure: F) -> Result<Self>
+    where
+        F: FnOnce(&mut Self),
+    {
+        let mut storage = SledStorage {
             jobinfo: open_tree(&db, "background-jobs-jobinfo")?,
             running: open_tree(&db, "background-jobs-running")?,
             running_inverse: open_tree(&db, "background-jobs-running-inverse")?,
             queue: open_tree(&db, "background-jobs-queue")?,
+            queue_index: open_tree(&db, "background-jobs-queue-index")?,
+            unique: open_tree(&db, "background-jobs-unique")?,
+            completed: open_tree(&db, "background-jobs-completed")?,
+            dead: open_tree(&db, "background-jobs-dead")?,
             stats: open_tree(&db, "background-jobs-stats")?,
-            lock: open_tree(&db, "background-jobs-lock")?,
+            stats_sink: None,
+            lock,
+            order: DispatchOrder::default(),
+            gc_interval: None,
+            idempotency_retention: TimeDelta::days(1),
             db,
-        })
+        };
+
+        (configure)(&mut storage);
+
+        storage.migrate_keys()?;
+        storage.index_queues()?;
+
+        Ok(storage)
+    }
+
+    /// Rewrite the keys of databases written by versions that stored IDs as strings
+    ///
+    /// Job and runner IDs used to be stored as strings like `job-10`, which sort before `job-2`.
+    /// They are now stored as fixed-width big-endian bytes, so jobs are iterated in ID order,
+    /// which is also the order they were created in. This moves every entry still using the old
+    /// format to its new key, and returns how many were moved. Running it again does nothing.
+    ///
+    /// This runs when the storage is created, so it only needs to be called directly by tools
+    /// that open the trees themselves.
+    ///
+    /// ```rust
+    /// use background_jobs_core::{NewJobInfo, Storage};
+    /// use background_jobs_sled_storage::SledStorage;
+    /// use serde_json::json;
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let db = Db::start(ConfigBuilder::new().temporary(true).build())?;
+    ///     let mut storage = SledStorage::new(db.clone())?;
+    ///
+    ///     for _ in 0..12 {
+    ///         storage.new_job(NewJobInfo::builder("Processor", "default").args(json!([])).build()?)?;
+    ///     }
+    ///
+    ///     // Jobs are listed in ID order, even past 10
+    ///     let ids: Vec<_> = storage.list_jobs()?.iter().map(|job| job.id()).collect();
+    ///     let mut sorted = ids.clone();
+    ///     sorted.sort();
+    ///     assert_eq!(ids, sorted);
+    ///
+    ///     // Write the jobs back the way older versions stored them
+    ///     for name in &["background-jobs-jobinfo", "background-jobs-queue"] {
+    ///         let tree = db.open_tree(name.to_string())?;
+    ///
+    ///         for res in tree.iter().collect::<Vec<_>>() {
+    ///             let (key, value) = res?;
+    ///             let mut id = [0; 8];
+    ///             id.copy_from_slice(&key);
+    ///
+    ///             tree.del(&key)?;
+    ///             tree.set(format!("job-{}", u64::from_be_bytes(id)), value.to_vec())?;
+    ///         }
+    ///     }
+    ///
+    ///     // Opening the storage again migrates them
+    ///     let mut storage = SledStorage::new(db)?;
+    ///     assert_eq!(storage.migrate_keys()?, 0);
+    ///     assert_eq!(storage.list_jobs()?.len(), 12);
+    ///     assert!(storage.fetch_job(ids[11])?.is_some());
+    ///     assert!(storage.request_job("default", 1)?.is_some());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn migrate_keys(&self) -> Result<usize> {
+        let job = |key: &[u8]| legacy_key(key, "job-");
+
+        Ok(self.jobinfo.rekey(job)?
+            + self.queue.rekey(job)?
+            + self.running_inverse.rekey(job)?
+            + self.dead.rekey(job)?
+            + self.running.rekey(|key| legacy_key(key, "runner-"))?)
+    }
+
+    /// Choose the order in which ready jobs of the same priority are handed out
+    pub fn with_dispatch_order(mut self, order: DispatchOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Keep the stats in the given sink instead of the database
+    ///
+    /// Every change to the stats is otherwise written to the database as a whole new `Stats`.
+    /// Stats kept in a sink don't survive restarts unless the sink persists them itself.
+    pub fn with_stats_sink(mut self, sink: impl StatsSink + 'static) -> Self {
+        self.stats_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Run `gc` on the given interval while a job server is using this storage
+    ///
+    /// By default, `gc` is never run automatically.
+    pub fn with_gc_interval(mut self, interval: Duration) -> Self {
+        self.gc_interval = Some(interval);
+        self
+    }
+
+    /// Keep completed idempotency keys for the given time
+    ///
+    /// By default, completed keys are kept for one day.
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention =
+            TimeDelta::from_std(retention).unwrap_or_else(|_| TimeDelta::max_value());
+        self
+    }
+
+    /// Clean up data that is no longer needed, and flush the database to disk
+    ///
+    /// Completed idempotency keys older than the retention window are deleted, since they no
+    /// longer prevent anything from running. Sled reclaims the space of deleted entries in the
+    /// background once their pages are rewritten, and flushing lets it do so sooner. This doesn't
+    /// lock any queue, so jobs keep being handed out while it runs.
+    ///
+    /// ```rust
+    /// use std::{thread, time::Duration};
+    ///
+    /// use background_jobs_core::{NewJobInfo, ReturnJobInfo, Storage};
+    /// use background_jobs_sled_storage::SledStorage;
+    /// use serde_json::json;
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// fn main() -> Result<(), failure::Error> {
+    ///     let db = Db::start(ConfigBuilder::new().temporary(true).build())?;
+    ///     let mut storage =
+    ///         SledStorage::new(db)?.with_idempotency_retention(Duration::from_millis(300));
+    ///
+    ///     let keys = (0..100).map(|i| format!("import-{}", i)).collect::<Vec<_>>();
+    ///     for key in &keys {
+    ///         let job = NewJobInfo::builder("ImportProcessor", "default")
+    ///             .args(json!([]))
+    ///             .idempotency_key(key)
+    ///             .build()?;
+    ///         storage.new_job(job)?;
+    ///     }
+    ///
+    ///     // Completed jobs are deleted, and only their keys are kept
+    ///     while let Some(job) = storage.request_job("default", 1)? {
+    ///         storage.return_job(ReturnJobInfo::pass(job.id()))?;
+    ///     }
+    ///     assert!(storage.list_jobs()?.is_empty());
+    ///     for key in &keys {
+    ///         assert!(storage.fetch_completed_key("default", key)?.is_some());
+    ///     }
+    ///
+    ///     // The key completed last is still within the retention window, so it is kept
+    ///     storage.gc()?;
+    ///     assert!(storage.fetch_completed_key("default", &keys[99])?.is_some());
+    ///
+    ///     thread::sleep(Duration::from_millis(350));
+    ///     storage.gc()?;
+    ///     for key in &keys {
+    ///         assert!(storage.fetch_completed_key("default", key)?.is_none());
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn gc(&self) -> Result<()> {
+        let cutoff = clock::now() - self.idempotency_retention();
+
+        let expired = self
+            .completed
+            .iter()
+            .filter_map(|res| res.ok())
+            .filter(|(_, (_, completed_at))| *completed_at < cutoff)
+            .filter_map(|(key, _)| String::from_utf8(key).ok())
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            // The key may have been completed again since it was read
+            self.completed.fetch_and_update(&key, |opt| {
+                opt.filter(|(_, completed_at)| *completed_at >= cutoff)
+            })?;
+        }
+
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    /// Rebuild the index of queued jobs from the queue tree
+    ///
+    /// The index only holds data taken from the queued jobs themselves, so it is rebuilt when the
+    /// storage is opened, which also covers databases written by versions that didn't keep it.
+    /// It reads every queued job, so encrypted jobs can only be indexed once the key is set.
+    fn index_queues(&self) -> Result<()> {
+        self.queue_index.clear()?;
+
+        for res in self.queue.iter() {
+            let (key, queue) = res?;
+
+            if let Some(job) = self.jobinfo.get(&key)? {
+                self.queue_index
+                    .set(queue_index_key(&queue, job.id()), queued(&job))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take a job out of the queue it is in, if it is in one
+    fn unqueue(&self, id: u64) -> Result<()> {
+        if let Some(queue) = self.queue.del(job_key(id))? {
+            self.queue_index.del(queue_index_key(&queue, id))?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_unique(&self, key: &str, id: u64) -> Result<()> {
+        self.unique.fetch_and_update(key, |opt| match opt {
+            Some(existing) if existing == id => None,
+            _ => opt,
+        })?;
+
+        Ok(())
     }
 
     fn lock_queue<T, F>(&self, queue: &str, f: F) -> Result<T>
     where
         F: Fn() -> Result<T>,
     {
-        let id = self.db.generate_id()?;
-
-        let mut prev;
-        while {
-            prev = self.lock.fetch_and_update(queue, move |opt| match opt {
-                Some(_) => opt,
-                None => Some(id),
-            })?;
-
-            prev.is_some()
-        } {}
+        let guard = LockGuard::acquire(&*self.lock, queue)?;
 
         let res = (f)();
 
-        self.lock.fetch_and_update(queue, |_| None)?;
+        guard.release()?;
 
         res
     }
 }
 
-fn job_key(id: u64) -> String {
-    format!("job-{}", id)
+/// Job IDs are stored as fixed-width big-endian bytes, so trees keyed by job sort by ID
+fn job_key(id: u64) -> [u8; 8] {
+    id.to_be_bytes()
 }
 
-fn runner_key(runner_id: u64) -> String {
-    format!("runner-{}", runner_id)
+/// Queued jobs are indexed under their queue's name, so the jobs of one queue can be scanned alone
+fn queue_prefix(queue: &str) -> Vec<u8> {
+    let mut prefix = queue.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+fn queue_index_key(queue: &str, id: u64) -> Vec<u8> {
+    let mut key = queue_prefix(queue);
+    key.extend_from_slice(&job_key(id));
+    key
+}
+
+/// The ID at the end of a queue index key
+fn index_id(key: &[u8]) -> u64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&key[key.len() - 8..]);
+    u64::from_be_bytes(id)
+}
+
+fn queued(job: &JobInfo) -> Queued {
+    (job.priority(), job.ready_at())
+}
+
+fn unique_key(queue: &str, key: &str) -> String {
+    format!("unique-{}-{}", queue, key)
+}
+
+fn global_key(key: &str) -> String {
+    format!("global-{}", key)
+}
+
+fn completed_key(queue: &str, key: &str) -> String {
+    format!("completed-{}-{}", queue, key)
+}
+
+fn runner_key(runner_id: u64) -> [u8; 8] {
+    runner_id.to_be_bytes()
+}
+
+/// The new key for a key written by versions that stored IDs as strings with the given prefix
+fn legacy_key(key: &[u8], prefix: &str) -> Option<Vec<u8>> {
+    let id = std::str::from_utf8(key)
+        .ok()?
+        .strip_prefix(prefix)?
+        .parse::<u64>()
+        .ok()?;
+
+    Some(id.to_be_bytes().to_vec())
 }
 
 fn open_tree<T>(db: &sled::Db, name: &str) -> sled::Result<Tree<T>>
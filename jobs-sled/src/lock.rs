@@ -0,0 +1,165 @@
+use std::{thread, time::Duration};
+
+use crate::{error::Result, sled_wrappers::Tree};
+
+/// How long a thread waits before its first retry when a queue's lock is taken
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+/// The longest a thread waits between two attempts at taking a queue's lock
+const MAX_WAIT: Duration = Duration::from_millis(16);
+
+/// A mechanism for guarding access to a queue while a job is being fetched from it
+///
+/// `SledStorage` holds a queue's lock for the duration of `fetch_job_from_queue`, which prevents
+/// two workers from being handed the same job. The default implementation, `SledLock`, only
+/// coordinates threads within a single process. Applications sharing a database between processes
+/// can provide their own implementation backed by a file lock or an external coordinator.
+pub trait QueueLock: Send + Sync {
+    /// Block until the lock for the given queue has been acquired
+    fn lock(&self, queue: &str) -> Result<()>;
+
+    /// Release the lock for the given queue
+    fn unlock(&self, queue: &str) -> Result<()>;
+}
+
+/// The default lock, backed by a tree in the Sled database
+///
+/// A thread finding a queue locked sleeps before trying again, starting at 1ms and doubling up
+/// to 16ms, so contending threads don't keep a core busy while they wait.
+///
+/// ```rust
+/// use std::{
+///     sync::{
+///         atomic::{AtomicUsize, Ordering},
+///         Arc,
+///     },
+///     thread,
+/// };
+///
+/// use background_jobs_sled_storage::{QueueLock, SledLock};
+/// use sled::{ConfigBuilder, Db};
+///
+/// fn main() -> Result<(), failure::Error> {
+///     let db = Db::start(ConfigBuilder::new().temporary(true).build())?;
+///     let lock = SledLock::open(&db)?;
+///     let holders = Arc::new(AtomicUsize::new(0));
+///
+///     let threads: Vec<_> = (0..16)
+///         .map(|_| {
+///             let (lock, holders) = (lock.clone(), holders.clone());
+///
+///             thread::spawn(move || {
+///                 for _ in 0..20 {
+///                     lock.lock("default").unwrap();
+///                     assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+///                     holders.fetch_sub(1, Ordering::SeqCst);
+///                     lock.unlock("default").unwrap();
+///                 }
+///             })
+///         })
+///         .collect();
+///
+///     for thread in threads {
+///         thread.join().unwrap();
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct SledLock {
+    tree: Tree<u64>,
+    db: sled::Db,
+}
+
+impl SledLock {
+    /// Open the lock kept in the given database, which is the one `SledStorage::new` uses
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree("background-jobs-lock").map(Tree::new)?;
+
+        Ok(SledLock {
+            tree,
+            db: db.clone(),
+        })
+    }
+}
+
+impl QueueLock for SledLock {
+    fn lock(&self, queue: &str) -> Result<()> {
+        let id = self.db.generate_id()?;
+        let mut wait = MIN_WAIT;
+
+        loop {
+            let prev = self.tree.fetch_and_update(queue, move |opt| match opt {
+                Some(_) => opt,
+                None => Some(id),
+            })?;
+
+            if prev.is_none() {
+                return Ok(());
+            }
+
+            thread::sleep(wait);
+            wait = (wait * 2).min(MAX_WAIT);
+        }
+    }
+
+    fn unlock(&self, queue: &str) -> Result<()> {
+        self.tree.fetch_and_update(queue, |_| None)?;
+
+        Ok(())
+    }
+}
+
+/// A lock that does nothing
+///
+/// This is only safe to use when the caller guarantees that a queue is never fetched from
+/// concurrently, for example when a single server thread owns the storage.
+#[derive(Clone, Debug, Default)]
+pub struct NoopLock;
+
+/// Holds a queue's lock, releasing it when dropped unless it was released already
+///
+/// This makes sure a queue is unlocked even when the code holding its lock panics.
+pub(crate) struct LockGuard<'a> {
+    lock: &'a dyn QueueLock,
+    queue: &'a str,
+    released: bool,
+}
+
+impl<'a> LockGuard<'a> {
+    /// Block until the lock for the given queue has been acquired
+    pub(crate) fn acquire(lock: &'a dyn QueueLock, queue: &'a str) -> Result<Self> {
+        lock.lock(queue)?;
+
+        Ok(LockGuard {
+            lock,
+            queue,
+            released: false,
+        })
+    }
+
+    /// Release the lock, reporting whether that failed
+    pub(crate) fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.lock.unlock(self.queue)
+    }
+}
+
+impl<'a> Drop for LockGuard<'a> {
+    fn drop(&mut self) {
+        if !self.released {
+            // Errors can't be returned from here, and the panic unwinding is more useful anyway
+            let _ = self.lock.unlock(self.queue);
+        }
+    }
+}
+
+impl QueueLock for NoopLock {
+    fn lock(&self, _: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&self, _: &str) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,20 +1,45 @@
 use std::{marker::PhantomData, sync::Arc};
 
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
 use crate::{Error, Result};
 
 #[derive(Clone)]
-pub struct Tree<T>(Arc<sled::Tree>, PhantomData<T>);
+pub struct Tree<T>(Arc<sled::Tree>, Codec, PhantomData<T>);
 
 impl<T> Tree<T>
 where
     T: serde::de::DeserializeOwned + serde::ser::Serialize,
 {
     pub(crate) fn new(t: Arc<sled::Tree>) -> Self {
-        Tree(t, PhantomData)
+        Tree(t, Codec::default(), PhantomData)
+    }
+
+    /// Encrypt the values written to this tree from now on, and decrypt the values read from it
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypt(&mut self, key: &[u8; 32]) {
+        self.1 = Codec {
+            cipher: Some(Arc::new(ChaCha20Poly1305::new(Key::from_slice(key)))),
+        };
     }
 
     pub(crate) fn iter(&self) -> Iter<T> {
-        Iter::new(self.0.iter())
+        Iter::new(self.0.iter(), &self.1)
+    }
+
+    /// Iterate over the entries whose key starts with the given prefix, in key order
+    pub(crate) fn scan_prefix<'a>(
+        &'a self,
+        prefix: &'a [u8],
+    ) -> impl Iterator<Item = Result<(Vec<u8>, T)>> + 'a {
+        Iter::new(self.0.scan(prefix), &self.1).take_while(move |res| match res {
+            Ok((key, _)) => key.starts_with(prefix),
+            Err(_) => true,
+        })
     }
 
     pub(crate) fn get<K>(&self, key: K) -> Result<Option<T>>
@@ -22,62 +47,145 @@ where
         K: AsRef<[u8]>,
     {
         match self.0.get(key)? {
-            Some(vec) => serde_json::from_slice(&vec)
-                .map_err(|_| Error::Deserialize)
-                .map(Some),
+            Some(vec) => self.1.decode(&vec).map(Some),
             None => Ok(None),
         }
     }
 
-    pub(crate) fn set(&self, key: &str, value: T) -> Result<Option<T>> {
-        let vec = serde_json::to_vec(&value).map_err(|_| Error::Serialize)?;
+    pub(crate) fn set<K>(&self, key: K, value: T) -> Result<Option<T>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let vec = self.1.encode(&value)?;
 
         Ok(self.0.set(key, vec)?.map(move |_| value))
     }
 
-    pub(crate) fn del(&self, key: &str) -> Result<Option<T>> {
+    pub(crate) fn del<K>(&self, key: K) -> Result<Option<T>>
+    where
+        K: AsRef<[u8]>,
+    {
         match self.0.del(key)? {
-            Some(vec) => serde_json::from_slice(&vec)
-                .map_err(|_| Error::Deserialize)
-                .map(Some),
+            Some(vec) => self.1.decode(&vec).map(Some),
             None => Ok(None),
         }
     }
 
-    pub(crate) fn fetch_and_update<F>(&self, key: &str, f: F) -> Result<Option<T>>
+    pub(crate) fn clear(&self) -> Result<()> {
+        Ok(self.0.clear()?)
+    }
+
+    pub(crate) fn fetch_and_update<K, F>(&self, key: K, f: F) -> Result<Option<T>>
     where
+        K: AsRef<[u8]>,
         F: Fn(Option<T>) -> Option<T>,
     {
+        let codec = &self.1;
+
         let final_opt = self.0.fetch_and_update(key, |opt| {
             let new_opt = match opt {
-                Some(vec) => {
-                    let t = serde_json::from_slice(&vec).map(Some).unwrap_or(None);
-
-                    (f)(t)
-                }
+                Some(vec) => (f)(codec.decode(vec).ok()),
                 None => (f)(None),
             };
 
             match new_opt {
-                Some(t) => serde_json::to_vec(&t).map(Some).unwrap_or(None),
+                Some(t) => codec.encode(&t).ok(),
                 None => None,
             }
         })?;
 
         match final_opt {
-            Some(vec) => serde_json::from_slice(&vec)
-                .map_err(|_| Error::Deserialize)
-                .map(Some),
+            Some(vec) => self.1.decode(&vec).map(Some),
             None => Ok(None),
         }
     }
+
+    /// Move every entry whose key is mapped to a new key by `f`, returning how many were moved
+    ///
+    /// Values are moved as they are stored, so this works on encrypted trees without the key.
+    pub(crate) fn rekey<F>(&self, f: F) -> Result<usize>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>>,
+    {
+        let moves = self
+            .0
+            .iter()
+            .filter_map(|res| res.ok())
+            .filter_map(|(old, value)| f(&old).map(|new| (old, new, value.to_vec())))
+            .collect::<Vec<_>>();
+
+        for (old, new, value) in &moves {
+            self.0.set(new.as_slice(), value.clone())?;
+            self.0.del(old.as_slice())?;
+        }
+
+        Ok(moves.len())
+    }
 }
 
-pub(crate) struct Iter<'a, T>(sled::Iter<'a>, PhantomData<T>);
+/// Turns values into the bytes stored in a tree, and back
+#[derive(Clone, Default)]
+struct Codec {
+    #[cfg(feature = "encryption")]
+    cipher: Option<Arc<ChaCha20Poly1305>>,
+}
+
+impl Codec {
+    fn encode<T>(&self, value: &T) -> Result<Vec<u8>>
+    where
+        T: serde::ser::Serialize,
+    {
+        let vec = serde_json::to_vec(value).map_err(|_| Error::Serialize)?;
+
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(ref cipher) = self.cipher {
+                // Each value gets a fresh nonce, which is stored in front of the ciphertext
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = cipher
+                    .encrypt(&nonce, vec.as_slice())
+                    .map_err(|_| Error::Encrypt)?;
+
+                let mut stored = nonce.to_vec();
+                stored.extend(ciphertext);
+                return Ok(stored);
+            }
+        }
+
+        Ok(vec)
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(ref cipher) = self.cipher {
+                const NONCE_LEN: usize = 12;
+
+                if bytes.len() < NONCE_LEN {
+                    return Err(Error::Decrypt);
+                }
+
+                let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+                let vec = cipher
+                    .decrypt(Nonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| Error::Decrypt)?;
+
+                return serde_json::from_slice(&vec).map_err(|_| Error::Deserialize);
+            }
+        }
+
+        serde_json::from_slice(bytes).map_err(|_| Error::Deserialize)
+    }
+}
+
+pub(crate) struct Iter<'a, T>(sled::Iter<'a>, Codec, PhantomData<T>);
 
 impl<'a, T> Iter<'a, T> {
-    fn new(i: sled::Iter<'a>) -> Self {
-        Iter(i, PhantomData)
+    fn new(i: sled::Iter<'a>, codec: &Codec) -> Self {
+        Iter(i, codec.clone(), PhantomData)
     }
 }
 
@@ -88,12 +196,11 @@ where
     type Item = Result<(Vec<u8>, T)>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let codec = &self.1;
+
         self.0.next().map(|res| {
-            res.map_err(Error::from).and_then(|(k, v)| {
-                serde_json::from_slice(&v)
-                    .map(|item| (k, item))
-                    .map_err(|_| Error::Deserialize)
-            })
+            res.map_err(Error::from)
+                .and_then(|(k, v)| codec.decode(&v).map(|item| (k, item)))
         })
     }
 }
@@ -103,12 +210,11 @@ where
     T: serde::de::DeserializeOwned,
 {
     fn next_back(&mut self) -> Option<Self::Item> {
+        let codec = &self.1;
+
         self.0.next_back().map(|res| {
-            res.map_err(Error::from).and_then(|(k, v)| {
-                serde_json::from_slice(&v)
-                    .map(|item| (k, item))
-                    .map_err(|_| Error::Deserialize)
-            })
+            res.map_err(Error::from)
+                .and_then(|(k, v)| codec.decode(&v).map(|item| (k, item)))
         })
     }
 }
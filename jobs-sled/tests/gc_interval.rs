@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+use actix::System;
+use background_jobs_actix::ServerConfig;
+use background_jobs_core::{NewJobInfo, ReturnJobInfo, Storage};
+use background_jobs_sled_storage::SledStorage;
+use futures::{Future, Stream};
+use serde_json::json;
+use sled::{ConfigBuilder, Db};
+use tokio_timer::Interval;
+
+#[test]
+fn the_server_runs_gc_on_the_configured_interval() {
+    let db = Db::start(ConfigBuilder::new().temporary(true).build()).unwrap();
+    let mut storage = SledStorage::new(db)
+        .unwrap()
+        .with_idempotency_retention(Duration::from_millis(100))
+        .with_gc_interval(Duration::from_millis(200));
+
+    let job = NewJobInfo::builder("ImportProcessor", "default")
+        .args(json!([]))
+        .idempotency_key("import")
+        .build()
+        .unwrap();
+    storage.new_job(job).unwrap();
+
+    let job = storage.request_job("default", 1).unwrap().unwrap();
+    storage.return_job(ReturnJobInfo::pass(job.id())).unwrap();
+    assert!(storage
+        .fetch_completed_key("default", "import")
+        .unwrap()
+        .is_some());
+
+    let sys = System::new("gc-interval");
+    ServerConfig::new(storage.clone()).thread_count(1).start();
+
+    // Nothing but the server's maintenance deletes the expired key
+    let started = Instant::now();
+    let mut watched = storage.clone();
+    actix::spawn(
+        Interval::new_interval(Duration::from_millis(10))
+            .map_err(|_| ())
+            .skip_while(move |_| {
+                let gone = watched
+                    .fetch_completed_key("default", "import")
+                    .unwrap()
+                    .is_none();
+                Ok(!gone && started.elapsed() < Duration::from_secs(5))
+            })
+            .into_future()
+            .then(|_| {
+                System::current().stop();
+                Ok(())
+            }),
+    );
+    sys.run().unwrap();
+
+    assert!(storage
+        .fetch_completed_key("default", "import")
+        .unwrap()
+        .is_none());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
@@ -132,13 +132,15 @@
 //!
 //!     // The number of times background-jobs should try to retry a job before giving up
 //!     //
-//!     // Jobs can optionally override this value
-//!     const MAX_RETRIES: MaxRetries = MaxRetries::Count(1);
+//!     // Jobs can optionally override this value, and when it's None, the server's default is
+//!     // used
+//!     const MAX_RETRIES: Option<MaxRetries> = Some(MaxRetries::Count(1));
 //!
 //!     // The logic to determine how often to retry this job if it fails
 //!     //
-//!     // Jobs can optionally override this value
-//!     const BACKOFF_STRATEGY: Backoff = Backoff::Exponential(2);
+//!     // Jobs can optionally override this value, and when it's None, the server's default is
+//!     // used
+//!     const BACKOFF_STRATEGY: Option<Backoff> = Some(Backoff::Exponential(2));
 //! }
 //! ```
 //!
@@ -150,7 +152,10 @@
 //! `background-jobs-actix` on it's own doesn't have a mechanism for storing worker state. This
 //! can be implemented manually by implementing the `Storage` trait from `background-jobs-core`,
 //! or the `background-jobs-sled-storage` crate can be used to provide a
-//! [Sled](https://github.com/spacejam/sled)-backed jobs store.
+//! [Sled](https://github.com/spacejam/sled)-backed jobs store. Applications running several
+//! processes can share a [Redis](https://redis.io)-backed jobs store from the
+//! `background-jobs-redis-storage` crate instead, which is also available through the
+//! `background-jobs-redis-storage` feature.
 //!
 //! With that out of the way, back to the examples:
 //!
@@ -178,9 +183,9 @@
 //!     worker_config.start(queue_handle.clone());
 //!
 //!     // Queue our jobs
-//!     queue_handle.queue::<MyProcessor>(MyJob::new(1, 2))?;
-//!     queue_handle.queue::<MyProcessor>(MyJob::new(3, 4))?;
-//!     queue_handle.queue::<MyProcessor>(MyJob::new(5, 6))?;
+//!     queue_handle.queue_fire_and_forget(MyJob::new(1, 2))?;
+//!     queue_handle.queue_fire_and_forget(MyJob::new(3, 4))?;
+//!     queue_handle.queue_fire_and_forget(MyJob::new(5, 6))?;
 //!
 //!     // Block on Actix
 //!     sys.run()?;
@@ -196,15 +201,43 @@
 //! If you want to create your own jobs processor based on this idea, you can depend on the
 //! `background-jobs-core` crate, which provides the Processor and Job traits, as well as some
 //! other useful types for implementing a jobs processor and job store.
+//!
+//! #### Metrics
+//! The `background-jobs-metrics` feature adds a `metrics` module, from the
+//! `background-jobs-metrics` crate, with a `MetricsSink` that keeps
+//! [Prometheus](https://prometheus.io) metrics of the pending, running, completed, and dead jobs
+//! up to date. Give it to a storage with `with_stats_sink`, and serve the text returned by
+//! `Metrics::gather` on `/metrics`.
 
 pub use background_jobs_core::{
-    memory_storage, Backoff, Job, JobStat, MaxRetries, Processor, Stats,
+    clock, memory_storage, run_now, AtomicStats, Backoff, CancellationToken, Checkpoint,
+    DispatchOrder, EnqueueOutcome, Job, JobResult, JobStat, MaxRetries, MissingProcessorPolicy,
+    Processor, ProcessorStat, QueueStat, RetryAfter, RetryWith, Stats, StatsEvent, StatsSink,
+    StdError, WaitStat,
 };
 
+#[cfg(feature = "jsonschema")]
+pub use background_jobs_core::InvalidArgs;
+
 #[cfg(feature = "background-jobs-actix")]
-pub use background_jobs_actix::{Every, QueueHandle, ServerConfig, WorkerConfig};
+pub use background_jobs_actix::{
+    register_all, CatchUp, ConfigIssue, Every, EveryHandle, QueueHandle, ScheduleDescriptor,
+    ServerBusy, ServerConfig, ShutdownStrategy, Utilization, WorkerCacheStats, WorkerConfig,
+};
 
 #[cfg(feature = "background-jobs-sled-storage")]
 pub mod sled_storage {
-    pub use background_jobs_sled_storage::{Error, SledStorage as Storage};
+    pub use background_jobs_sled_storage::{
+        Error, NoopLock, QueueLock, SledLock, SledStorage as Storage,
+    };
+}
+
+#[cfg(feature = "background-jobs-redis-storage")]
+pub mod redis_storage {
+    pub use background_jobs_redis_storage::{Error, RedisStorage as Storage};
+}
+
+#[cfg(feature = "background-jobs-metrics")]
+pub mod metrics {
+    pub use background_jobs_metrics::{Error, Metrics, MetricsSink};
 }